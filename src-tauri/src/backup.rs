@@ -0,0 +1,502 @@
+//! Backups of the whole data store.
+//!
+//! An archive is a directory `backups/wishmaster-YYYYMMDD-HHMMSS-<kind>/` with
+//! `wishmaster.db` (taken with SQLite's online backup API, safe while the app runs),
+//! a copy of `voice_recordings/` and `manifest.json`. Export files are only listed
+//! in the manifest, they can be regenerated from the database. On an encrypted
+//! install the archive is encrypted with the same data key.
+
+use rusqlite::OpenFlags;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::database;
+use crate::encryption;
+use crate::migrations;
+
+const BACKUPS_DIR: &str = "backups";
+const DB_FILE: &str = "wishmaster.db";
+const VOICE_DIR: &str = "voice_recordings";
+const EXPORTS_DIR: &str = "exports";
+const MANIFEST_FILE: &str = "manifest.json";
+/// Bumped when the archive layout changes
+const FORMAT_VERSION: u32 = 1;
+/// How often the scheduler checks whether a snapshot is due
+const SCHEDULER_TICK: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupKind {
+    /// Created by the user
+    Manual,
+    /// Created by the scheduler; only these are pruned by the retention count
+    Scheduled,
+    /// Safety copy of the current data taken right before a restore
+    PreRestore,
+}
+
+impl BackupKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BackupKind::Manual => "manual",
+            BackupKind::Scheduled => "scheduled",
+            BackupKind::PreRestore => "pre-restore",
+        }
+    }
+}
+
+/// A file inside the archive (or, for exports, inside the app data directory)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupFile {
+    pub path: String,
+    pub size: u64,
+    pub modified: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupManifest {
+    pub format_version: u32,
+    pub kind: BackupKind,
+    pub created_at: i64,
+    pub app_version: String,
+    pub schema_version: u32,
+    pub database: BackupFile,
+    pub voice_recordings: Vec<BackupFile>,
+    /// Listed only, not copied
+    pub exports: Vec<BackupFile>,
+    /// Database and recordings are encrypted with the install's data key
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupInfo {
+    /// Archive directory name, used to restore or delete it
+    pub name: String,
+    pub path: String,
+    /// Size of the database and the copied recordings in bytes
+    pub size: u64,
+    pub manifest: BackupManifest,
+}
+
+pub fn backups_dir(app_dir: &Path) -> PathBuf {
+    app_dir.join(BACKUPS_DIR)
+}
+
+/// Create a new archive of the live data
+pub fn create_backup(app_dir: &Path, kind: BackupKind) -> Result<BackupInfo, String> {
+    let archive = new_archive_dir(&backups_dir(app_dir), kind)?;
+
+    match write_archive(app_dir, &archive, kind) {
+        Ok(manifest) => Ok(backup_info(&archive, manifest)),
+        Err(e) => {
+            // Never leave a half-written archive that looks restorable
+            let _ = std::fs::remove_dir_all(&archive);
+            Err(e)
+        }
+    }
+}
+
+fn write_archive(app_dir: &Path, archive: &Path, kind: BackupKind) -> Result<BackupManifest, String> {
+    let db_path = archive.join(DB_FILE);
+    database::backup_to_file(&db_path).map_err(|e| format!("Не удалось скопировать базу данных: {}", e))?;
+
+    let schema_version = {
+        let conn = database::open_connection(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| e.to_string())?;
+        migrations::schema_version(&conn).map_err(|e| e.to_string())?
+    };
+
+    let voice_recordings = copy_files(&app_dir.join(VOICE_DIR), &archive.join(VOICE_DIR), VOICE_DIR)?;
+    let exports = list_files(&app_dir.join(EXPORTS_DIR), EXPORTS_DIR)?;
+
+    let manifest = BackupManifest {
+        format_version: FORMAT_VERSION,
+        kind,
+        created_at: now_millis(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version,
+        database: file_entry(&db_path, DB_FILE.to_string())?,
+        voice_recordings,
+        exports,
+        encrypted: encryption::is_unlocked(),
+    };
+
+    let json = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+    std::fs::write(archive.join(MANIFEST_FILE), json).map_err(|e| e.to_string())?;
+
+    Ok(manifest)
+}
+
+/// All readable archives, newest first
+pub fn list_backups(app_dir: &Path) -> Result<Vec<BackupInfo>, String> {
+    let dir = backups_dir(app_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups: Vec<BackupInfo> = std::fs::read_dir(&dir)
+        .map_err(|e| e.to_string())?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .filter_map(|path| read_manifest(&path).ok().map(|manifest| backup_info(&path, manifest)))
+        .collect();
+
+    backups.sort_by_key(|b| std::cmp::Reverse(b.manifest.created_at));
+    Ok(backups)
+}
+
+/// Check that an archive is complete and its database is intact and not newer than this app
+pub fn validate_backup(archive: &Path) -> Result<BackupManifest, String> {
+    let manifest = read_manifest(archive)?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err("Резервная копия создана более новой версией приложения".to_string());
+    }
+
+    if manifest.encrypted != encryption::is_unlocked() {
+        return Err(if manifest.encrypted {
+            "Резервная копия зашифрована, а текущая база — нет".to_string()
+        } else {
+            "Резервная копия не зашифрована, её нельзя восстановить в зашифрованную базу".to_string()
+        });
+    }
+
+    let db_path = archive.join(DB_FILE);
+    let size = std::fs::metadata(&db_path)
+        .map_err(|_| "В резервной копии нет файла базы данных".to_string())?
+        .len();
+    if size != manifest.database.size {
+        return Err("Файл базы данных в резервной копии повреждён (размер не совпадает)".to_string());
+    }
+
+    // Not read-only: the FTS5 integrity check needs a writable handle even though it changes nothing
+    let conn = database::open_connection(&db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+        .map_err(|e| format!("Не удалось открыть базу из резервной копии: {}", e))?;
+    let integrity: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .map_err(|e| format!("База в резервной копии повреждена: {}", e))?;
+    if integrity != "ok" {
+        return Err(format!("База в резервной копии повреждена: {}", integrity));
+    }
+    let schema_version = migrations::schema_version(&conn).map_err(|e| e.to_string())?;
+    if schema_version > migrations::latest_version() {
+        return Err("Резервная копия создана более новой версией приложения".to_string());
+    }
+    let core_tables: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name IN ('sessions', 'messages')",
+        [],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+    if core_tables != 2 {
+        return Err("Файл в резервной копии не является базой Wishmaster".to_string());
+    }
+
+    for recording in &manifest.voice_recordings {
+        if !archive.join(&recording.path).is_file() {
+            return Err(format!("В резервной копии нет файла {}", recording.path));
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// Restore an archive: validate it, save the current data as a pre-restore archive,
+/// swap the database contents and put the voice recordings back.
+/// Returns the pre-restore archive so the user can undo.
+pub fn restore_backup(app_dir: &Path, name: &str) -> Result<BackupInfo, String> {
+    let archive = archive_path(app_dir, name)?;
+    let manifest = validate_backup(&archive)?;
+
+    let safety = create_backup(app_dir, BackupKind::PreRestore)?;
+
+    database::restore_from_file(&archive.join(DB_FILE))
+        .map_err(|e| format!("Не удалось восстановить базу данных: {}", e))?;
+
+    let voice_dir = app_dir.join(VOICE_DIR);
+    std::fs::create_dir_all(&voice_dir).map_err(|e| e.to_string())?;
+    for recording in &manifest.voice_recordings {
+        let file_name = Path::new(&recording.path).file_name()
+            .ok_or_else(|| format!("Некорректный путь в манифесте: {}", recording.path))?;
+        std::fs::copy(archive.join(&recording.path), voice_dir.join(file_name))
+            .map_err(|e| e.to_string())?;
+    }
+
+    println!("Restored backup {} (pre-restore copy: {})", name, safety.name);
+    Ok(safety)
+}
+
+pub fn delete_backup(app_dir: &Path, name: &str) -> Result<(), String> {
+    let archive = archive_path(app_dir, name)?;
+    std::fs::remove_dir_all(&archive).map_err(|e| e.to_string())
+}
+
+/// Delete scheduled snapshots beyond the newest `keep`. Returns how many were deleted.
+pub fn prune_scheduled(app_dir: &Path, keep: usize) -> Result<usize, String> {
+    let expired: Vec<BackupInfo> = list_backups(app_dir)?
+        .into_iter()
+        .filter(|b| b.manifest.kind == BackupKind::Scheduled)
+        .skip(keep)
+        .collect();
+
+    for backup in &expired {
+        std::fs::remove_dir_all(&backup.path).map_err(|e| e.to_string())?;
+    }
+    Ok(expired.len())
+}
+
+/// Start the background thread that takes scheduled snapshots
+/// (`Settings::backup_interval_hours`, 0 = off) and applies the retention count
+pub fn start_scheduler(app_dir: PathBuf) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(SCHEDULER_TICK);
+        if let Err(e) = run_scheduled_snapshot(&app_dir) {
+            eprintln!("Scheduled backup failed: {}", e);
+        }
+    });
+}
+
+fn run_scheduled_snapshot(app_dir: &Path) -> Result<(), String> {
+    // Still locked (encrypted install waiting for the passphrase)
+    if !database::is_initialized() {
+        return Ok(());
+    }
+    let settings = database::get_settings().map_err(|e| e.to_string())?;
+    let last = list_backups(app_dir)?
+        .into_iter()
+        .find(|b| b.manifest.kind == BackupKind::Scheduled)
+        .map(|b| b.manifest.created_at);
+
+    if !snapshot_due(last, now_millis(), settings.backup_interval_hours) {
+        return Ok(());
+    }
+
+    let backup = create_backup(app_dir, BackupKind::Scheduled)?;
+    println!("Scheduled backup created: {}", backup.name);
+    prune_scheduled(app_dir, settings.backup_keep.max(1) as usize)?;
+    Ok(())
+}
+
+fn snapshot_due(last: Option<i64>, now: i64, interval_hours: u32) -> bool {
+    if interval_hours == 0 {
+        return false;
+    }
+    match last {
+        Some(last) => now - last >= interval_hours as i64 * 3_600_000,
+        None => true,
+    }
+}
+
+/// Resolve an archive name, refusing anything that would leave the backups directory
+fn archive_path(app_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let valid = !name.is_empty()
+        && !name.contains(['/', '\\'])
+        && name != "."
+        && name != "..";
+    if !valid {
+        return Err(format!("Некорректное имя резервной копии: {}", name));
+    }
+
+    let path = backups_dir(app_dir).join(name);
+    if !path.is_dir() {
+        return Err(format!("Резервная копия не найдена: {}", name));
+    }
+    Ok(path)
+}
+
+fn new_archive_dir(backups: &Path, kind: BackupKind) -> Result<PathBuf, String> {
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let base = format!("wishmaster-{}-{}", stamp, kind.as_str());
+
+    let mut path = backups.join(&base);
+    let mut n = 2;
+    while path.exists() {
+        path = backups.join(format!("{}-{}", base, n));
+        n += 1;
+    }
+
+    std::fs::create_dir_all(&path).map_err(|e| format!("Не удалось создать папку резервной копии: {}", e))?;
+    Ok(path)
+}
+
+fn read_manifest(archive: &Path) -> Result<BackupManifest, String> {
+    let json = std::fs::read_to_string(archive.join(MANIFEST_FILE))
+        .map_err(|_| "В резервной копии нет манифеста".to_string())?;
+    serde_json::from_str(&json).map_err(|e| format!("Манифест резервной копии повреждён: {}", e))
+}
+
+fn backup_info(archive: &Path, manifest: BackupManifest) -> BackupInfo {
+    let size = manifest.database.size + manifest.voice_recordings.iter().map(|f| f.size).sum::<u64>();
+    BackupInfo {
+        name: archive.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+        path: archive.to_string_lossy().to_string(),
+        size,
+        manifest,
+    }
+}
+
+/// Copy the regular files of `from` into `to`; entries are recorded as `prefix/<name>`
+fn copy_files(from: &Path, to: &Path, prefix: &str) -> Result<Vec<BackupFile>, String> {
+    let files = list_files(from, prefix)?;
+    if files.is_empty() {
+        return Ok(files);
+    }
+
+    std::fs::create_dir_all(to).map_err(|e| e.to_string())?;
+    for file in &files {
+        let name = Path::new(&file.path).file_name().unwrap_or_default();
+        std::fs::copy(from.join(name), to.join(name)).map_err(|e| e.to_string())?;
+    }
+    Ok(files)
+}
+
+/// Regular files directly inside `dir` (missing directory = no files)
+fn list_files(dir: &Path, prefix: &str) -> Result<Vec<BackupFile>, String> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.is_file() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            files.push(file_entry(&path, format!("{}/{}", prefix, name))?);
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn file_entry(path: &Path, relative: String) -> Result<BackupFile, String> {
+    let meta = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    let modified = meta.modified().ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    Ok(BackupFile { path: relative, size: meta.len(), modified })
+}
+
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// ==================== TESTS ====================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+    use tempfile::TempDir;
+
+    /// Hand-made archive with a migrated database and one recording
+    fn fake_archive(app_dir: &Path, name: &str, kind: BackupKind, created_at: i64) -> PathBuf {
+        let archive = backups_dir(app_dir).join(name);
+        std::fs::create_dir_all(archive.join(VOICE_DIR)).unwrap();
+
+        let db_path = archive.join(DB_FILE);
+        let mut conn = Connection::open(&db_path).unwrap();
+        migrations::run(&mut conn, &db_path).unwrap();
+        drop(conn);
+        std::fs::write(archive.join(VOICE_DIR).join("voice_1.webm"), b"webm").unwrap();
+
+        let manifest = BackupManifest {
+            format_version: FORMAT_VERSION,
+            kind,
+            created_at,
+            app_version: "1.0.0".to_string(),
+            schema_version: migrations::latest_version(),
+            database: file_entry(&db_path, DB_FILE.to_string()).unwrap(),
+            voice_recordings: vec![file_entry(
+                &archive.join(VOICE_DIR).join("voice_1.webm"),
+                format!("{}/voice_1.webm", VOICE_DIR),
+            ).unwrap()],
+            exports: vec![],
+            encrypted: false,
+        };
+        std::fs::write(archive.join(MANIFEST_FILE), serde_json::to_string(&manifest).unwrap()).unwrap();
+        archive
+    }
+
+    #[test]
+    fn test_snapshot_due() {
+        let hour = 3_600_000;
+        assert!(!snapshot_due(None, 10 * hour, 0), "interval 0 disables snapshots");
+        assert!(snapshot_due(None, 10 * hour, 24));
+        assert!(!snapshot_due(Some(hour), 10 * hour, 24));
+        assert!(snapshot_due(Some(hour), 25 * hour, 24));
+    }
+
+    #[test]
+    fn test_manifest_serialization() {
+        let dir = TempDir::new().unwrap();
+        let archive = fake_archive(dir.path(), "a", BackupKind::PreRestore, 5);
+
+        let json = std::fs::read_to_string(archive.join(MANIFEST_FILE)).unwrap();
+        assert!(json.contains("\"kind\":\"pre-restore\""));
+        assert!(json.contains("\"schemaVersion\""));
+        assert!(json.contains("\"voiceRecordings\""));
+    }
+
+    #[test]
+    fn test_validate_backup_accepts_complete_archive() {
+        let dir = TempDir::new().unwrap();
+        let archive = fake_archive(dir.path(), "ok", BackupKind::Manual, 1);
+
+        let manifest = validate_backup(&archive).expect("archive should be valid");
+        assert_eq!(manifest.voice_recordings.len(), 1);
+    }
+
+    #[test]
+    fn test_validate_backup_rejects_damaged_archives() {
+        let dir = TempDir::new().unwrap();
+
+        let archive = fake_archive(dir.path(), "garbage", BackupKind::Manual, 1);
+        let size = std::fs::metadata(archive.join(DB_FILE)).unwrap().len() as usize;
+        std::fs::write(archive.join(DB_FILE), vec![0x42u8; size]).unwrap();
+        assert!(validate_backup(&archive).is_err());
+
+        let archive = fake_archive(dir.path(), "truncated", BackupKind::Manual, 1);
+        std::fs::write(archive.join(DB_FILE), b"SQLite").unwrap();
+        assert!(validate_backup(&archive).unwrap_err().contains("размер"));
+
+        let archive = fake_archive(dir.path(), "no-recording", BackupKind::Manual, 1);
+        std::fs::remove_file(archive.join(VOICE_DIR).join("voice_1.webm")).unwrap();
+        assert!(validate_backup(&archive).is_err());
+
+        let archive = fake_archive(dir.path(), "no-manifest", BackupKind::Manual, 1);
+        std::fs::remove_file(archive.join(MANIFEST_FILE)).unwrap();
+        assert!(validate_backup(&archive).is_err());
+    }
+
+    #[test]
+    fn test_archive_path_stays_in_backups_dir() {
+        let dir = TempDir::new().unwrap();
+        fake_archive(dir.path(), "real", BackupKind::Manual, 1);
+
+        assert!(archive_path(dir.path(), "real").is_ok());
+        assert!(archive_path(dir.path(), "..").is_err());
+        assert!(archive_path(dir.path(), "../backups/real").is_err());
+        assert!(archive_path(dir.path(), "missing").is_err());
+    }
+
+    #[test]
+    fn test_prune_keeps_newest_scheduled_only() {
+        let dir = TempDir::new().unwrap();
+        fake_archive(dir.path(), "s1", BackupKind::Scheduled, 1);
+        fake_archive(dir.path(), "s2", BackupKind::Scheduled, 2);
+        fake_archive(dir.path(), "s3", BackupKind::Scheduled, 3);
+        fake_archive(dir.path(), "m1", BackupKind::Manual, 0);
+
+        assert_eq!(prune_scheduled(dir.path(), 2).unwrap(), 1);
+
+        let names: Vec<String> = list_backups(dir.path()).unwrap().into_iter().map(|b| b.name).collect();
+        assert_eq!(names, vec!["s3", "s2", "m1"]);
+    }
+}
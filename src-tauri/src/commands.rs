@@ -3,15 +3,32 @@ use serde::{Deserialize, Serialize};
 use tauri::{AppHandle, Emitter, Manager};
 use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::backup;
 use crate::database;
-#[cfg(feature = "embeddings")]
+use crate::dataset;
+use crate::embedding_models::{self, EmbeddingSettings};
+#[cfg(feature = "semantic-search")]
 use crate::embeddings;
+use crate::encryption;
+use crate::export_stream;
 use crate::hf_models;
+use crate::importers;
 #[cfg(feature = "native-llm")]
 use crate::llm;
+use crate::redaction;
+use crate::retrieval::{self, RetrievalConfig};
+use crate::sampling::SamplingParams;
+use crate::transcript;
+#[cfg(feature = "semantic-search")]
+use crate::vector_index;
 use crate::voice;
 
 static STOP_GENERATION: AtomicBool = AtomicBool::new(false);
+/// Set by `cancel_export`, checked between pages of a streaming export
+static CANCEL_EXPORT: AtomicBool = AtomicBool::new(false);
+
+/// Upper bound for `generate_candidates` (each candidate is a full generation run)
+const MAX_CANDIDATES: usize = 5;
 
 /// GPU info (used when native-llm is off; native-llm returns llm::GpuInfo, we map to this for API)
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,12 +69,28 @@ pub struct Settings {
     /// LLM backend: always "native" (built-in llama.cpp)
     #[serde(rename = "llmBackend", default = "default_llm_backend")]
     pub llm_backend: String,
+    /// Hours between automatic snapshots; 0 = off
+    #[serde(rename = "backupIntervalHours", default)]
+    pub backup_interval_hours: u32,
+    /// How many automatic snapshots to keep
+    #[serde(rename = "backupKeep", default = "default_backup_keep")]
+    pub backup_keep: u32,
+    /// Weights and limits of the hybrid context retriever
+    #[serde(default)]
+    pub retrieval: RetrievalConfig,
+    /// Model of the semantic search
+    #[serde(default)]
+    pub embedding: EmbeddingSettings,
 }
 
 fn default_llm_backend() -> String {
     "native".to_string()
 }
 
+fn default_backup_keep() -> u32 {
+    7
+}
+
 fn default_system_prompt() -> String {
     "Ты — Wishmaster, умный диалоговый AI-ассистент с долговременной памятью. \
      Отвечай кратко и по делу на русском языке. \
@@ -91,6 +124,10 @@ impl Default for Settings {
             model_paths: Vec::new(),
             system_prompt: default_system_prompt(),
             llm_backend: default_llm_backend(),
+            backup_interval_hours: 0,
+            backup_keep: default_backup_keep(),
+            retrieval: RetrievalConfig::default(),
+            embedding: EmbeddingSettings::default(),
         }
     }
 }
@@ -98,10 +135,21 @@ impl Default for Settings {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub id: i64,
+    #[serde(rename = "sessionId", default)]
+    pub session_id: i64,
     pub content: String,
     #[serde(rename = "isUser")]
     pub is_user: bool,
     pub timestamp: i64,
+    /// Message this one answers; alternatives of a reply share the same parent
+    #[serde(rename = "parentId", default)]
+    pub parent_id: Option<i64>,
+    /// "stop", "length" (hit max tokens) or "cancelled"; None for user messages
+    #[serde(rename = "finishReason", default)]
+    pub finish_reason: Option<String>,
+    /// Model that generated the answer; None for user messages and older answers
+    #[serde(default)]
+    pub model: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -112,6 +160,17 @@ pub struct Session {
     pub created_at: i64,
     #[serde(rename = "messageCount")]
     pub message_count: i32,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Assistant the session talks to; None = the default assistant
+    #[serde(rename = "assistantId", default)]
+    pub assistant_id: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -141,6 +200,23 @@ pub struct HistoryMessage {
     pub is_user: bool,
 }
 
+impl From<&Message> for HistoryMessage {
+    fn from(msg: &Message) -> Self {
+        Self {
+            content: msg.content.clone(),
+            is_user: msg.is_user,
+        }
+    }
+}
+
+/// Token of one of several candidates being generated in a single run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CandidateToken {
+    pub index: usize,
+    pub token: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VoiceRecording {
     pub id: i64,
@@ -156,9 +232,27 @@ pub fn load_settings() -> Result<Settings, String> {
     database::get_settings().map_err(|e| e.to_string())
 }
 
+/// Local copies of embedding models shipped with the app
+fn bundled_models_dir(app: &AppHandle) -> Option<std::path::PathBuf> {
+    app.path()
+        .resolve("resources/embedding-models", tauri::path::BaseDirectory::Resource)
+        .ok()
+}
+
 #[tauri::command]
-pub fn save_settings(settings: Settings) -> Result<(), String> {
-    database::save_settings(&settings).map_err(|e| e.to_string())
+pub fn save_settings(app: AppHandle, settings: Settings) -> Result<(), String> {
+    let bundled = bundled_models_dir(&app);
+    let choice = settings.embedding.resolve(bundled.as_deref())?;
+    let previous = database::get_settings().map_err(|e| e.to_string())?;
+    database::save_settings(&settings).map_err(|e| e.to_string())?;
+    
+    // Another embedding model: load it and re-embed in the background. Other
+    // embedding settings (say, the models folder) don't change the vectors.
+    if previous.embedding.resolve(bundled.as_deref()).map(|previous| previous.id).ok() != Some(choice.id) {
+        let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+        start_embedder(app, app_dir);
+    }
+    Ok(())
 }
 
 // ==================== Model Commands ====================
@@ -192,7 +286,12 @@ pub fn remove_model_path(path: String) -> Result<(), String> {
 }
 
 #[tauri::command]
-pub async fn load_model(path: String, _context_length: i32) -> Result<(), String> {
+pub async fn load_model(path: String, context_length: i32) -> Result<(), String> {
+    load_model_path(path, context_length).await
+}
+
+#[cfg_attr(not(feature = "native-llm"), allow(unused_variables))]
+async fn load_model_path(path: String, context_length: i32) -> Result<(), String> {
     // Track model name
     if let Ok(mut guard) = CURRENT_MODEL.lock() {
         let name = path.split('/').last()
@@ -202,7 +301,7 @@ pub async fn load_model(path: String, _context_length: i32) -> Result<(), String
     }
     #[cfg(feature = "native-llm")]
     {
-        let context_length = _context_length as usize;
+        let context_length = context_length as usize;
         tauri::async_runtime::spawn_blocking(move || llm::load_model(&path, context_length))
             .await
             .map_err(|e| format!("Load model task join error: {}", e))?
@@ -212,6 +311,13 @@ pub async fn load_model(path: String, _context_length: i32) -> Result<(), String
     Err("Native LLM не собран. Соберите с --features native-llm".to_string())
 }
 
+/// Name of the loaded model (file name without `.gguf`), recorded on generated answers
+fn current_model_name() -> Option<String> {
+    CURRENT_MODEL.lock().ok()
+        .map(|name| name.clone())
+        .filter(|name| !name.is_empty())
+}
+
 #[tauri::command]
 pub fn unload_model() -> Result<(), String> {
     if let Ok(mut guard) = CURRENT_MODEL.lock() {
@@ -255,14 +361,15 @@ pub fn is_gpu_available() -> bool {
 
 // ==================== Session Commands ====================
 
+/// List sessions, pinned first. Without a filter every session is returned.
 #[tauri::command]
-pub fn get_sessions() -> Result<Vec<Session>, String> {
-    database::get_sessions().map_err(|e| e.to_string())
+pub fn get_sessions(filter: Option<database::SessionFilter>) -> Result<Vec<Session>, String> {
+    database::get_sessions(&filter.unwrap_or_default()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub fn create_session(title: String) -> Result<i64, String> {
-    database::create_session(&title).map_err(|e| e.to_string())
+pub fn create_session(title: String, assistant_id: Option<i64>) -> Result<i64, String> {
+    database::create_session(&title, assistant_id).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -270,6 +377,150 @@ pub fn delete_session(session_id: i64) -> Result<(), String> {
     database::delete_session(session_id).map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+pub fn rename_session(session_id: i64, title: String) -> Result<(), String> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err("Название чата не может быть пустым".to_string());
+    }
+    database::rename_session(session_id, title).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn set_session_pinned(session_id: i64, pinned: bool) -> Result<(), String> {
+    database::set_session_pinned(session_id, pinned).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn set_session_archived(session_id: i64, archived: bool) -> Result<(), String> {
+    database::set_session_archived(session_id, archived).map_err(|e| e.to_string())
+}
+
+/// Move a session into a folder; None or an empty name takes it out of any folder
+#[tauri::command]
+pub fn set_session_folder(session_id: i64, folder: Option<String>) -> Result<(), String> {
+    let folder = folder.as_deref().map(str::trim).filter(|f| !f.is_empty());
+    database::set_session_folder(session_id, folder).map_err(|e| e.to_string())
+}
+
+/// Replace all tags of a session
+#[tauri::command]
+pub fn set_session_tags(session_id: i64, tags: Vec<String>) -> Result<Vec<String>, String> {
+    database::set_session_tags(session_id, &tags).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_session_folders() -> Result<Vec<String>, String> {
+    database::get_session_folders().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_session_tags() -> Result<Vec<String>, String> {
+    database::get_all_session_tags().map_err(|e| e.to_string())
+}
+
+// ==================== Session Settings Commands ====================
+
+#[tauri::command]
+pub fn get_session_settings(session_id: i64) -> Result<database::SessionSettings, String> {
+    database::get_session_settings(session_id).map_err(|e| e.to_string())
+}
+
+/// Store per-session overrides; unset fields fall back to the global settings
+#[tauri::command]
+pub fn save_session_settings(settings: database::SessionSettings) -> Result<(), String> {
+    validate_session_settings(&settings)?;
+    database::save_session_settings(&settings).map_err(|e| e.to_string())
+}
+
+/// Called when the user switches to a session: loads the session's preferred model
+/// (or its assistant's default model) if another one is active. Returns the session's settings.
+#[tauri::command]
+pub async fn activate_session(session_id: i64) -> Result<database::SessionSettings, String> {
+    let settings = database::get_session_settings(session_id).map_err(|e| e.to_string())?;
+    let assistant = database::get_session_assistant(session_id).map_err(|e| e.to_string())?;
+    let model_path = settings.model_path.clone()
+        .or_else(|| assistant.and_then(|a| a.model_path));
+    ensure_model(model_path).await?;
+    Ok(settings)
+}
+
+fn validate_session_settings(settings: &database::SessionSettings) -> Result<(), String> {
+    if settings.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+        return Err("Температура должна быть от 0 до 2".to_string());
+    }
+    if settings.top_p.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+        return Err("top_p должен быть от 0 до 1".to_string());
+    }
+    if settings.top_k.is_some_and(|k| k < 0) {
+        return Err("top_k не может быть отрицательным".to_string());
+    }
+    if settings.min_p.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+        return Err("min_p должен быть от 0 до 1".to_string());
+    }
+    if settings.repeat_penalty.is_some_and(|p| p <= 0.0) {
+        return Err("repeat_penalty должен быть больше 0".to_string());
+    }
+    if settings.max_tokens.is_some_and(|n| n < 1) {
+        return Err("max_tokens должен быть больше 0".to_string());
+    }
+    Ok(())
+}
+
+/// Load a session's preferred model unless it is already loaded
+async fn ensure_model(model_path: Option<String>) -> Result<(), String> {
+    let Some(path) = model_path else {
+        return Ok(());
+    };
+    #[cfg(feature = "native-llm")]
+    if llm::current_model_path().as_deref() == Some(path.as_str()) {
+        return Ok(());
+    }
+    let context_length = database::get_settings().unwrap_or_default().context_length;
+    load_model_path(path, context_length).await
+}
+
+// ==================== Assistant Commands ====================
+
+#[tauri::command]
+pub fn get_assistants() -> Result<Vec<database::Assistant>, String> {
+    database::get_assistants().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn create_assistant(assistant: database::Assistant) -> Result<i64, String> {
+    let assistant = validate_assistant(assistant)?;
+    database::create_assistant(&assistant).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn update_assistant(assistant: database::Assistant) -> Result<(), String> {
+    let assistant = validate_assistant(assistant)?;
+    database::update_assistant(&assistant).map_err(|e| e.to_string())
+}
+
+/// Delete an assistant; its sessions fall back to the default assistant
+#[tauri::command]
+pub fn delete_assistant(id: i64) -> Result<(), String> {
+    database::delete_assistant(id).map_err(|e| e.to_string())
+}
+
+fn validate_assistant(mut assistant: database::Assistant) -> Result<database::Assistant, String> {
+    assistant.name = assistant.name.trim().to_string();
+    if assistant.name.is_empty() {
+        return Err("Имя ассистента не может быть пустым".to_string());
+    }
+    if assistant.memory_mode != "shared" && assistant.memory_mode != "isolated" {
+        return Err(format!("Неизвестный режим памяти: {}", assistant.memory_mode));
+    }
+    if let Some(sampling) = &assistant.sampling {
+        if !(0.0..=2.0).contains(&sampling.temperature) {
+            return Err("Температура должна быть от 0 до 2".to_string());
+        }
+    }
+    Ok(assistant)
+}
+
 // ==================== Message Commands ====================
 
 #[tauri::command]
@@ -278,27 +529,172 @@ pub fn get_messages(session_id: i64) -> Result<Vec<Message>, String> {
 }
 
 #[tauri::command]
-pub fn save_message(session_id: i64, content: String, is_user: bool) -> Result<i64, String> {
-    let msg_id = database::insert_message(session_id, &content, is_user).map_err(|e| e.to_string())?;
+pub fn save_message(session_id: i64, content: String, is_user: bool, finish_reason: Option<String>) -> Result<i64, String> {
+    let model = if is_user { None } else { current_model_name() };
+    let msg_id = database::insert_message(session_id, &content, is_user, finish_reason.as_deref(), model.as_deref())
+        .map_err(|e| e.to_string())?;
     
-    // Auto-index message for semantic search (async, non-blocking)
-    #[cfg(feature = "embeddings")]
-    {
-        let content_clone = content.clone();
-        std::thread::spawn(move || {
-            let result = database::with_connection(|conn| {
-                embeddings::index_message(conn, msg_id, &content_clone)
-            });
-            
-            match result {
-                Ok(Ok(())) => {} // Success
+    index_message_async(msg_id, content);
+    
+    Ok(msg_id)
+}
+
+/// Auto-index message for semantic search (async, non-blocking)
+fn index_message_async(msg_id: i64, content: String) {
+    #[cfg(feature = "semantic-search")]
+    std::thread::spawn(move || {
+        let result = database::with_connection(|conn| {
+            embeddings::index_message(conn, msg_id, &content)
+        });
+        
+        match result {
+            Ok(Ok(())) => {} // Success
+            Ok(Err(e)) => eprintln!("Failed to index message {}: {}", msg_id, e),
+            Err(e) => eprintln!("Database error indexing message {}: {}", msg_id, e),
+        }
+    });
+    #[cfg(not(feature = "semantic-search"))]
+    let _ = (msg_id, content);
+}
+
+/// Index imported messages and memories one after another in a single background thread
+/// (the connection is taken per row so the UI keeps working during big imports)
+fn index_imported_async(messages: Vec<(i64, String)>, memories: Vec<(i64, String)>) {
+    #[cfg(feature = "semantic-search")]
+    std::thread::spawn(move || {
+        let total = messages.len() + memories.len();
+        let mut indexed = 0;
+        for (msg_id, content) in messages {
+            match database::with_connection(|conn| embeddings::index_message(conn, msg_id, &content)) {
+                Ok(Ok(())) => indexed += 1,
                 Ok(Err(e)) => eprintln!("Failed to index message {}: {}", msg_id, e),
                 Err(e) => eprintln!("Database error indexing message {}: {}", msg_id, e),
             }
-        });
+        }
+        for (memory_id, content) in memories {
+            match database::with_connection(|conn| embeddings::index_memory(conn, memory_id, &content)) {
+                Ok(Ok(())) => indexed += 1,
+                Ok(Err(e)) => eprintln!("Failed to index memory {}: {}", memory_id, e),
+                Err(e) => eprintln!("Database error indexing memory {}: {}", memory_id, e),
+            }
+        }
+        println!("🔍 Indexed {}/{} imported entries", indexed, total);
+    });
+    #[cfg(not(feature = "semantic-search"))]
+    let _ = (messages, memories);
+}
+
+/// Drop the vector index after the database was swapped out under it and build it
+/// again from the table in the background
+fn rebuild_vector_index(app_dir: std::path::PathBuf) {
+    #[cfg(feature = "semantic-search")]
+    {
+        if let Err(e) = vector_index::reset() {
+            eprintln!("Failed to reset vector index: {}", e);
+        }
+        // Without a loaded model the index is built when one loads
+        if embeddings::active_model().is_some() {
+            std::thread::spawn(move || {
+                if let Err(e) = embeddings::init_index(&app_dir) {
+                    eprintln!("Failed to rebuild vector index: {}", e);
+                }
+            });
+        }
+    }
+    #[cfg(not(feature = "semantic-search"))]
+    let _ = app_dir;
+}
+
+/// Get all alternatives of a message (regenerated replies / candidates), oldest first
+#[tauri::command]
+pub fn get_message_alternatives(message_id: i64) -> Result<Vec<Message>, String> {
+    database::get_alternatives(message_id).map_err(|e| e.to_string())
+}
+
+/// Show another alternative in place of the current reply
+#[tauri::command]
+pub fn select_alternative(message_id: i64) -> Result<(), String> {
+    database::switch_branch(message_id).map_err(|e| e.to_string())
+}
+
+/// Fix a message in place (no new branch). FTS follows via triggers, the vector is rebuilt.
+#[tauri::command]
+pub fn update_message(message_id: i64, content: String) -> Result<Message, String> {
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("Сообщение не может быть пустым".to_string());
     }
     
-    Ok(msg_id)
+    let message = database::update_message(message_id, content).map_err(|e| e.to_string())?;
+    index_message_async(message.id, message.content.clone());
+    
+    Ok(message)
+}
+
+/// Delete a message together with its vector and the memories extracted from it
+#[tauri::command]
+pub fn delete_message(message_id: i64) -> Result<(), String> {
+    database::delete_message(message_id).map_err(|e| e.to_string())
+}
+
+// ==================== Conversation Tree Commands ====================
+
+/// Edit a message without losing what came after it: the edited text starts a new
+/// branch and the old branch stays reachable through `switch_branch`
+#[tauri::command]
+pub fn edit_message(message_id: i64, content: String) -> Result<Message, String> {
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("Сообщение не может быть пустым".to_string());
+    }
+    
+    let message = database::edit_message_as_branch(message_id, content).map_err(|e| e.to_string())?;
+    index_message_async(message.id, message.content.clone());
+    
+    Ok(message)
+}
+
+/// Make the branch containing a message the active one
+#[tauri::command]
+pub fn switch_branch(message_id: i64) -> Result<Vec<Message>, String> {
+    database::switch_branch(message_id).map_err(|e| e.to_string())?;
+    let message = database::get_message(message_id).map_err(|e| e.to_string())?;
+    database::get_messages(message.session_id).map_err(|e| e.to_string())
+}
+
+/// Get every branch of a session
+#[tauri::command]
+pub fn get_message_tree(session_id: i64) -> Result<database::MessageTree, String> {
+    database::get_message_tree(session_id).map_err(|e| e.to_string())
+}
+
+// ==================== Feedback Commands ====================
+
+/// Rate an assistant answer (thumbs up/down, 1-5 score, correction).
+/// Replaces the previous feedback; empty feedback clears it and returns None.
+#[tauri::command]
+pub fn set_message_feedback(message_id: i64, feedback: database::MessageFeedback) -> Result<Option<database::MessageFeedback>, String> {
+    if feedback.score.is_some_and(|score| !(1..=5).contains(&score)) {
+        return Err("Оценка должна быть от 1 до 5".to_string());
+    }
+    let message = database::get_message(message_id).map_err(|e| e.to_string())?;
+    if message.is_user {
+        return Err("Оценить можно только ответ ассистента".to_string());
+    }
+    
+    database::set_message_feedback(&database::MessageFeedback { message_id, ..feedback })
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_message_feedback(message_id: i64) -> Result<Option<database::MessageFeedback>, String> {
+    database::get_message_feedback(message_id).map_err(|e| e.to_string())
+}
+
+/// List feedback by session, rating range or corrections, newest first
+#[tauri::command]
+pub fn get_feedback(filter: Option<database::FeedbackFilter>) -> Result<Vec<database::MessageFeedback>, String> {
+    database::get_feedback(&filter.unwrap_or_default()).map_err(|e| e.to_string())
 }
 
 // ==================== MEMORY SYSTEM Commands ====================
@@ -309,6 +705,18 @@ pub fn search_all_messages(query: String, limit: i32) -> Result<Vec<database::Ex
     database::search_all_messages(&query, limit).map_err(|e| e.to_string())
 }
 
+/// Ranked search with snippets and session / role / date filters
+#[tauri::command]
+pub fn search_messages(search: database::MessageSearch) -> Result<Vec<database::MessageSearchHit>, String> {
+    database::search_messages(&search).map_err(|e| e.to_string())
+}
+
+/// Search messages, memories and session titles together, one page at a time
+#[tauri::command]
+pub fn search(search: database::UnifiedSearch) -> Result<database::SearchPage, String> {
+    database::search(&search).map_err(|e| e.to_string())
+}
+
 /// Get recent messages from ALL sessions
 #[tauri::command]
 pub fn get_recent_global_messages(limit: i32) -> Result<Vec<database::ExportMessage>, String> {
@@ -453,87 +861,563 @@ pub fn analyze_persona() -> Result<database::UserPersona, String> {
     Ok(persona)
 }
 
-// ==================== EXPORT Commands (for fine-tuning) ====================
+// ==================== EXPORT Commands (for fine-tuning) ====================
+
+/// Export ALL data for digital twin creation
+#[tauri::command]
+pub fn export_all_data() -> Result<database::ExportData, String> {
+    database::export_all_data().map_err(|e| e.to_string())
+}
+
+/// Export in Alpaca format for fine-tuning; `min_rating` keeps only answers rated that high (1-5)
+#[tauri::command]
+pub fn export_alpaca_format(min_rating: Option<i32>) -> Result<Vec<serde_json::Value>, String> {
+    database::export_alpaca_format(min_rating).map_err(|e| e.to_string())
+}
+
+/// Export in ShareGPT format for fine-tuning
+#[tauri::command]
+pub fn export_sharegpt_format() -> Result<Vec<serde_json::Value>, String> {
+    database::export_sharegpt_format().map_err(|e| e.to_string())
+}
+
+/// Export in OpenAI chat fine-tuning format (messages with the system prompt)
+#[tauri::command]
+pub fn export_openai_format() -> Result<Vec<serde_json::Value>, String> {
+    database::export_openai_format(&session_system_prompt).map_err(|e| e.to_string())
+}
+
+/// Export whole conversations as ChatML text
+#[tauri::command]
+pub fn export_chatml_format() -> Result<Vec<serde_json::Value>, String> {
+    database::export_chatml_format(&session_system_prompt).map_err(|e| e.to_string())
+}
+
+/// Export DPO / ORPO preference pairs (chosen vs rejected answers)
+#[tauri::command]
+pub fn export_preference_pairs(min_rating: Option<i32>) -> Result<Vec<serde_json::Value>, String> {
+    database::export_preference_pairs(&session_system_prompt, min_rating).map_err(|e| e.to_string())
+}
+
+/// Settings a session is answered with when the request brings none of its own
+fn session_generation_config(session_id: i64) -> GenerationConfig {
+    let settings = database::get_settings().unwrap_or_default();
+    let overrides = database::get_session_settings(session_id).unwrap_or_default();
+    let assistant = database::get_session_assistant(session_id).ok().flatten();
+    generation_config(&overrides, assistant.as_ref(), base_system_prompt(), settings.temperature, settings.max_tokens)
+}
+
+/// System prompt a session is answered with, without memory / RAG enrichment
+fn session_system_prompt(session_id: i64) -> String {
+    session_generation_config(session_id).system_prompt
+}
+
+/// One JSON object per line
+fn to_jsonl(records: &[serde_json::Value]) -> String {
+    records.iter()
+        .map(|v| serde_json::to_string(v).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Get statistics about stored data
+#[tauri::command]
+pub fn get_data_stats() -> Result<serde_json::Value, String> {
+    database::get_data_stats().map_err(|e| e.to_string())
+}
+
+/// Export data to file. With `redaction` personal data is replaced by placeholders
+/// and a report `<name>.redaction.json` is written next to the export.
+#[tauri::command]
+pub fn export_to_file(
+    app: AppHandle,
+    format: String,
+    min_rating: Option<i32>,
+    redaction: Option<redaction::RedactionConfig>,
+) -> Result<String, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    let export_dir = app_dir.join("exports");
+    std::fs::create_dir_all(&export_dir).map_err(|e| e.to_string())?;
+    
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    
+    // ShareGPT and the full export are streamed from the database instead of built in memory
+    let stream_json = |content| {
+        let options = export_stream::StreamExportOptions {
+            content,
+            layout: export_stream::ExportLayout::Json,
+            compression: export_stream::Compression::None,
+            redaction: redaction.clone(),
+        };
+        stream_export_file(&export_dir, timestamp, &options, &mut |_| {}, &AtomicBool::new(false))
+            .map(|result| result.path)
+    };
+    
+    let mut redactor = redaction.clone().map(redaction::Redactor::new).transpose()?;
+    let mut redact = |records: &mut [serde_json::Value]| {
+        if let Some(redactor) = redactor.as_mut() {
+            records.iter_mut().for_each(|record| redactor.redact_record(record));
+        }
+    };
+    
+    let (filename, content) = match format.as_str() {
+        "alpaca" => {
+            let mut data = database::export_alpaca_format(min_rating).map_err(|e| e.to_string())?;
+            redact(&mut data);
+            (format!("alpaca_{}.jsonl", timestamp), to_jsonl(&data))
+        }
+        "openai" => {
+            let mut data = database::export_openai_format(&session_system_prompt).map_err(|e| e.to_string())?;
+            redact(&mut data);
+            (format!("openai_{}.jsonl", timestamp), to_jsonl(&data))
+        }
+        "chatml" => {
+            let mut data = database::export_chatml_format(&session_system_prompt).map_err(|e| e.to_string())?;
+            redact(&mut data);
+            (format!("chatml_{}.jsonl", timestamp), to_jsonl(&data))
+        }
+        "dpo" => {
+            let mut data = database::export_preference_pairs(&session_system_prompt, min_rating).map_err(|e| e.to_string())?;
+            redact(&mut data);
+            (format!("dpo_{}.jsonl", timestamp), to_jsonl(&data))
+        }
+        "sharegpt" => return stream_json(export_stream::ExportContent::ShareGpt),
+        _ => return stream_json(export_stream::ExportContent::Full),
+    };
+    
+    if let Some(redactor) = &redactor {
+        let stem = filename.split('.').next().unwrap_or(&filename);
+        let report = serde_json::to_string_pretty(&redactor.report()).map_err(|e| e.to_string())?;
+        write_export(&export_dir, format!("{}.redaction.json", stem), report.as_bytes())?;
+    }
+    let path = write_export(&export_dir, filename, content.as_bytes())?;
+    
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Stream an export into the exports directory and write the redaction report next to it
+fn stream_export_file(
+    export_dir: &std::path::Path,
+    timestamp: u64,
+    options: &export_stream::StreamExportOptions,
+    progress: &mut dyn FnMut(&export_stream::ExportProgress),
+    cancel: &AtomicBool,
+) -> Result<export_stream::StreamExportResult, String> {
+    let filename = options.file_name(timestamp);
+    let filename = if encryption::is_unlocked() { format!("{}.enc", filename) } else { filename };
+    let result = export_stream::export_to_file(options, &export_dir.join(&filename), progress, cancel)?;
+    
+    if let Some(report) = &result.redaction {
+        let stem = filename.split('.').next().unwrap_or(&filename);
+        let report = serde_json::to_string_pretty(report).map_err(|e| e.to_string())?;
+        write_export(export_dir, format!("{}.redaction.json", stem), report.as_bytes())?;
+    }
+    Ok(result)
+}
+
+/// Export straight from the database to `exports/` as JSONL or incremental JSON,
+/// optionally gzip / zstd compressed. Emits `export-progress` after every page;
+/// `cancel_export` stops it and removes the partial file.
+#[tauri::command]
+pub async fn export_stream(
+    app: AppHandle,
+    options: export_stream::StreamExportOptions,
+) -> Result<export_stream::StreamExportResult, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    let export_dir = app_dir.join("exports");
+    std::fs::create_dir_all(&export_dir).map_err(|e| e.to_string())?;
+    
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    
+    CANCEL_EXPORT.store(false, Ordering::SeqCst);
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut progress = |progress: &export_stream::ExportProgress| {
+            let _ = app.emit("export-progress", progress);
+        };
+        stream_export_file(&export_dir, timestamp, &options, &mut progress, &CANCEL_EXPORT)
+    })
+    .await
+    .map_err(|e| format!("Export task error: {}", e))?
+}
+
+#[tauri::command]
+pub fn cancel_export() {
+    CANCEL_EXPORT.store(true, Ordering::SeqCst);
+}
+
+/// Write a file into the exports directory.
+/// Encrypted exports can be turned back into plain files with decrypt_file.
+fn write_export(export_dir: &std::path::Path, filename: String, data: &[u8]) -> Result<std::path::PathBuf, String> {
+    let filename = if encryption::is_unlocked() { format!("{}.enc", filename) } else { filename };
+    let path = export_dir.join(&filename);
+    encryption::write_file(&path, data)?;
+    Ok(path)
+}
+
+/// Build a curated dataset (filters, deduplication, train/validation split) into
+/// `exports/dataset_<timestamp in ms>/`; returns its manifest
+#[tauri::command]
+pub async fn build_dataset(app: AppHandle, config: dataset::DatasetConfig) -> Result<dataset::DatasetManifest, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    let export_dir = app_dir.join("exports");
+    std::fs::create_dir_all(&export_dir).map_err(|e| e.to_string())?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let out_dir = new_dir(&export_dir, &format!("dataset_{}", timestamp))?;
+    
+    tauri::async_runtime::spawn_blocking(move || dataset::build(&config, &session_system_prompt, &out_dir))
+        .await
+        .map_err(|e| format!("Dataset task error: {}", e))?
+}
+
+/// Create `parent/name`, or `name_2`, `name_3`... if it already exists (two builds in one instant)
+fn new_dir(parent: &std::path::Path, name: &str) -> Result<std::path::PathBuf, String> {
+    let mut dir = parent.join(name);
+    let mut n = 1;
+    loop {
+        match std::fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                n += 1;
+                dir = parent.join(format!("{}_{}", name, n));
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+// ==================== Transcript Commands ====================
+
+/// Result of a bulk transcript export
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptExport {
+    pub directory: String,
+    pub files: Vec<String>,
+}
+
+/// File name of the model configured for a session, if any
+fn session_model_name(session_id: i64) -> Option<String> {
+    session_generation_config(session_id).model_path
+        .and_then(|path| {
+            std::path::Path::new(&path).file_stem().map(|s| s.to_string_lossy().into_owned())
+        })
+}
+
+fn render_transcript(session_id: i64, options: &transcript::TranscriptOptions) -> Result<(Session, String), String> {
+    let session = database::get_session(session_id)
+        .map_err(|_| format!("Сессия {} не найдена", session_id))?;
+    let messages = database::get_messages(session_id).map_err(|e| e.to_string())?;
+    let assistant_name = database::get_session_assistant(session_id).ok().flatten()
+        .map(|a| a.name)
+        .unwrap_or_else(|| "Wishmaster".to_string());
+    let default_model = session_model_name(session_id);
+    
+    let text = transcript::render(&transcript::Transcript {
+        session: &session,
+        messages: &messages,
+        assistant_name: &assistant_name,
+        default_model: default_model.as_deref(),
+    }, options);
+    Ok((session, text))
+}
+
+/// Write one transcript. Without `directory` it goes to `exports/transcripts`
+/// (encrypted when encryption is unlocked); a chosen directory gets plain files.
+fn write_transcript(dir: &std::path::Path, plain: bool, filename: String, data: &str) -> Result<std::path::PathBuf, String> {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    if plain {
+        let path = dir.join(filename);
+        std::fs::write(&path, data).map_err(|e| e.to_string())?;
+        Ok(path)
+    } else {
+        write_export(dir, filename, data.as_bytes())
+    }
+}
+
+/// Render a session transcript (Markdown, standalone HTML or plain text)
+#[tauri::command]
+pub fn get_transcript(session_id: i64, options: Option<transcript::TranscriptOptions>) -> Result<String, String> {
+    render_transcript(session_id, &options.unwrap_or_default()).map(|(_, text)| text)
+}
+
+/// Save a session transcript to a file; returns its path
+#[tauri::command]
+pub fn export_transcript(
+    app: AppHandle,
+    session_id: i64,
+    options: Option<transcript::TranscriptOptions>,
+    directory: Option<String>,
+) -> Result<String, String> {
+    let options = options.unwrap_or_default();
+    let (session, text) = render_transcript(session_id, &options)?;
+    
+    let (dir, plain) = match directory {
+        Some(dir) => (std::path::PathBuf::from(dir), true),
+        None => {
+            let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+            (app_dir.join("exports").join("transcripts"), false)
+        }
+    };
+    let path = write_transcript(&dir, plain, transcript::file_name(&session, options.format), &text)?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Save one transcript per session into a folder (all sessions when `session_ids`
+/// is not given). Default folder: `exports/transcripts_<timestamp>/`
+#[tauri::command]
+pub async fn export_transcripts(
+    app: AppHandle,
+    session_ids: Option<Vec<i64>>,
+    options: Option<transcript::TranscriptOptions>,
+    directory: Option<String>,
+) -> Result<TranscriptExport, String> {
+    let options = options.unwrap_or_default();
+    let (dir, plain) = match directory {
+        Some(dir) => (std::path::PathBuf::from(dir), true),
+        None => {
+            let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            (app_dir.join("exports").join(format!("transcripts_{}", timestamp)), false)
+        }
+    };
+    
+    tauri::async_runtime::spawn_blocking(move || {
+        let session_ids = match session_ids {
+            Some(ids) => ids,
+            None => database::get_sessions(&database::SessionFilter::default())
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|s| s.id)
+                .collect(),
+        };
+    
+        let mut files = Vec::new();
+        for session_id in session_ids {
+            let (session, text) = render_transcript(session_id, &options)?;
+            let path = write_transcript(&dir, plain, transcript::file_name(&session, options.format), &text)?;
+            files.push(path.to_string_lossy().to_string());
+        }
+    
+        Ok(TranscriptExport {
+            directory: dir.to_string_lossy().to_string(),
+            files,
+        })
+    })
+    .await
+    .map_err(|e| format!("Transcript task error: {}", e))?
+}
+
+// ==================== Import Commands ====================
+
+/// Import chats exported from ChatGPT, Claude, ShareGPT/Alpaca or generic message JSON.
+/// Every conversation becomes a session with its original timestamps; full-text search
+/// picks the messages up at once, embeddings are computed in the background.
+#[tauri::command]
+pub async fn import_conversations(path: String, format: Option<importers::ImportFormat>) -> Result<importers::ImportReport, String> {
+    let (report, to_index) = tauri::async_runtime::spawn_blocking(move || {
+        let data = std::fs::read_to_string(&path).map_err(|e| format!("Не удалось прочитать файл: {}", e))?;
+        let (conversations, errors) = importers::parse(&data, format.unwrap_or(importers::ImportFormat::Auto))?;
+        
+        let mut report = importers::ImportReport { errors, ..Default::default() };
+        let mut to_index = Vec::new();
+        for conversation in &conversations {
+            match database::import_conversation(conversation) {
+                Ok(Some((_, messages))) => {
+                    report.sessions += 1;
+                    report.messages += messages.len();
+                    to_index.extend(messages);
+                }
+                Ok(None) => report.skipped += 1,
+                Err(e) => report.errors.push(format!("«{}»: {}", conversation.title, e)),
+            }
+        }
+        Ok::<_, String>((report, to_index))
+    })
+    .await
+    .map_err(|e| format!("Import task error: {}", e))??;
+    
+    index_imported_async(to_index, Vec::new());
+    Ok(report)
+}
+
+/// Load a `full_export_*.json` written by `export_to_file` (encrypted `.enc` exports too).
+/// `replace` wipes chats, memories and the persona first, after a safety backup;
+/// `dry_run` only reports what would happen.
+#[tauri::command]
+pub async fn import_full_export(
+    app: AppHandle,
+    path: String,
+    mode: database::ImportMode,
+    dry_run: bool,
+) -> Result<database::FullImportReport, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    let (report, messages, memories) = tauri::async_runtime::spawn_blocking(move || {
+        let bytes = encryption::read_file(std::path::Path::new(&path))?;
+        let data = export_stream::read_full_export(bytes)
+            .map_err(|e| format!("Файл не является полным экспортом Wishmaster: {}", e))?;
+        
+        let backup = if mode == database::ImportMode::Replace && !dry_run {
+            Some(backup::create_backup(&app_dir, backup::BackupKind::PreRestore)?.name)
+        } else {
+            None
+        };
+        
+        let (mut report, messages, memories) = database::import_full_export(&data, mode, dry_run)
+            .map_err(|e| format!("Ошибка импорта: {}", e))?;
+        if mode == database::ImportMode::Replace && !dry_run {
+            rebuild_vector_index(app_dir);
+        }
+        report.backup = backup;
+        Ok::<_, String>((report, messages, memories))
+    })
+    .await
+    .map_err(|e| format!("Import task error: {}", e))??;
+    
+    index_imported_async(messages, memories);
+    Ok(report)
+}
+
+// ==================== Backup Commands ====================
+
+/// Archive the database, voice recordings and an exports manifest into `backups/`
+#[tauri::command]
+pub async fn create_backup(app: AppHandle) -> Result<backup::BackupInfo, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    tauri::async_runtime::spawn_blocking(move || backup::create_backup(&app_dir, backup::BackupKind::Manual))
+        .await
+        .map_err(|e| format!("Backup task error: {}", e))?
+}
+
+#[tauri::command]
+pub fn list_backups(app: AppHandle) -> Result<Vec<backup::BackupInfo>, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    backup::list_backups(&app_dir)
+}
+
+/// Restore an archive by name. The current data is saved as a "pre-restore" archive first,
+/// which is returned so the restore can be undone.
+#[tauri::command]
+pub async fn restore_backup(app: AppHandle, name: String) -> Result<backup::BackupInfo, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let safety = backup::restore_backup(&app_dir, &name)?;
+        rebuild_vector_index(app_dir);
+        Ok(safety)
+    })
+    .await
+    .map_err(|e| format!("Restore task error: {}", e))?
+}
+
+#[tauri::command]
+pub fn delete_backup(app: AppHandle, name: String) -> Result<(), String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    backup::delete_backup(&app_dir, &name)
+}
+
+// ==================== Encryption Commands ====================
 
-/// Export ALL data for digital twin creation
 #[tauri::command]
-pub fn export_all_data() -> Result<database::ExportData, String> {
-    database::export_all_data().map_err(|e| e.to_string())
+pub fn get_encryption_status(app: AppHandle) -> Result<encryption::EncryptionStatus, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    Ok(encryption::status(&app_dir))
 }
 
-/// Export in Alpaca format for fine-tuning
+/// Open the encrypted database; until then every data command fails
 #[tauri::command]
-pub fn export_alpaca_format() -> Result<Vec<serde_json::Value>, String> {
-    database::export_alpaca_format().map_err(|e| e.to_string())
+pub async fn unlock_database(app: AppHandle, passphrase: String) -> Result<(), String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    tauri::async_runtime::spawn_blocking(move || {
+        encryption::unlock(&app_dir, &passphrase)?;
+        // The model chosen in the now readable settings, and its index
+        start_embedder(app, app_dir);
+        Ok(())
+    })
+    .await
+    .map_err(|e| format!("Unlock task error: {}", e))?
 }
 
-/// Export in ShareGPT format for fine-tuning
+/// Encrypt the database, voice recordings and exports under a new passphrase
 #[tauri::command]
-pub fn export_sharegpt_format() -> Result<Vec<serde_json::Value>, String> {
-    database::export_sharegpt_format().map_err(|e| e.to_string())
+pub async fn enable_encryption(app: AppHandle, passphrase: String) -> Result<encryption::EncryptionReport, String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let report = encryption::enable(&app_dir, &passphrase)?;
+        // Rewrite the vector index, which holds message embeddings, encrypted
+        #[cfg(feature = "semantic-search")]
+        if let Err(e) = vector_index::save() {
+            eprintln!("Failed to save vector index: {}", e);
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(|e| format!("Encryption task error: {}", e))?
 }
 
-/// Get statistics about stored data
 #[tauri::command]
-pub fn get_data_stats() -> Result<serde_json::Value, String> {
-    database::get_data_stats().map_err(|e| e.to_string())
+pub async fn change_passphrase(app: AppHandle, current_passphrase: String, new_passphrase: String) -> Result<(), String> {
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    tauri::async_runtime::spawn_blocking(move || {
+        encryption::change_passphrase(&app_dir, &current_passphrase, &new_passphrase)
+    })
+    .await
+    .map_err(|e| format!("Encryption task error: {}", e))?
 }
 
-/// Export data to file
+/// Save a plain copy of an encrypted export or recording (e.g. to hand a dataset to a trainer)
 #[tauri::command]
-pub fn export_to_file(app: AppHandle, format: String) -> Result<String, String> {
-    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
-    let export_dir = app_dir.join("exports");
-    std::fs::create_dir_all(&export_dir).map_err(|e| e.to_string())?;
-    
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_secs())
-        .unwrap_or(0);
-    
-    let (filename, content) = match format.as_str() {
-        "alpaca" => {
-            let data = database::export_alpaca_format().map_err(|e| e.to_string())?;
-            let json = data.iter()
-                .map(|v| serde_json::to_string(v).unwrap_or_default())
-                .collect::<Vec<_>>()
-                .join("\n");
-            (format!("alpaca_{}.jsonl", timestamp), json)
-        }
-        "sharegpt" => {
-            let data = database::export_sharegpt_format().map_err(|e| e.to_string())?;
-            let json = serde_json::to_string_pretty(&data).unwrap_or_else(|_| "[]".to_string());
-            (format!("sharegpt_{}.json", timestamp), json)
-        }
-        _ => {
-            let data = database::export_all_data().map_err(|e| e.to_string())?;
-            let json = serde_json::to_string_pretty(&data).unwrap_or_else(|_| "{}".to_string());
-            (format!("full_export_{}.json", timestamp), json)
-        }
-    };
-    
-    let path = export_dir.join(&filename);
-    std::fs::write(&path, content).map_err(|e| e.to_string())?;
-    
-    Ok(path.to_string_lossy().to_string())
+pub fn decrypt_file(source: String, destination: String) -> Result<(), String> {
+    let data = encryption::read_file(std::path::Path::new(&source))?;
+    std::fs::write(&destination, data).map_err(|e| e.to_string())
 }
 
 // ==================== Memory Context Builder ====================
 
 /// Build enriched system prompt with memory, RAG context, and persona info.
-/// Used by the native llama.cpp backend.
-fn build_enriched_system_prompt(
-    base_prompt: &str,
-    _prompt: &str,
-    _session_id: i64,
-) -> String {
-    let mut enriched = String::with_capacity(base_prompt.len() + 2048);
-    enriched.push_str(base_prompt);
-    enriched.push_str("\nТы помнишь ВСЕ предыдущие разговоры и используешь эту информацию.");
+/// Used by the native llama.cpp backend. `use_memory` covers top memories and the
+/// persona, `use_rag` the retrieved context (see `retrieval`), leaving out what `history`
+/// already holds. Only memories visible to the session's assistant are used.
+fn build_enriched_system_prompt(config: &GenerationConfig, prompt: &str, session_id: i64, history: &[HistoryMessage]) -> String {
+    let mut enriched = String::with_capacity(config.system_prompt.len() + 2048);
+    enriched.push_str(&config.system_prompt);
+    if config.use_memory || config.use_rag {
+        enriched.push_str("\nТы помнишь ВСЕ предыдущие разговоры и используешь эту информацию.");
+    }
     enriched.push_str(" Отвечай только текстом ответа пользователю — без процентов, сходства и метаданных.\n\n");
 
-    // Add important memories
-    if let Ok(memories) = database::get_top_memories(5) {
+    if config.use_memory {
+        append_memories(&mut enriched, config.assistant_id);
+    }
+    if config.use_rag {
+        append_rag_context(&mut enriched, prompt, session_id, config.assistant_id, history);
+    }
+
+    // Add persona info if available
+    if config.use_memory {
+        if let Ok(Some(persona)) = database::get_user_persona() {
+            enriched.push_str(&format!(
+                "=== ПРОФИЛЬ ПОЛЬЗОВАТЕЛЯ ===\nСтиль: {}, Тон: {}, Язык: {}\n\n",
+                persona.writing_style, persona.tone, persona.language
+            ));
+        }
+    }
+
+    enriched
+}
+
+/// Add the assistant's most important memories
+fn append_memories(enriched: &mut String, assistant_id: Option<i64>) {
+    if let Ok(memories) = database::get_top_memories_for_assistant(assistant_id, 5) {
         if !memories.is_empty() {
             enriched.push_str("=== ВАЖНЫЕ ФАКТЫ ИЗ ПАМЯТИ ===\n");
             for mem in memories {
@@ -542,74 +1426,184 @@ fn build_enriched_system_prompt(
             enriched.push('\n');
         }
     }
+}
 
-    // Add relevant context using SEMANTIC SEARCH (RAG)
-    #[cfg(feature = "embeddings")]
-    if let Ok(rag_results) = database::with_connection(|conn| {
-        embeddings::find_rag_context(conn, _prompt, 5)
-    }) {
-        if let Ok(results) = rag_results {
-            let relevant: Vec<_> = results.iter()
-                .filter(|r| r.similarity > 0.5)
-                .take(3)
-                .collect();
-
-            if !relevant.is_empty() {
-                enriched.push_str("=== РЕЛЕВАНТНЫЙ КОНТЕКСТ (для справки) ===\n");
-                for result in relevant {
-                    let source = match result.source_type.as_str() {
-                        "memory" => "Память",
-                        "message" => "Сообщение",
-                        _ => &result.source_type,
-                    };
-                    enriched.push_str(&format!("[{}] {}\n",
-                        source,
-                        result.content.chars().take(200).collect::<String>()));
-                }
-                enriched.push('\n');
-            }
+/// Add context from other chats and memory found by the hybrid retriever
+fn append_rag_context(enriched: &mut String, prompt: &str, session_id: i64, assistant_id: Option<i64>, history: &[HistoryMessage]) {
+    let config = database::get_settings().map(|s| s.retrieval).unwrap_or_default();
+    let history: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+    let items = match retrieval::retrieve(prompt, session_id, assistant_id, &history, &config) {
+        Ok(items) => items,
+        Err(e) => {
+            eprintln!("Context retrieval failed: {}", e);
+            return;
         }
+    };
+    if items.is_empty() {
+        return;
     }
-
-    // Fallback to keyword search
-    let keywords: Vec<&str> = _prompt.split_whitespace()
-        .filter(|w| w.len() > 3)
-        .take(3)
-        .collect();
-
-    if !keywords.is_empty() {
-        let search_query = keywords.join(" OR ");
-        if let Ok(relevant) = database::search_all_messages(&search_query, 3) {
-            let other_session_msgs: Vec<_> = relevant.iter()
-                .filter(|m| m.session_id != _session_id)
-                .collect();
-
-            if !other_session_msgs.is_empty() {
-                enriched.push_str("=== КОНТЕКСТ ИЗ ДРУГИХ ЧАТОВ ===\n");
-                for msg in other_session_msgs {
-                    let role = if msg.is_user { "Пользователь" } else { "Ассистент" };
-                    enriched.push_str(&format!("[{}] {}: {}\n",
-                        msg.session_title, role,
-                        msg.content.chars().take(200).collect::<String>()));
-                }
-                enriched.push('\n');
+    
+    enriched.push_str("=== РЕЛЕВАНТНЫЙ КОНТЕКСТ (для справки) ===\n");
+    for item in items {
+        let content = item.content.chars().take(200).collect::<String>();
+        match (item.session_title, item.is_user) {
+            (Some(title), Some(is_user)) => {
+                let role = if is_user { "Пользователь" } else { "Ассистент" };
+                enriched.push_str(&format!("[{}] {}: {}\n", title, role, content));
             }
+            _ => enriched.push_str(&format!("[Память] {}\n", content)),
         }
     }
+    enriched.push('\n');
+}
 
-    // Add persona info if available
-    if let Ok(Some(persona)) = database::get_user_persona() {
-        enriched.push_str(&format!(
-            "=== ПРОФИЛЬ ПОЛЬЗОВАТЕЛЯ ===\nСтиль: {}, Тон: {}, Язык: {}\n\n",
-            persona.writing_style, persona.tone, persona.language
-        ));
+// ==================== Generation Commands (with MEMORY) ====================
+
+/// Get user's custom system prompt (replace known-bad "similarity comparison" prompt with safe default)
+fn base_system_prompt() -> String {
+    let settings = database::get_settings().unwrap_or_default();
+    if is_similarity_comparison_prompt(&settings.system_prompt) {
+        default_system_prompt()
+    } else {
+        settings.system_prompt
     }
+}
 
-    enriched
+/// Build prompt with ChatML format (native LLM), ending with an open assistant turn
+fn build_chat_prompt(system_prompt: &str, history: &[HistoryMessage], prompt: &str) -> String {
+    let mut full_prompt = String::from("<|im_start|>system\n");
+    full_prompt.push_str(system_prompt);
+    full_prompt.push_str("<|im_end|>\n");
+
+    // Add session history
+    for msg in history.iter() {
+        let role = if msg.is_user { "user" } else { "assistant" };
+        full_prompt.push_str(&format!("<|im_start|>{}\n{}<|im_end|>\n", role, msg.content));
+    }
+
+    // Current message
+    full_prompt.push_str(&format!("<|im_start|>user\n{}<|im_end|>\n", prompt));
+    full_prompt.push_str("<|im_start|>assistant\n");
+    full_prompt
 }
 
-// ==================== Generation Commands (with MEMORY) ====================
+/// Effective settings of one generation. Precedence: session overrides, then the
+/// session's assistant, then the request values / global settings.
+#[derive(Debug, Clone)]
+struct GenerationConfig {
+    system_prompt: String,
+    sampling: SamplingParams,
+    max_tokens: i32,
+    use_memory: bool,
+    use_rag: bool,
+    assistant_id: Option<i64>,
+    model_path: Option<String>,
+}
+
+fn generation_config(
+    overrides: &database::SessionSettings,
+    assistant: Option<&database::Assistant>,
+    global_prompt: String,
+    temperature: f32,
+    max_tokens: i32,
+) -> GenerationConfig {
+    let base = assistant
+        .and_then(|a| a.sampling.clone())
+        .unwrap_or(SamplingParams { temperature, ..SamplingParams::default() });
+    let assistant_prompt = assistant
+        .map(|a| a.system_prompt.clone())
+        .filter(|p| !p.trim().is_empty());
+    
+    GenerationConfig {
+        system_prompt: overrides.system_prompt.clone()
+            .filter(|p| !p.trim().is_empty())
+            .or(assistant_prompt)
+            .unwrap_or(global_prompt),
+        sampling: SamplingParams {
+            temperature: overrides.temperature.unwrap_or(base.temperature),
+            top_p: overrides.top_p.unwrap_or(base.top_p),
+            top_k: overrides.top_k.unwrap_or(base.top_k),
+            min_p: overrides.min_p.unwrap_or(base.min_p),
+            repeat_penalty: overrides.repeat_penalty.unwrap_or(base.repeat_penalty),
+        },
+        max_tokens: overrides.max_tokens.unwrap_or(max_tokens),
+        use_memory: overrides.use_memory.unwrap_or(true),
+        use_rag: overrides.use_rag.unwrap_or(true),
+        assistant_id: assistant.map(|a| a.id),
+        model_path: overrides.model_path.clone()
+            .or_else(|| assistant.and_then(|a| a.model_path.clone())),
+    }
+}
+
+/// Resolve the session's settings and make sure its preferred model is loaded
+async fn prepare_generation(session_id: i64, temperature: f32, max_tokens: i32) -> Result<GenerationConfig, String> {
+    let overrides = database::get_session_settings(session_id).map_err(|e| e.to_string())?;
+    let assistant = database::get_session_assistant(session_id).map_err(|e| e.to_string())?;
+    let config = generation_config(&overrides, assistant.as_ref(), base_system_prompt(), temperature, max_tokens);
+    ensure_model(config.model_path.clone()).await?;
+    Ok(config)
+}
+
+/// Build the full prompt for answering `prompt` (system prompt enriched with memory, RAG, persona)
+fn build_full_prompt(history: &[HistoryMessage], prompt: &str, session_id: i64, config: &GenerationConfig) -> String {
+    let system_prompt = build_enriched_system_prompt(config, prompt, session_id, history);
+    build_chat_prompt(&system_prompt, history, prompt)
+}
 
+/// Split the visible conversation at the user message `prompt_idx`:
+/// everything before it is history, the message itself is the prompt.
+fn split_at_prompt(messages: &[Message], prompt_idx: usize) -> (Vec<HistoryMessage>, String) {
+    let history = messages[..prompt_idx].iter().map(HistoryMessage::from).collect();
+    (history, messages[prompt_idx].content.clone())
+}
+
+/// Run the model on a full prompt, passing every token to `on_token`.
+/// Returns the generated text and the finish reason. Must run on a blocking thread.
+#[cfg(feature = "native-llm")]
+fn run_generation<F>(full_prompt: &str, sampling: &SamplingParams, max_tokens: i32, mut on_token: F) -> Result<(String, String), String>
+where
+    F: FnMut(&str),
+{
+    let mut text = String::new();
+    let finish_reason = llm::generate(full_prompt, sampling, max_tokens.max(1) as usize, |token| {
+        if STOP_GENERATION.load(Ordering::SeqCst) {
+            return false;
+        }
+        on_token(&token);
+        text.push_str(&token);
+        true
+    })?;
+    Ok((text, finish_reason.as_str().to_string()))
+}
+
+#[cfg(not(feature = "native-llm"))]
+fn run_generation<F>(_full_prompt: &str, _sampling: &SamplingParams, _max_tokens: i32, _on_token: F) -> Result<(String, String), String>
+where
+    F: FnMut(&str),
+{
+    Err("Native LLM не собран. Соберите с --features native-llm".to_string())
+}
+
+/// Generate with streaming ("llm-token" events, then "llm-finished") in a blocking thread
+/// so the async runtime is not blocked.
+async fn stream_generation(app: AppHandle, full_prompt: String, config: GenerationConfig) -> Result<(String, String), String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let result = run_generation(&full_prompt, &config.sampling, config.max_tokens, |token| {
+            if let Err(e) = app.emit("llm-token", token) {
+                eprintln!("Failed to emit token: {}", e);
+            }
+        });
+        if let Err(e) = app.emit("llm-finished", ()) {
+            eprintln!("Failed to emit finished event: {}", e);
+        }
+        result
+    })
+    .await
+    .map_err(|e| format!("Generation task error: {}", e))?
+}
+
+/// Generate a reply to `prompt`. Returns the finish reason ("stop", "length", "cancelled")
+/// so the caller can store it with the message and offer to continue truncated answers.
 #[tauri::command]
 pub async fn generate(
     app: AppHandle,
@@ -618,74 +1612,157 @@ pub async fn generate(
     temperature: f32,
     max_tokens: i32,
     session_id: i64,
-) -> Result<(), String> {
+) -> Result<String, String> {
     STOP_GENERATION.store(false, Ordering::SeqCst);
 
-    // Get user's custom system prompt (replace known-bad "similarity comparison" prompt with safe default)
-    let settings = database::get_settings().unwrap_or_default();
-    let base_system_prompt = if is_similarity_comparison_prompt(&settings.system_prompt) {
-        default_system_prompt()
-    } else {
-        settings.system_prompt.clone()
+    let config = prepare_generation(session_id, temperature, max_tokens).await?;
+    let full_prompt = build_full_prompt(&history, &prompt, session_id, &config);
+    let (_, finish_reason) = stream_generation(app, full_prompt, config).await?;
+    Ok(finish_reason)
+}
+
+/// Re-run the last assistant reply of a session with the same history.
+/// The new reply is stored as a sibling of the old one and becomes the visible one.
+#[tauri::command]
+pub async fn regenerate_response(
+    app: AppHandle,
+    session_id: i64,
+    temperature: f32,
+    max_tokens: i32,
+) -> Result<Message, String> {
+    STOP_GENERATION.store(false, Ordering::SeqCst);
+
+    let messages = database::get_messages(session_id).map_err(|e| e.to_string())?;
+    let last = match messages.last() {
+        Some(m) if !m.is_user => m.clone(),
+        _ => return Err("Нет ответа ассистента для перегенерации".to_string()),
     };
+    let prompt_idx = messages.iter().rposition(|m| m.is_user)
+        .ok_or_else(|| "Нет сообщения пользователя для перегенерации".to_string())?;
+    let parent_id = last.parent_id.unwrap_or(messages[prompt_idx].id);
 
-    // Build enriched system prompt with memory, RAG, persona (for ALL backends)
-    let system_prompt = build_enriched_system_prompt(
-        &base_system_prompt,
-        &prompt,
-        session_id,
-    );
+    let (history, prompt) = split_at_prompt(&messages, prompt_idx);
+    let config = prepare_generation(session_id, temperature, max_tokens).await?;
+    let full_prompt = build_full_prompt(&history, &prompt, session_id, &config);
+    let (text, finish_reason) = stream_generation(app, full_prompt, config).await?;
 
-    // Build prompt with ChatML format (native LLM)
-    let mut full_prompt = String::from("<|im_start|>system\n");
-    full_prompt.push_str(&system_prompt);
-    full_prompt.push_str("<|im_end|>\n");
+    let model = current_model_name();
+    let reply = database::insert_reply(session_id, parent_id, text.trim(), Some(&finish_reason), model.as_deref(), true)
+        .map_err(|e| e.to_string())?;
+    index_message_async(reply.id, reply.content.clone());
 
-    // Add session history
-    for msg in history.iter() {
-        let role = if msg.is_user { "user" } else { "assistant" };
-        full_prompt.push_str(&format!("<|im_start|>{}\n{}<|im_end|>\n", role, msg.content));
+    Ok(reply)
+}
+
+/// Continue an assistant message (e.g. one cut off by `max_tokens`) without starting a new turn.
+/// Generated text is appended to the same message.
+#[tauri::command]
+pub async fn continue_response(
+    app: AppHandle,
+    message_id: i64,
+    temperature: f32,
+    max_tokens: i32,
+) -> Result<Message, String> {
+    STOP_GENERATION.store(false, Ordering::SeqCst);
+
+    let target = database::get_message(message_id).map_err(|e| e.to_string())?;
+    if target.is_user {
+        return Err("Продолжить можно только ответ ассистента".to_string());
     }
 
-    // Current message
-    full_prompt.push_str(&format!("<|im_start|>user\n{}<|im_end|>\n", prompt));
-    full_prompt.push_str("<|im_start|>assistant\n");
+    let messages = database::get_messages(target.session_id).map_err(|e| e.to_string())?;
+    let target_idx = messages.iter().position(|m| m.id == message_id)
+        .ok_or_else(|| "Сообщение не входит в текущий диалог".to_string())?;
+    let prompt_idx = messages[..target_idx].iter().rposition(|m| m.is_user)
+        .ok_or_else(|| "Нет сообщения пользователя перед ответом".to_string())?;
 
-    // Generate with streaming (native LLM) — run in blocking thread to not block async runtime
-    #[cfg(feature = "native-llm")]
-    let result = {
-        let app_handle = app.clone();
-        let max_tokens_usize = max_tokens as usize;
-        tauri::async_runtime::spawn_blocking(move || {
-            match llm::generate(&full_prompt, temperature, max_tokens_usize, |token| {
-                if STOP_GENERATION.load(Ordering::SeqCst) {
-                    return false;
-                }
-                if let Err(e) = app_handle.emit("llm-token", token) {
-                    eprintln!("Failed to emit token: {}", e);
-                }
-                true
-            }) {
-                Ok(_) => {
-                    if let Err(e) = app_handle.emit("llm-finished", ()) {
-                        eprintln!("Failed to emit finished event: {}", e);
-                    }
-                    Ok(())
+    let (history, prompt) = split_at_prompt(&messages, prompt_idx);
+    // Leave the assistant turn open after the existing text so the model picks up where it stopped
+    let config = prepare_generation(target.session_id, temperature, max_tokens).await?;
+    let mut full_prompt = build_full_prompt(&history, &prompt, target.session_id, &config);
+    full_prompt.push_str(&target.content);
+
+    let (text, finish_reason) = stream_generation(app, full_prompt, config).await?;
+
+    let updated = database::append_to_message(message_id, text.trim_end(), Some(&finish_reason))
+        .map_err(|e| e.to_string())?;
+    index_message_async(updated.id, updated.content.clone());
+
+    Ok(updated)
+}
+
+/// Sample `n` candidate replies to the last user message of a session.
+/// Tokens are streamed as "llm-candidate-token" events. All candidates are stored as
+/// alternatives; if the user message had no reply yet the first one becomes visible,
+/// otherwise the current reply stays and the candidates can be picked with `select_alternative`.
+#[tauri::command]
+pub async fn generate_candidates(
+    app: AppHandle,
+    session_id: i64,
+    n: usize,
+    temperature: f32,
+    max_tokens: i32,
+) -> Result<Vec<Message>, String> {
+    STOP_GENERATION.store(false, Ordering::SeqCst);
+
+    let n = n.clamp(1, MAX_CANDIDATES);
+    let messages = database::get_messages(session_id).map_err(|e| e.to_string())?;
+    let prompt_idx = messages.iter().rposition(|m| m.is_user)
+        .ok_or_else(|| "Нет сообщения пользователя для генерации".to_string())?;
+    let existing_reply = messages.get(prompt_idx + 1).filter(|m| !m.is_user).cloned();
+
+    let (history, prompt) = split_at_prompt(&messages, prompt_idx);
+    let config = prepare_generation(session_id, temperature, max_tokens).await?;
+    let full_prompt = build_full_prompt(&history, &prompt, session_id, &config);
+
+    let app_handle = app.clone();
+    let results = tauri::async_runtime::spawn_blocking(move || {
+        let mut results = Vec::with_capacity(n);
+        for index in 0..n {
+            if STOP_GENERATION.load(Ordering::SeqCst) {
+                break;
+            }
+            let result = run_generation(&full_prompt, &config.sampling, config.max_tokens, |token| {
+                let payload = CandidateToken { index, token: token.to_string() };
+                if let Err(e) = app_handle.emit("llm-candidate-token", &payload) {
+                    eprintln!("Failed to emit candidate token: {}", e);
                 }
+            });
+            match result {
+                Ok(candidate) => results.push(candidate),
                 Err(e) => {
-                    if let Err(emit_err) = app_handle.emit("llm-finished", ()) {
-                        eprintln!("Failed to emit finished event: {}", emit_err);
+                    if results.is_empty() {
+                        let _ = app_handle.emit("llm-finished", ());
+                        return Err(e);
                     }
-                    Err(e.to_string())
+                    eprintln!("Candidate {} failed: {}", index, e);
+                    break;
                 }
             }
-        })
-        .await
-        .map_err(|e| format!("Generation task error: {}", e))?
-    };
-    #[cfg(not(feature = "native-llm"))]
-    let result = Err("Native LLM не собран. Соберите с --features native-llm".to_string());
-    result
+        }
+        if let Err(e) = app_handle.emit("llm-finished", ()) {
+            eprintln!("Failed to emit finished event: {}", e);
+        }
+        Ok(results)
+    })
+    .await
+    .map_err(|e| format!("Generation task error: {}", e))??;
+
+    let parent_id = existing_reply.as_ref()
+        .and_then(|reply| reply.parent_id)
+        .unwrap_or(messages[prompt_idx].id);
+
+    let model = current_model_name();
+    let mut candidates = Vec::with_capacity(results.len());
+    for (index, (text, finish_reason)) in results.iter().enumerate() {
+        let activate = existing_reply.is_none() && index == 0;
+        let reply = database::insert_reply(session_id, parent_id, text.trim(), Some(finish_reason), model.as_deref(), activate)
+            .map_err(|e| e.to_string())?;
+        index_message_async(reply.id, reply.content.clone());
+        candidates.push(reply);
+    }
+
+    Ok(candidates)
 }
 
 #[tauri::command]
@@ -722,7 +1799,13 @@ pub async fn stop_recording() -> Result<String, String> {
 }
 
 #[tauri::command]
-pub async fn speak(text: String, voice_id: Option<i64>) -> Result<(), String> {
+/// Speak `text`. Without an explicit voice the session's assistant voice is used.
+pub async fn speak(text: String, voice_id: Option<i64>, session_id: Option<i64>) -> Result<(), String> {
+    let voice_id = voice_id.or_else(|| {
+        session_id
+            .and_then(|id| database::get_session_assistant(id).ok().flatten())
+            .and_then(|assistant| assistant.voice_profile_id)
+    });
     voice::speak(&text, voice_id).map_err(|e| e.to_string())
 }
 
@@ -766,7 +1849,7 @@ pub fn save_voice_from_chat(app: AppHandle, base64_audio: String) -> Result<Stri
         .unwrap_or(0);
     let filename = format!("chat_{}.webm", now);
     let path = voice_dir.join(&filename);
-    std::fs::write(&path, &bytes).map_err(|e| e.to_string())?;
+    encryption::write_file(&path, &bytes)?;
     
     let path_str = path.to_string_lossy().to_string();
     database::save_voice_recording(&path_str).map_err(|e| e.to_string())?;
@@ -787,8 +1870,8 @@ pub fn create_voice_profile_from_recording(recording_id: i64, name: String) -> R
 
 // ==================== SEMANTIC SEARCH Commands (RAG) ====================
 
-/// Search result type for RAG (used when embeddings feature is disabled)
-#[cfg(not(feature = "embeddings"))]
+/// Search result type for RAG (used when semantic search is not built in)
+#[cfg(not(feature = "semantic-search"))]
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SearchResult {
@@ -799,7 +1882,7 @@ pub struct SearchResult {
 }
 
 /// Find relevant context for RAG using semantic search
-#[cfg(feature = "embeddings")]
+#[cfg(feature = "semantic-search")]
 #[tauri::command]
 pub fn find_rag_context(query: String, limit: i32) -> Result<Vec<embeddings::SearchResult>, String> {
     let results = database::with_connection(|conn| {
@@ -810,14 +1893,134 @@ pub fn find_rag_context(query: String, limit: i32) -> Result<Vec<embeddings::Sea
 }
 
 /// Find relevant context - stub when embeddings disabled
-#[cfg(not(feature = "embeddings"))]
+#[cfg(not(feature = "semantic-search"))]
 #[tauri::command]
 pub fn find_rag_context(_query: String, _limit: i32) -> Result<Vec<SearchResult>, String> {
     Ok(vec![])
 }
 
+/// The context the retriever would add to a prompt in `session_id`, with scores.
+/// `config` overrides the one in the settings, for tuning the weights.
+#[tauri::command]
+pub async fn retrieve_context(prompt: String, session_id: i64, config: Option<RetrievalConfig>) -> Result<Vec<retrieval::RetrievedItem>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let config = match config {
+            Some(config) => config,
+            None => database::get_settings().map_err(|e| e.to_string())?.retrieval,
+        };
+        let assistant = database::get_session_assistant(session_id).map_err(|e| e.to_string())?;
+        let messages = database::get_messages(session_id).map_err(|e| e.to_string())?;
+        let history: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        retrieval::retrieve(&prompt, session_id, assistant.map(|a| a.id), &history, &config)
+    })
+    .await
+    .map_err(|e| format!("Retrieval task error: {}", e))?
+}
+
+/// Progress of re-embedding after a model change ("embedding-reindex-progress")
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReindexProgress {
+    pub model: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Load the embedding model chosen in the settings and, once the database is open,
+/// its vector index; then re-embed what earlier models indexed. Runs in the background;
+/// the embedder state goes out as "embedder-state" events.
+#[cfg(feature = "semantic-search")]
+pub fn start_embedder(app: AppHandle, app_dir: std::path::PathBuf) {
+    std::thread::spawn(move || {
+        // Default model while the encrypted settings can't be read yet
+        let settings = database::get_settings().map(|s| s.embedding).unwrap_or_default();
+        let bundled = bundled_models_dir(&app);
+        let on_state = |state: &embedding_models::EmbedderState| {
+            let _ = app.emit("embedder-state", state);
+        };
+        if let Err(e) = embeddings::init_embedder(&settings, bundled.as_deref(), on_state) {
+            eprintln!("Warning: Failed to initialize embeddings: {}", e);
+            return;
+        }
+        println!("🔍 Semantic search ready");
+        
+        // An encrypted database loads it on unlock
+        if encryption::is_enabled(&app_dir) && !encryption::is_unlocked() {
+            return;
+        }
+        if let Err(e) = embeddings::init_index(&app_dir) {
+            eprintln!("Warning: Failed to load vector index: {}", e);
+        }
+        if let Err(e) = reindex_with_progress(&app) {
+            eprintln!("Re-index failed: {}", e);
+        }
+    });
+}
+
+#[cfg(not(feature = "semantic-search"))]
+pub fn start_embedder(_app: AppHandle, _app_dir: std::path::PathBuf) {}
+
+#[cfg(feature = "semantic-search")]
+fn reindex_with_progress(app: &AppHandle) -> Result<usize, String> {
+    let model = embeddings::current_model_id();
+    let indexed = embeddings::reindex(|done, total| {
+        let progress = ReindexProgress { model: model.clone(), done, total };
+        let _ = app.emit("embedding-reindex-progress", &progress);
+    })?;
+    if indexed > 0 {
+        println!("🔍 Re-embedded {} entries with {}", indexed, model);
+    }
+    Ok(indexed)
+}
+
+/// Catalog of embedding models and the loaded one
+#[tauri::command]
+pub fn get_embedding_models() -> serde_json::Value {
+    #[cfg(feature = "semantic-search")]
+    let active = embeddings::active_model();
+    #[cfg(not(feature = "semantic-search"))]
+    let active: Option<()> = None;
+    serde_json::json!({
+        "models": embedding_models::CATALOG,
+        "active": active,
+    })
+}
+
+/// Embedder state: loading, ready or failed with the reason
+#[cfg(feature = "semantic-search")]
+#[tauri::command]
+pub fn get_embedder_state() -> embedding_models::EmbedderState {
+    embeddings::state()
+}
+
+/// Embedder state - stub when semantic search is not built in
+#[cfg(not(feature = "semantic-search"))]
+#[tauri::command]
+pub fn get_embedder_state() -> embedding_models::EmbedderState {
+    embedding_models::EmbedderState::Failed {
+        model: String::new(),
+        reason: "Semantic search is disabled in this build (neither embeddings nor native-llm feature)".to_string(),
+    }
+}
+
+/// Re-embed messages and memories indexed by an earlier model
+#[cfg(feature = "semantic-search")]
+#[tauri::command]
+pub async fn reindex_embeddings(app: AppHandle) -> Result<usize, String> {
+    tauri::async_runtime::spawn_blocking(move || reindex_with_progress(&app))
+        .await
+        .map_err(|e| format!("Re-index task error: {}", e))?
+}
+
+/// Re-index - stub when embeddings disabled
+#[cfg(not(feature = "semantic-search"))]
+#[tauri::command]
+pub async fn reindex_embeddings() -> Result<usize, String> {
+    Err("Semantic search is disabled in this build (neither embeddings nor native-llm feature)".to_string())
+}
+
 /// Index all existing messages for semantic search
-#[cfg(feature = "embeddings")]
+#[cfg(feature = "semantic-search")]
 #[tauri::command]
 pub async fn index_all_messages() -> Result<i32, String> {
     let messages = database::get_all_messages_for_indexing()
@@ -839,14 +2042,14 @@ pub async fn index_all_messages() -> Result<i32, String> {
 }
 
 /// Index all messages - stub when embeddings disabled
-#[cfg(not(feature = "embeddings"))]
+#[cfg(not(feature = "semantic-search"))]
 #[tauri::command]
 pub async fn index_all_messages() -> Result<i32, String> {
-    Err("Semantic search is disabled in this build (no embeddings feature)".to_string())
+    Err("Semantic search is disabled in this build (neither embeddings nor native-llm feature)".to_string())
 }
 
 /// Get embedding statistics
-#[cfg(feature = "embeddings")]
+#[cfg(feature = "semantic-search")]
 #[tauri::command]
 pub fn get_embedding_stats() -> Result<serde_json::Value, String> {
     database::with_connection(|conn| {
@@ -855,7 +2058,7 @@ pub fn get_embedding_stats() -> Result<serde_json::Value, String> {
 }
 
 /// Get embedding stats - stub when embeddings disabled
-#[cfg(not(feature = "embeddings"))]
+#[cfg(not(feature = "semantic-search"))]
 #[tauri::command]
 pub fn get_embedding_stats() -> Result<serde_json::Value, String> {
     Ok(serde_json::json!({
@@ -1270,9 +2473,13 @@ mod tests {
     fn test_message_structure() {
         let msg = Message {
             id: 1,
+            session_id: 1,
             content: "Hello".to_string(),
             is_user: true,
             timestamp: 1234567890,
+            parent_id: None,
+            finish_reason: None,
+            model: None,
         };
         
         assert_eq!(msg.id, 1);
@@ -1283,14 +2490,20 @@ mod tests {
     fn test_message_serialization_camel_case() {
         let msg = Message {
             id: 1,
+            session_id: 1,
             content: "Test".to_string(),
             is_user: false,
             timestamp: 0,
+            parent_id: Some(1),
+            finish_reason: Some("length".to_string()),
+            model: None,
         };
         
         let json = serde_json::to_string(&msg).expect("Serialization failed");
         assert!(json.contains("\"isUser\""));
         assert!(!json.contains("\"is_user\""));
+        assert!(json.contains("\"parentId\""));
+        assert!(json.contains("\"finishReason\""));
     }
 
     // ==================== HistoryMessage Tests ====================
@@ -1307,8 +2520,140 @@ mod tests {
         assert!(!history[1].is_user);
     }
 
+    #[test]
+    fn test_history_from_message() {
+        let msg = Message {
+            id: 7,
+            session_id: 1,
+            content: "Ответ".to_string(),
+            is_user: false,
+            timestamp: 0,
+            parent_id: Some(6),
+            finish_reason: Some("stop".to_string()),
+            model: None,
+        };
+        
+        let history = HistoryMessage::from(&msg);
+        assert_eq!(history.content, "Ответ");
+        assert!(!history.is_user);
+    }
+
     // ==================== Prompt Building Tests ====================
 
+    #[test]
+    fn test_build_chat_prompt() {
+        let history = vec![
+            HistoryMessage { content: "Привет".to_string(), is_user: true },
+            HistoryMessage { content: "Здравствуйте!".to_string(), is_user: false },
+        ];
+        
+        let prompt = build_chat_prompt("Ты Wishmaster", &history, "Как дела?");
+        
+        assert!(prompt.starts_with("<|im_start|>system\nТы Wishmaster<|im_end|>"));
+        assert!(prompt.contains("<|im_start|>assistant\nЗдравствуйте!<|im_end|>"));
+        assert!(prompt.ends_with("<|im_start|>user\nКак дела?<|im_end|>\n<|im_start|>assistant\n"));
+    }
+
+    #[test]
+    fn test_split_at_prompt() {
+        let make = |id: i64, content: &str, is_user: bool| Message {
+            id,
+            session_id: 1,
+            content: content.to_string(),
+            is_user,
+            timestamp: id,
+            parent_id: if id > 1 { Some(id - 1) } else { None },
+            finish_reason: None,
+            model: None,
+        };
+        let messages = vec![
+            make(1, "Привет", true),
+            make(2, "Здравствуйте!", false),
+            make(3, "Как дела?", true),
+            make(4, "Хорошо", false),
+        ];
+        
+        let (history, prompt) = split_at_prompt(&messages, 2);
+        
+        assert_eq!(history.len(), 2);
+        assert_eq!(prompt, "Как дела?");
+    }
+
+    #[test]
+    fn test_sampling_params_default_is_temperature_only() {
+        let params: SamplingParams = serde_json::from_str(r#"{"temperature": 0.3}"#)
+            .expect("Failed to deserialize");
+        
+        assert_eq!(params.temperature, 0.3);
+        assert_eq!(params.top_p, 1.0);
+        assert_eq!(params.top_k, 0);
+        assert_eq!(params.min_p, 0.0);
+        assert_eq!(params.repeat_penalty, 1.0);
+    }
+
+    #[test]
+    fn test_generation_config_session_overrides() {
+        let global = || "Глобальный промпт".to_string();
+        
+        let config = generation_config(&database::SessionSettings::default(), None, global(), 0.7, 512);
+        assert_eq!(config.system_prompt, "Глобальный промпт");
+        assert_eq!(config.sampling, SamplingParams::default());
+        assert_eq!(config.max_tokens, 512);
+        assert!(config.use_memory && config.use_rag);
+        
+        let overrides = database::SessionSettings {
+            session_id: 1,
+            system_prompt: Some("Ты помощник программиста".to_string()),
+            temperature: Some(0.2),
+            top_k: Some(40),
+            max_tokens: Some(2048),
+            use_rag: Some(false),
+            ..Default::default()
+        };
+        let config = generation_config(&overrides, None, global(), 0.7, 512);
+        assert_eq!(config.system_prompt, "Ты помощник программиста");
+        assert_eq!(config.sampling.temperature, 0.2);
+        assert_eq!(config.sampling.top_k, 40);
+        assert_eq!(config.sampling.top_p, 1.0);
+        assert_eq!(config.max_tokens, 2048);
+        assert!(config.use_memory);
+        assert!(!config.use_rag);
+    }
+
+    #[test]
+    fn test_generation_config_assistant_defaults() {
+        let assistant = database::Assistant {
+            id: 7,
+            name: "Кодер".to_string(),
+            system_prompt: "Ты опытный Rust-разработчик".to_string(),
+            model_path: Some("/models/coder.gguf".to_string()),
+            sampling: Some(SamplingParams { temperature: 0.1, top_k: 20, ..SamplingParams::default() }),
+            voice_profile_id: None,
+            memory_mode: "isolated".to_string(),
+            created_at: 0,
+        };
+        
+        let config = generation_config(
+            &database::SessionSettings::default(), Some(&assistant), "Глобальный".to_string(), 0.7, 512,
+        );
+        assert_eq!(config.system_prompt, "Ты опытный Rust-разработчик");
+        assert_eq!(config.sampling.temperature, 0.1);
+        assert_eq!(config.sampling.top_k, 20);
+        assert_eq!(config.assistant_id, Some(7));
+        assert_eq!(config.model_path.as_deref(), Some("/models/coder.gguf"));
+        
+        // Session overrides win over the assistant
+        let overrides = database::SessionSettings {
+            temperature: Some(0.9),
+            model_path: Some("/models/other.gguf".to_string()),
+            ..Default::default()
+        };
+        let config = generation_config(&overrides, Some(&assistant), "Глобальный".to_string(), 0.7, 512);
+        assert_eq!(config.sampling.temperature, 0.9);
+        assert_eq!(config.sampling.top_k, 20);
+        assert_eq!(config.model_path.as_deref(), Some("/models/other.gguf"));
+    }
+
     #[test]
     fn test_chatml_format_system() {
         let system = "Ты AI ассистент";
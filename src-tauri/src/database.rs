@@ -1,12 +1,26 @@
 use once_cell::sync::OnceCell;
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Result};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 use crate::commands::{Message, Session, Settings, VoiceProfile, VoiceRecording};
+use crate::sampling::SamplingParams;
+use crate::encryption;
+use crate::fts_query;
+use crate::importers::ImportedConversation;
+use crate::migrations;
+#[cfg(feature = "semantic-search")]
+use crate::vector_index;
 
 static DB: OnceCell<Mutex<Connection>> = OnceCell::new();
+static DB_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// First 16 bytes of every unencrypted SQLite file
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+/// Pages copied per backup step; the connection lock is held throughout anyway
+const BACKUP_PAGES_PER_STEP: i32 = 1024;
 
 // ==================== Memory Types ====================
 
@@ -20,6 +34,9 @@ pub struct MemoryEntry {
     pub source_message_id: i64,
     pub importance: i32,       // 1-10
     pub created_at: i64,
+    /// Assistant whose chat produced the memory; None = default assistant
+    #[serde(default)]
+    pub assistant_id: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,6 +61,9 @@ pub struct ExportData {
     pub messages: Vec<ExportMessage>,
     pub memory: Vec<MemoryEntry>,
     pub persona: Option<UserPersona>,
+    /// Missing in exports made before feedback existed
+    #[serde(default)]
+    pub feedback: Vec<MessageFeedback>,
     pub exported_at: i64,
 }
 
@@ -56,6 +76,114 @@ pub struct ExportMessage {
     pub content: String,
     pub is_user: bool,
     pub timestamp: i64,
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    /// Lies on the visible branch (exports made before branches were exported only have those)
+    #[serde(default = "default_true")]
+    pub is_active: bool,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// How `import_full_export` treats the data already in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Add what is missing, skipping duplicates
+    Merge,
+    /// Delete all chats, memories and the persona first
+    Replace,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FullImportReport {
+    pub dry_run: bool,
+    pub sessions_added: usize,
+    /// Sessions that already existed (same title and creation time, or sharing messages)
+    pub sessions_merged: usize,
+    pub messages_added: usize,
+    pub messages_duplicate: usize,
+    /// Messages whose session is missing from the export
+    pub messages_orphaned: usize,
+    pub memories_added: usize,
+    pub memories_duplicate: usize,
+    pub feedback_added: usize,
+    pub persona_imported: bool,
+    /// Replace mode: what was deleted first
+    pub sessions_removed: usize,
+    pub messages_removed: usize,
+    pub memories_removed: usize,
+    /// Safety backup taken before a replace
+    pub backup: Option<String>,
+}
+
+/// One message of a conversation tree
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageNode {
+    #[serde(flatten)]
+    pub message: Message,
+    /// Lies on the active branch
+    pub is_active: bool,
+    pub child_ids: Vec<i64>,
+}
+
+/// Every branch of a session; `active_leaf_id` is the last message of the visible branch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageTree {
+    pub session_id: i64,
+    pub active_leaf_id: Option<i64>,
+    pub nodes: Vec<MessageNode>,
+}
+
+/// User feedback on an answer; every part is optional
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MessageFeedback {
+    pub message_id: i64,
+    /// true = thumbs up, false = thumbs down
+    pub thumbs_up: Option<bool>,
+    /// 1-5
+    pub score: Option<i32>,
+    /// What the answer should have said
+    pub correction: Option<String>,
+    pub updated_at: i64,
+}
+
+impl MessageFeedback {
+    /// Rating on the 1-5 scale: the score, otherwise 5 / 1 for thumbs up / down
+    pub fn rating(&self) -> Option<i32> {
+        self.score.or(self.thumbs_up.map(|up| if up { 5 } else { 1 }))
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.thumbs_up.is_none() && self.score.is_none() && self.correction.is_none()
+    }
+    
+    /// How far the feedback moves the importance of memories taken from the message
+    fn importance_shift(&self) -> i32 {
+        self.rating().map(|r| r - 3).unwrap_or(0)
+    }
+}
+
+/// Filters for the feedback list; unset fields don't restrict anything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FeedbackFilter {
+    pub session_id: Option<i64>,
+    /// Rating range (see `MessageFeedback::rating`), inclusive
+    pub min_rating: Option<i32>,
+    pub max_rating: Option<i32>,
+    /// Only feedback with a correction
+    pub with_correction: bool,
 }
 
 /// Initialize the database connection
@@ -65,118 +193,11 @@ pub fn init(db_path: &Path) -> Result<()> {
         return Ok(());
     }
     
-    let conn = Connection::open(db_path)?;
-    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    let mut conn = open_live(db_path, encryption::db_key().as_deref())?;
     
-    // Create all tables including memory system
-    conn.execute_batch(r#"
-        -- Core tables
-        CREATE TABLE IF NOT EXISTS sessions (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            title TEXT NOT NULL DEFAULT 'Новый чат',
-            created_at INTEGER NOT NULL,
-            message_count INTEGER NOT NULL DEFAULT 0
-        );
-        
-        CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            session_id INTEGER NOT NULL,
-            content TEXT NOT NULL,
-            is_user INTEGER NOT NULL,
-            timestamp INTEGER NOT NULL,
-            FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
-        );
-        
-        CREATE TABLE IF NOT EXISTS settings (
-            key TEXT PRIMARY KEY,
-            value TEXT NOT NULL
-        );
-        
-        CREATE TABLE IF NOT EXISTS voice_profiles (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            name TEXT NOT NULL,
-            audio_path TEXT NOT NULL,
-            created_at INTEGER NOT NULL
-        );
-        
-        CREATE TABLE IF NOT EXISTS voice_recordings (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            path TEXT NOT NULL,
-            created_at INTEGER NOT NULL
-        );
-        
-        -- MEMORY SYSTEM: Long-term memory across all sessions
-        CREATE TABLE IF NOT EXISTS memory (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            content TEXT NOT NULL,
-            category TEXT NOT NULL DEFAULT 'fact',
-            source_session_id INTEGER,
-            source_message_id INTEGER,
-            importance INTEGER NOT NULL DEFAULT 5,
-            created_at INTEGER NOT NULL,
-            FOREIGN KEY (source_session_id) REFERENCES sessions(id) ON DELETE SET NULL
-        );
-        
-        -- USER PERSONA: Digital twin data
-        CREATE TABLE IF NOT EXISTS user_persona (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            writing_style TEXT NOT NULL DEFAULT 'casual',
-            avg_message_length REAL NOT NULL DEFAULT 0,
-            common_phrases TEXT NOT NULL DEFAULT '[]',
-            topics_of_interest TEXT NOT NULL DEFAULT '[]',
-            language TEXT NOT NULL DEFAULT 'ru',
-            emoji_usage TEXT NOT NULL DEFAULT 'minimal',
-            tone TEXT NOT NULL DEFAULT 'friendly',
-            messages_analyzed INTEGER NOT NULL DEFAULT 0,
-            last_updated INTEGER NOT NULL
-        );
-        
-        -- Indexes for fast search
-        CREATE INDEX IF NOT EXISTS idx_messages_session ON messages(session_id);
-        CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp DESC);
-        CREATE INDEX IF NOT EXISTS idx_memory_category ON memory(category);
-        CREATE INDEX IF NOT EXISTS idx_memory_importance ON memory(importance DESC);
-        CREATE INDEX IF NOT EXISTS idx_memory_session ON memory(source_session_id);
-        CREATE INDEX IF NOT EXISTS idx_memory_created ON memory(created_at DESC);
-        
-        -- Full-text search virtual table
-        CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
-            content,
-            content='messages',
-            content_rowid='id'
-        );
-        
-        -- Triggers to keep FTS in sync
-        CREATE TRIGGER IF NOT EXISTS messages_ai AFTER INSERT ON messages BEGIN
-            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
-        END;
-        
-        CREATE TRIGGER IF NOT EXISTS messages_ad AFTER DELETE ON messages BEGIN
-            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES('delete', old.id, old.content);
-        END;
-        
-        CREATE TRIGGER IF NOT EXISTS messages_au AFTER UPDATE ON messages BEGIN
-            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES('delete', old.id, old.content);
-            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
-        END;
-    "#)?;
-    
-    // Initialize embeddings table
-    conn.execute_batch(r#"
-        -- Vector embeddings storage
-        CREATE TABLE IF NOT EXISTS embeddings (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            source_type TEXT NOT NULL,
-            source_id INTEGER NOT NULL,
-            content_hash TEXT NOT NULL,
-            vector BLOB NOT NULL,
-            created_at INTEGER NOT NULL,
-            UNIQUE(source_type, source_id)
-        );
-        
-        CREATE INDEX IF NOT EXISTS idx_embeddings_source ON embeddings(source_type, source_id);
-        CREATE INDEX IF NOT EXISTS idx_embeddings_hash ON embeddings(content_hash);
-    "#)?;
+    // Bring the schema up to date (the file is backed up first if anything changes)
+    migrations::run(&mut conn, db_path)?;
+    let _ = DB_PATH.set(db_path.to_path_buf());
     
     match DB.set(Mutex::new(conn)) {
         Ok(()) => {
@@ -202,6 +223,101 @@ fn get_conn() -> Result<std::sync::MutexGuard<'static, Connection>> {
     })
 }
 
+pub fn is_initialized() -> bool {
+    DB.get().is_some()
+}
+
+/// Open a database file, keyed with the data key when encryption is on
+pub fn open_connection(path: &Path, flags: OpenFlags) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, flags)?;
+    encryption::apply_key(&conn, encryption::db_key().as_deref())?;
+    Ok(conn)
+}
+
+fn open_live(path: &Path, key: Option<&str>) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    encryption::apply_key(&conn, key)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    Ok(conn)
+}
+
+/// Copy the live database to `dest` with SQLite's online backup API (safe while the app runs)
+pub fn backup_to_file(dest: &Path) -> Result<()> {
+    let conn = get_conn()?;
+    let mut dest = open_connection(dest, OpenFlags::default())?;
+    let backup = rusqlite::backup::Backup::new(&conn, &mut dest)?;
+    backup.run_to_completion(BACKUP_PAGES_PER_STEP, std::time::Duration::ZERO, None)
+}
+
+/// Replace the live database with the contents of `src` (online backup API in reverse)
+/// and migrate it if it comes from an older version
+pub fn restore_from_file(src: &Path) -> Result<()> {
+    let src = open_connection(src, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut conn = get_conn()?;
+    rusqlite::backup::Backup::new(&src, &mut conn)?
+        .run_to_completion(BACKUP_PAGES_PER_STEP, std::time::Duration::ZERO, None)?;
+    
+    let db_path = DB_PATH.get().ok_or(rusqlite::Error::InvalidQuery)?;
+    migrations::run(&mut conn, db_path)?;
+    Ok(())
+}
+
+pub fn is_plaintext_file(path: &Path) -> bool {
+    use std::io::Read;
+    let mut header = [0u8; 16];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok_and(|_| header == SQLITE_HEADER)
+}
+
+/// Convert a closed plaintext database file to SQLCipher with `key`.
+/// The encrypted copy is verified before it replaces the original.
+pub fn encrypt_file(db_path: &Path, key: &str) -> Result<()> {
+    let tmp = db_path.with_extension("db.encrypting");
+    let _ = std::fs::remove_file(&tmp);
+    
+    {
+        let conn = Connection::open(db_path)?;
+        conn.execute("ATTACH DATABASE ?1 AS encrypted KEY ?2", params![tmp.to_string_lossy(), key])?;
+        conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+        let version = migrations::schema_version(&conn)?;
+        conn.pragma_update(Some(rusqlite::DatabaseName::Attached("encrypted")), "user_version", version)?;
+        conn.execute_batch("DETACH DATABASE encrypted;")?;
+    }
+    
+    {
+        let conn = open_live(&tmp, Some(key))?;
+        let check: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+        if check != "ok" {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CORRUPT),
+                Some(format!("Зашифрованная копия не прошла проверку: {}", check)),
+            ));
+        }
+    }
+    
+    // Note: the plaintext file is unlinked, not wiped; its blocks may stay on disk
+    std::fs::rename(&tmp, db_path).map_err(|e| {
+        rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_IOERR), Some(e.to_string()))
+    })
+}
+
+/// Encrypt the live database in place and reconnect to it with `key`
+pub fn encrypt_database(key: &str) -> Result<()> {
+    let mut conn = get_conn()?;
+    let db_path = DB_PATH.get().ok_or(rusqlite::Error::InvalidQuery)?;
+    
+    // Close the file while it is converted; holding the guard keeps other callers waiting
+    *conn = Connection::open_in_memory()?;
+    if let Err(e) = encrypt_file(db_path, key) {
+        *conn = open_live(db_path, None)?;
+        return Err(e);
+    }
+    *conn = open_live(db_path, Some(key))?;
+    Ok(())
+}
+
 /// Execute a function with database connection (for embeddings module)
 pub fn with_connection<F, T>(f: F) -> Result<T>
 where
@@ -211,6 +327,23 @@ where
     Ok(f(&conn))
 }
 
+/// Start a test from an empty in-memory database. There is one global connection,
+/// so the returned guard keeps database tests of every module from running at once.
+#[cfg(test)]
+pub(crate) fn test_database() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    init(Path::new(":memory:")).expect("Failed to init test DB");
+    get_conn().unwrap().execute_batch(
+        r#"DELETE FROM message_feedback; DELETE FROM embeddings; DELETE FROM memory;
+           DELETE FROM messages; DELETE FROM session_tags; DELETE FROM session_settings;
+           DELETE FROM sessions; DELETE FROM assistants; DELETE FROM user_persona;
+           DELETE FROM sqlite_sequence;"#,
+    ).unwrap();
+    guard
+}
+
 /// Get all messages for indexing (id, content pairs)
 pub fn get_all_messages_for_indexing() -> Result<Vec<(i64, String)>> {
     let conn = get_conn()?;
@@ -223,184 +356,1387 @@ pub fn get_all_messages_for_indexing() -> Result<Vec<(i64, String)>> {
     messages.collect()
 }
 
-fn get_timestamp() -> i64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_millis() as i64)
-        .unwrap_or(0)
+fn get_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// ==================== Settings ====================
+
+pub fn get_settings() -> Result<Settings> {
+    let conn = get_conn()?;
+    let mut settings = Settings::default();
+    
+    let mut stmt = conn.prepare("SELECT key, value FROM settings")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    
+    for row in rows.flatten() {
+        let (key, value) = row;
+        match key.as_str() {
+            "temperature" => settings.temperature = value.parse().unwrap_or(0.7),
+            "maxTokens" => settings.max_tokens = value.parse().unwrap_or(512),
+            "contextLength" => settings.context_length = value.parse().unwrap_or(2048),
+            "theme" => settings.theme = value,
+            "accentColor" => settings.accent_color = value,
+            "autoSpeak" => settings.auto_speak = value == "true",
+            "sttEnabled" => settings.stt_enabled = value == "true",
+            "ttsEnabled" => settings.tts_enabled = value == "true",
+            "modelPaths" => settings.model_paths = serde_json::from_str(&value).unwrap_or_default(),
+            "systemPrompt" => settings.system_prompt = value,
+            "backupIntervalHours" => settings.backup_interval_hours = value.parse().unwrap_or(0),
+            "backupKeep" => settings.backup_keep = value.parse().unwrap_or(settings.backup_keep),
+            "retrieval" => settings.retrieval = serde_json::from_str(&value).unwrap_or_default(),
+            "embedding" => settings.embedding = serde_json::from_str(&value).unwrap_or_default(),
+            // Legacy: migrate any old backend value to "native"
+            "llmBackend" | "ollamaBaseUrl" | "ollamaModel" | "customLlmUrl" | "serverUrl" | "modelName" => {
+                // All legacy keys ignored — backend is always "native" now
+            },
+            _ => {}
+        }
+    }
+
+    // Always force native backend
+    settings.llm_backend = "native".to_string();
+    
+    Ok(settings)
+}
+
+pub fn save_settings(settings: &Settings) -> Result<()> {
+    let conn = get_conn()?;
+    let model_paths_json = serde_json::to_string(&settings.model_paths).unwrap_or_else(|_| "[]".to_string());
+    let retrieval_json = serde_json::to_string(&settings.retrieval).unwrap_or_else(|_| "{}".to_string());
+    let embedding_json = serde_json::to_string(&settings.embedding).unwrap_or_else(|_| "{}".to_string());
+    
+    let pairs = vec![
+        ("temperature", settings.temperature.to_string()),
+        ("maxTokens", settings.max_tokens.to_string()),
+        ("contextLength", settings.context_length.to_string()),
+        ("theme", settings.theme.clone()),
+        ("accentColor", settings.accent_color.clone()),
+        ("autoSpeak", settings.auto_speak.to_string()),
+        ("sttEnabled", settings.stt_enabled.to_string()),
+        ("ttsEnabled", settings.tts_enabled.to_string()),
+        ("modelPaths", model_paths_json),
+        ("systemPrompt", settings.system_prompt.clone()),
+        ("llmBackend", "native".to_string()),
+        ("backupIntervalHours", settings.backup_interval_hours.to_string()),
+        ("backupKeep", settings.backup_keep.to_string()),
+        ("retrieval", retrieval_json),
+        ("embedding", embedding_json),
+    ];
+    
+    for (key, value) in pairs {
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+    }
+    
+    Ok(())
+}
+
+// ==================== Sessions ====================
+
+/// Filters for the session list; unset fields don't restrict anything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SessionFilter {
+    /// Exact folder name; an empty string selects sessions outside any folder
+    pub folder: Option<String>,
+    pub tag: Option<String>,
+    pub archived: Option<bool>,
+    pub pinned: Option<bool>,
+    /// Creation time range, inclusive, in milliseconds
+    pub created_from: Option<i64>,
+    pub created_to: Option<i64>,
+    /// Case-insensitive substring of the title or of a tag
+    pub text: Option<String>,
+    pub assistant_id: Option<i64>,
+}
+
+const SESSION_COLUMNS: &str = "id, title, created_at, message_count, pinned, archived, folder, assistant_id";
+
+/// Session without its tags
+fn row_to_session(row: &rusqlite::Row) -> Result<Session> {
+    Ok(Session {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        created_at: row.get(2)?,
+        message_count: row.get(3)?,
+        pinned: row.get::<_, i32>(4)? != 0,
+        archived: row.get::<_, i32>(5)? != 0,
+        folder: row.get(6)?,
+        tags: Vec::new(),
+        assistant_id: row.get(7)?,
+    })
+}
+
+pub fn get_sessions(filter: &SessionFilter) -> Result<Vec<Session>> {
+    let conn = get_conn()?;
+    
+    let mut sql = format!("SELECT {} FROM sessions WHERE 1 = 1", SESSION_COLUMNS);
+    let mut args: Vec<rusqlite::types::Value> = Vec::new();
+    
+    match filter.folder.as_deref() {
+        Some("") => sql.push_str(" AND folder IS NULL"),
+        Some(folder) => {
+            args.push(folder.to_string().into());
+            sql.push_str(&format!(" AND folder = ?{}", args.len()));
+        }
+        None => {}
+    }
+    if let Some(tag) = &filter.tag {
+        args.push(tag.clone().into());
+        sql.push_str(&format!(
+            " AND id IN (SELECT session_id FROM session_tags WHERE tag = ?{})", args.len()
+        ));
+    }
+    if let Some(archived) = filter.archived {
+        args.push((archived as i64).into());
+        sql.push_str(&format!(" AND archived = ?{}", args.len()));
+    }
+    if let Some(pinned) = filter.pinned {
+        args.push((pinned as i64).into());
+        sql.push_str(&format!(" AND pinned = ?{}", args.len()));
+    }
+    if let Some(from) = filter.created_from {
+        args.push(from.into());
+        sql.push_str(&format!(" AND created_at >= ?{}", args.len()));
+    }
+    if let Some(to) = filter.created_to {
+        args.push(to.into());
+        sql.push_str(&format!(" AND created_at <= ?{}", args.len()));
+    }
+    if let Some(assistant_id) = filter.assistant_id {
+        args.push(assistant_id.into());
+        sql.push_str(&format!(" AND assistant_id = ?{}", args.len()));
+    }
+    sql.push_str(" ORDER BY pinned DESC, created_at DESC");
+    
+    let mut tags = load_session_tags(&conn)?;
+    let mut stmt = conn.prepare(&sql)?;
+    let sessions = stmt.query_map(rusqlite::params_from_iter(args), row_to_session)?;
+    
+    // SQLite's LIKE/lower() only fold ASCII, so the text filter runs here
+    let needle = filter.text.as_deref().map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty());
+    let mut result = Vec::new();
+    for session in sessions {
+        let mut session = session?;
+        session.tags = tags.remove(&session.id).unwrap_or_default();
+        if let Some(needle) = &needle {
+            let matches = session.title.to_lowercase().contains(needle.as_str())
+                || session.tags.iter().any(|t| t.to_lowercase().contains(needle.as_str()));
+            if !matches {
+                continue;
+            }
+        }
+        result.push(session);
+    }
+    
+    Ok(result)
+}
+
+pub fn get_session(session_id: i64) -> Result<Session> {
+    let conn = get_conn()?;
+    query_session(&conn, session_id)
+}
+
+/// One session with its tags
+fn query_session(conn: &Connection, session_id: i64) -> Result<Session> {
+    let mut session = conn.query_row(
+        &format!("SELECT {} FROM sessions WHERE id = ?1", SESSION_COLUMNS), params![session_id], row_to_session
+    )?;
+    let mut stmt = conn.prepare("SELECT tag FROM session_tags WHERE session_id = ?1 ORDER BY tag")?;
+    session.tags = stmt.query_map(params![session_id], |row| row.get(0))?.collect::<Result<_>>()?;
+    Ok(session)
+}
+
+fn load_session_tags(conn: &Connection) -> Result<std::collections::HashMap<i64, Vec<String>>> {
+    let mut stmt = conn.prepare("SELECT session_id, tag FROM session_tags ORDER BY tag")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+    
+    let mut tags: std::collections::HashMap<i64, Vec<String>> = std::collections::HashMap::new();
+    for row in rows {
+        let (session_id, tag) = row?;
+        tags.entry(session_id).or_default().push(tag);
+    }
+    Ok(tags)
+}
+
+/// Create a session; `assistant_id` None talks to the default assistant
+pub fn create_session(title: &str, assistant_id: Option<i64>) -> Result<i64> {
+    let conn = get_conn()?;
+    let now = get_timestamp();
+    
+    conn.execute(
+        "INSERT INTO sessions (title, created_at, message_count, assistant_id) VALUES (?1, ?2, 0, ?3)",
+        params![title, now, assistant_id],
+    )?;
+    
+    Ok(conn.last_insert_rowid())
+}
+
+/// Delete a session with its messages and their vectors. Memories extracted from it stay.
+pub fn delete_session(session_id: i64) -> Result<()> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    let vectors = delete_vectors(
+        &tx,
+        "source_type = 'message' AND source_id IN (SELECT id FROM messages WHERE session_id = ?1)",
+        params![session_id],
+    )?;
+    tx.execute(
+        "DELETE FROM message_feedback WHERE message_id IN (SELECT id FROM messages WHERE session_id = ?1)",
+        params![session_id],
+    )?;
+    tx.execute("DELETE FROM messages WHERE session_id = ?1", params![session_id])?;
+    tx.execute("DELETE FROM session_tags WHERE session_id = ?1", params![session_id])?;
+    tx.execute("DELETE FROM session_settings WHERE session_id = ?1", params![session_id])?;
+    tx.execute("DELETE FROM sessions WHERE id = ?1", params![session_id])?;
+    tx.commit()?;
+    forget_vectors(&vectors);
+    Ok(())
+}
+
+/// Delete the `embeddings` rows matching `condition` and return their sources, to be
+/// passed to `forget_vectors` once the transaction has committed
+fn delete_vectors(conn: &Connection, condition: &str, params: impl rusqlite::Params) -> Result<Vec<(String, i64)>> {
+    let mut stmt = conn.prepare(&format!(
+        "DELETE FROM embeddings WHERE {} RETURNING source_type, source_id", condition
+    ))?;
+    let sources = stmt.query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    sources.collect()
+}
+
+/// Drop deleted vectors from the nearest-neighbour index, so they stop taking
+/// the places of live ones among its candidates
+fn forget_vectors(sources: &[(String, i64)]) {
+    #[cfg(feature = "semantic-search")]
+    for (source_type, source_id) in sources {
+        vector_index::remove_source(source_type, *source_id);
+    }
+    #[cfg(not(feature = "semantic-search"))]
+    let _ = sources;
+}
+
+/// Run an UPDATE on one session, failing if the session doesn't exist
+fn update_session(sql: &str, session_id: i64, value: rusqlite::types::Value) -> Result<()> {
+    let conn = get_conn()?;
+    if conn.execute(sql, params![value, session_id])? == 0 {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    Ok(())
+}
+
+pub fn rename_session(session_id: i64, title: &str) -> Result<()> {
+    update_session("UPDATE sessions SET title = ?1 WHERE id = ?2", session_id, title.to_string().into())
+}
+
+pub fn set_session_pinned(session_id: i64, pinned: bool) -> Result<()> {
+    update_session("UPDATE sessions SET pinned = ?1 WHERE id = ?2", session_id, (pinned as i64).into())
+}
+
+pub fn set_session_archived(session_id: i64, archived: bool) -> Result<()> {
+    update_session("UPDATE sessions SET archived = ?1 WHERE id = ?2", session_id, (archived as i64).into())
+}
+
+pub fn set_session_folder(session_id: i64, folder: Option<&str>) -> Result<()> {
+    let value = match folder {
+        Some(folder) => folder.to_string().into(),
+        None => rusqlite::types::Value::Null,
+    };
+    update_session("UPDATE sessions SET folder = ?1 WHERE id = ?2", session_id, value)
+}
+
+/// Replace the tags of a session. Tags are trimmed, empty ones and duplicates dropped.
+pub fn set_session_tags(session_id: i64, tags: &[String]) -> Result<Vec<String>> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    
+    let exists: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM sessions WHERE id = ?1",
+        params![session_id],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    
+    let mut clean: Vec<String> = tags.iter()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect();
+    clean.sort();
+    clean.dedup();
+    
+    tx.execute("DELETE FROM session_tags WHERE session_id = ?1", params![session_id])?;
+    for tag in &clean {
+        tx.execute(
+            "INSERT INTO session_tags (session_id, tag) VALUES (?1, ?2)",
+            params![session_id, tag],
+        )?;
+    }
+    
+    tx.commit()?;
+    Ok(clean)
+}
+
+/// All folder names in use
+pub fn get_session_folders() -> Result<Vec<String>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT folder FROM sessions WHERE folder IS NOT NULL ORDER BY folder"
+    )?;
+    let folders = stmt.query_map([], |row| row.get(0))?;
+    folders.collect()
+}
+
+/// All tags in use
+pub fn get_all_session_tags() -> Result<Vec<String>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare("SELECT DISTINCT tag FROM session_tags ORDER BY tag")?;
+    let tags = stmt.query_map([], |row| row.get(0))?;
+    tags.collect()
+}
+
+// ==================== Assistants ====================
+
+/// A named assistant persona. Sessions are created against one; empty/None fields
+/// fall back to the global settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Assistant {
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub system_prompt: String,
+    #[serde(default)]
+    pub model_path: Option<String>,
+    /// Sampling preset; None = the temperature from the global settings
+    #[serde(default)]
+    pub sampling: Option<SamplingParams>,
+    /// Voice used for TTS of this assistant's replies
+    #[serde(default)]
+    pub voice_profile_id: Option<i64>,
+    /// "shared" (common memory pool) or "isolated" (own memories only)
+    #[serde(default = "default_memory_mode")]
+    pub memory_mode: String,
+    #[serde(default)]
+    pub created_at: i64,
+}
+
+fn default_memory_mode() -> String {
+    "shared".to_string()
+}
+
+const ASSISTANT_COLUMNS: &str =
+    "id, name, system_prompt, model_path, sampling, voice_profile_id, memory_mode, created_at";
+
+fn row_to_assistant(row: &rusqlite::Row) -> Result<Assistant> {
+    let sampling: Option<String> = row.get(4)?;
+    Ok(Assistant {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        system_prompt: row.get(2)?,
+        model_path: row.get(3)?,
+        sampling: sampling.and_then(|json| serde_json::from_str(&json).ok()),
+        voice_profile_id: row.get(5)?,
+        memory_mode: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+fn sampling_to_json(sampling: &Option<SamplingParams>) -> Option<String> {
+    sampling.as_ref().and_then(|s| serde_json::to_string(s).ok())
+}
+
+pub fn get_assistants() -> Result<Vec<Assistant>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM assistants ORDER BY name COLLATE NOCASE", ASSISTANT_COLUMNS
+    ))?;
+    let assistants = stmt.query_map([], row_to_assistant)?;
+    assistants.collect()
+}
+
+pub fn get_assistant(id: i64) -> Result<Assistant> {
+    let conn = get_conn()?;
+    conn.query_row(
+        &format!("SELECT {} FROM assistants WHERE id = ?1", ASSISTANT_COLUMNS),
+        params![id],
+        row_to_assistant,
+    )
+}
+
+/// Assistant a session was created against (None = default assistant)
+pub fn get_session_assistant(session_id: i64) -> Result<Option<Assistant>> {
+    let conn = get_conn()?;
+    let assistant = conn.query_row(
+        &format!(
+            "SELECT {} FROM assistants WHERE id = (SELECT assistant_id FROM sessions WHERE id = ?1)",
+            ASSISTANT_COLUMNS
+        ),
+        params![session_id],
+        row_to_assistant,
+    );
+    
+    match assistant {
+        Ok(assistant) => Ok(Some(assistant)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn create_assistant(assistant: &Assistant) -> Result<i64> {
+    let conn = get_conn()?;
+    conn.execute(
+        r#"INSERT INTO assistants (name, system_prompt, model_path, sampling, voice_profile_id, memory_mode, created_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+        params![
+            assistant.name,
+            assistant.system_prompt,
+            assistant.model_path,
+            sampling_to_json(&assistant.sampling),
+            assistant.voice_profile_id,
+            assistant.memory_mode,
+            get_timestamp(),
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn update_assistant(assistant: &Assistant) -> Result<()> {
+    let conn = get_conn()?;
+    let updated = conn.execute(
+        r#"UPDATE assistants SET name = ?1, system_prompt = ?2, model_path = ?3, sampling = ?4,
+               voice_profile_id = ?5, memory_mode = ?6
+           WHERE id = ?7"#,
+        params![
+            assistant.name,
+            assistant.system_prompt,
+            assistant.model_path,
+            sampling_to_json(&assistant.sampling),
+            assistant.voice_profile_id,
+            assistant.memory_mode,
+            assistant.id,
+        ],
+    )?;
+    if updated == 0 {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    Ok(())
+}
+
+/// Delete an assistant. Its sessions move to the default assistant; memories of an
+/// isolated assistant are deleted with their vectors, shared ones join the common pool.
+pub fn delete_assistant(id: i64) -> Result<()> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    
+    let memory_mode: String = tx.query_row(
+        "SELECT memory_mode FROM assistants WHERE id = ?1",
+        params![id],
+        |row| row.get(0),
+    )?;
+    let mut vectors = Vec::new();
+    if memory_mode == "isolated" {
+        vectors = delete_vectors(
+            &tx,
+            "source_type = 'memory' AND source_id IN (SELECT id FROM memory WHERE assistant_id = ?1)",
+            params![id],
+        )?;
+        tx.execute("DELETE FROM memory WHERE assistant_id = ?1", params![id])?;
+    } else {
+        tx.execute("UPDATE memory SET assistant_id = NULL WHERE assistant_id = ?1", params![id])?;
+    }
+    tx.execute("UPDATE sessions SET assistant_id = NULL WHERE assistant_id = ?1", params![id])?;
+    tx.execute("DELETE FROM assistants WHERE id = ?1", params![id])?;
+    
+    tx.commit()?;
+    forget_vectors(&vectors);
+    Ok(())
+}
+
+// ==================== Session Settings ====================
+
+/// Per-session overrides of the global settings; None means "use the global value"
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SessionSettings {
+    pub session_id: i64,
+    pub system_prompt: Option<String>,
+    /// Model loaded automatically when the session is opened
+    pub model_path: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub min_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub max_tokens: Option<i32>,
+    /// Inject long-term memory and the user persona (default on)
+    pub use_memory: Option<bool>,
+    /// Inject RAG / keyword context from other chats (default on)
+    pub use_rag: Option<bool>,
+}
+
+/// Settings of a session; a session without overrides gets an empty `SessionSettings`
+pub fn get_session_settings(session_id: i64) -> Result<SessionSettings> {
+    let conn = get_conn()?;
+    let settings = conn.query_row(
+        r#"SELECT system_prompt, model_path, temperature, top_p, top_k, min_p,
+                  repeat_penalty, max_tokens, use_memory, use_rag
+           FROM session_settings WHERE session_id = ?1"#,
+        params![session_id],
+        |row| Ok(SessionSettings {
+            session_id,
+            system_prompt: row.get(0)?,
+            model_path: row.get(1)?,
+            temperature: row.get::<_, Option<f64>>(2)?.map(|v| v as f32),
+            top_p: row.get::<_, Option<f64>>(3)?.map(|v| v as f32),
+            top_k: row.get(4)?,
+            min_p: row.get::<_, Option<f64>>(5)?.map(|v| v as f32),
+            repeat_penalty: row.get::<_, Option<f64>>(6)?.map(|v| v as f32),
+            max_tokens: row.get(7)?,
+            use_memory: row.get(8)?,
+            use_rag: row.get(9)?,
+        }),
+    );
+    
+    match settings {
+        Ok(settings) => Ok(settings),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(SessionSettings { session_id, ..Default::default() }),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn save_session_settings(settings: &SessionSettings) -> Result<()> {
+    let conn = get_conn()?;
+    conn.execute(
+        r#"INSERT OR REPLACE INTO session_settings
+           (session_id, system_prompt, model_path, temperature, top_p, top_k, min_p,
+            repeat_penalty, max_tokens, use_memory, use_rag)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"#,
+        params![
+            settings.session_id,
+            settings.system_prompt,
+            settings.model_path,
+            settings.temperature.map(|v| v as f64),
+            settings.top_p.map(|v| v as f64),
+            settings.top_k,
+            settings.min_p.map(|v| v as f64),
+            settings.repeat_penalty.map(|v| v as f64),
+            settings.max_tokens,
+            settings.use_memory,
+            settings.use_rag,
+        ],
+    )?;
+    Ok(())
+}
+
+// ==================== Messages ====================
+
+const MESSAGE_COLUMNS: &str = "id, session_id, content, is_user, timestamp, parent_id, finish_reason, model";
+
+fn row_to_message(row: &rusqlite::Row) -> Result<Message> {
+    Ok(Message {
+        id: row.get(0)?,
+        session_id: row.get(1)?,
+        content: row.get(2)?,
+        is_user: row.get::<_, i32>(3)? != 0,
+        timestamp: row.get(4)?,
+        parent_id: row.get(5)?,
+        finish_reason: row.get(6)?,
+        model: row.get(7)?,
+    })
+}
+
+/// Get the active branch of a session, oldest first
+pub fn get_messages(session_id: i64) -> Result<Vec<Message>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE session_id = ?1 AND is_active = 1 ORDER BY timestamp ASC, id ASC",
+        MESSAGE_COLUMNS
+    ))?;
+    
+    let messages = stmt.query_map(params![session_id], row_to_message)?;
+    
+    messages.collect()
+}
+
+pub fn get_message(message_id: i64) -> Result<Message> {
+    let conn = get_conn()?;
+    query_message(&conn, message_id)
+}
+
+fn query_message(conn: &Connection, message_id: i64) -> Result<Message> {
+    conn.query_row(
+        &format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS),
+        params![message_id],
+        row_to_message,
+    )
+}
+
+/// Append a message to the end of the active branch
+pub fn insert_message(session_id: i64, content: &str, is_user: bool, finish_reason: Option<&str>, model: Option<&str>) -> Result<i64> {
+    let conn = get_conn()?;
+    let now = get_timestamp();
+    
+    let parent_id: Option<i64> = conn.query_row(
+        "SELECT active_leaf_id FROM sessions WHERE id = ?1",
+        params![session_id],
+        |row| row.get(0),
+    )?;
+    
+    conn.execute(
+        "INSERT INTO messages (session_id, content, is_user, timestamp, parent_id, finish_reason, model) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![session_id, content, is_user as i32, now, parent_id, finish_reason, model],
+    )?;
+    let message_id = conn.last_insert_rowid();
+    
+    conn.execute(
+        "UPDATE sessions SET message_count = message_count + 1, active_leaf_id = ?1 WHERE id = ?2",
+        params![message_id, session_id],
+    )?;
+    
+    Ok(message_id)
+}
+
+// ==================== Conversation Tree (branches / alternatives) ====================
+
+/// Make the path from the root to `leaf_id` the active branch of the session.
+/// Only rows whose flag actually changes are touched, so FTS triggers stay cheap.
+fn set_active_leaf(conn: &Connection, session_id: i64, leaf_id: i64) -> Result<()> {
+    conn.execute(
+        r#"WITH RECURSIVE path(id) AS (
+               SELECT ?2
+               UNION ALL
+               SELECT m.parent_id FROM messages m JOIN path p ON m.id = p.id
+               WHERE m.parent_id IS NOT NULL
+           )
+           UPDATE messages SET is_active = (id IN (SELECT id FROM path))
+           WHERE session_id = ?1 AND is_active != (id IN (SELECT id FROM path))"#,
+        params![session_id, leaf_id],
+    )?;
+    
+    conn.execute(
+        r#"UPDATE sessions SET
+               active_leaf_id = ?2,
+               message_count = (SELECT COUNT(*) FROM messages WHERE session_id = ?1 AND is_active = 1)
+           WHERE id = ?1"#,
+        params![session_id, leaf_id],
+    )?;
+    
+    Ok(())
+}
+
+/// The most recent message in the subtree of `message_id`. Children are always newer
+/// than their parent, so this is a leaf — the branch the user was last working on.
+fn latest_leaf_under(conn: &Connection, message_id: i64) -> Result<i64> {
+    conn.query_row(
+        r#"WITH RECURSIVE subtree(id) AS (
+               SELECT ?1
+               UNION ALL
+               SELECT m.id FROM messages m JOIN subtree s ON m.parent_id = s.id
+           )
+           SELECT m.id FROM messages m JOIN subtree s ON m.id = s.id
+           ORDER BY m.timestamp DESC, m.id DESC LIMIT 1"#,
+        params![message_id],
+        |row| row.get(0),
+    )
+}
+
+/// Store a new assistant reply to `parent_id`. With `activate` it becomes the visible
+/// reply, otherwise it is kept as a hidden alternative.
+pub fn insert_reply(session_id: i64, parent_id: i64, content: &str, finish_reason: Option<&str>, model: Option<&str>, activate: bool) -> Result<Message> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    let now = get_timestamp();
+    
+    tx.execute(
+        "INSERT INTO messages (session_id, content, is_user, timestamp, parent_id, is_active, finish_reason, model) VALUES (?1, ?2, 0, ?3, ?4, 0, ?5, ?6)",
+        params![session_id, content, now, parent_id, finish_reason, model],
+    )?;
+    let message_id = tx.last_insert_rowid();
+    
+    if activate {
+        set_active_leaf(&tx, session_id, message_id)?;
+    }
+    
+    let message = query_message(&tx, message_id)?;
+    tx.commit()?;
+    Ok(message)
+}
+
+/// Edit a message by creating a new branch: the edited copy becomes a sibling of the
+/// original and the new active leaf. Everything said after the original stays on the old branch.
+pub fn edit_message_as_branch(message_id: i64, content: &str) -> Result<Message> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    let now = get_timestamp();
+    
+    let original = query_message(&tx, message_id)?;
+    tx.execute(
+        "INSERT INTO messages (session_id, content, is_user, timestamp, parent_id, is_active) VALUES (?1, ?2, ?3, ?4, ?5, 0)",
+        params![original.session_id, content, original.is_user as i32, now, original.parent_id],
+    )?;
+    let branch_id = tx.last_insert_rowid();
+    set_active_leaf(&tx, original.session_id, branch_id)?;
+    
+    let message = query_message(&tx, branch_id)?;
+    tx.commit()?;
+    Ok(message)
+}
+
+/// Switch to the branch containing `message_id` (continuing to its most recent leaf)
+pub fn switch_branch(message_id: i64) -> Result<()> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    
+    let message = query_message(&tx, message_id)?;
+    let leaf_id = latest_leaf_under(&tx, message_id)?;
+    set_active_leaf(&tx, message.session_id, leaf_id)?;
+    
+    tx.commit()
+}
+
+/// Get all alternatives of a message (siblings sharing its parent, including itself), oldest first
+pub fn get_alternatives(message_id: i64) -> Result<Vec<Message>> {
+    let conn = get_conn()?;
+    let message = query_message(&conn, message_id)?;
+    
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE session_id = ?1 AND parent_id IS ?2 AND is_user = ?3 ORDER BY timestamp ASC, id ASC",
+        MESSAGE_COLUMNS
+    ))?;
+    
+    let messages = stmt.query_map(
+        params![message.session_id, message.parent_id, message.is_user as i32],
+        row_to_message,
+    )?;
+    
+    messages.collect()
+}
+
+/// Get the whole conversation tree of a session
+pub fn get_message_tree(session_id: i64) -> Result<MessageTree> {
+    let conn = get_conn()?;
+    
+    let active_leaf_id: Option<i64> = conn.query_row(
+        "SELECT active_leaf_id FROM sessions WHERE id = ?1",
+        params![session_id],
+        |row| row.get(0),
+    )?;
+    
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, is_active FROM messages WHERE session_id = ?1 ORDER BY timestamp ASC, id ASC",
+        MESSAGE_COLUMNS
+    ))?;
+    let mut nodes: Vec<MessageNode> = stmt.query_map(params![session_id], |row| {
+        Ok(MessageNode {
+            message: row_to_message(row)?,
+            is_active: row.get::<_, i32>(8)? != 0,
+            child_ids: Vec::new(),
+        })
+    })?.collect::<Result<_>>()?;
+    
+    let index: std::collections::HashMap<i64, usize> = nodes.iter()
+        .enumerate()
+        .map(|(i, n)| (n.message.id, i))
+        .collect();
+    let links: Vec<(usize, i64)> = nodes.iter()
+        .filter_map(|n| n.message.parent_id.and_then(|p| index.get(&p)).map(|&i| (i, n.message.id)))
+        .collect();
+    for (parent_idx, child_id) in links {
+        nodes[parent_idx].child_ids.push(child_id);
+    }
+    
+    Ok(MessageTree {
+        session_id,
+        active_leaf_id,
+        nodes,
+    })
+}
+
+/// Change the text of a message in place. The stale vector is dropped right away
+/// so RAG never serves the old text; the caller re-embeds the new content.
+pub fn update_message(message_id: i64, content: &str) -> Result<Message> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    
+    let updated = tx.execute(
+        "UPDATE messages SET content = ?1 WHERE id = ?2",
+        params![content, message_id],
+    )?;
+    if updated == 0 {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    let vectors = delete_vectors(&tx, "source_type = 'message' AND source_id = ?1", params![message_id])?;
+    
+    let message = query_message(&tx, message_id)?;
+    tx.commit()?;
+    forget_vectors(&vectors);
+    Ok(message)
+}
+
+/// Delete a single message. Its replies are re-attached to its parent so the rest of the
+/// conversation survives. The message's vector and any memories extracted from it
+/// (with their vectors) are removed too.
+pub fn delete_message(message_id: i64) -> Result<()> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    
+    let message = query_message(&tx, message_id)?;
+    let active_leaf_id: Option<i64> = tx.query_row(
+        "SELECT active_leaf_id FROM sessions WHERE id = ?1",
+        params![message.session_id],
+        |row| row.get(0),
+    )?;
+    
+    tx.execute(
+        "UPDATE messages SET parent_id = ?1 WHERE parent_id = ?2",
+        params![message.parent_id, message_id],
+    )?;
+    let vectors = delete_vectors(
+        &tx,
+        "(source_type = 'message' AND source_id = ?1)
+            OR (source_type = 'memory' AND source_id IN (SELECT id FROM memory WHERE source_message_id = ?1))",
+        params![message_id],
+    )?;
+    tx.execute("DELETE FROM memory WHERE source_message_id = ?1", params![message_id])?;
+    tx.execute("DELETE FROM message_feedback WHERE message_id = ?1", params![message_id])?;
+    tx.execute("DELETE FROM messages WHERE id = ?1", params![message_id])?;
+    
+    // Deleting the last message of the active branch moves the branch end one step back
+    let leaf_id = if active_leaf_id == Some(message_id) {
+        match message.parent_id {
+            Some(parent_id) => Some(parent_id),
+            None => tx.query_row(
+                "SELECT id FROM messages WHERE session_id = ?1 ORDER BY timestamp DESC, id DESC LIMIT 1",
+                params![message.session_id],
+                |row| row.get(0),
+            ).optional()?,
+        }
+    } else {
+        active_leaf_id
+    };
+    
+    match leaf_id {
+        Some(leaf_id) => set_active_leaf(&tx, message.session_id, leaf_id)?,
+        None => {
+            tx.execute(
+                "UPDATE sessions SET active_leaf_id = NULL, message_count = 0 WHERE id = ?1",
+                params![message.session_id],
+            )?;
+        }
+    }
+    
+    tx.commit()?;
+    forget_vectors(&vectors);
+    Ok(())
+}
+
+/// Append generated text to an existing message (continue a truncated answer)
+pub fn append_to_message(message_id: i64, text: &str, finish_reason: Option<&str>) -> Result<Message> {
+    let conn = get_conn()?;
+    
+    conn.execute(
+        "UPDATE messages SET content = content || ?1, finish_reason = ?2 WHERE id = ?3",
+        params![text, finish_reason, message_id],
+    )?;
+    
+    query_message(&conn, message_id)
+}
+
+// ==================== Message Feedback ====================
+
+const FEEDBACK_COLUMNS: &str = "message_id, thumbs_up, score, correction, updated_at";
+
+/// Rating of a `message_feedback f` row in SQL, same as `MessageFeedback::rating`
+const FEEDBACK_RATING: &str = "COALESCE(f.score, CASE f.thumbs_up WHEN 1 THEN 5 WHEN 0 THEN 1 END)";
+
+fn row_to_feedback(row: &rusqlite::Row) -> Result<MessageFeedback> {
+    Ok(MessageFeedback {
+        message_id: row.get(0)?,
+        thumbs_up: row.get::<_, Option<i32>>(1)?.map(|v| v != 0),
+        score: row.get(2)?,
+        correction: row.get(3)?,
+        updated_at: row.get(4)?,
+    })
+}
+
+fn query_feedback(conn: &Connection, message_id: i64) -> Result<Option<MessageFeedback>> {
+    match conn.query_row(
+        &format!("SELECT {} FROM message_feedback WHERE message_id = ?1", FEEDBACK_COLUMNS),
+        params![message_id],
+        row_to_feedback,
+    ) {
+        Ok(feedback) => Ok(Some(feedback)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn get_message_feedback(message_id: i64) -> Result<Option<MessageFeedback>> {
+    let conn = get_conn()?;
+    query_feedback(&conn, message_id)
+}
+
+/// Store the feedback on a message, replacing the previous one; empty feedback removes it.
+/// Memories taken from the message gain or lose importance with the rating. Each memory
+/// remembers the shift it got (the 1..10 bounds can cut it short), so a new rating
+/// first takes back exactly that.
+pub fn set_message_feedback(feedback: &MessageFeedback) -> Result<Option<MessageFeedback>> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    
+    let feedback = MessageFeedback {
+        correction: feedback.correction.as_deref()
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(str::to_string),
+        updated_at: get_timestamp(),
+        ..feedback.clone()
+    };
+    if feedback.is_empty() {
+        tx.execute("DELETE FROM message_feedback WHERE message_id = ?1", params![feedback.message_id])?;
+    } else {
+        tx.execute(
+            r#"INSERT INTO message_feedback (message_id, thumbs_up, score, correction, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5)
+               ON CONFLICT(message_id) DO UPDATE SET
+                   thumbs_up = excluded.thumbs_up,
+                   score = excluded.score,
+                   correction = excluded.correction,
+                   updated_at = excluded.updated_at"#,
+            params![
+                feedback.message_id,
+                feedback.thumbs_up.map(|up| up as i32),
+                feedback.score,
+                feedback.correction,
+                feedback.updated_at
+            ],
+        )?;
+    }
+    
+    tx.execute(
+        r#"UPDATE memory SET
+               importance = MAX(1, MIN(10, importance - feedback_shift + ?1)),
+               feedback_shift = MAX(1, MIN(10, importance - feedback_shift + ?1)) - (importance - feedback_shift)
+           WHERE source_message_id = ?2"#,
+        params![feedback.importance_shift(), feedback.message_id],
+    )?;
+    
+    tx.commit()?;
+    Ok((!feedback.is_empty()).then_some(feedback))
+}
+
+/// Feedback matching the filter, newest first
+pub fn get_feedback(filter: &FeedbackFilter) -> Result<Vec<MessageFeedback>> {
+    let conn = get_conn()?;
+    
+    let mut sql = format!("SELECT {} FROM message_feedback f WHERE 1 = 1", FEEDBACK_COLUMNS);
+    let mut args: Vec<rusqlite::types::Value> = Vec::new();
+    
+    if let Some(session_id) = filter.session_id {
+        args.push(session_id.into());
+        sql.push_str(&format!(
+            " AND message_id IN (SELECT id FROM messages WHERE session_id = ?{})", args.len()
+        ));
+    }
+    if let Some(min) = filter.min_rating {
+        args.push((min as i64).into());
+        sql.push_str(&format!(" AND {} >= ?{}", FEEDBACK_RATING, args.len()));
+    }
+    if let Some(max) = filter.max_rating {
+        args.push((max as i64).into());
+        sql.push_str(&format!(" AND {} <= ?{}", FEEDBACK_RATING, args.len()));
+    }
+    if filter.with_correction {
+        sql.push_str(" AND correction IS NOT NULL");
+    }
+    sql.push_str(" ORDER BY updated_at DESC");
+    
+    let mut stmt = conn.prepare(&sql)?;
+    let feedback = stmt.query_map(rusqlite::params_from_iter(args), row_to_feedback)?;
+    
+    feedback.collect()
+}
+
+// ==================== GLOBAL SEARCH (across ALL sessions) ====================
+
+fn row_to_export_message(row: &rusqlite::Row) -> Result<ExportMessage> {
+    Ok(ExportMessage {
+        id: row.get(0)?,
+        session_id: row.get(1)?,
+        session_title: row.get(2)?,
+        content: row.get(3)?,
+        is_user: row.get::<_, i32>(4)? != 0,
+        timestamp: row.get(5)?,
+        parent_id: row.get(6)?,
+        is_active: row.get::<_, i32>(7)? != 0,
+        finish_reason: row.get(8)?,
+        model: row.get(9)?,
+    })
+}
+
+/// Who wrote a message, for search filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRole {
+    User,
+    Assistant,
 }
 
-// ==================== Settings ====================
+/// Full-text search over the visible branches. `query` uses the syntax of
+/// `fts_query::parse`: words, "phrases", prefix*, -exclusions and OR.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MessageSearch {
+    pub query: String,
+    pub session_id: Option<i64>,
+    pub role: Option<MessageRole>,
+    /// Message time range, inclusive, in milliseconds
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: i64,
+    /// Put around matched words in the snippet
+    pub mark_start: String,
+    pub mark_end: String,
+}
 
-pub fn get_settings() -> Result<Settings> {
-    let conn = get_conn()?;
-    let mut settings = Settings::default();
-    
-    let mut stmt = conn.prepare("SELECT key, value FROM settings")?;
-    let rows = stmt.query_map([], |row| {
-        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-    })?;
-    
-    for row in rows.flatten() {
-        let (key, value) = row;
-        match key.as_str() {
-            "temperature" => settings.temperature = value.parse().unwrap_or(0.7),
-            "maxTokens" => settings.max_tokens = value.parse().unwrap_or(512),
-            "contextLength" => settings.context_length = value.parse().unwrap_or(2048),
-            "theme" => settings.theme = value,
-            "accentColor" => settings.accent_color = value,
-            "autoSpeak" => settings.auto_speak = value == "true",
-            "sttEnabled" => settings.stt_enabled = value == "true",
-            "ttsEnabled" => settings.tts_enabled = value == "true",
-            "modelPaths" => settings.model_paths = serde_json::from_str(&value).unwrap_or_default(),
-            "systemPrompt" => settings.system_prompt = value,
-            // Legacy: migrate any old backend value to "native"
-            "llmBackend" | "ollamaBaseUrl" | "ollamaModel" | "customLlmUrl" | "serverUrl" | "modelName" => {
-                // All legacy keys ignored — backend is always "native" now
-            },
-            _ => {}
+impl Default for MessageSearch {
+    fn default() -> Self {
+        Self {
+            query: String::new(),
+            session_id: None,
+            role: None,
+            from: None,
+            to: None,
+            limit: 20,
+            mark_start: "**".to_string(),
+            mark_end: "**".to_string(),
         }
     }
+}
 
-    // Always force native backend
-    settings.llm_backend = "native".to_string();
-    
-    Ok(settings)
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSearchHit {
+    #[serde(flatten)]
+    pub message: ExportMessage,
+    /// Fragment around the matches with the matched words marked
+    pub snippet: String,
+    /// bm25 score; lower is more relevant
+    pub rank: f64,
 }
 
-pub fn save_settings(settings: &Settings) -> Result<()> {
+/// Words shown around the matches in a snippet
+const SNIPPET_TOKENS: i32 = 16;
+
+/// Search messages across sessions, most relevant first (bm25).
+/// A query with nothing to look for returns no results.
+pub fn search_messages(search: &MessageSearch) -> Result<Vec<MessageSearchHit>> {
+    let Some(expression) = fts_query::parse(&search.query) else {
+        return Ok(Vec::new());
+    };
     let conn = get_conn()?;
-    let model_paths_json = serde_json::to_string(&settings.model_paths).unwrap_or_else(|_| "[]".to_string());
     
-    let pairs = vec![
-        ("temperature", settings.temperature.to_string()),
-        ("maxTokens", settings.max_tokens.to_string()),
-        ("contextLength", settings.context_length.to_string()),
-        ("theme", settings.theme.clone()),
-        ("accentColor", settings.accent_color.clone()),
-        ("autoSpeak", settings.auto_speak.to_string()),
-        ("sttEnabled", settings.stt_enabled.to_string()),
-        ("ttsEnabled", settings.tts_enabled.to_string()),
-        ("modelPaths", model_paths_json),
-        ("systemPrompt", settings.system_prompt.clone()),
-        ("llmBackend", "native".to_string()),
+    let mut sql = String::from(r#"
+        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp, m.parent_id, m.is_active, m.finish_reason, m.model,
+            snippet(messages_fts, 0, ?2, ?3, '…', ?4), bm25(messages_fts)
+        FROM messages_fts
+        JOIN messages m ON m.id = messages_fts.rowid
+        JOIN sessions s ON s.id = m.session_id
+        WHERE messages_fts MATCH ?1 AND m.is_active = 1
+    "#);
+    let mut args: Vec<rusqlite::types::Value> = vec![
+        expression.into(),
+        search.mark_start.clone().into(),
+        search.mark_end.clone().into(),
+        SNIPPET_TOKENS.into(),
     ];
     
-    for (key, value) in pairs {
-        conn.execute(
-            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
-            params![key, value],
-        )?;
+    if let Some(session_id) = search.session_id {
+        args.push(session_id.into());
+        sql.push_str(&format!(" AND m.session_id = ?{}", args.len()));
+    }
+    if let Some(role) = search.role {
+        args.push(((role == MessageRole::User) as i64).into());
+        sql.push_str(&format!(" AND m.is_user = ?{}", args.len()));
+    }
+    if let Some(from) = search.from {
+        args.push(from.into());
+        sql.push_str(&format!(" AND m.timestamp >= ?{}", args.len()));
+    }
+    if let Some(to) = search.to {
+        args.push(to.into());
+        sql.push_str(&format!(" AND m.timestamp <= ?{}", args.len()));
     }
+    args.push(search.limit.max(1).into());
+    sql.push_str(&format!(" ORDER BY bm25(messages_fts) LIMIT ?{}", args.len()));
+    
+    let mut stmt = conn.prepare(&sql)?;
+    let hits = stmt.query_map(rusqlite::params_from_iter(args), |row| {
+        Ok(MessageSearchHit {
+            message: row_to_export_message(row)?,
+            snippet: row.get(10)?,
+            rank: row.get(11)?,
+        })
+    })?;
     
-    Ok(())
+    hits.collect()
 }
 
-// ==================== Sessions ====================
+/// Search messages across ALL sessions using full-text search, most relevant first
+pub fn search_all_messages(query: &str, limit: i32) -> Result<Vec<ExportMessage>> {
+    let search = MessageSearch {
+        query: query.to_string(),
+        limit: limit as i64,
+        ..MessageSearch::default()
+    };
+    Ok(search_messages(&search)?.into_iter().map(|hit| hit.message).collect())
+}
 
-pub fn get_sessions() -> Result<Vec<Session>> {
-    let conn = get_conn()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, title, created_at, message_count FROM sessions ORDER BY created_at DESC"
-    )?;
+/// What a unified search result is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSource {
+    Message,
+    Memory,
+    Session,
+}
+
+impl SearchSource {
+    const ALL: [SearchSource; 3] = [SearchSource::Message, SearchSource::Memory, SearchSource::Session];
+    
+    /// Matching rows of the source's index as `source, id, snippet, rank`.
+    /// ?1 is the MATCH expression, ?2 / ?3 the marks, ?4 the snippet length.
+    fn select(&self) -> &'static str {
+        match self {
+            SearchSource::Message => "SELECT 'message' AS source, rowid AS id, \
+                snippet(messages_fts, 0, ?2, ?3, '…', ?4) AS snippet, bm25(messages_fts) AS rank \
+                FROM messages_fts WHERE messages_fts MATCH ?1",
+            SearchSource::Memory => "SELECT 'memory' AS source, rowid AS id, \
+                snippet(memory_fts, 0, ?2, ?3, '…', ?4) AS snippet, bm25(memory_fts) AS rank \
+                FROM memory_fts WHERE memory_fts MATCH ?1",
+            SearchSource::Session => "SELECT 'session' AS source, rowid AS id, \
+                snippet(sessions_fts, 0, ?2, ?3, '…', ?4) AS snippet, bm25(sessions_fts) AS rank \
+                FROM sessions_fts WHERE sessions_fts MATCH ?1",
+        }
+    }
     
-    let sessions = stmt.query_map([], |row| {
-        Ok(Session {
-            id: row.get(0)?,
-            title: row.get(1)?,
-            created_at: row.get(2)?,
-            message_count: row.get(3)?,
-        })
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "message" => Some(SearchSource::Message),
+            "memory" => Some(SearchSource::Memory),
+            "session" => Some(SearchSource::Session),
+            _ => None,
+        }
+    }
+}
+
+/// Condition selecting the chats (`s` = sessions) an assistant may take context from
+/// (`?1` = assistant id or NULL). Same rule as `MEMORY_SCOPE`: chats of an isolated
+/// assistant are seen only by it, all other chats form one common pool.
+const SESSION_SCOPE: &str = r#"
+    CASE WHEN (SELECT memory_mode FROM assistants WHERE id = ?1) = 'isolated'
+        THEN s.assistant_id = ?1
+        ELSE s.assistant_id IS NULL
+            OR s.assistant_id IN (SELECT id FROM assistants WHERE memory_mode = 'shared')
+    END"#;
+
+/// Best bm25 matches of an FTS expression among the memories and other chats' messages
+/// the assistant of `session_id` can see, most relevant first
+pub fn rank_matches(expression: &str, session_id: i64, assistant_id: Option<i64>, limit: i64) -> Result<Vec<(SearchSource, i64, f64)>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(&format!(r#"
+        SELECT 'message' AS source, messages_fts.rowid AS id, bm25(messages_fts) AS rank
+        FROM messages_fts
+        JOIN messages m ON m.id = messages_fts.rowid
+        JOIN sessions s ON s.id = m.session_id
+        WHERE messages_fts MATCH ?2 AND m.is_active = 1 AND m.session_id != ?4 AND {}
+        UNION ALL
+        SELECT 'memory', memory_fts.rowid, bm25(memory_fts)
+        FROM memory_fts
+        JOIN memory ON memory.id = memory_fts.rowid
+        WHERE memory_fts MATCH ?2 AND {}
+        ORDER BY rank LIMIT ?3
+    "#, SESSION_SCOPE, MEMORY_SCOPE))?;
+    
+    let rows = stmt.query_map(params![assistant_id, expression, limit, session_id], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, f64>(2)?))
     })?;
+    let mut matches = Vec::new();
+    for row in rows {
+        let (source, id, rank) = row?;
+        if let Some(source) = SearchSource::from_name(&source) {
+            matches.push((source, id, rank));
+        }
+    }
     
-    sessions.collect()
+    Ok(matches)
 }
 
-pub fn create_session(title: &str) -> Result<i64> {
+/// Messages among `ids` on a visible branch of a chat other than `session_id` that the
+/// assistant can see; others are left out
+pub fn get_context_messages(ids: &[i64], session_id: i64, assistant_id: Option<i64>) -> Result<HashMap<i64, ExportMessage>> {
     let conn = get_conn()?;
-    let now = get_timestamp();
-    
-    conn.execute(
-        "INSERT INTO sessions (title, created_at, message_count) VALUES (?1, ?2, 0)",
-        params![title, now],
-    )?;
+    let mut stmt = conn.prepare(&format!(r#"
+        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp, m.parent_id, m.is_active, m.finish_reason, m.model
+        FROM messages m
+        JOIN sessions s ON m.session_id = s.id
+        WHERE m.id = ?2 AND m.is_active = 1 AND m.session_id != ?3 AND {}
+    "#, SESSION_SCOPE))?;
+    let mut messages = HashMap::new();
+    
+    for &id in ids {
+        match stmt.query_row(params![assistant_id, id, session_id], row_to_export_message) {
+            Ok(message) => {
+                messages.insert(id, message);
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e),
+        }
+    }
     
-    Ok(conn.last_insert_rowid())
+    Ok(messages)
 }
 
-pub fn delete_session(session_id: i64) -> Result<()> {
+/// Memories among `ids` that still exist
+pub fn get_memories_by_ids(ids: &[i64]) -> Result<HashMap<i64, MemoryEntry>> {
     let conn = get_conn()?;
-    conn.execute("DELETE FROM messages WHERE session_id = ?1", params![session_id])?;
-    conn.execute("DELETE FROM sessions WHERE id = ?1", params![session_id])?;
-    Ok(())
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM memory WHERE id = ?1", MEMORY_COLUMNS))?;
+    let mut memories = HashMap::new();
+    
+    for &id in ids {
+        match stmt.query_row(params![id], row_to_memory) {
+            Ok(memory) => {
+                memories.insert(id, memory);
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    
+    Ok(memories)
 }
 
-// ==================== Messages ====================
+/// One query over messages, memories and session titles
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UnifiedSearch {
+    /// Same syntax as `MessageSearch::query`
+    pub query: String,
+    /// Sources to search; empty means all
+    pub sources: Vec<SearchSource>,
+    pub offset: i64,
+    pub limit: i64,
+    pub mark_start: String,
+    pub mark_end: String,
+}
 
-pub fn get_messages(session_id: i64) -> Result<Vec<Message>> {
-    let conn = get_conn()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, content, is_user, timestamp FROM messages WHERE session_id = ?1 ORDER BY timestamp ASC"
-    )?;
-    
-    let messages = stmt.query_map(params![session_id], |row| {
-        Ok(Message {
-            id: row.get(0)?,
-            content: row.get(1)?,
-            is_user: row.get::<_, i32>(2)? != 0,
-            timestamp: row.get(3)?,
-        })
-    })?;
-    
-    messages.collect()
+impl Default for UnifiedSearch {
+    fn default() -> Self {
+        Self {
+            query: String::new(),
+            sources: Vec::new(),
+            offset: 0,
+            limit: 20,
+            mark_start: "**".to_string(),
+            mark_end: "**".to_string(),
+        }
+    }
 }
 
-pub fn insert_message(session_id: i64, content: &str, is_user: bool) -> Result<i64> {
-    let conn = get_conn()?;
-    let now = get_timestamp();
-    
-    conn.execute(
-        "INSERT INTO messages (session_id, content, is_user, timestamp) VALUES (?1, ?2, ?3, ?4)",
-        params![session_id, content, is_user as i32, now],
-    )?;
-    
-    conn.execute(
-        "UPDATE sessions SET message_count = message_count + 1 WHERE id = ?1",
-        params![session_id],
-    )?;
-    
-    Ok(conn.last_insert_rowid())
+/// The matched record, tagged with its source
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum SearchItem {
+    Message(ExportMessage),
+    Memory(MemoryEntry),
+    Session(Session),
 }
 
-// ==================== GLOBAL SEARCH (across ALL sessions) ====================
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub item: SearchItem,
+    pub snippet: String,
+    /// bm25 score within its source; lower is more relevant
+    pub rank: f64,
+}
 
-/// Search messages across ALL sessions using full-text search
-pub fn search_all_messages(query: &str, limit: i32) -> Result<Vec<ExportMessage>> {
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchPage {
+    pub hits: Vec<SearchHit>,
+    /// Matches across all pages
+    pub total: i64,
+    pub offset: i64,
+    pub has_more: bool,
+}
+
+/// Search messages, memories and session titles at once, the best matches of
+/// every source first
+pub fn search(search: &UnifiedSearch) -> Result<SearchPage> {
+    let offset = search.offset.max(0);
+    let empty = SearchPage { hits: Vec::new(), total: 0, offset, has_more: false };
+    let Some(expression) = fts_query::parse(&search.query) else {
+        return Ok(empty);
+    };
+    let sources: Vec<SearchSource> = SearchSource::ALL.into_iter()
+        .filter(|s| search.sources.is_empty() || search.sources.contains(s))
+        .collect();
+    if sources.is_empty() {
+        return Ok(empty);
+    }
+    // bm25 scores of different indexes are not comparable, so each source is
+    // ranked on its own and the rankings are interleaved (reciprocal rank fusion
+    // with equal weights): the best of every source first, then the second best...
+    let union = sources.iter().map(|s| s.select()).collect::<Vec<_>>().join(" UNION ALL ");
+    let args = params![expression, search.mark_start, search.mark_end, SNIPPET_TOKENS];
+    
     let conn = get_conn()?;
+    let total: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM ({})", union), args, |row| row.get(0))?;
     
-    let mut stmt = conn.prepare(r#"
-        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp
-        FROM messages m
-        JOIN sessions s ON m.session_id = s.id
-        WHERE m.id IN (
-            SELECT rowid FROM messages_fts WHERE messages_fts MATCH ?1
+    // Materialized so that snippet() and bm25() run inside their own FTS queries
+    let mut stmt = conn.prepare(&format!(r#"
+        WITH matches AS MATERIALIZED ({})
+        SELECT source, id, snippet, rank FROM (
+            SELECT *, ROW_NUMBER() OVER (PARTITION BY source ORDER BY rank) AS position FROM matches
         )
-        ORDER BY m.timestamp DESC
-        LIMIT ?2
-    "#)?;
-    
-    let messages = stmt.query_map(params![query, limit], |row| {
-        Ok(ExportMessage {
-            id: row.get(0)?,
-            session_id: row.get(1)?,
-            session_title: row.get(2)?,
-            content: row.get(3)?,
-            is_user: row.get::<_, i32>(4)? != 0,
-            timestamp: row.get(5)?,
-        })
-    })?;
+        ORDER BY position, rank LIMIT ?5 OFFSET ?6
+    "#, union))?;
+    let matches = stmt.query_map(
+        params![expression, search.mark_start, search.mark_end, SNIPPET_TOKENS, search.limit.max(1), offset],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?, row.get::<_, f64>(3)?)),
+    )?.collect::<Result<Vec<_>>>()?;
+    
+    let mut hits = Vec::with_capacity(matches.len());
+    for (source, id, snippet, rank) in matches {
+        let item = match source.as_str() {
+            "message" => SearchItem::Message(conn.query_row(r#"
+                SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp, m.parent_id, m.is_active, m.finish_reason, m.model
+                FROM messages m
+                JOIN sessions s ON m.session_id = s.id
+                WHERE m.id = ?1
+            "#, params![id], row_to_export_message)?),
+            "memory" => SearchItem::Memory(conn.query_row(
+                &format!("SELECT {} FROM memory WHERE id = ?1", MEMORY_COLUMNS), params![id], row_to_memory
+            )?),
+            _ => SearchItem::Session(query_session(&conn, id)?),
+        };
+        hits.push(SearchHit { item, snippet, rank });
+    }
     
-    messages.collect()
+    let has_more = offset + (hits.len() as i64) < total;
+    Ok(SearchPage { hits, total, offset, has_more })
 }
 
 /// Get recent messages from ALL sessions (for context building)
@@ -408,23 +1744,15 @@ pub fn get_recent_global_messages(limit: i32) -> Result<Vec<ExportMessage>> {
     let conn = get_conn()?;
     
     let mut stmt = conn.prepare(r#"
-        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp
+        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp, m.parent_id, m.is_active, m.finish_reason, m.model
         FROM messages m
         JOIN sessions s ON m.session_id = s.id
+        WHERE m.is_active = 1
         ORDER BY m.timestamp DESC
         LIMIT ?1
     "#)?;
     
-    let messages = stmt.query_map(params![limit], |row| {
-        Ok(ExportMessage {
-            id: row.get(0)?,
-            session_id: row.get(1)?,
-            session_title: row.get(2)?,
-            content: row.get(3)?,
-            is_user: row.get::<_, i32>(4)? != 0,
-            timestamp: row.get(5)?,
-        })
-    })?;
+    let messages = stmt.query_map(params![limit], row_to_export_message)?;
     
     messages.collect()
 }
@@ -433,7 +1761,7 @@ pub fn get_recent_global_messages(limit: i32) -> Result<Vec<ExportMessage>> {
 pub fn get_all_user_messages() -> Result<Vec<String>> {
     let conn = get_conn()?;
     let mut stmt = conn.prepare(
-        "SELECT content FROM messages WHERE is_user = 1 ORDER BY timestamp ASC"
+        "SELECT content FROM messages WHERE is_user = 1 AND is_active = 1 ORDER BY timestamp ASC"
     )?;
     
     let messages: Vec<String> = stmt
@@ -446,13 +1774,41 @@ pub fn get_all_user_messages() -> Result<Vec<String>> {
 
 // ==================== MEMORY SYSTEM ====================
 
-/// Add a memory entry
+const MEMORY_COLUMNS: &str =
+    "id, content, category, source_session_id, source_message_id, importance, created_at, assistant_id";
+
+/// Condition selecting the memories an assistant can see (`?1` = assistant id or NULL).
+/// An isolated assistant sees only its own memories; the default assistant and all
+/// shared assistants see one common pool.
+const MEMORY_SCOPE: &str = r#"
+    CASE WHEN (SELECT memory_mode FROM assistants WHERE id = ?1) = 'isolated'
+        THEN assistant_id = ?1
+        ELSE assistant_id IS NULL
+            OR assistant_id IN (SELECT id FROM assistants WHERE memory_mode = 'shared')
+    END"#;
+
+fn row_to_memory(row: &rusqlite::Row) -> Result<MemoryEntry> {
+    Ok(MemoryEntry {
+        id: row.get(0)?,
+        content: row.get(1)?,
+        category: row.get(2)?,
+        // NULL once the source session is gone
+        source_session_id: row.get::<_, Option<i64>>(3)?.unwrap_or(0),
+        source_message_id: row.get::<_, Option<i64>>(4)?.unwrap_or(0),
+        importance: row.get(5)?,
+        created_at: row.get(6)?,
+        assistant_id: row.get(7)?,
+    })
+}
+
+/// Add a memory entry. It is tagged with the assistant of the source session.
 pub fn add_memory(content: &str, category: &str, session_id: i64, message_id: i64, importance: i32) -> Result<i64> {
     let conn = get_conn()?;
     let now = get_timestamp();
     
     conn.execute(
-        "INSERT INTO memory (content, category, source_session_id, source_message_id, importance, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        r#"INSERT INTO memory (content, category, source_session_id, source_message_id, importance, created_at, assistant_id)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, (SELECT assistant_id FROM sessions WHERE id = ?3))"#,
         params![content, category, session_id, message_id, importance, now],
     )?;
     
@@ -462,73 +1818,63 @@ pub fn add_memory(content: &str, category: &str, session_id: i64, message_id: i6
 /// Get all memories, sorted by importance
 pub fn get_all_memories() -> Result<Vec<MemoryEntry>> {
     let conn = get_conn()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, content, category, source_session_id, source_message_id, importance, created_at FROM memory ORDER BY importance DESC, created_at DESC"
-    )?;
-    
-    let memories = stmt.query_map([], |row| {
-        Ok(MemoryEntry {
-            id: row.get(0)?,
-            content: row.get(1)?,
-            category: row.get(2)?,
-            source_session_id: row.get(3)?,
-            source_message_id: row.get(4)?,
-            importance: row.get(5)?,
-            created_at: row.get(6)?,
-        })
-    })?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM memory ORDER BY importance DESC, created_at DESC", MEMORY_COLUMNS
+    ))?;
     
+    let memories = stmt.query_map([], row_to_memory)?;
     memories.collect()
 }
 
 /// Get memories by category
 pub fn get_memories_by_category(category: &str) -> Result<Vec<MemoryEntry>> {
     let conn = get_conn()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, content, category, source_session_id, source_message_id, importance, created_at FROM memory WHERE category = ?1 ORDER BY importance DESC"
-    )?;
-    
-    let memories = stmt.query_map(params![category], |row| {
-        Ok(MemoryEntry {
-            id: row.get(0)?,
-            content: row.get(1)?,
-            category: row.get(2)?,
-            source_session_id: row.get(3)?,
-            source_message_id: row.get(4)?,
-            importance: row.get(5)?,
-            created_at: row.get(6)?,
-        })
-    })?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM memory WHERE category = ?1 ORDER BY importance DESC", MEMORY_COLUMNS
+    ))?;
     
+    let memories = stmt.query_map(params![category], row_to_memory)?;
     memories.collect()
 }
 
 /// Get top N most important memories
 pub fn get_top_memories(limit: i32) -> Result<Vec<MemoryEntry>> {
     let conn = get_conn()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, content, category, source_session_id, source_message_id, importance, created_at FROM memory ORDER BY importance DESC LIMIT ?1"
-    )?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM memory ORDER BY importance DESC LIMIT ?1", MEMORY_COLUMNS
+    ))?;
     
-    let memories = stmt.query_map(params![limit], |row| {
-        Ok(MemoryEntry {
-            id: row.get(0)?,
-            content: row.get(1)?,
-            category: row.get(2)?,
-            source_session_id: row.get(3)?,
-            source_message_id: row.get(4)?,
-            importance: row.get(5)?,
-            created_at: row.get(6)?,
-        })
-    })?;
+    let memories = stmt.query_map(params![limit], row_to_memory)?;
+    memories.collect()
+}
+
+/// Get top N most important memories visible to an assistant (None = default assistant)
+pub fn get_top_memories_for_assistant(assistant_id: Option<i64>, limit: i32) -> Result<Vec<MemoryEntry>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM memory WHERE {} ORDER BY importance DESC LIMIT ?2", MEMORY_COLUMNS, MEMORY_SCOPE
+    ))?;
     
+    let memories = stmt.query_map(params![assistant_id, limit], row_to_memory)?;
     memories.collect()
 }
 
-/// Delete a memory entry
-pub fn delete_memory(id: i64) -> Result<()> {
+/// Ids of all memories visible to an assistant (for filtering vector search hits)
+pub fn get_visible_memory_ids(assistant_id: Option<i64>) -> Result<std::collections::HashSet<i64>> {
     let conn = get_conn()?;
-    conn.execute("DELETE FROM memory WHERE id = ?1", params![id])?;
+    let mut stmt = conn.prepare(&format!("SELECT id FROM memory WHERE {}", MEMORY_SCOPE))?;
+    let ids = stmt.query_map(params![assistant_id], |row| row.get(0))?;
+    ids.collect()
+}
+
+/// Delete a memory entry (and its vector, so RAG forgets it too)
+pub fn delete_memory(id: i64) -> Result<()> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    let vectors = delete_vectors(&tx, "source_type = 'memory' AND source_id = ?1", params![id])?;
+    tx.execute("DELETE FROM memory WHERE id = ?1", params![id])?;
+    tx.commit()?;
+    forget_vectors(&vectors);
     Ok(())
 }
 
@@ -625,44 +1971,142 @@ pub fn save_user_persona(persona: &UserPersona) -> Result<()> {
 
 /// Export ALL data for creating digital twin
 pub fn export_all_data() -> Result<ExportData> {
-    let sessions = get_sessions()?;
+    let sessions = get_sessions(&SessionFilter::default())?;
     let persona = get_user_persona()?;
     let memory = get_all_memories()?;
+    let feedback = get_feedback(&FeedbackFilter::default())?;
     
     let conn = get_conn()?;
     let mut stmt = conn.prepare(r#"
-        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp
+        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp, m.parent_id, m.is_active, m.finish_reason, m.model
         FROM messages m
         JOIN sessions s ON m.session_id = s.id
         ORDER BY m.timestamp ASC
     "#)?;
     
-    let messages: Vec<ExportMessage> = stmt.query_map([], |row| {
-        Ok(ExportMessage {
-            id: row.get(0)?,
-            session_id: row.get(1)?,
-            session_title: row.get(2)?,
-            content: row.get(3)?,
-            is_user: row.get::<_, i32>(4)? != 0,
-            timestamp: row.get(5)?,
-        })
-    })?.filter_map(|r| r.ok()).collect();
+    let messages: Vec<ExportMessage> = stmt.query_map([], row_to_export_message)?
+        .filter_map(|r| r.ok())
+        .collect();
     
     Ok(ExportData {
         sessions,
         messages,
         memory,
         persona,
+        feedback,
         exported_at: get_timestamp(),
     })
 }
 
-/// Export in Alpaca format for fine-tuning
-pub fn export_alpaca_format() -> Result<Vec<serde_json::Value>> {
+// Streaming exports page through the tables by id and take the connection lock
+// only for one page at a time, so the app stays usable during a long export.
+
+/// Row counts of a full export, for progress reporting
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportCounts {
+    pub sessions: u64,
+    pub messages: u64,
+    pub memory: u64,
+    pub feedback: u64,
+    /// Sessions with at least one message on the visible branch
+    pub conversations: u64,
+}
+
+pub fn export_counts() -> Result<ExportCounts> {
+    let conn = get_conn()?;
+    let count = |sql: &str| conn.query_row(sql, [], |row| row.get::<_, i64>(0)).map(|n| n as u64);
+    
+    Ok(ExportCounts {
+        sessions: count("SELECT COUNT(*) FROM sessions")?,
+        messages: count("SELECT COUNT(*) FROM messages")?,
+        memory: count("SELECT COUNT(*) FROM memory")?,
+        feedback: count("SELECT COUNT(*) FROM message_feedback")?,
+        conversations: count("SELECT COUNT(DISTINCT session_id) FROM messages WHERE is_active = 1")?,
+    })
+}
+
+/// Sessions with `id > after_id`, with their tags
+pub fn export_sessions_page(after_id: i64, limit: i64) -> Result<Vec<Session>> {
     let conn = get_conn()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM sessions WHERE id > ?1 ORDER BY id LIMIT ?2", SESSION_COLUMNS
+    ))?;
+    let mut sessions: Vec<Session> = stmt.query_map(params![after_id, limit], row_to_session)?
+        .collect::<Result<_>>()?;
+    
+    if let (Some(first), Some(last)) = (sessions.first().map(|s| s.id), sessions.last().map(|s| s.id)) {
+        let mut stmt = conn.prepare(
+            "SELECT session_id, tag FROM session_tags WHERE session_id BETWEEN ?1 AND ?2 ORDER BY tag"
+        )?;
+        let rows = stmt.query_map(params![first, last], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (session_id, tag) = row?;
+            if let Some(session) = sessions.iter_mut().find(|s| s.id == session_id) {
+                session.tags.push(tag);
+            }
+        }
+    }
     
-    // Get conversation pairs (user message -> assistant response)
+    Ok(sessions)
+}
+
+/// Messages of all branches with `id > after_id`. A reply always has a larger id than
+/// its parent, so id order keeps parents ahead of their replies.
+pub fn export_messages_page(after_id: i64, limit: i64) -> Result<Vec<ExportMessage>> {
+    let conn = get_conn()?;
     let mut stmt = conn.prepare(r#"
+        SELECT m.id, m.session_id, s.title, m.content, m.is_user, m.timestamp, m.parent_id, m.is_active, m.finish_reason, m.model
+        FROM messages m
+        JOIN sessions s ON m.session_id = s.id
+        WHERE m.id > ?1
+        ORDER BY m.id
+        LIMIT ?2
+    "#)?;
+    
+    let messages = stmt.query_map(params![after_id, limit], row_to_export_message)?;
+    messages.collect()
+}
+
+pub fn export_memory_page(after_id: i64, limit: i64) -> Result<Vec<MemoryEntry>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM memory WHERE id > ?1 ORDER BY id LIMIT ?2", MEMORY_COLUMNS
+    ))?;
+    
+    let memories = stmt.query_map(params![after_id, limit], row_to_memory)?;
+    memories.collect()
+}
+
+/// Feedback of messages with `message_id > after_message_id`
+pub fn export_feedback_page(after_message_id: i64, limit: i64) -> Result<Vec<MessageFeedback>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM message_feedback WHERE message_id > ?1 ORDER BY message_id LIMIT ?2", FEEDBACK_COLUMNS
+    ))?;
+    
+    let feedback = stmt.query_map(params![after_message_id, limit], row_to_feedback)?;
+    feedback.collect()
+}
+
+/// Ids of sessions with `id > after_id` that have messages on the visible branch
+pub fn export_conversation_ids_page(after_id: i64, limit: i64) -> Result<Vec<i64>> {
+    let conn = get_conn()?;
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT session_id FROM messages WHERE session_id > ?1 AND is_active = 1 ORDER BY session_id LIMIT ?2"
+    )?;
+    
+    let ids = stmt.query_map(params![after_id, limit], |row| row.get(0))?;
+    ids.collect()
+}
+
+/// Export in Alpaca format for fine-tuning.
+/// With `min_rating` only answers rated at least that high (1-5) are exported.
+pub fn export_alpaca_format(min_rating: Option<i32>) -> Result<Vec<serde_json::Value>> {
+    let conn = get_conn()?;
+    
+    // Get conversation pairs (user message -> assistant response)
+    let mut stmt = conn.prepare(&format!(r#"
         SELECT 
             u.content as instruction,
             a.content as output
@@ -670,17 +2114,21 @@ pub fn export_alpaca_format() -> Result<Vec<serde_json::Value>> {
         JOIN messages a ON a.session_id = u.session_id 
             AND a.timestamp > u.timestamp 
             AND a.is_user = 0
+        LEFT JOIN message_feedback f ON f.message_id = a.id
         WHERE u.is_user = 1
+        AND u.is_active = 1
         AND a.id = (
             SELECT MIN(id) FROM messages 
             WHERE session_id = u.session_id 
             AND timestamp > u.timestamp 
             AND is_user = 0
+            AND is_active = 1
         )
+        AND (?1 IS NULL OR {} >= ?1)
         ORDER BY u.timestamp ASC
-    "#)?;
+    "#, FEEDBACK_RATING))?;
     
-    let pairs: Vec<serde_json::Value> = stmt.query_map([], |row| {
+    let pairs: Vec<serde_json::Value> = stmt.query_map(params![min_rating], |row| {
         let instruction: String = row.get(0)?;
         let output: String = row.get(1)?;
         Ok(serde_json::json!({
@@ -690,36 +2138,187 @@ pub fn export_alpaca_format() -> Result<Vec<serde_json::Value>> {
         }))
     })?.filter_map(|r| r.ok()).collect();
     
-    Ok(pairs)
+    Ok(pairs)
+}
+
+/// Export in ShareGPT format
+pub fn export_sharegpt_format() -> Result<Vec<serde_json::Value>> {
+    let sessions = get_sessions(&SessionFilter::default())?;
+    let mut conversations = Vec::new();
+    
+    for session in sessions {
+        let messages = get_messages(session.id)?;
+        if messages.is_empty() {
+            continue;
+        }
+        
+        conversations.push(sharegpt_conversation(session.id, &messages));
+    }
+    
+    Ok(conversations)
+}
+
+/// One ShareGPT record: the visible branch of a session
+pub fn sharegpt_conversation(session_id: i64, messages: &[Message]) -> serde_json::Value {
+    let conv: Vec<serde_json::Value> = messages.iter().map(|m| {
+        serde_json::json!({
+            "from": if m.is_user { "human" } else { "gpt" },
+            "value": m.content
+        })
+    }).collect();
+    
+    serde_json::json!({
+        "id": format!("session_{}", session_id),
+        "conversations": conv
+    })
 }
 
-/// Export in ShareGPT format
-pub fn export_sharegpt_format() -> Result<Vec<serde_json::Value>> {
-    let sessions = get_sessions()?;
+/// Chat role of a message in OpenAI / ChatML exports
+fn chat_role(is_user: bool) -> &'static str {
+    if is_user { "user" } else { "assistant" }
+}
+
+/// OpenAI chat messages: the system prompt (if any) followed by the turns
+pub fn openai_messages(system_prompt: &str, turns: &[Message]) -> Vec<serde_json::Value> {
+    let system = (!system_prompt.trim().is_empty())
+        .then(|| serde_json::json!({ "role": "system", "content": system_prompt }));
+    
+    system.into_iter()
+        .chain(turns.iter().map(|m| serde_json::json!({
+            "role": chat_role(m.is_user),
+            "content": m.content
+        })))
+        .collect()
+}
+
+/// Whole conversation as ChatML text, the same markup the native model is prompted with.
+/// Like `openai_messages`, an empty system prompt is left out.
+pub fn chatml_text(system_prompt: &str, turns: &[Message]) -> String {
+    let mut text = String::new();
+    if !system_prompt.trim().is_empty() {
+        text.push_str(&format!("<|im_start|>system\n{}<|im_end|>\n", system_prompt));
+    }
+    for m in turns {
+        text.push_str(&format!("<|im_start|>{}\n{}<|im_end|>\n", chat_role(m.is_user), m.content));
+    }
+    text
+}
+
+/// Visible branch of every session that has an assistant answer, with its system prompt
+fn conversations_for_training(system_prompt: &dyn Fn(i64) -> String) -> Result<Vec<(Session, String, Vec<Message>)>> {
     let mut conversations = Vec::new();
     
-    for session in sessions {
+    for session in get_sessions(&SessionFilter::default())? {
         let messages = get_messages(session.id)?;
-        if messages.is_empty() {
+        if !messages.iter().any(|m| !m.is_user) {
             continue;
         }
-        
-        let conv: Vec<serde_json::Value> = messages.iter().map(|m| {
-            serde_json::json!({
-                "from": if m.is_user { "human" } else { "gpt" },
-                "value": m.content
-            })
-        }).collect();
-        
-        conversations.push(serde_json::json!({
-            "id": format!("session_{}", session.id),
-            "conversations": conv
-        }));
+        let prompt = system_prompt(session.id);
+        conversations.push((session, prompt, messages));
     }
     
     Ok(conversations)
 }
 
+/// Export in OpenAI chat fine-tuning format: one `{"messages": [...]}` per session.
+/// `system_prompt` resolves the system prompt the session is answered with.
+pub fn export_openai_format(system_prompt: &dyn Fn(i64) -> String) -> Result<Vec<serde_json::Value>> {
+    Ok(conversations_for_training(system_prompt)?
+        .into_iter()
+        .map(|(_, prompt, messages)| serde_json::json!({
+            "messages": openai_messages(&prompt, &messages)
+        }))
+        .collect())
+}
+
+/// Export as raw ChatML text: one `{"text": "<|im_start|>..."}` per session
+pub fn export_chatml_format(system_prompt: &dyn Fn(i64) -> String) -> Result<Vec<serde_json::Value>> {
+    Ok(conversations_for_training(system_prompt)?
+        .into_iter()
+        .map(|(session, prompt, messages)| serde_json::json!({
+            "id": format!("session_{}", session.id),
+            "text": chatml_text(&prompt, &messages)
+        }))
+        .collect())
+}
+
+/// One preference pair in the conversational DPO / ORPO layout
+/// (`prompt` messages, then the `chosen` and `rejected` assistant turns)
+fn preference_pair(system_prompt: &str, history: &[Message], chosen: &str, rejected: &str) -> serde_json::Value {
+    serde_json::json!({
+        "prompt": openai_messages(system_prompt, history),
+        "chosen": [{ "role": "assistant", "content": chosen }],
+        "rejected": [{ "role": "assistant", "content": rejected }]
+    })
+}
+
+/// Chosen / rejected answers among the alternatives of one reply.
+/// Rated answers rank by rating (unrated count as 3), ties go to the visible one;
+/// the best answer is chosen over every lower-ranked sibling. A correction is
+/// always chosen over the answer it corrects. With `min_rating` rating-based
+/// pairs need a chosen answer rated at least that high.
+fn preferences(
+    siblings: &[Message],
+    visible_id: i64,
+    feedback: &HashMap<i64, MessageFeedback>,
+    min_rating: Option<i32>,
+) -> Vec<(String, String)> {
+    let rating = |m: &Message| feedback.get(&m.id).and_then(MessageFeedback::rating);
+    let rank = |m: &Message| (rating(m).unwrap_or(3), m.id == visible_id);
+    let answers: Vec<&Message> = siblings.iter()
+        .filter(|m| !m.is_user && !m.content.trim().is_empty())
+        .collect();
+    let mut pairs = Vec::new();
+    
+    if let Some(best) = answers.iter().copied().max_by_key(|m| rank(m)) {
+        if min_rating.is_none_or(|min| rating(best).is_some_and(|r| r >= min)) {
+            for other in &answers {
+                if rank(other) < rank(best) && other.content.trim() != best.content.trim() {
+                    pairs.push((best.content.clone(), other.content.clone()));
+                }
+            }
+        }
+    }
+    for answer in &answers {
+        if let Some(correction) = feedback.get(&answer.id).and_then(|f| f.correction.as_ref()) {
+            if correction.trim() != answer.content.trim() {
+                pairs.push((correction.clone(), answer.content.clone()));
+            }
+        }
+    }
+    
+    pairs
+}
+
+/// Export DPO / ORPO preference pairs from regenerated answers, ratings and corrections
+/// (see `preferences`). Without feedback the alternative the user kept on the visible
+/// branch is chosen over its siblings.
+pub fn export_preference_pairs(system_prompt: &dyn Fn(i64) -> String, min_rating: Option<i32>) -> Result<Vec<serde_json::Value>> {
+    let mut pairs = Vec::new();
+    
+    for (session, prompt, messages) in conversations_for_training(system_prompt)? {
+        let feedback: HashMap<i64, MessageFeedback> = get_feedback(&FeedbackFilter {
+            session_id: Some(session.id),
+            ..Default::default()
+        })?
+            .into_iter()
+            .map(|f| (f.message_id, f))
+            .collect();
+        
+        for (idx, visible) in messages.iter().enumerate() {
+            if visible.is_user {
+                continue;
+            }
+            let siblings = get_alternatives(visible.id)?;
+            for (chosen, rejected) in preferences(&siblings, visible.id, &feedback, min_rating) {
+                pairs.push(preference_pair(&prompt, &messages[..idx], &chosen, &rejected));
+            }
+        }
+    }
+    
+    Ok(pairs)
+}
+
 /// Get statistics about stored data
 pub fn get_data_stats() -> Result<serde_json::Value> {
     let conn = get_conn()?;
@@ -755,6 +2354,335 @@ pub fn get_data_stats() -> Result<serde_json::Value> {
     }))
 }
 
+// ==================== IMPORT ====================
+
+/// (id, content) of imported rows, handed to the embedder afterwards
+pub type ImportedRows = Vec<(i64, String)>;
+
+/// Store one imported conversation as a new session with its original timestamps.
+/// Returns the session id and the stored (id, content) messages for embedding,
+/// or None if a session with the same source id exists already.
+pub fn import_conversation(conversation: &ImportedConversation) -> Result<Option<(i64, ImportedRows)>> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    let source_key = conversation.source_key();
+    
+    if let Some(key) = &source_key {
+        let exists: bool = tx.query_row(
+            "SELECT COUNT(*) > 0 FROM sessions WHERE external_id = ?1",
+            params![key],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(None);
+        }
+    }
+    
+    let timestamps = import_timestamps(conversation, get_timestamp());
+    let created_at = conversation.created_at
+        .unwrap_or_else(|| timestamps.iter().copied().min().unwrap_or_else(get_timestamp));
+    
+    // The visible branch: the active leaf and its ancestors
+    let mut active = vec![false; conversation.messages.len()];
+    let mut cursor = conversation.active_leaf.or(conversation.messages.len().checked_sub(1));
+    while let Some(index) = cursor {
+        active[index] = true;
+        cursor = conversation.messages[index].parent;
+    }
+    
+    tx.execute(
+        "INSERT INTO sessions (title, created_at, message_count, external_id) VALUES (?1, ?2, 0, ?3)",
+        params![conversation.title, created_at, source_key],
+    )?;
+    let session_id = tx.last_insert_rowid();
+    
+    let mut ids: Vec<i64> = Vec::with_capacity(conversation.messages.len());
+    let mut stored = Vec::with_capacity(conversation.messages.len());
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO messages (session_id, content, is_user, timestamp, parent_id, is_active) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+        )?;
+        for (index, message) in conversation.messages.iter().enumerate() {
+            let parent_id = message.parent.map(|p| ids[p]);
+            stmt.execute(params![
+                session_id,
+                message.content,
+                message.is_user as i32,
+                timestamps[index],
+                parent_id,
+                active[index] as i32,
+            ])?;
+            let id = tx.last_insert_rowid();
+            ids.push(id);
+            stored.push((id, message.content.clone()));
+        }
+    }
+    
+    let leaf_id = conversation.active_leaf.or(conversation.messages.len().checked_sub(1)).map(|i| ids[i]);
+    tx.execute(
+        "UPDATE sessions SET active_leaf_id = ?1, message_count = ?2 WHERE id = ?3",
+        params![leaf_id, active.iter().filter(|a| **a).count() as i64, session_id],
+    )?;
+    
+    tx.commit()?;
+    Ok(Some((session_id, stored)))
+}
+
+/// Original timestamps where present. Missing ones are filled in after the parent,
+/// and a child is never older than its parent (the tree code relies on that).
+fn import_timestamps(conversation: &ImportedConversation, now: i64) -> Vec<i64> {
+    let base = conversation.created_at.unwrap_or(now);
+    let mut timestamps: Vec<i64> = Vec::with_capacity(conversation.messages.len());
+    
+    for (index, message) in conversation.messages.iter().enumerate() {
+        let after_parent = message.parent.map(|p| timestamps[p] + 1);
+        let fallback = after_parent.unwrap_or(base + index as i64);
+        let timestamp = match (message.timestamp, after_parent) {
+            (Some(ts), Some(min)) => ts.max(min),
+            (Some(ts), None) => ts,
+            (None, _) => fallback,
+        };
+        timestamps.push(timestamp);
+    }
+    
+    timestamps
+}
+
+/// Key for duplicate detection: content hash, timestamp and author
+fn message_key(content: &str, timestamp: i64, is_user: bool) -> (u64, i64, bool) {
+    (content_hash(content), timestamp, is_user)
+}
+
+fn content_hash(content: &str) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Load a Wishmaster full export (`ExportData`). Ids are remapped; in merge mode sessions,
+/// messages and memories that already exist (same content hash and timestamp) are skipped.
+/// With `dry_run` everything runs in a transaction that is rolled back, so the report is
+/// exactly what a real import would do.
+/// Also returns the new (id, content) messages and memories for embedding.
+pub fn import_full_export(
+    data: &ExportData,
+    mode: ImportMode,
+    dry_run: bool,
+) -> Result<(FullImportReport, ImportedRows, ImportedRows)> {
+    let mut conn = get_conn()?;
+    let tx = conn.transaction()?;
+    let mut report = FullImportReport { dry_run, ..Default::default() };
+    
+    if mode == ImportMode::Replace {
+        let count = |table: &str| -> Result<usize> {
+            tx.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get::<_, i64>(0))
+                .map(|n| n as usize)
+        };
+        report.sessions_removed = count("sessions")?;
+        report.messages_removed = count("messages")?;
+        report.memories_removed = count("memory")?;
+        tx.execute_batch(r#"
+            DELETE FROM embeddings WHERE source_type IN ('message', 'memory');
+            DELETE FROM memory;
+            DELETE FROM message_feedback;
+            DELETE FROM messages;
+            DELETE FROM session_tags;
+            DELETE FROM session_settings;
+            DELETE FROM sessions;
+            DELETE FROM user_persona;
+        "#)?;
+    }
+    
+    let assistants: HashSet<i64> = tx.prepare("SELECT id FROM assistants")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_>>()?;
+    let mut sessions_by_key: HashMap<(String, i64), i64> = tx.prepare("SELECT title, created_at, id FROM sessions")?
+        .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+        .collect::<Result<_>>()?;
+    // message key -> (local id, local session id)
+    let mut messages_by_key: HashMap<(u64, i64, bool), (i64, i64)> = HashMap::new();
+    {
+        let mut stmt = tx.prepare("SELECT content, timestamp, is_user, id, session_id FROM messages")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let key = message_key(&row.get::<_, String>(0)?, row.get(1)?, row.get::<_, i32>(2)? != 0);
+            messages_by_key.insert(key, (row.get(3)?, row.get(4)?));
+        }
+    }
+    
+    // ---- Sessions ----
+    let mut messages: Vec<&ExportMessage> = data.messages.iter().collect();
+    messages.sort_by_key(|m| (m.timestamp, m.id));
+    
+    let mut session_map: HashMap<i64, i64> = HashMap::new();
+    let mut new_sessions: HashSet<i64> = HashSet::new();
+    for session in &data.sessions {
+        // A renamed session is still found through its messages
+        let existing = sessions_by_key.get(&(session.title.clone(), session.created_at)).copied().or_else(|| {
+            messages.iter()
+                .filter(|m| m.session_id == session.id)
+                .find_map(|m| messages_by_key.get(&message_key(&m.content, m.timestamp, m.is_user)))
+                .map(|&(_, session_id)| session_id)
+        });
+        if let Some(local_id) = existing {
+            session_map.insert(session.id, local_id);
+            report.sessions_merged += 1;
+            continue;
+        }
+        
+        tx.execute(
+            "INSERT INTO sessions (title, created_at, message_count, pinned, archived, folder, assistant_id) VALUES (?1, ?2, 0, ?3, ?4, ?5, ?6)",
+            params![
+                session.title,
+                session.created_at,
+                session.pinned as i32,
+                session.archived as i32,
+                session.folder,
+                session.assistant_id.filter(|id| assistants.contains(id)),
+            ],
+        )?;
+        let local_id = tx.last_insert_rowid();
+        for tag in &session.tags {
+            tx.execute("INSERT OR IGNORE INTO session_tags (session_id, tag) VALUES (?1, ?2)", params![local_id, tag])?;
+        }
+        sessions_by_key.insert((session.title.clone(), session.created_at), local_id);
+        session_map.insert(session.id, local_id);
+        new_sessions.insert(local_id);
+        report.sessions_added += 1;
+    }
+    
+    // ---- Messages (oldest first, so parents exist before their children) ----
+    // Exports made before conversation trees have no parent ids: chain them per session
+    let has_tree = data.messages.iter().any(|m| m.parent_id.is_some());
+    let mut message_map: HashMap<i64, i64> = HashMap::new();
+    let mut last_in_session: HashMap<i64, i64> = HashMap::new();
+    let mut new_leaves: HashMap<i64, i64> = HashMap::new();
+    let mut new_messages = Vec::new();
+    
+    for message in messages {
+        let Some(&session_id) = session_map.get(&message.session_id) else {
+            report.messages_orphaned += 1;
+            continue;
+        };
+        let key = message_key(&message.content, message.timestamp, message.is_user);
+        if let Some(&(local_id, _)) = messages_by_key.get(&key) {
+            message_map.insert(message.id, local_id);
+            last_in_session.insert(session_id, local_id);
+            report.messages_duplicate += 1;
+            continue;
+        }
+        
+        let parent_id = match message.parent_id {
+            Some(parent) => message_map.get(&parent).or(last_in_session.get(&session_id)).copied(),
+            None if !has_tree => last_in_session.get(&session_id).copied(),
+            None => None,
+        };
+        // Messages added to an existing session stay off its visible branch
+        let is_active = message.is_active && new_sessions.contains(&session_id);
+        tx.execute(
+            "INSERT INTO messages (session_id, content, is_user, timestamp, parent_id, is_active, finish_reason, model) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![session_id, message.content, message.is_user as i32, message.timestamp, parent_id, is_active as i32, message.finish_reason, message.model],
+        )?;
+        let local_id = tx.last_insert_rowid();
+        
+        message_map.insert(message.id, local_id);
+        messages_by_key.insert(key, (local_id, session_id));
+        last_in_session.insert(session_id, local_id);
+        if is_active {
+            new_leaves.insert(session_id, local_id);
+        }
+        new_messages.push((local_id, message.content.clone()));
+        report.messages_added += 1;
+    }
+    
+    for (&session_id, &leaf_id) in &new_leaves {
+        set_active_leaf(&tx, session_id, leaf_id)?;
+    }
+    // Merged sessions that were empty get the imported messages as their branch
+    for &session_id in session_map.values().collect::<HashSet<_>>() {
+        let leaf: Option<i64> = tx.query_row("SELECT active_leaf_id FROM sessions WHERE id = ?1", params![session_id], |row| row.get(0))?;
+        if leaf.is_none() {
+            if let Some(&last) = last_in_session.get(&session_id) {
+                set_active_leaf(&tx, session_id, latest_leaf_under(&tx, last)?)?;
+            }
+        }
+    }
+    
+    // ---- Memories ----
+    let mut memory_keys: HashSet<(u64, i64)> = tx.prepare("SELECT content, created_at FROM memory")?
+        .query_map([], |row| Ok((content_hash(&row.get::<_, String>(0)?), row.get(1)?)))?
+        .collect::<Result<_>>()?;
+    let mut new_memories = Vec::new();
+    
+    for memory in &data.memory {
+        if !memory_keys.insert((content_hash(&memory.content), memory.created_at)) {
+            report.memories_duplicate += 1;
+            continue;
+        }
+        tx.execute(
+            "INSERT INTO memory (content, category, source_session_id, source_message_id, importance, created_at, assistant_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                memory.content,
+                memory.category,
+                session_map.get(&memory.source_session_id),
+                message_map.get(&memory.source_message_id),
+                memory.importance,
+                memory.created_at,
+                memory.assistant_id.filter(|id| assistants.contains(id)),
+            ],
+        )?;
+        new_memories.push((tx.last_insert_rowid(), memory.content.clone()));
+        report.memories_added += 1;
+    }
+    
+    // ---- Feedback (merge keeps the local one) ----
+    for feedback in &data.feedback {
+        let Some(&message_id) = message_map.get(&feedback.message_id) else {
+            continue;
+        };
+        report.feedback_added += tx.execute(
+            "INSERT OR IGNORE INTO message_feedback (message_id, thumbs_up, score, correction, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                message_id,
+                feedback.thumbs_up.map(|up| up as i32),
+                feedback.score,
+                feedback.correction,
+                feedback.updated_at
+            ],
+        )?;
+    }
+    
+    // ---- Persona (merge keeps the local one) ----
+    let has_persona: bool = tx.query_row("SELECT COUNT(*) > 0 FROM user_persona", [], |row| row.get(0))?;
+    if let Some(persona) = data.persona.as_ref().filter(|_| !has_persona) {
+        tx.execute(
+            r#"INSERT INTO user_persona 
+                (writing_style, avg_message_length, common_phrases, topics_of_interest, language, emoji_usage, tone, messages_analyzed, last_updated)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+            params![
+                persona.writing_style,
+                persona.avg_message_length,
+                persona.common_phrases,
+                persona.topics_of_interest,
+                persona.language,
+                persona.emoji_usage,
+                persona.tone,
+                persona.messages_analyzed,
+                persona.last_updated
+            ],
+        )?;
+        report.persona_imported = true;
+    }
+    
+    if dry_run {
+        tx.rollback()?;
+        return Ok((report, Vec::new(), Vec::new()));
+    }
+    tx.commit()?;
+    Ok((report, new_messages, new_memories))
+}
+
 // ==================== Voice Profiles ====================
 
 pub fn get_voice_profiles() -> Result<Vec<VoiceProfile>> {
@@ -873,6 +2801,7 @@ mod tests {
             source_message_id: 10,
             importance: 8,
             created_at: get_timestamp(),
+            assistant_id: None,
         };
         
         assert_eq!(memory.id, 1);
@@ -909,6 +2838,10 @@ mod tests {
             content: "Hello world".to_string(),
             is_user: true,
             timestamp: get_timestamp(),
+            parent_id: None,
+            is_active: true,
+            finish_reason: None,
+            model: None,
         };
         
         assert!(msg.is_user);
@@ -922,6 +2855,11 @@ mod tests {
             title: "New Chat".to_string(),
             created_at: get_timestamp(),
             message_count: 5,
+            pinned: false,
+            archived: false,
+            folder: None,
+            tags: vec![],
+            assistant_id: None,
         };
         
         assert_eq!(session.title, "New Chat");
@@ -932,15 +2870,85 @@ mod tests {
     fn test_message_structure() {
         let msg = Message {
             id: 1,
+            session_id: 1,
             content: "Привет!".to_string(),
             is_user: true,
             timestamp: get_timestamp(),
+            parent_id: None,
+            finish_reason: None,
+            model: None,
         };
         
         assert!(msg.is_user);
         assert!(msg.content.contains("Привет"));
     }
 
+    #[test]
+    fn test_message_tree_serialization() {
+        let tree = MessageTree {
+            session_id: 1,
+            active_leaf_id: Some(2),
+            nodes: vec![MessageNode {
+                message: Message {
+                    id: 1,
+                    session_id: 1,
+                    content: "Привет".to_string(),
+                    is_user: true,
+                    timestamp: get_timestamp(),
+                    parent_id: None,
+                    finish_reason: None,
+                    model: None,
+                },
+                is_active: true,
+                child_ids: vec![2, 3],
+            }],
+        };
+        
+        let json = serde_json::to_string(&tree).expect("Failed to serialize");
+        assert!(json.contains("\"activeLeafId\":2"));
+        assert!(json.contains("\"childIds\":[2,3]"));
+        // Message fields are flattened into the node
+        assert!(json.contains("\"isUser\":true"));
+        assert!(json.contains("\"isActive\":true"));
+    }
+
+    #[test]
+    fn test_assistant_deserialization_defaults() {
+        let assistant: Assistant = serde_json::from_str(
+            r#"{"name": "Писатель", "systemPrompt": "Пиши красиво", "sampling": {"temperature": 1.1}}"#
+        ).expect("Failed to deserialize");
+        
+        assert_eq!(assistant.memory_mode, "shared");
+        assert!(assistant.model_path.is_none());
+        assert!(assistant.voice_profile_id.is_none());
+        let sampling = assistant.sampling.as_ref().expect("sampling preset");
+        assert_eq!(sampling.temperature, 1.1);
+        assert_eq!(sampling.top_p, 1.0);
+        
+        let json = serde_json::to_string(&assistant).expect("Failed to serialize");
+        assert!(json.contains("\"memoryMode\":\"shared\""));
+    }
+
+    #[test]
+    fn test_session_filter_deserialization() {
+        let filter: SessionFilter = serde_json::from_str(
+            r#"{"folder": "Работа", "archived": false, "createdFrom": 1000}"#
+        ).expect("Failed to deserialize");
+        
+        assert_eq!(filter.folder.as_deref(), Some("Работа"));
+        assert_eq!(filter.archived, Some(false));
+        assert_eq!(filter.created_from, Some(1000));
+        assert!(filter.tag.is_none());
+        assert!(filter.text.is_none());
+        
+        // Sessions from older exports have no organisation fields
+        let session: Session = serde_json::from_str(
+            r#"{"id": 1, "title": "Чат", "createdAt": 0, "messageCount": 2}"#
+        ).expect("Failed to deserialize");
+        assert!(!session.pinned);
+        assert!(session.tags.is_empty());
+    }
+
     #[test]
     fn test_voice_profile_structure() {
         let profile = VoiceProfile {
@@ -983,6 +2991,10 @@ mod tests {
             model_paths: vec!["/path/to/model.gguf".to_string()],
             system_prompt: "Test prompt".to_string(),
             llm_backend: "native".to_string(),
+            backup_interval_hours: 24,
+            backup_keep: 3,
+            retrieval: crate::retrieval::RetrievalConfig { vector_weight: 0.5, ..Default::default() },
+            embedding: crate::embedding_models::EmbeddingSettings { model: "bge-small-en-v1.5".to_string(), models_dir: Some("~/models".to_string()), ..Default::default() },
         };
         
         // Test JSON serialization
@@ -995,6 +3007,8 @@ mod tests {
         assert_eq!(parsed.temperature, 0.8);
         assert_eq!(parsed.theme, "light");
         assert_eq!(parsed.llm_backend, "native");
+        assert_eq!(parsed.retrieval, settings.retrieval);
+        assert_eq!(parsed.embedding, settings.embedding);
     }
 
     #[test]
@@ -1004,6 +3018,7 @@ mod tests {
             messages: vec![],
             memory: vec![],
             persona: None,
+            feedback: vec![],
             exported_at: get_timestamp(),
         };
         
@@ -1011,4 +3026,424 @@ mod tests {
         assert!(export.persona.is_none());
         assert!(export.exported_at > 0);
     }
+    
+    #[test]
+    fn test_export_message_from_older_export() {
+        let json = r#"{"id": 3, "sessionId": 1, "sessionTitle": "Старый чат", "content": "hi", "isUser": true, "timestamp": 10}"#;
+        let msg: ExportMessage = serde_json::from_str(json).unwrap();
+        
+        assert!(msg.is_active, "older exports only contained the visible branch");
+        assert!(msg.parent_id.is_none());
+        assert!(msg.finish_reason.is_none());
+        
+        let mode: ImportMode = serde_json::from_str("\"replace\"").unwrap();
+        assert_eq!(mode, ImportMode::Replace);
+    }
+    
+    fn active_ids(session_id: i64) -> Vec<i64> {
+        get_messages(session_id).unwrap().iter().map(|m| m.id).collect()
+    }
+    
+    #[test]
+    fn test_replies_and_branch_switching() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        let question = insert_message(session_id, "вопрос", true, None, None).unwrap();
+        let first = insert_message(session_id, "ответ 1", false, Some("stop"), None).unwrap();
+        
+        // A hidden alternative leaves the visible branch alone
+        let hidden = insert_reply(session_id, question, "ответ 2", Some("stop"), None, false).unwrap();
+        assert_eq!(hidden.parent_id, Some(question));
+        assert_eq!(active_ids(session_id), vec![question, first]);
+        assert_eq!(get_message_tree(session_id).unwrap().active_leaf_id, Some(first));
+        
+        let third = insert_reply(session_id, question, "ответ 3", Some("stop"), None, true).unwrap();
+        assert_eq!(active_ids(session_id), vec![question, third.id]);
+        assert_eq!(get_alternatives(first).unwrap().len(), 3);
+        
+        switch_branch(hidden.id).unwrap();
+        let tree = get_message_tree(session_id).unwrap();
+        assert_eq!(tree.active_leaf_id, Some(hidden.id));
+        let active: Vec<i64> = tree.nodes.iter().filter(|n| n.is_active).map(|n| n.message.id).collect();
+        assert_eq!(active, vec![question, hidden.id]);
+        assert_eq!(get_session(session_id).unwrap().message_count, 2);
+    }
+    
+    #[test]
+    fn test_get_session_loads_its_own_tags() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        let other = create_session("o", None).unwrap();
+        set_session_tags(session_id, &["б".to_string(), "а".to_string()]).unwrap();
+        set_session_tags(other, &["в".to_string()]).unwrap();
+        
+        let session = get_session(session_id).unwrap();
+        assert_eq!(session.title, "t");
+        assert_eq!(session.tags, vec!["а", "б"]);
+        assert!(matches!(get_session(other + 1), Err(rusqlite::Error::QueryReturnedNoRows)));
+    }
+    
+    #[test]
+    fn test_edit_creates_branch_and_switch_restores_old_one() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        let q1 = insert_message(session_id, "q1", true, None, None).unwrap();
+        let a1 = insert_message(session_id, "a1", false, None, None).unwrap();
+        let q2 = insert_message(session_id, "q2", true, None, None).unwrap();
+        let a2 = insert_message(session_id, "a2", false, None, None).unwrap();
+        
+        let edited = edit_message_as_branch(q2, "q2 исправлен").unwrap();
+        assert_eq!(edited.parent_id, Some(a1));
+        assert_eq!(active_ids(session_id), vec![q1, a1, edited.id]);
+        assert_eq!(get_message_tree(session_id).unwrap().active_leaf_id, Some(edited.id));
+        
+        // The new branch continues from the edited message
+        let a3 = insert_message(session_id, "a3", false, None, None).unwrap();
+        assert_eq!(get_message(a3).unwrap().parent_id, Some(edited.id));
+        
+        // Switching to the original question continues to the end of its branch
+        switch_branch(q2).unwrap();
+        assert_eq!(active_ids(session_id), vec![q1, a1, q2, a2]);
+        assert_eq!(get_message_tree(session_id).unwrap().active_leaf_id, Some(a2));
+        
+        switch_branch(edited.id).unwrap();
+        assert_eq!(active_ids(session_id), vec![q1, a1, edited.id, a3]);
+    }
+    
+    #[test]
+    fn test_search_skips_hidden_branches() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        insert_message(session_id, "вопрос про кошек", true, None, None).unwrap();
+        let answer = insert_message(session_id, "ответ про кошек", false, None, None).unwrap();
+        let edited = edit_message_as_branch(answer, "ответ про собак").unwrap();
+        
+        let found = search_all_messages("кошек", 10).unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(found[0].is_user);
+        assert_eq!(search_all_messages("собак", 10).unwrap()[0].id, edited.id);
+        
+        switch_branch(answer).unwrap();
+        assert_eq!(search_all_messages("кошек", 10).unwrap().len(), 2);
+        assert!(search_all_messages("собак", 10).unwrap().is_empty());
+    }
+    
+    fn store_vector(source_type: &str, source_id: i64) {
+        with_connection(|conn| conn.execute(
+            "INSERT INTO embeddings (source_type, source_id, content_hash, vector, created_at) VALUES (?1, ?2, '', x'00', 0)",
+            params![source_type, source_id],
+        )).unwrap().unwrap();
+    }
+    
+    fn vector_count(source_type: &str, source_id: i64) -> i64 {
+        with_connection(|conn| conn.query_row(
+            "SELECT COUNT(*) FROM embeddings WHERE source_type = ?1 AND source_id = ?2",
+            params![source_type, source_id],
+            |row| row.get(0),
+        )).unwrap().unwrap()
+    }
+    
+    #[test]
+    fn test_unified_search_interleaves_sources() {
+        let _db = test_database();
+        let session_id = create_session("Длинный разговор о погоде, отпуске, работе, и ещё про кошки", None).unwrap();
+        for i in 0..5 {
+            insert_message(session_id, &format!("кошки кошки {}", i), true, None, None).unwrap();
+            insert_message(session_id, &format!("собаки {}", i), false, None, None).unwrap();
+        }
+        let memory_id = add_memory("у пользователя две кошки и аквариум с рыбками", "fact", session_id, 0, 5).unwrap();
+        
+        // The lone title and memory score worse than every message in bm25 terms,
+        // yet the best match of each source comes first
+        let page = search(&UnifiedSearch { query: "кошки".to_string(), limit: 3, ..UnifiedSearch::default() }).unwrap();
+        assert_eq!(page.total, 7);
+        assert!(page.has_more);
+        let sources: HashSet<&str> = page.hits.iter().map(|hit| match &hit.item {
+            SearchItem::Message(_) => "message",
+            SearchItem::Memory(memory) => {
+                assert_eq!(memory.id, memory_id);
+                "memory"
+            }
+            SearchItem::Session(session) => {
+                assert_eq!(session.id, session_id);
+                "session"
+            }
+        }).collect();
+        assert_eq!(sources.len(), 3);
+        
+        let rest = search(&UnifiedSearch { query: "кошки".to_string(), offset: 3, ..UnifiedSearch::default() }).unwrap();
+        assert_eq!(rest.hits.len(), 4);
+        assert!(rest.hits.iter().all(|hit| matches!(hit.item, SearchItem::Message(_))));
+        assert!(rest.hits.windows(2).all(|pair| pair[0].rank <= pair[1].rank));
+    }
+    
+    #[test]
+    fn test_update_message_resyncs_search_and_drops_vector() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        let id = insert_message(session_id, "старый текст про кошек", true, None, None).unwrap();
+        store_vector("message", id);
+        
+        let updated = update_message(id, "новый текст про собак").unwrap();
+        assert_eq!(updated.content, "новый текст про собак");
+        assert!(search_all_messages("кошек", 10).unwrap().is_empty());
+        assert_eq!(search_all_messages("собак", 10).unwrap().len(), 1);
+        assert_eq!(vector_count("message", id), 0);
+        assert!(update_message(id + 100, "x").is_err());
+    }
+    
+    #[test]
+    fn test_delete_session_removes_vectors() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        let question = insert_message(session_id, "меня зовут Аня", true, None, None).unwrap();
+        let memory_id = add_memory("Пользователя зовут Аня", "name", session_id, question, 8).unwrap();
+        store_vector("message", question);
+        store_vector("memory", memory_id);
+        let other = create_session("другой", None).unwrap();
+        let kept = insert_message(other, "привет", true, None, None).unwrap();
+        store_vector("message", kept);
+        
+        delete_session(session_id).unwrap();
+        assert_eq!(vector_count("message", question), 0);
+        // Memories outlive their chat, and so do their vectors
+        assert_eq!(vector_count("memory", memory_id), 1);
+        assert_eq!(vector_count("message", kept), 1);
+        assert_eq!(row_count("messages"), 1);
+    }
+    
+    #[test]
+    fn test_delete_message_removes_vectors_and_memories() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        let question = insert_message(session_id, "меня зовут Аня", true, None, None).unwrap();
+        let answer = insert_message(session_id, "приятно познакомиться", false, None, None).unwrap();
+        let memory_id = add_memory("Пользователя зовут Аня", "fact", session_id, question, 5).unwrap();
+        store_vector("message", question);
+        store_vector("memory", memory_id);
+        
+        delete_message(question).unwrap();
+        assert_eq!(vector_count("message", question), 0);
+        assert_eq!(vector_count("memory", memory_id), 0);
+        assert!(get_all_memories().unwrap().is_empty());
+        
+        // The reply is re-attached to the deleted message's parent
+        let messages = get_messages(session_id).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, answer);
+        assert_eq!(messages[0].parent_id, None);
+        
+        delete_message(answer).unwrap();
+        assert_eq!(get_message_tree(session_id).unwrap().active_leaf_id, None);
+        assert_eq!(get_session(session_id).unwrap().message_count, 0);
+    }
+    
+    fn row_count(table: &str) -> i64 {
+        with_connection(|conn| conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)))
+            .unwrap()
+            .unwrap()
+    }
+    
+    /// A chat with a question, an answer rated up and a memory taken from the question
+    fn sample_chat() -> ExportData {
+        let session_id = create_session("Черепахи", None).unwrap();
+        let question = insert_message(session_id, "Сколько живут черепахи?", true, None, None).unwrap();
+        let answer = insert_message(session_id, "До 150 лет.", false, Some("stop"), None).unwrap();
+        add_memory("Интересуется черепахами", "interest", session_id, question, 5).unwrap();
+        set_message_feedback(&MessageFeedback { message_id: answer, thumbs_up: Some(true), ..Default::default() }).unwrap();
+        export_all_data().unwrap()
+    }
+    
+    #[test]
+    fn test_full_import_merge_skips_what_exists() {
+        let _db = test_database();
+        let mut data = sample_chat();
+        
+        let (report, messages, memories) = import_full_export(&data, ImportMode::Merge, false).unwrap();
+        assert_eq!((report.sessions_merged, report.sessions_added), (1, 0));
+        assert_eq!((report.messages_duplicate, report.messages_added), (2, 0));
+        assert_eq!((report.memories_duplicate, report.feedback_added), (1, 0));
+        assert!(messages.is_empty() && memories.is_empty());
+        
+        // A message the database lacks is added once, however often the file is merged
+        let answer = data.messages[1].clone();
+        data.messages.push(ExportMessage { id: answer.id + 1, content: "Уточнение".into(), timestamp: answer.timestamp + 1, ..answer });
+        let (report, messages, _) = import_full_export(&data, ImportMode::Merge, false).unwrap();
+        assert_eq!((report.messages_added, report.messages_duplicate), (1, 2));
+        assert_eq!(messages.len(), 1);
+        let (report, _, _) = import_full_export(&data, ImportMode::Merge, false).unwrap();
+        assert_eq!((report.messages_added, report.messages_duplicate), (0, 3));
+        assert_eq!(row_count("messages"), 3);
+        assert_eq!(row_count("sessions"), 1);
+    }
+    
+    #[test]
+    fn test_full_import_replace_remaps_ids() {
+        let _db = test_database();
+        let data = sample_chat();
+        let session_id = data.sessions[0].id;
+        delete_session(session_id).unwrap();
+        let other = create_session("Другое", None).unwrap();
+        insert_message(other, "занимает старые id", true, None, None).unwrap();
+        
+        let (report, messages, memories) = import_full_export(&data, ImportMode::Replace, false).unwrap();
+        assert_eq!((report.sessions_removed, report.messages_removed), (1, 1));
+        assert_eq!((report.sessions_added, report.messages_added, report.memories_added, report.feedback_added), (1, 2, 1, 1));
+        assert_eq!((messages.len(), memories.len()), (2, 1));
+        
+        let session = &get_sessions(&SessionFilter::default()).unwrap()[0];
+        assert_eq!(session.title, "Черепахи");
+        assert_ne!(session.id, session_id, "ids are assigned anew");
+        let chat = get_messages(session.id).unwrap();
+        assert_eq!(chat.len(), 2);
+        assert_eq!(chat[1].parent_id, Some(chat[0].id));
+        let memory = &get_all_memories().unwrap()[0];
+        assert_eq!((memory.source_session_id, memory.source_message_id), (session.id, chat[0].id));
+        assert_eq!(get_message_feedback(chat[1].id).unwrap().unwrap().thumbs_up, Some(true));
+    }
+    
+    #[test]
+    fn test_full_import_dry_run_changes_nothing() {
+        let _db = test_database();
+        let data = sample_chat();
+        let other = create_session("Другое", None).unwrap();
+        insert_message(other, "останется на месте", true, None, None).unwrap();
+        let before = export_all_data().unwrap();
+        
+        for mode in [ImportMode::Replace, ImportMode::Merge] {
+            let (report, messages, memories) = import_full_export(&data, mode, true).unwrap();
+            assert!(report.dry_run);
+            assert!(messages.is_empty() && memories.is_empty());
+            if mode == ImportMode::Replace {
+                assert_eq!((report.sessions_removed, report.sessions_added, report.messages_added), (2, 1, 2));
+            }
+        }
+        
+        let after = export_all_data().unwrap();
+        assert_eq!(serde_json::to_value(&after.messages).unwrap(), serde_json::to_value(&before.messages).unwrap());
+        assert_eq!(serde_json::to_value(&after.memory).unwrap(), serde_json::to_value(&before.memory).unwrap());
+        assert_eq!(after.sessions.len(), 2);
+        assert_eq!(after.feedback.len(), 1);
+    }
+    
+    #[test]
+    fn test_feedback_shift_is_taken_back_exactly() {
+        let _db = test_database();
+        let session_id = create_session("t", None).unwrap();
+        let message_id = insert_message(session_id, "Я живу в Казани", true, None, None).unwrap();
+        let high = add_memory("Живёт в Казани", "fact", session_id, message_id, 9).unwrap();
+        let low = add_memory("Город: Казань", "fact", session_id, message_id, 2).unwrap();
+        let importance = || -> (i32, i32) {
+            let memories = get_memories_by_ids(&[high, low]).unwrap();
+            (memories[&high].importance, memories[&low].importance)
+        };
+        let rate = |thumbs_up: Option<bool>, score: Option<i32>| {
+            set_message_feedback(&MessageFeedback { message_id, thumbs_up, score, ..Default::default() }).unwrap();
+        };
+        
+        rate(Some(true), None);
+        assert_eq!(importance(), (10, 4), "+2, cut short at 10");
+        rate(Some(false), None);
+        assert_eq!(importance(), (7, 1), "-2 from the original, cut short at 1");
+        rate(Some(true), None);
+        assert_eq!(importance(), (10, 4));
+        rate(None, Some(3));
+        assert_eq!(importance(), (9, 2));
+        rate(Some(false), None);
+        rate(None, None);
+        assert_eq!(importance(), (9, 2), "removing the rating restores the original");
+    }
+    
+    #[test]
+    fn test_import_timestamps_keep_children_after_parents() {
+        use crate::importers::{ImportFormat, ImportedMessage};
+        
+        let message = |timestamp, parent| ImportedMessage { content: "x".into(), is_user: true, timestamp, parent };
+        let conversation = ImportedConversation {
+            format: ImportFormat::Generic,
+            external_id: None,
+            title: "t".into(),
+            created_at: Some(1000),
+            messages: vec![message(None, None), message(Some(5000), Some(0)), message(Some(10), Some(1)), message(None, Some(0))],
+            active_leaf: Some(2),
+        };
+        
+        assert_eq!(import_timestamps(&conversation, 99), vec![1000, 5000, 5001, 1001]);
+    }
+    
+    #[test]
+    fn test_fine_tune_formats() {
+        let message = |id, is_user, content: &str| Message {
+            id,
+            session_id: 1,
+            content: content.to_string(),
+            is_user,
+            timestamp: id,
+            parent_id: None,
+            finish_reason: None,
+            model: None,
+        };
+        let turns = vec![message(1, true, "Привет"), message(2, false, "Здравствуй!")];
+        
+        let messages = openai_messages("Будь кратким", &turns);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[2]["role"], "assistant");
+        assert_eq!(openai_messages("  ", &turns).len(), 2, "empty system prompt is left out");
+        
+        assert_eq!(
+            chatml_text("S", &turns),
+            "<|im_start|>system\nS<|im_end|>\n<|im_start|>user\nПривет<|im_end|>\n<|im_start|>assistant\nЗдравствуй!<|im_end|>\n"
+        );
+        assert!(chatml_text("  ", &turns).starts_with("<|im_start|>user\n"), "empty system prompt is left out");
+        
+        let pair = preference_pair("S", &turns[..1], "хорошо", "плохо");
+        assert_eq!(pair["prompt"].as_array().unwrap().len(), 2);
+        assert_eq!(pair["prompt"][1]["content"], "Привет");
+        assert_eq!(pair["chosen"][0]["content"], "хорошо");
+        assert_eq!(pair["rejected"][0]["role"], "assistant");
+    }
+    
+    #[test]
+    fn test_preferences_follow_feedback() {
+        let answer = |id, content: &str| Message {
+            id,
+            session_id: 1,
+            content: content.to_string(),
+            is_user: false,
+            timestamp: id,
+            parent_id: Some(1),
+            finish_reason: Some("stop".to_string()),
+            model: None,
+        };
+        let siblings = vec![answer(2, "первый"), answer(3, "второй"), answer(4, "третий")];
+        let rated = |message_id, thumbs_up, score: Option<i32>, correction: Option<&str>| (message_id, MessageFeedback {
+            message_id,
+            thumbs_up,
+            score,
+            correction: correction.map(str::to_string),
+            updated_at: 0,
+        });
+        
+        // No feedback: the visible answer beats its siblings
+        let pairs = preferences(&siblings, 3, &HashMap::new(), None);
+        assert_eq!(pairs, vec![("второй".into(), "первый".into()), ("второй".into(), "третий".into())]);
+        assert!(preferences(&siblings, 3, &HashMap::new(), Some(4)).is_empty());
+        
+        // A thumbs-up on a hidden answer outranks the visible one; the thumbs-down loses to both
+        let feedback: HashMap<_, _> = [rated(2, Some(true), None, None), rated(4, Some(false), None, Some("исправлено"))].into();
+        assert_eq!(feedback[&2].rating(), Some(5));
+        let pairs = preferences(&siblings, 3, &feedback, Some(4));
+        assert_eq!(pairs, vec![
+            ("первый".into(), "второй".into()),
+            ("первый".into(), "третий".into()),
+            ("исправлено".into(), "третий".into()),
+        ]);
+        
+        // Score wins over thumbs
+        let (_, feedback) = rated(2, Some(true), Some(2), None);
+        assert_eq!(feedback.rating(), Some(2));
+        assert_eq!(feedback.importance_shift(), -1);
+        assert!(MessageFeedback::default().is_empty());
+    }
 }
@@ -0,0 +1,578 @@
+//! Curated fine-tuning datasets.
+//!
+//! An example is one user message and the assistant answer to it on the visible
+//! branch of a session, optionally with some preceding messages as context. The
+//! builder selects sessions, drops examples that don't pass the filters, removes
+//! exact and near duplicates, splits the rest into train / validation and writes
+//! `train.jsonl`, `validation.jsonl` and `manifest.json` into one directory.
+//! The manifest records the filters used and how many examples each one removed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::commands::Message;
+use crate::database;
+use crate::encryption;
+use crate::redaction::{RedactionConfig, RedactionReport, Redactor};
+#[cfg(feature = "semantic-search")]
+use crate::embeddings;
+#[cfg(feature = "semantic-search")]
+use crate::vector_index::Hnsw;
+
+const TRAIN_FILE: &str = "train.jsonl";
+const VALIDATION_FILE: &str = "validation.jsonl";
+const MANIFEST_FILE: &str = "manifest.json";
+/// Nearest kept prompts an example is compared with when looking for near duplicates
+#[cfg(feature = "semantic-search")]
+const NEAR_DUPLICATE_NEIGHBOURS: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatasetFormat {
+    /// `{"instruction", "input", "output"}`; context messages are not included
+    #[default]
+    Alpaca,
+    ShareGpt,
+    /// OpenAI chat fine-tuning `{"messages": [...]}` with the system prompt
+    OpenAi,
+    /// `{"text": "<|im_start|>..."}`
+    ChatMl,
+}
+
+/// Filters and split settings; the defaults keep everything except truncated
+/// answers and exact duplicates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DatasetConfig {
+    pub format: DatasetFormat,
+    /// Answer time range, inclusive, in milliseconds
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    /// Only these sessions; empty = all
+    pub session_ids: Vec<i64>,
+    /// Only sessions with at least one of these tags; empty = all
+    pub tags: Vec<String>,
+    /// Example length (prompt + answer) in characters
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    /// Only answers rated at least this high (1-5); unrated answers are dropped
+    pub min_rating: Option<i32>,
+    /// Language codes as detected by `detect_language` ("ru", "en", "other"); empty = any
+    pub languages: Vec<String>,
+    /// Drop answers cut off by the token limit or stopped by the user
+    pub exclude_truncated: bool,
+    /// Drop examples whose prompt and answer repeat an earlier example (ignoring case and spacing)
+    pub dedup: bool,
+    /// Drop examples whose prompt and answer are both at least this similar (cosine of the
+    /// stored embeddings) to an earlier example with one of the nearest prompts; None = off
+    pub near_duplicate_threshold: Option<f32>,
+    /// Preceding messages of the branch included as context
+    pub history_turns: usize,
+    /// Share of sessions put into the validation file (0 = no validation file)
+    pub validation_ratio: f32,
+    /// Same seed and data give the same split
+    pub seed: u64,
+    /// Replace personal data in the written records; None = off
+    pub redaction: Option<RedactionConfig>,
+}
+
+impl Default for DatasetConfig {
+    fn default() -> Self {
+        Self {
+            format: DatasetFormat::default(),
+            from: None,
+            to: None,
+            session_ids: Vec::new(),
+            tags: Vec::new(),
+            min_length: None,
+            max_length: None,
+            min_rating: None,
+            languages: Vec::new(),
+            exclude_truncated: true,
+            dedup: true,
+            near_duplicate_threshold: None,
+            history_turns: 0,
+            validation_ratio: 0.1,
+            seed: 42,
+            redaction: None,
+        }
+    }
+}
+
+/// How many examples each step removed, in the order the steps run
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatasetCounts {
+    pub sessions: usize,
+    /// Examples in the selected sessions before filtering
+    pub candidates: usize,
+    pub out_of_date_range: usize,
+    pub truncated: usize,
+    pub too_short: usize,
+    pub too_long: usize,
+    pub low_rating: usize,
+    pub wrong_language: usize,
+    pub duplicates: usize,
+    pub near_duplicates: usize,
+    /// Examples that couldn't be checked for near duplicates (no stored embedding)
+    pub not_embedded: usize,
+    pub train: usize,
+    pub validation: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatasetManifest {
+    pub created_at: i64,
+    pub config: DatasetConfig,
+    pub counts: DatasetCounts,
+    /// Examples per detected language after filtering
+    pub languages: HashMap<String, usize>,
+    pub train_file: String,
+    pub validation_file: Option<String>,
+    /// What was redacted, when redaction was on
+    pub redaction: Option<RedactionReport>,
+    /// Filters that were requested but couldn't be applied
+    pub warnings: Vec<String>,
+}
+
+/// One prompt / answer pair with its context
+#[derive(Debug, Clone)]
+struct Example {
+    session_id: i64,
+    history: Vec<Message>,
+    prompt: Message,
+    answer: Message,
+    language: &'static str,
+}
+
+impl Example {
+    fn len(&self) -> usize {
+        self.prompt.content.chars().count() + self.answer.content.chars().count()
+    }
+
+    fn turns(&self) -> Vec<Message> {
+        self.history.iter()
+            .chain([&self.prompt, &self.answer])
+            .cloned()
+            .collect()
+    }
+}
+
+/// Build the dataset into `out_dir` (created if missing). `system_prompt` resolves
+/// the system prompt of a session for the OpenAI and ChatML formats.
+pub fn build(config: &DatasetConfig, system_prompt: &dyn Fn(i64) -> String, out_dir: &Path) -> Result<DatasetManifest, String> {
+    if !(0.0..1.0).contains(&config.validation_ratio) {
+        return Err("Доля валидации должна быть от 0 до 1".to_string());
+    }
+
+    let mut counts = DatasetCounts::default();
+    let mut warnings = Vec::new();
+
+    let sessions = select_sessions(config).map_err(|e| e.to_string())?;
+    counts.sessions = sessions.len();
+    let mut examples = Vec::new();
+    for &session_id in &sessions {
+        let messages = database::get_messages(session_id).map_err(|e| e.to_string())?;
+        examples.extend(examples_from_branch(session_id, &messages, config.history_turns));
+    }
+    counts.candidates = examples.len();
+
+    let examples = apply_filters(examples, config, &mut counts).map_err(|e| e.to_string())?;
+    let examples = remove_near_duplicates(examples, config, &mut counts, &mut warnings)?;
+
+    let mut languages: HashMap<String, usize> = HashMap::new();
+    for example in &examples {
+        *languages.entry(example.language.to_string()).or_default() += 1;
+    }
+
+    let mut redactor = config.redaction.clone().map(Redactor::new).transpose()?;
+    let mut prompts: HashMap<i64, String> = HashMap::new();
+    let mut train = Vec::new();
+    let mut validation = Vec::new();
+    for example in &examples {
+        let system = match config.format {
+            DatasetFormat::OpenAi | DatasetFormat::ChatMl => prompts
+                .entry(example.session_id)
+                .or_insert_with(|| system_prompt(example.session_id))
+                .as_str(),
+            _ => "",
+        };
+        let mut record = render(config.format, example, system);
+        if let Some(redactor) = redactor.as_mut() {
+            redactor.redact_record(&mut record);
+        }
+        if in_validation(example.session_id, config.seed, config.validation_ratio) {
+            validation.push(record);
+        } else {
+            train.push(record);
+        }
+    }
+    counts.train = train.len();
+    counts.validation = validation.len();
+
+    std::fs::create_dir_all(out_dir).map_err(|e| e.to_string())?;
+    let train_file = write_jsonl(out_dir, TRAIN_FILE, &train)?;
+    let validation_file = if config.validation_ratio > 0.0 {
+        Some(write_jsonl(out_dir, VALIDATION_FILE, &validation)?)
+    } else {
+        None
+    };
+
+    // The manifest goes along with the data, so it must not list the hidden terms
+    let mut used = config.clone();
+    if let Some(redaction) = used.redaction.as_mut() {
+        redaction.terms.clear();
+    }
+    let manifest = DatasetManifest {
+        created_at: now_millis(),
+        config: used,
+        counts,
+        languages,
+        train_file,
+        validation_file,
+        redaction: redactor.map(|r| r.report()),
+        warnings,
+    };
+    let json = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+    write_file(out_dir, MANIFEST_FILE, json.as_bytes())?;
+
+    Ok(manifest)
+}
+
+/// Ids of the sessions picked by id and tag
+fn select_sessions(config: &DatasetConfig) -> rusqlite::Result<Vec<i64>> {
+    let ids: HashSet<i64> = config.session_ids.iter().copied().collect();
+
+    let mut selected: Vec<i64> = database::get_sessions(&database::SessionFilter::default())?
+        .into_iter()
+        .filter(|s| ids.is_empty() || ids.contains(&s.id))
+        .filter(|s| config.tags.is_empty() || s.tags.iter().any(|t| config.tags.contains(t)))
+        .map(|s| s.id)
+        .collect();
+    // Oldest first, so deduplication keeps the first occurrence
+    selected.sort_unstable();
+    Ok(selected)
+}
+
+/// Every assistant answer that directly follows a user message on the branch
+fn examples_from_branch(session_id: i64, messages: &[Message], history_turns: usize) -> Vec<Example> {
+    (1..messages.len())
+        .filter(|&idx| messages[idx - 1].is_user && !messages[idx].is_user)
+        .map(|idx| {
+            let (prompt, answer) = (&messages[idx - 1], &messages[idx]);
+            let start = (idx - 1).saturating_sub(history_turns);
+            Example {
+                session_id,
+                history: messages[start..idx - 1].to_vec(),
+                prompt: prompt.clone(),
+                answer: answer.clone(),
+                language: detect_language(&format!("{}\n{}", prompt.content, answer.content)),
+            }
+        })
+        .collect()
+}
+
+/// Date, truncation, length, rating, language and exact-duplicate filters
+fn apply_filters(examples: Vec<Example>, config: &DatasetConfig, counts: &mut DatasetCounts) -> rusqlite::Result<Vec<Example>> {
+    let ratings: HashMap<i64, i32> = if config.min_rating.is_some() {
+        database::get_feedback(&database::FeedbackFilter::default())?
+            .into_iter()
+            .filter_map(|f| Some((f.message_id, f.rating()?)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let mut seen = HashSet::new();
+    let mut kept = Vec::new();
+
+    for example in examples {
+        let timestamp = example.answer.timestamp;
+        let counter = if config.from.is_some_and(|from| timestamp < from) || config.to.is_some_and(|to| timestamp > to) {
+            &mut counts.out_of_date_range
+        } else if config.exclude_truncated && is_truncated(&example.answer) {
+            &mut counts.truncated
+        } else if config.min_length.is_some_and(|min| example.len() < min) {
+            &mut counts.too_short
+        } else if config.max_length.is_some_and(|max| example.len() > max) {
+            &mut counts.too_long
+        } else if config.min_rating.is_some_and(|min| ratings.get(&example.answer.id).is_none_or(|&r| r < min)) {
+            &mut counts.low_rating
+        } else if !config.languages.is_empty() && !config.languages.iter().any(|l| l == example.language) {
+            &mut counts.wrong_language
+        } else if config.dedup && !seen.insert((normalize(&example.prompt.content), normalize(&example.answer.content))) {
+            &mut counts.duplicates
+        } else {
+            kept.push(example);
+            continue;
+        };
+        *counter += 1;
+    }
+
+    Ok(kept)
+}
+
+/// Answers that stopped early: token limit ("length") or the user pressed stop
+fn is_truncated(answer: &Message) -> bool {
+    matches!(answer.finish_reason.as_deref(), Some("length") | Some("cancelled"))
+}
+
+/// Lowercase with runs of whitespace collapsed, for exact-duplicate detection
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Script-based language guess: "ru" for mostly Cyrillic letters, "en" for mostly
+/// Latin ones, "other" otherwise
+pub fn detect_language(text: &str) -> &'static str {
+    let (mut cyrillic, mut latin, mut other) = (0usize, 0usize, 0usize);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        match c {
+            'а'..='я' | 'А'..='Я' | 'ё' | 'Ё' => cyrillic += 1,
+            'a'..='z' | 'A'..='Z' => latin += 1,
+            _ => other += 1,
+        }
+    }
+
+    if cyrillic > 0 && cyrillic >= latin && cyrillic >= other {
+        "ru"
+    } else if latin > 0 && latin >= other {
+        "en"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(feature = "semantic-search")]
+fn remove_near_duplicates(
+    examples: Vec<Example>,
+    config: &DatasetConfig,
+    counts: &mut DatasetCounts,
+    _warnings: &mut Vec<String>,
+) -> Result<Vec<Example>, String> {
+    let Some(threshold) = config.near_duplicate_threshold else {
+        return Ok(examples);
+    };
+
+    let ids: Vec<i64> = examples.iter()
+        .flat_map(|e| [e.prompt.id, e.answer.id])
+        .collect();
+    // Only vectors of one model are comparable
+    let model = embeddings::current_model_id();
+    let vectors = database::with_connection(|conn| embeddings::get_vectors(conn, &model, "message", &ids))
+        .and_then(|r| r)
+        .map_err(|e| e.to_string())?;
+
+    // Kept prompts go into a nearest-neighbour graph keyed by their place in `kept_answers`,
+    // so each example is compared with a few similar prompts instead of all kept ones
+    let dim = vectors.values().next().map_or(0, Vec::len);
+    let mut prompts = Hnsw::new(&model, dim);
+    let mut kept_answers: Vec<&Vec<f32>> = Vec::new();
+    let mut kept = Vec::new();
+    for example in &examples {
+        let (Some(prompt), Some(answer)) = (vectors.get(&example.prompt.id), vectors.get(&example.answer.id)) else {
+            counts.not_embedded += 1;
+            kept.push(example.clone());
+            continue;
+        };
+        let duplicate = prompts.search(prompt, NEAR_DUPLICATE_NEIGHBOURS, None)
+            .into_iter()
+            .take_while(|&(_, similarity)| similarity >= threshold)
+            .any(|(index, _)| embeddings::cosine_similarity(answer, kept_answers[index as usize]) >= threshold);
+        if duplicate {
+            counts.near_duplicates += 1;
+        } else {
+            prompts.insert(kept_answers.len() as i64, "message", example.prompt.id, prompt);
+            kept_answers.push(answer);
+            kept.push(example.clone());
+        }
+    }
+
+    Ok(kept)
+}
+
+#[cfg(not(feature = "semantic-search"))]
+fn remove_near_duplicates(
+    examples: Vec<Example>,
+    config: &DatasetConfig,
+    _counts: &mut DatasetCounts,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<Example>, String> {
+    if config.near_duplicate_threshold.is_some() {
+        warnings.push("Поиск почти-дубликатов недоступен: приложение собрано без эмбеддингов".to_string());
+    }
+    Ok(examples)
+}
+
+/// Stable split by session, so context never leaks from train into validation
+fn in_validation(session_id: i64, seed: u64, ratio: f32) -> bool {
+    let roll = splitmix64(seed ^ session_id as u64) as f64 / u64::MAX as f64;
+    roll < ratio as f64
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
+
+fn render(format: DatasetFormat, example: &Example, system_prompt: &str) -> serde_json::Value {
+    match format {
+        DatasetFormat::Alpaca => serde_json::json!({
+            "instruction": example.prompt.content,
+            "input": "",
+            "output": example.answer.content
+        }),
+        DatasetFormat::ShareGpt => serde_json::json!({
+            "id": format!("session_{}_{}", example.session_id, example.answer.id),
+            "conversations": example.turns().iter().map(|m| serde_json::json!({
+                "from": if m.is_user { "human" } else { "gpt" },
+                "value": m.content
+            })).collect::<Vec<_>>()
+        }),
+        DatasetFormat::OpenAi => serde_json::json!({
+            "messages": database::openai_messages(system_prompt, &example.turns())
+        }),
+        DatasetFormat::ChatMl => serde_json::json!({
+            "text": database::chatml_text(system_prompt, &example.turns())
+        }),
+    }
+}
+
+/// Write records one per line; returns the written path
+fn write_jsonl(dir: &Path, name: &str, records: &[serde_json::Value]) -> Result<String, String> {
+    let mut data = String::new();
+    for record in records {
+        data.push_str(&serde_json::to_string(record).map_err(|e| e.to_string())?);
+        data.push('\n');
+    }
+    write_file(dir, name, data.as_bytes())
+}
+
+/// Encrypted installs write `<name>.enc`, like every other export
+fn write_file(dir: &Path, name: &str, data: &[u8]) -> Result<String, String> {
+    let name = if encryption::is_unlocked() { format!("{}.enc", name) } else { name.to_string() };
+    let path = dir.join(name);
+    encryption::write_file(&path, data)?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i64, is_user: bool, content: &str) -> Message {
+        Message {
+            id,
+            session_id: 1,
+            content: content.to_string(),
+            is_user,
+            timestamp: id * 1000,
+            parent_id: (id > 1).then_some(id - 1),
+            finish_reason: (!is_user).then(|| "stop".to_string()),
+            model: None,
+        }
+    }
+
+    #[test]
+    fn test_examples_pair_each_answer_with_its_prompt() {
+        let branch = vec![
+            message(1, true, "Привет"),
+            message(2, false, "Здравствуй"),
+            message(3, true, "Как дела?"),
+            message(4, true, "Ау"),
+            message(5, false, "Хорошо"),
+        ];
+        let examples = examples_from_branch(1, &branch, 2);
+
+        assert_eq!(examples.len(), 2);
+        assert!(examples[0].history.is_empty());
+        assert_eq!(examples[1].prompt.content, "Ау");
+        assert_eq!(examples[1].history.iter().map(|m| m.id).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(examples[1].turns().len(), 4);
+    }
+
+    #[test]
+    fn test_filters_count_what_they_remove() {
+        let mut truncated = message(4, false, "Обрыв");
+        truncated.finish_reason = Some("length".to_string());
+        let examples = [
+            examples_from_branch(1, &[message(1, true, "Привет"), message(2, false, "Здравствуй")], 0),
+            examples_from_branch(1, &[message(1, true, "привет"), message(2, false, "  здравствуй ")], 0),
+            examples_from_branch(1, &[message(3, true, "Hello"), message(4, false, "Hi there")], 0),
+            examples_from_branch(1, &[message(3, true, "Стоп"), truncated], 0),
+        ].concat();
+        let config = DatasetConfig { languages: vec!["ru".to_string()], ..Default::default() };
+        let mut counts = DatasetCounts::default();
+
+        let kept = apply_filters(examples, &config, &mut counts).unwrap();
+
+        assert_eq!(kept.len(), 1);
+        assert_eq!((counts.duplicates, counts.wrong_language, counts.truncated), (1, 1, 1));
+    }
+
+    #[cfg(feature = "semantic-search")]
+    #[test]
+    fn test_near_duplicates_need_similar_prompt_and_answer() {
+        let _db = database::test_database();
+        let model = embeddings::current_model_id();
+        // Each pair is stored as message ids (2k+1, 2k+2): prompt and answer
+        let pairs = [
+            ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            ([0.99, 0.1, 0.0], [0.0, 0.99, 0.1]),   // same as the first
+            ([0.99, 0.0, 0.1], [0.0, 0.0, 1.0]),    // same prompt, other answer
+            ([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),     // other prompt, same answer
+        ];
+        let mut examples = Vec::new();
+        for (k, (prompt, answer)) in pairs.iter().enumerate() {
+            let id = 2 * k as i64 + 1;
+            database::with_connection(|conn| {
+                for (id, vector) in [(id, prompt), (id + 1, answer)] {
+                    let embedding = embeddings::Embedding { model: model.clone(), vector: vector.to_vec() };
+                    embeddings::store_embedding(conn, "message", id, "", &embedding).unwrap();
+                }
+            }).unwrap();
+            examples.extend(examples_from_branch(1, &[message(id, true, "q"), message(id + 1, false, "a")], 0));
+        }
+        examples.extend(examples_from_branch(1, &[message(20, true, "q"), message(21, false, "a")], 0));
+
+        let config = DatasetConfig { near_duplicate_threshold: Some(0.95), ..Default::default() };
+        let mut counts = DatasetCounts::default();
+        let kept = remove_near_duplicates(examples, &config, &mut counts, &mut Vec::new()).unwrap();
+
+        assert_eq!(kept.iter().map(|e| e.prompt.id).collect::<Vec<_>>(), vec![1, 5, 7, 20]);
+        assert_eq!((counts.near_duplicates, counts.not_embedded), (1, 1));
+    }
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(detect_language("Привет, как дела? OK"), "ru");
+        assert_eq!(detect_language("Hello there, Вася"), "en");
+        assert_eq!(detect_language("你好世界"), "other");
+        assert_eq!(detect_language("12345"), "other");
+    }
+
+    #[test]
+    fn test_split_is_stable_and_close_to_ratio() {
+        let validation = (0..1000).filter(|&id| in_validation(id, 42, 0.2)).count();
+        assert!((150..250).contains(&validation), "got {}", validation);
+        assert!((0..1000).all(|id| in_validation(id, 42, 0.2) == in_validation(id, 42, 0.2)));
+        assert!((0..1000).all(|id| !in_validation(id, 7, 0.0)));
+        assert_ne!(
+            (0..100).filter(|&id| in_validation(id, 1, 0.5)).collect::<Vec<_>>(),
+            (0..100).filter(|&id| in_validation(id, 2, 0.5)).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Embedding models that semantic search can run on.
+//!
+//! Vectors of different models live in different spaces, so every stored vector is
+//! tagged with the id of the model that produced it and searches only compare
+//! vectors of the query's model. Catalog models are fetched by fastembed; a custom
+//! model is an ONNX export with its tokenizer files in a local directory, or a GGUF
+//! embedding model run by llama.cpp (builds without fastembed have only that one and
+//! default to it). A GGUF model without a chosen file is the first `*.gguf` found
+//! in the models folder or among the bundled resources.
+//!
+//! Without network access a catalog model is taken from a local copy instead: a
+//! `<model id>/` directory in the configured models folder or among the bundled
+//! resources, holding the ONNX export, its tokenizer files and a `SHA256SUMS`
+//! manifest (`sha256sum` output) that every file is checked against before loading.
+
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// One model of the catalog
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelSpec {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub dim: usize,
+    /// Prepended to search queries and to indexed texts, as the model was trained
+    pub query_prefix: &'static str,
+    pub passage_prefix: &'static str,
+    pub multilingual: bool,
+    /// How token vectors are pooled into one, needed to run a local copy
+    pub pooling: Pooling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Pooling {
+    Mean,
+    Cls,
+}
+
+#[cfg(feature = "embeddings")]
+pub const DEFAULT_MODEL: &str = "multilingual-e5-small";
+/// Without fastembed only GGUF models can run
+#[cfg(not(feature = "embeddings"))]
+pub const DEFAULT_MODEL: &str = GGUF_MODEL;
+/// `EmbeddingSettings::model` value selecting the ONNX model in `onnx_path`
+pub const CUSTOM_MODEL: &str = "custom";
+/// `EmbeddingSettings::model` value selecting the GGUF model in `gguf_path`
+pub const GGUF_MODEL: &str = "gguf";
+
+/// Files of an ONNX model directory
+pub const MODEL_FILES: [&str; 5] = [
+    "model.onnx",
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+/// `sha256sum` manifest of a model directory
+pub const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+pub const CATALOG: &[ModelSpec] = &[
+    ModelSpec { id: "multilingual-e5-small", name: "Multilingual E5 Small", dim: 384, query_prefix: "query: ", passage_prefix: "passage: ", multilingual: true, pooling: Pooling::Mean },
+    ModelSpec { id: "multilingual-e5-base", name: "Multilingual E5 Base", dim: 768, query_prefix: "query: ", passage_prefix: "passage: ", multilingual: true, pooling: Pooling::Mean },
+    ModelSpec { id: "multilingual-e5-large", name: "Multilingual E5 Large", dim: 1024, query_prefix: "query: ", passage_prefix: "passage: ", multilingual: true, pooling: Pooling::Mean },
+    ModelSpec { id: "paraphrase-multilingual-minilm-l12-v2", name: "Paraphrase Multilingual MiniLM L12 v2", dim: 384, query_prefix: "", passage_prefix: "", multilingual: true, pooling: Pooling::Mean },
+    ModelSpec { id: "bge-small-en-v1.5", name: "BGE Small EN v1.5", dim: 384, query_prefix: "Represent this sentence for searching relevant passages: ", passage_prefix: "", multilingual: false, pooling: Pooling::Cls },
+    ModelSpec { id: "bge-base-en-v1.5", name: "BGE Base EN v1.5", dim: 768, query_prefix: "Represent this sentence for searching relevant passages: ", passage_prefix: "", multilingual: false, pooling: Pooling::Cls },
+    ModelSpec { id: "all-minilm-l6-v2", name: "all-MiniLM-L6-v2", dim: 384, query_prefix: "", passage_prefix: "", multilingual: false, pooling: Pooling::Mean },
+    ModelSpec { id: "nomic-embed-text-v1.5", name: "Nomic Embed Text v1.5", dim: 768, query_prefix: "search_query: ", passage_prefix: "search_document: ", multilingual: false, pooling: Pooling::Mean },
+];
+
+pub fn find(id: &str) -> Option<&'static ModelSpec> {
+    CATALOG.iter().find(|spec| spec.id == id)
+}
+
+/// Catalog model of the same family as a custom model file or directory, judged by
+/// its name, to borrow the query and passage prefixes the family was trained with
+fn family_of(path: &Path) -> Option<&'static ModelSpec> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    let id = if name.contains("nomic") {
+        "nomic-embed-text-v1.5"
+    } else if name.contains("e5") {
+        "multilingual-e5-small"
+    } else if name.contains("bge") {
+        "bge-small-en-v1.5"
+    } else {
+        return None;
+    };
+    find(id)
+}
+
+/// Which embedding model to use, kept in the settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EmbeddingSettings {
+    /// Catalog id or `CUSTOM_MODEL`
+    pub model: String,
+    /// Directory with `model.onnx`, `tokenizer.json`, `config.json`,
+    /// `special_tokens_map.json` and `tokenizer_config.json` for a custom model
+    pub onnx_path: Option<String>,
+    /// GGUF embedding model (nomic-embed, bge, e5...) for `GGUF_MODEL`; when empty,
+    /// the first one in `models_dir` or the bundled resources
+    pub gguf_path: Option<String>,
+    /// Folder with local copies of catalog models, checked before downloading
+    pub models_dir: Option<String>,
+}
+
+impl Default for EmbeddingSettings {
+    fn default() -> Self {
+        Self { model: DEFAULT_MODEL.to_string(), onnx_path: None, gguf_path: None, models_dir: None }
+    }
+}
+
+/// Where a model comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelSource {
+    Catalog(&'static ModelSpec),
+    Onnx(PathBuf),
+    Gguf(PathBuf),
+}
+
+/// A resolved choice: the id vectors are tagged with and where to load the model from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelChoice {
+    pub id: String,
+    pub source: ModelSource,
+}
+
+impl ModelChoice {
+    /// Query and passage prefixes; a custom model gets those of its family, if known
+    pub fn prefixes(&self) -> (&'static str, &'static str) {
+        let spec = match &self.source {
+            ModelSource::Catalog(spec) => Some(*spec),
+            ModelSource::Onnx(path) | ModelSource::Gguf(path) => family_of(path),
+        };
+        spec.map(|spec| (spec.query_prefix, spec.passage_prefix)).unwrap_or(("", ""))
+    }
+
+    /// Pooling of a catalog model or of a custom model's family, if known
+    pub fn pooling(&self) -> Option<Pooling> {
+        match &self.source {
+            ModelSource::Catalog(spec) => Some(spec.pooling),
+            ModelSource::Onnx(path) | ModelSource::Gguf(path) => family_of(path).map(|spec| spec.pooling),
+        }
+    }
+}
+
+fn local_path(path: Option<&str>, missing: &str) -> Result<PathBuf, String> {
+    let path = path.map(str::trim)
+        .filter(|p| !p.is_empty())
+        .ok_or_else(|| missing.to_string())?;
+    Ok(PathBuf::from(shellexpand::tilde(path).as_ref()))
+}
+
+impl EmbeddingSettings {
+    /// `bundled` is the folder of models shipped with the app (see `local_dirs`)
+    pub fn resolve(&self, bundled: Option<&Path>) -> Result<ModelChoice, String> {
+        if self.model == CUSTOM_MODEL {
+            let dir = local_path(self.onnx_path.as_deref(), "Не указана папка с ONNX-моделью")?;
+            return Ok(ModelChoice { id: format!("{}:{}", CUSTOM_MODEL, dir.display()), source: ModelSource::Onnx(dir) });
+        }
+        if self.model == GGUF_MODEL {
+            let missing = "Не указан файл GGUF-модели эмбеддингов, и в папке моделей нет файлов .gguf";
+            let file = local_path(self.gguf_path.as_deref(), missing)
+                .or_else(|missing| find_gguf(&self.local_dirs(bundled)).ok_or(missing))?;
+            return Ok(ModelChoice { id: format!("{}:{}", GGUF_MODEL, file.display()), source: ModelSource::Gguf(file) });
+        }
+        let spec = find(&self.model).ok_or_else(|| format!("Неизвестная модель эмбеддингов: {}", self.model))?;
+        Ok(ModelChoice { id: spec.id.to_string(), source: ModelSource::Catalog(spec) })
+    }
+
+    /// Folders searched for local copies of catalog models: the configured one, then
+    /// the bundled resources
+    pub fn local_dirs(&self, bundled: Option<&Path>) -> Vec<PathBuf> {
+        let configured = self.models_dir.as_deref()
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|p| PathBuf::from(shellexpand::tilde(p).as_ref()));
+        configured.into_iter().chain(bundled.map(Path::to_path_buf)).collect()
+    }
+}
+
+// ==================== Local copies ====================
+
+/// The first `<dir>/<model id>` holding a copy of `spec`
+pub fn find_local_copy(spec: &ModelSpec, dirs: &[PathBuf]) -> Option<PathBuf> {
+    dirs.iter()
+        .map(|dir| dir.join(spec.id))
+        .find(|dir| dir.join(MODEL_FILES[0]).is_file())
+}
+
+/// The first `*.gguf` file (by name) directly in one of `dirs`
+pub fn find_gguf(dirs: &[PathBuf]) -> Option<PathBuf> {
+    dirs.iter().find_map(|dir| {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir).ok()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gguf")))
+            .collect();
+        files.sort();
+        files.into_iter().next()
+    })
+}
+
+/// File name → lowercase hex SHA-256 from `sha256sum` output
+/// (`<hash>  <name>`, or `<hash> *<name>` for binary mode)
+pub fn parse_checksums(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut sums = HashMap::new();
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let (hash, name) = line.split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Некорректная строка в {}: {}", CHECKSUMS_FILE, line))?;
+        let name = name.trim_start().trim_start_matches('*');
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) || name.is_empty() {
+            return Err(format!("Некорректная строка в {}: {}", CHECKSUMS_FILE, line));
+        }
+        sums.insert(name.to_string(), hash.to_lowercase());
+    }
+    Ok(sums)
+}
+
+fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = std::fs::File::open(path)
+        .map_err(|e| format!("Не удалось прочитать {}: {}", path.display(), e))?;
+    let mut context = digest::Context::new(&digest::SHA256);
+    // Model files run to hundreds of megabytes
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)
+            .map_err(|e| format!("Не удалось прочитать {}: {}", path.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+    }
+    Ok(context.finish().as_ref().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Check the model files of `dir` against its `SHA256SUMS`. Without a manifest this
+/// fails when `required`, and passes otherwise.
+pub fn verify_checksums(dir: &Path, required: bool) -> Result<(), String> {
+    let manifest = dir.join(CHECKSUMS_FILE);
+    if !manifest.exists() && !required {
+        return Ok(());
+    }
+    let text = std::fs::read_to_string(&manifest)
+        .map_err(|e| format!("Не удалось прочитать {}: {}", manifest.display(), e))?;
+    let sums = parse_checksums(&text)?;
+
+    for name in MODEL_FILES {
+        let expected = sums.get(name)
+            .ok_or_else(|| format!("В {} нет контрольной суммы для {}", manifest.display(), name))?;
+        if sha256_file(&dir.join(name))? != *expected {
+            return Err(format!("Контрольная сумма {} не совпадает: файл повреждён или заменён", dir.join(name).display()));
+        }
+    }
+    Ok(())
+}
+
+// ==================== Embedder state ====================
+
+/// What the embedder is doing, reported by `get_embedder_state` and the
+/// `embedder-state` event
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum EmbedderState {
+    /// Nothing loaded yet
+    Idle,
+    Loading { model: String },
+    /// `path` is the local copy or file the model came from; None when fastembed
+    /// downloaded it
+    Ready { model: String, dim: usize, path: Option<String> },
+    Failed { model: String, reason: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_ids_are_unique() {
+        for (i, spec) in CATALOG.iter().enumerate() {
+            assert!(CATALOG[i + 1..].iter().all(|other| other.id != spec.id), "{} twice", spec.id);
+            assert!(!spec.id.contains(':'), "{} looks like a custom id", spec.id);
+        }
+        #[cfg(feature = "embeddings")]
+        assert!(find(DEFAULT_MODEL).is_some());
+    }
+
+    #[test]
+    fn test_resolve() {
+        #[cfg(feature = "embeddings")]
+        {
+            let default = EmbeddingSettings::default().resolve(None).unwrap();
+            assert_eq!(default.id, DEFAULT_MODEL);
+            assert_eq!(default.source, ModelSource::Catalog(find(DEFAULT_MODEL).unwrap()));
+        }
+
+        let custom = EmbeddingSettings { model: CUSTOM_MODEL.into(), onnx_path: Some("/models/e5".into()), ..Default::default() };
+        assert_eq!(custom.resolve(None).unwrap().id, "custom:/models/e5");
+        assert_eq!(custom.resolve(None).unwrap().source, ModelSource::Onnx(PathBuf::from("/models/e5")));
+
+        assert!(EmbeddingSettings { model: CUSTOM_MODEL.into(), onnx_path: Some(" ".into()), ..Default::default() }.resolve(None).is_err());
+        assert!(EmbeddingSettings { model: "word2vec".into(), ..Default::default() }.resolve(None).is_err());
+
+        let gguf = EmbeddingSettings { model: GGUF_MODEL.into(), gguf_path: Some("/models/bge.gguf".into()), ..Default::default() };
+        assert_eq!(gguf.resolve(None).unwrap().id, "gguf:/models/bge.gguf");
+        assert!(EmbeddingSettings { model: GGUF_MODEL.into(), ..Default::default() }.resolve(None).is_err());
+    }
+
+    #[test]
+    fn test_gguf_without_a_file_is_looked_up() {
+        let models = tempfile::tempdir().unwrap();
+        let bundled = tempfile::tempdir().unwrap();
+        let settings = EmbeddingSettings {
+            model: GGUF_MODEL.into(),
+            models_dir: Some(models.path().display().to_string()),
+            ..Default::default()
+        };
+        let missing = settings.resolve(Some(bundled.path())).unwrap_err();
+        assert!(missing.contains(".gguf"), "{}", missing);
+
+        std::fs::write(bundled.path().join("e5-small.gguf"), "").unwrap();
+        assert_eq!(settings.resolve(Some(bundled.path())).unwrap().source, ModelSource::Gguf(bundled.path().join("e5-small.gguf")));
+
+        // The models folder comes first, files in it by name
+        std::fs::write(models.path().join("readme.txt"), "").unwrap();
+        std::fs::write(models.path().join("nomic.gguf"), "").unwrap();
+        std::fs::write(models.path().join("bge.GGUF"), "").unwrap();
+        let found = settings.resolve(Some(bundled.path())).unwrap();
+        assert_eq!(found.id, format!("gguf:{}", models.path().join("bge.GGUF").display()));
+
+        let chosen = EmbeddingSettings { gguf_path: Some("/models/e5.gguf".into()), ..settings };
+        assert_eq!(chosen.resolve(Some(bundled.path())).unwrap().id, "gguf:/models/e5.gguf");
+    }
+
+    #[test]
+    fn test_parse_checksums() {
+        let hash = "a".repeat(64);
+        let text = format!("# manifest\n{}  model.onnx\n{} *tokenizer.json\n\n", hash, hash.to_uppercase());
+        let sums = parse_checksums(&text).unwrap();
+        assert_eq!(sums.len(), 2);
+        assert_eq!(sums["model.onnx"], hash);
+        assert_eq!(sums["tokenizer.json"], hash);
+
+        assert!(parse_checksums("abc  model.onnx").is_err());
+        assert!(parse_checksums(&hash).is_err());
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("multilingual-e5-small");
+        std::fs::create_dir(&dir).unwrap();
+        let mut manifest = String::new();
+        for name in MODEL_FILES {
+            std::fs::write(dir.join(name), name).unwrap();
+            manifest.push_str(&format!("{}  {}\n", sha256_file(&dir.join(name)).unwrap(), name));
+        }
+
+        // A copy without a manifest is only accepted where one isn't required
+        assert!(verify_checksums(&dir, false).is_ok());
+        assert!(verify_checksums(&dir, true).is_err());
+
+        std::fs::write(dir.join(CHECKSUMS_FILE), &manifest).unwrap();
+        assert!(verify_checksums(&dir, true).is_ok());
+        assert_eq!(find_local_copy(find("multilingual-e5-small").unwrap(), &[PathBuf::from("/nonexistent"), root.path().to_path_buf()]), Some(dir.clone()));
+        assert_eq!(find_local_copy(find("bge-small-en-v1.5").unwrap(), &[root.path().to_path_buf()]), None);
+
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+        assert!(verify_checksums(&dir, true).unwrap_err().contains("config.json"));
+
+        let without_tokenizer: String = manifest.lines().filter(|l| !l.ends_with(" tokenizer.json")).map(|l| format!("{}\n", l)).collect();
+        std::fs::write(dir.join(CHECKSUMS_FILE), without_tokenizer).unwrap();
+        assert!(verify_checksums(&dir, true).unwrap_err().contains("tokenizer.json"));
+    }
+
+    #[test]
+    fn test_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("abc"), "abc").unwrap();
+        assert_eq!(
+            sha256_file(&dir.path().join("abc")).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let choice = |source| ModelChoice { id: String::new(), source };
+        assert_eq!(choice(ModelSource::Catalog(find("multilingual-e5-base").unwrap())).prefixes(), ("query: ", "passage: "));
+        assert_eq!(choice(ModelSource::Gguf("/m/nomic-embed-text-v1.5.Q8_0.gguf".into())).prefixes(), ("search_query: ", "search_document: "));
+        assert_eq!(choice(ModelSource::Gguf("/m/multilingual-e5-small-q8_0.gguf".into())).prefixes(), ("query: ", "passage: "));
+        assert_eq!(choice(ModelSource::Onnx("/m/bge-small-en-v1.5".into())).prefixes().1, "");
+        assert_eq!(choice(ModelSource::Gguf("/m/gte-small.gguf".into())).prefixes(), ("", ""));
+    }
+}
@@ -1,12 +1,42 @@
-use fastembed::{TextEmbedding, InitOptions, EmbeddingModel};
-use once_cell::sync::OnceCell;
+//! Embedding vectors of messages and memories, and semantic search over them.
+//!
+//! Vectors come from one of two backends behind the same `embed_query` /
+//! `embed_passage` API: fastembed (ONNX models, `embeddings` feature) or llama.cpp
+//! with a GGUF embedding model (`native-llm` feature), so builds where fastembed
+//! doesn't run still get semantic search.
+
+#[cfg(feature = "embeddings")]
+use fastembed::{
+    EmbeddingModel, InitOptions, InitOptionsUserDefined, Pooling as FastembedPooling, TextEmbedding, TokenizerFiles,
+    UserDefinedEmbeddingModel,
+};
+use once_cell::sync::Lazy;
 use rusqlite::{params, Connection, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
-static EMBEDDER: OnceCell<Mutex<TextEmbedding>> = OnceCell::new();
-
-const EMBEDDING_DIM: usize = 384; // multilingual-e5-small dimension (used in stats)
+use crate::database;
+use crate::embedding_models::{self, EmbedderState, EmbeddingSettings, ModelChoice, ModelSource, ModelSpec, Pooling};
+#[cfg(feature = "native-llm")]
+use crate::llm;
+use crate::quantization;
+use crate::vector_index;
+
+/// The loaded model; replaced when the settings choose another one
+static EMBEDDER: Lazy<Mutex<Option<Embedder>>> = Lazy::new(|| Mutex::new(None));
+/// Held while a model loads, so two settings changes don't load side by side
+static LOADING: Mutex<()> = Mutex::new(());
+/// Held by a running `reindex`
+static REINDEXING: Mutex<()> = Mutex::new(());
+/// Reported by `state`; a failed switch leaves the previous model loaded
+static STATE: Lazy<Mutex<EmbedderState>> = Lazy::new(|| Mutex::new(EmbedderState::Idle));
+
+/// A sign-bit scan keeps this many candidates per requested result for re-scoring
+const RESCORE_FACTOR: usize = 20;
+/// ...and at least this many
+const MIN_RESCORE: usize = 200;
 
 // ==================== Types ====================
 
@@ -17,6 +47,8 @@ pub struct EmbeddingEntry {
     pub source_type: String,      // "message", "memory", "document"
     pub source_id: i64,
     pub content_hash: String,
+    pub model: String,
+    pub dim: usize,
     pub created_at: i64,
 }
 
@@ -29,25 +61,244 @@ pub struct SearchResult {
     pub similarity: f32,
 }
 
-// ==================== Initialization ====================
+#[cfg(not(any(feature = "embeddings", feature = "native-llm")))]
+compile_error!("semantic-search needs an embedding backend: enable the `embeddings` or `native-llm` feature");
 
-/// Initialize the embedding model
-pub fn init_embedder() -> Result<(), String> {
-    if EMBEDDER.get().is_some() {
-        return Ok(());
+enum Backend {
+    #[cfg(feature = "embeddings")]
+    Fastembed(TextEmbedding),
+    #[cfg(feature = "native-llm")]
+    Llama(llm::LlamaEmbedder),
+}
+
+impl Backend {
+    fn embed(&mut self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, String> {
+        match self {
+            #[cfg(feature = "embeddings")]
+            Backend::Fastembed(model) => model.embed(texts, None)
+                .map_err(|e| format!("Embedding failed: {}", e)),
+            #[cfg(feature = "native-llm")]
+            Backend::Llama(model) => model.embed(&texts),
+        }
     }
+}
 
-    println!("Loading embedding model (multilingual-e5-small)...");
-    
+struct Embedder {
+    backend: Backend,
+    id: String,
+    dim: usize,
+    /// Local file or directory the model came from; None when fastembed downloaded it
+    path: Option<String>,
+    query_prefix: String,
+    passage_prefix: String,
+}
+
+/// The model vectors are produced with right now
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveModel {
+    pub id: String,
+    pub dim: usize,
+}
+
+/// A vector and the model that produced it
+#[derive(Debug, Clone)]
+pub struct Embedding {
+    pub model: String,
+    pub vector: Vec<f32>,
+}
+
+// ==================== Initialization ====================
+
+#[cfg(feature = "embeddings")]
+fn fastembed_model(spec: &ModelSpec) -> Option<EmbeddingModel> {
+    Some(match spec.id {
+        "multilingual-e5-small" => EmbeddingModel::MultilingualE5Small,
+        "multilingual-e5-base" => EmbeddingModel::MultilingualE5Base,
+        "multilingual-e5-large" => EmbeddingModel::MultilingualE5Large,
+        "paraphrase-multilingual-minilm-l12-v2" => EmbeddingModel::ParaphraseMLMiniLML12V2,
+        "bge-small-en-v1.5" => EmbeddingModel::BGESmallENV15,
+        "bge-base-en-v1.5" => EmbeddingModel::BGEBaseENV15,
+        "all-minilm-l6-v2" => EmbeddingModel::AllMiniLML6V2,
+        "nomic-embed-text-v1.5" => EmbeddingModel::NomicEmbedTextV15,
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "embeddings")]
+fn load_catalog(spec: &ModelSpec) -> Result<(Backend, usize), String> {
+    let model = fastembed_model(spec).ok_or_else(|| format!("Модель {} не поддерживается", spec.id))?;
     let model = TextEmbedding::try_new(
-        InitOptions::new(EmbeddingModel::MultilingualE5Small)
+        InitOptions::new(model)
             .with_show_download_progress(true)
     ).map_err(|e| format!("Failed to load embedding model: {}", e))?;
+    Ok((Backend::Fastembed(model), spec.dim))
+}
+
+/// An ONNX export and its tokenizer files from `dir`; fastembed's default pooling
+/// when `pooling` is unknown
+#[cfg(feature = "embeddings")]
+fn load_onnx(dir: &Path, pooling: Option<Pooling>) -> Result<(Backend, usize), String> {
+    let read = |name: &str| {
+        std::fs::read(dir.join(name)).map_err(|e| format!("Не удалось прочитать {}: {}", dir.join(name).display(), e))
+    };
+    let [onnx, tokenizer, config, special_tokens_map, tokenizer_config] = embedding_models::MODEL_FILES;
+    let mut model = UserDefinedEmbeddingModel::new(read(onnx)?, TokenizerFiles {
+        tokenizer_file: read(tokenizer)?,
+        config_file: read(config)?,
+        special_tokens_map_file: read(special_tokens_map)?,
+        tokenizer_config_file: read(tokenizer_config)?,
+    });
+    if let Some(pooling) = pooling {
+        model = model.with_pooling(match pooling {
+            Pooling::Mean => FastembedPooling::Mean,
+            Pooling::Cls => FastembedPooling::Cls,
+        });
+    }
+    let model = TextEmbedding::try_new_from_user_defined(model, InitOptionsUserDefined::new())
+        .map_err(|e| format!("Failed to load embedding model: {}", e))?;
+
+    let mut backend = Backend::Fastembed(model);
+    // The dimension of a custom model is only known from its output
+    let dim = backend.embed(vec!["dimension probe".to_string()])?
+        .first()
+        .map(Vec::len)
+        .filter(|&dim| dim > 0)
+        .ok_or("No embedding returned")?;
+    Ok((backend, dim))
+}
+
+#[cfg(not(feature = "embeddings"))]
+const NO_FASTEMBED: &str = "Эта сборка без fastembed: выберите GGUF-модель эмбеддингов";
+
+#[cfg(not(feature = "embeddings"))]
+fn load_catalog(_spec: &ModelSpec) -> Result<(Backend, usize), String> {
+    Err(NO_FASTEMBED.to_string())
+}
+
+#[cfg(not(feature = "embeddings"))]
+fn load_onnx(_dir: &Path, _pooling: Option<Pooling>) -> Result<(Backend, usize), String> {
+    Err(NO_FASTEMBED.to_string())
+}
+
+#[cfg(feature = "native-llm")]
+fn load_gguf(path: &Path) -> Result<(Backend, usize), String> {
+    let model = llm::load_embedding_model(path)?;
+    let dim = model.dim();
+    Ok((Backend::Llama(model), dim))
+}
+
+#[cfg(not(feature = "native-llm"))]
+fn load_gguf(_path: &Path) -> Result<(Backend, usize), String> {
+    Err("GGUF-модели эмбеддингов доступны только в сборке с llama.cpp (native-llm)".to_string())
+}
+
+/// The embedder of `choice`. A catalog model is taken from a verified local copy in
+/// `local_dirs` when there is one, and downloaded by fastembed otherwise.
+fn load(choice: &ModelChoice, local_dirs: &[PathBuf]) -> Result<Embedder, String> {
+    let ((backend, dim), path) = match &choice.source {
+        ModelSource::Catalog(spec) => match embedding_models::find_local_copy(spec, local_dirs) {
+            Some(dir) => {
+                embedding_models::verify_checksums(&dir, true)?;
+                let (backend, dim) = load_onnx(&dir, Some(spec.pooling))?;
+                if dim != spec.dim {
+                    return Err(format!("{}: размерность {} вместо {} — это другая модель", dir.display(), dim, spec.dim));
+                }
+                ((backend, dim), Some(dir))
+            }
+            None => (load_catalog(spec)?, None),
+        },
+        ModelSource::Onnx(dir) => {
+            embedding_models::verify_checksums(dir, false)?;
+            (load_onnx(dir, choice.pooling())?, Some(dir.clone()))
+        }
+        ModelSource::Gguf(path) => (load_gguf(path)?, Some(path.clone())),
+    };
+    let (query_prefix, passage_prefix) = choice.prefixes();
+    let embedder = Embedder {
+        backend,
+        id: choice.id.clone(),
+        dim,
+        path: path.map(|p| p.display().to_string()),
+        query_prefix: query_prefix.to_string(),
+        passage_prefix: passage_prefix.to_string(),
+    };
+    Ok(embedder)
+}
 
-    EMBEDDER.set(Mutex::new(model))
-        .map_err(|_| "Embedder already initialized".to_string())?;
+/// Record the state, then announce it, so that a listener asking `state()` in
+/// response already gets the new one
+fn set_state(state: EmbedderState, on_state: &impl Fn(&EmbedderState)) {
+    if let Ok(mut current) = STATE.lock() {
+        *current = state.clone();
+    }
+    on_state(&state);
+}
+
+/// Load the model chosen in `settings` unless it is the one already loaded, looking
+/// for local copies in the configured folder and in `bundled`. `on_state` gets every
+/// change of the embedder state. Returns whether a model was loaded.
+pub fn init_embedder(
+    settings: &EmbeddingSettings,
+    bundled: Option<&Path>,
+    on_state: impl Fn(&EmbedderState),
+) -> Result<bool, String> {
+    let choice = settings.resolve(bundled).inspect_err(|reason| {
+        set_state(EmbedderState::Failed { model: settings.model.clone(), reason: reason.clone() }, &on_state);
+    })?;
+    let _loading = LOADING.lock().map_err(|e| format!("Failed to lock embedder: {}", e))?;
+    let loaded = EMBEDDER.lock().map_err(|e| format!("Failed to lock embedder: {}", e))?
+        .as_ref()
+        .filter(|embedder| embedder.id == choice.id)
+        .map(|embedder| EmbedderState::Ready { model: embedder.id.clone(), dim: embedder.dim, path: embedder.path.clone() });
+    if let Some(ready) = loaded {
+        // The state may still report a failed switch to another model
+        set_state(ready, &on_state);
+        return Ok(false);
+    }
+
+    println!("Loading embedding model ({})...", choice.id);
+    set_state(EmbedderState::Loading { model: choice.id.clone() }, &on_state);
+    let embedder = match load(&choice, &settings.local_dirs(bundled)) {
+        Ok(loaded) => loaded,
+        Err(reason) => {
+            set_state(EmbedderState::Failed { model: choice.id.clone(), reason: reason.clone() }, &on_state);
+            return Err(reason);
+        }
+    };
+    let ready = EmbedderState::Ready { model: choice.id, dim: embedder.dim, path: embedder.path.clone() };
+    *EMBEDDER.lock().map_err(|e| format!("Failed to lock embedder: {}", e))? = Some(embedder);
     
     println!("Embedding model loaded successfully");
+    set_state(ready, &on_state);
+    Ok(true)
+}
+
+/// What the embedder is doing (see `EmbedderState`)
+pub fn state() -> EmbedderState {
+    STATE.lock().map(|state| state.clone()).unwrap_or(EmbedderState::Idle)
+}
+
+pub fn active_model() -> Option<ActiveModel> {
+    let embedder = EMBEDDER.lock().ok()?;
+    embedder.as_ref().map(|e| ActiveModel { id: e.id.clone(), dim: e.dim })
+}
+
+/// The loaded model, or the one the settings choose while nothing is loaded
+pub fn current_model_id() -> String {
+    active_model().map(|model| model.id).unwrap_or_else(|| {
+        database::get_settings().ok()
+            .and_then(|settings| settings.embedding.resolve(None).ok())
+            .map(|choice| choice.id)
+            .unwrap_or_else(|| embedding_models::DEFAULT_MODEL.to_string())
+    })
+}
+
+/// Load the nearest-neighbour index of the loaded model (see `vector_index`)
+pub fn init_index(app_dir: &Path) -> Result<(), String> {
+    let model = active_model().ok_or("Embedder not initialized")?;
+    let count = vector_index::load(app_dir, &model.id, model.dim)?;
+    println!("Vector index ready ({} vectors)", count);
     Ok(())
 }
 
@@ -55,155 +306,189 @@ pub fn init_embedder() -> Result<(), String> {
 
 // ==================== Embedding Operations ====================
 
-/// Generate embedding for search query (use "query:" prefix)
-pub fn embed_query(text: &str) -> Result<Vec<f32>, String> {
-    let mut embedder = EMBEDDER.get()
-        .ok_or("Embedder not initialized")?
-        .lock()
+fn embed(texts: &[String], query: bool) -> Result<(String, Vec<Vec<f32>>), String> {
+    let mut embedder = EMBEDDER.lock()
         .map_err(|e| format!("Failed to lock embedder: {}", e))?;
+    let embedder = embedder.as_mut().ok_or("Embedder not initialized")?;
 
-    // E5 model: use "query:" prefix for search queries
-    let prefixed = format!("query: {}", text);
+    let prefix = if query { &embedder.query_prefix } else { &embedder.passage_prefix };
+    let prefixed: Vec<String> = texts.iter()
+        .map(|t| format!("{}{}", prefix, t))
+        .collect();
     
-    let embeddings = embedder.embed(vec![prefixed], None)
-        .map_err(|e| format!("Embedding failed: {}", e))?;
-
-    embeddings.into_iter().next()
-        .ok_or("No embedding returned".to_string())
+    let vectors = embedder.backend.embed(prefixed)?;
+    Ok((embedder.id.clone(), vectors))
 }
 
-/// Generate embedding for document/passage (use "passage:" prefix)
-pub fn embed_passage(text: &str) -> Result<Vec<f32>, String> {
-    let mut embedder = EMBEDDER.get()
-        .ok_or("Embedder not initialized")?
-        .lock()
-        .map_err(|e| format!("Failed to lock embedder: {}", e))?;
+fn embed_one(text: &str, query: bool) -> Result<Embedding, String> {
+    let (model, vectors) = embed(&[text.to_string()], query)?;
+    let vector = vectors.into_iter().next()
+        .ok_or("No embedding returned".to_string())?;
+    Ok(Embedding { model, vector })
+}
 
-    // E5 model: use "passage:" prefix for documents being indexed
-    let prefixed = format!("passage: {}", text);
-    
-    let embeddings = embedder.embed(vec![prefixed], None)
-        .map_err(|e| format!("Embedding failed: {}", e))?;
+/// Generate embedding for search query (with the model's query prefix, e.g. "query:")
+pub fn embed_query(text: &str) -> Result<Embedding, String> {
+    embed_one(text, true)
+}
 
-    embeddings.into_iter().next()
-        .ok_or("No embedding returned".to_string())
+/// Generate embedding for document/passage (with the model's passage prefix, e.g. "passage:")
+pub fn embed_passage(text: &str) -> Result<Embedding, String> {
+    embed_one(text, false)
 }
 
 /// Generate embeddings for multiple passages (batch indexing)
 #[allow(dead_code)]
 pub fn embed_passages_batch(texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
-    let mut embedder = EMBEDDER.get()
-        .ok_or("Embedder not initialized")?
-        .lock()
-        .map_err(|e| format!("Failed to lock embedder: {}", e))?;
-
-    // E5 model: use "passage:" prefix for batch indexing
-    let prefixed: Vec<String> = texts.iter()
-        .map(|t| format!("passage: {}", t))
-        .collect();
-    
-    embedder.embed(prefixed, None)
-        .map_err(|e| format!("Batch embedding failed: {}", e))
+    embed(texts, false).map(|(_, vectors)| vectors)
+        .map_err(|e| e.replace("Embedding failed", "Batch embedding failed"))
 }
 
 // ==================== Database Operations ====================
 
-/// Store embedding in database
+/// Store embedding in database, tagged with its model and dimension
 pub fn store_embedding(
     conn: &Connection,
     source_type: &str,
     source_id: i64,
     content: &str,
-    vector: &[f32],
+    embedding: &Embedding,
 ) -> Result<i64> {
     let content_hash = format!("{:x}", md5_hash(content));
-    let vector_bytes = floats_to_bytes(vector);
+    let quantized = quantization::quantize(&embedding.vector);
     let now = get_timestamp();
 
     conn.execute(
         r#"INSERT OR REPLACE INTO embeddings 
-           (source_type, source_id, content_hash, vector, created_at)
-           VALUES (?1, ?2, ?3, ?4, ?5)"#,
-        params![source_type, source_id, content_hash, vector_bytes, now],
+           (source_type, source_id, content_hash, vector, scale, bits, half, model, dim, created_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"#,
+        params![
+            source_type, source_id, content_hash, quantized.codes, quantized.scale, quantized.bits,
+            quantized.half, embedding.model, embedding.vector.len() as i64, now
+        ],
     )?;
+    let id = conn.last_insert_rowid();
+    vector_index::insert(id, now, source_type, source_id, &embedding.model, &embedding.vector);
 
-    Ok(conn.last_insert_rowid())
+    Ok(id)
 }
 
-/// Check if content already has embedding (by hash)
-pub fn has_embedding(conn: &Connection, source_type: &str, source_id: i64, content: &str) -> Result<bool> {
+/// Check if content already has an embedding of `model` (by hash)
+pub fn has_embedding(conn: &Connection, source_type: &str, source_id: i64, content: &str, model: &str) -> Result<bool> {
     let content_hash = format!("{:x}", md5_hash(content));
     
     let exists: bool = conn.query_row(
-        "SELECT COUNT(*) > 0 FROM embeddings WHERE source_type = ?1 AND source_id = ?2 AND content_hash = ?3",
-        params![source_type, source_id, content_hash],
+        "SELECT COUNT(*) > 0 FROM embeddings WHERE source_type = ?1 AND source_id = ?2 AND content_hash = ?3 AND model = ?4",
+        params![source_type, source_id, content_hash, model],
         |row| row.get(0),
     ).unwrap_or(false);
 
     Ok(exists)
 }
 
-/// Semantic search - find similar content
+/// Semantic search - find similar content. Only vectors of the query's model are
+/// compared; those of other models are in a different space.
 pub fn semantic_search(
     conn: &Connection,
-    query_vector: &[f32],
+    query: &Embedding,
     source_type: Option<&str>,
     limit: i32,
     min_similarity: f32,
 ) -> Result<Vec<(i64, String, i64, f32)>> {
-    // Get all embeddings (for small datasets this is fine, for large use approximate NN)
-    let mut stmt = if let Some(_st) = source_type {
-        conn.prepare(
-            "SELECT id, source_type, source_id, vector FROM embeddings WHERE source_type = ?1"
-        )?
-    } else {
-        conn.prepare(
-            "SELECT id, source_type, source_id, vector FROM embeddings"
-        )?
-    };
+    let wanted = limit.max(0) as usize;
+    // A rare `source_type` can be crowded out of the graph's candidates; scan then
+    if let Some(candidates) = vector_index::search(&query.model, &query.vector, wanted * 2, source_type)
+        .filter(|candidates| candidates.len() >= wanted)
+    {
+        let ids: Vec<i64> = candidates.into_iter().map(|(id, _)| id).collect();
+        return rescore(conn, query, &ids, limit, min_similarity);
+    }
 
-    let rows: Vec<(i64, String, i64, Vec<u8>)> = if let Some(st) = source_type {
-        stmt.query_map(params![st], |row| {
-            Ok((
-                row.get(0)?,
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-            ))
-        })?
-        .filter_map(|r| r.ok())
-        .collect()
+    // Small store, index still loading or too few candidates: scan the sign bits of
+    // every embedding and re-score the closest ones
+    let query_bits = quantization::sign_bits(&query.vector);
+    let mut stmt = if source_type.is_some() {
+        conn.prepare("SELECT id, bits FROM embeddings WHERE model = ?1 AND source_type = ?2")?
     } else {
-        stmt.query_map([], |row| {
-            Ok((
-                row.get(0)?,
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-            ))
-        })?
-        .filter_map(|r| r.ok())
-        .collect()
+        conn.prepare("SELECT id, bits FROM embeddings WHERE model = ?1")?
+    };
+    let map_row = |row: &rusqlite::Row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?));
+    let rows: Vec<(i64, Vec<u8>)> = match source_type {
+        Some(st) => stmt.query_map(params![query.model, st], map_row)?.filter_map(|r| r.ok()).collect(),
+        None => stmt.query_map(params![query.model], map_row)?.filter_map(|r| r.ok()).collect(),
     };
 
-    // Calculate similarities
-    let mut results: Vec<(i64, String, i64, f32)> = rows
-        .into_iter()
-        .map(|(id, st, sid, vec_bytes)| {
-            let stored_vector = bytes_to_floats(&vec_bytes);
-            let similarity = cosine_similarity(query_vector, &stored_vector);
-            (id, st, sid, similarity)
-        })
-        .filter(|(_, _, _, sim)| *sim >= min_similarity)
+    let mut scored: Vec<(u32, i64)> = rows.into_iter()
+        .map(|(id, bits)| (quantization::hamming(&query_bits, &bits), id))
         .collect();
+    let shortlist = (wanted * RESCORE_FACTOR).max(MIN_RESCORE);
+    if scored.len() > shortlist {
+        scored.select_nth_unstable(shortlist);
+        scored.truncate(shortlist);
+    }
+
+    let ids: Vec<i64> = scored.into_iter().map(|(_, id)| id).collect();
+    rescore(conn, query, &ids, limit, min_similarity)
+}
 
+/// Similarity of candidate rows against their half float vectors, best first.
+/// Rows deleted since the index saw them are left out and dropped from it, and so
+/// are rows re-embedded by another model meanwhile.
+fn rescore(
+    conn: &Connection,
+    query: &Embedding,
+    ids: &[i64],
+    limit: i32,
+    min_similarity: f32,
+) -> Result<Vec<(i64, String, i64, f32)>> {
+    let mut stmt = conn.prepare("SELECT source_type, source_id, half, model FROM embeddings WHERE id = ?1")?;
+    let mut results = Vec::new();
+    let mut gone = Vec::new();
+    
+    for &id in ids {
+        let row = stmt.query_row(params![id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, Vec<u8>>(2)?, row.get::<_, String>(3)?))
+        });
+        match row {
+            Ok((_, _, _, model)) if model != query.model => gone.push(id),
+            Ok((st, sid, half, _)) => {
+                let similarity = cosine_similarity(&query.vector, &quantization::from_half(&half));
+                if similarity >= min_similarity {
+                    results.push((id, st, sid, similarity));
+                }
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => gone.push(id),
+            Err(e) => return Err(e),
+        }
+    }
+    vector_index::remove(&gone);
+    
     // Sort by similarity (descending)
     results.sort_by(|a, b| b.3.partial_cmp(&a.3).unwrap_or(std::cmp::Ordering::Equal));
-    results.truncate(limit as usize);
-
+    results.truncate(limit.max(0) as usize);
     Ok(results)
 }
 
+/// Stored vectors of `model` for the given sources; sources without one are left out
+pub fn get_vectors(conn: &Connection, model: &str, source_type: &str, source_ids: &[i64]) -> Result<HashMap<i64, Vec<f32>>> {
+    let mut stmt = conn.prepare(
+        "SELECT half FROM embeddings WHERE source_type = ?1 AND source_id = ?2 AND model = ?3"
+    )?;
+    let mut vectors = HashMap::new();
+    
+    for &source_id in source_ids {
+        match stmt.query_row(params![source_type, source_id, model], |row| row.get::<_, Vec<u8>>(0)) {
+            Ok(half) => {
+                vectors.insert(source_id, quantization::from_half(&half));
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    
+    Ok(vectors)
+}
+
 /// Delete embedding
 #[allow(dead_code)] // Utility for future use
 pub fn delete_embedding(conn: &Connection, source_type: &str, source_id: i64) -> Result<()> {
@@ -211,15 +496,16 @@ pub fn delete_embedding(conn: &Connection, source_type: &str, source_id: i64) ->
         "DELETE FROM embeddings WHERE source_type = ?1 AND source_id = ?2",
         params![source_type, source_id],
     )?;
+    vector_index::remove_source(source_type, source_id);
     Ok(())
 }
 
 /// Get embedding stats
 pub fn get_embedding_stats(conn: &Connection) -> Result<serde_json::Value> {
-    let total: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM embeddings",
+    let (total, storage_bytes): (i64, i64) = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(LENGTH(vector) + LENGTH(bits) + LENGTH(half)), 0) FROM embeddings",
         [],
-        |row| row.get(0),
+        |row| Ok((row.get(0)?, row.get(1)?)),
     )?;
 
     let by_type: Vec<(String, i64)> = {
@@ -231,39 +517,33 @@ pub fn get_embedding_stats(conn: &Connection) -> Result<serde_json::Value> {
             .collect();
         rows
     };
+    // Vectors of earlier models stay until the re-index replaces them
+    let by_model: Vec<(String, i64)> = {
+        let mut stmt = conn.prepare(
+            "SELECT model, COUNT(*) FROM embeddings GROUP BY model"
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .filter_map(|r| r.ok())
+            .collect();
+        rows
+    };
+    let model = active_model();
 
     Ok(serde_json::json!({
         "totalEmbeddings": total,
         "byType": by_type.into_iter().collect::<std::collections::HashMap<_, _>>(),
-        "embeddingDimension": EMBEDDING_DIM,
-        "model": "multilingual-e5-small"
+        "byModel": by_model.into_iter().collect::<std::collections::HashMap<_, _>>(),
+        "embeddingDimension": model.as_ref().map(|m| m.dim),
+        "indexedVectors": vector_index::len(),
+        "storageBytes": storage_bytes,
+        "model": model.map(|m| m.id)
     }))
 }
 
 // ==================== Utility Functions ====================
 
-/// Convert float array to bytes for SQLite BLOB
-fn floats_to_bytes(floats: &[f32]) -> Vec<u8> {
-    floats.iter()
-        .flat_map(|f| f.to_le_bytes())
-        .collect()
-}
-
-/// Convert bytes back to float array
-/// 
-/// Safety: chunks_exact(4) guarantees exactly 4 bytes per chunk,
-/// so the conversion to [u8; 4] will always succeed.
-fn bytes_to_floats(bytes: &[u8]) -> Vec<f32> {
-    bytes.chunks_exact(4)
-        .filter_map(|chunk| {
-            // chunks_exact(4) guarantees 4 bytes, but we're defensive
-            chunk.try_into().ok().map(|arr: [u8; 4]| f32::from_le_bytes(arr))
-        })
-        .collect()
-}
-
 /// Cosine similarity between two vectors
-fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     if a.len() != b.len() || a.is_empty() {
         return 0.0;
     }
@@ -299,34 +579,81 @@ fn get_timestamp() -> i64 {
 
 // ==================== High-Level API ====================
 
-/// Index a message for semantic search
-pub fn index_message(conn: &Connection, message_id: i64, content: &str) -> Result<(), String> {
+/// Embed and store one source unless it already has a vector of the loaded model
+fn index_source(conn: &Connection, source_type: &str, source_id: i64, content: &str) -> Result<(), String> {
+    let model = active_model().ok_or("Embedder not initialized")?;
     // Skip if already indexed with same content
-    if has_embedding(conn, "message", message_id, content).unwrap_or(false) {
+    if has_embedding(conn, source_type, source_id, content, &model.id).unwrap_or(false) {
         return Ok(());
     }
 
     // Use embed_passage for documents being indexed
-    let vector = embed_passage(content)?;
-    store_embedding(conn, "message", message_id, content, &vector)
+    let embedding = embed_passage(content)?;
+    store_embedding(conn, source_type, source_id, content, &embedding)
         .map_err(|e| format!("Failed to store embedding: {}", e))?;
 
     Ok(())
 }
 
+/// Index a message for semantic search
+pub fn index_message(conn: &Connection, message_id: i64, content: &str) -> Result<(), String> {
+    index_source(conn, "message", message_id, content)
+}
+
 /// Index a memory entry for semantic search
 #[allow(dead_code)] // Will be used when memory auto-indexing is implemented
 pub fn index_memory(conn: &Connection, memory_id: i64, content: &str) -> Result<(), String> {
-    if has_embedding(conn, "memory", memory_id, content).unwrap_or(false) {
-        return Ok(());
+    index_source(conn, "memory", memory_id, content)
+}
+
+/// Messages and memories whose vector comes from another model than `model`
+fn pending_sources(conn: &Connection, model: &str) -> Result<Vec<(&'static str, i64, String)>> {
+    let mut pending = Vec::new();
+    for (source_type, table) in [("message", "messages"), ("memory", "memory")] {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT t.id, t.content FROM {} t
+             JOIN embeddings e ON e.source_type = ?1 AND e.source_id = t.id
+             WHERE e.model != ?2
+             ORDER BY t.id DESC",
+            table
+        ))?;
+        let rows = stmt.query_map(params![source_type, model], |row| Ok((source_type, row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            pending.push(row?);
+        }
     }
+    Ok(pending)
+}
 
-    // Use embed_passage for documents being indexed
-    let vector = embed_passage(content)?;
-    store_embedding(conn, "memory", memory_id, content, &vector)
-        .map_err(|e| format!("Failed to store embedding: {}", e))?;
+/// Re-embed everything indexed by another model than the loaded one, newest first.
+/// `progress` gets (done, total) after each entry. Stops early when another model
+/// is loaded meanwhile: that switch starts its own re-index.
+pub fn reindex(progress: impl Fn(usize, usize)) -> Result<usize, String> {
+    // One re-index at a time; a second one then finds little left to do
+    let _running = REINDEXING.lock().map_err(|e| format!("Failed to lock re-index: {}", e))?;
+    let model = active_model().ok_or("Embedder not initialized")?;
+    let pending = database::with_connection(|conn| pending_sources(conn, &model.id))
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let total = pending.len();
+    let mut indexed = 0;
+    for (done, (source_type, source_id, content)) in pending.into_iter().enumerate() {
+        // Embedding is the slow part, so the connection is only taken to store
+        let embedding = embed_passage(&content)?;
+        if embedding.model != model.id {
+            println!("Embedding model changed, re-index of {} stopped", model.id);
+            break;
+        }
+        match database::with_connection(|conn| store_embedding(conn, source_type, source_id, &content, &embedding)) {
+            Ok(Ok(_)) => indexed += 1,
+            Ok(Err(e)) => eprintln!("Failed to store embedding of {} {}: {}", source_type, source_id, e),
+            Err(e) => eprintln!("Database error indexing {} {}: {}", source_type, source_id, e),
+        }
+        progress(done + 1, total);
+    }
 
-    Ok(())
+    Ok(indexed)
 }
 
 /// Find similar messages using semantic search
@@ -337,9 +664,9 @@ pub fn find_similar_messages(
     limit: i32,
 ) -> Result<Vec<(i64, f32)>, String> {
     // Use embed_query for search queries
-    let query_vector = embed_query(query)?;
+    let query_embedding = embed_query(query)?;
     
-    let results = semantic_search(conn, &query_vector, Some("message"), limit, 0.5)
+    let results = semantic_search(conn, &query_embedding, Some("message"), limit, 0.5)
         .map_err(|e| format!("Search failed: {}", e))?;
 
     Ok(results.into_iter().map(|(_, _, source_id, sim)| (source_id, sim)).collect())
@@ -352,10 +679,10 @@ pub fn find_rag_context(
     limit: i32,
 ) -> Result<Vec<SearchResult>, String> {
     // Use embed_query for search queries
-    let query_vector = embed_query(query)?;
+    let query_embedding = embed_query(query)?;
     
     // Search both messages and memories
-    let results = semantic_search(conn, &query_vector, None, limit, 0.4)
+    let results = semantic_search(conn, &query_embedding, None, limit, 0.4)
         .map_err(|e| format!("Search failed: {}", e))?;
 
     // Fetch actual content for each result
@@ -364,8 +691,9 @@ pub fn find_rag_context(
     for (_, source_type, source_id, similarity) in results {
         let content = match source_type.as_str() {
             "message" => {
+                // Messages from abandoned branches are not used as context
                 conn.query_row(
-                    "SELECT content FROM messages WHERE id = ?1",
+                    "SELECT content FROM messages WHERE id = ?1 AND is_active = 1",
                     params![source_id],
                     |row| row.get::<_, String>(0),
                 ).ok()
@@ -399,18 +727,6 @@ pub fn find_rag_context(
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_floats_to_bytes_roundtrip() {
-        let original = vec![1.0f32, 2.5, -3.14, 0.0];
-        let bytes = floats_to_bytes(&original);
-        let recovered = bytes_to_floats(&bytes);
-        
-        assert_eq!(original.len(), recovered.len());
-        for (a, b) in original.iter().zip(recovered.iter()) {
-            assert!((a - b).abs() < 1e-6);
-        }
-    }
-
     #[test]
     fn test_cosine_similarity_identical() {
         let a = vec![1.0, 2.0, 3.0];
@@ -435,6 +751,72 @@ mod tests {
         assert!((sim + 1.0).abs() < 1e-6);
     }
 
+    #[test]
+    fn test_rescore_uses_half_floats() {
+        let _db = database::test_database();
+        // The small components are below the int8 step of a vector with one large one
+        let mut stored = vec![0.003f32; 64];
+        stored[0] = 1.0;
+        let mut query = vec![1.0f32; 64];
+        query[0] = 0.0;
+        let exact = cosine_similarity(&query, &stored);
+        let quantized = quantization::quantize(&stored);
+        assert!(cosine_similarity(&query, &quantization::dequantize(&quantized.codes, quantized.scale)) < exact / 2.0);
+
+        let found = database::with_connection(|conn| {
+            store_embedding(conn, "message", 1, "текст", &Embedding { model: "test".to_string(), vector: stored.clone() })?;
+            semantic_search(conn, &Embedding { model: "test".to_string(), vector: query.clone() }, None, 5, 0.0)
+        }).unwrap().unwrap();
+        assert_eq!(found.len(), 1);
+        assert!((found[0].3 - exact).abs() < 1e-3, "{} vs {}", found[0].3, exact);
+    }
+
+    #[test]
+    fn test_reindex_replaces_vectors_of_other_models() {
+        let _db = database::test_database();
+        let session_id = database::create_session("t", None).unwrap();
+        let old = database::insert_message(session_id, "старая модель", true, None, None).unwrap();
+        let current = database::insert_message(session_id, "текущая модель", false, None, None).unwrap();
+        let memory = database::add_memory("память старой модели", "fact", session_id, old, 5).unwrap();
+        database::insert_message(session_id, "без вектора", true, None, None).unwrap();
+
+        let embedding = |model: &str| Embedding { model: model.to_string(), vector: vec![0.5, -0.5, 0.25] };
+        database::with_connection(|conn| {
+            store_embedding(conn, "message", old, "старая модель", &embedding("old"))?;
+            store_embedding(conn, "message", current, "текущая модель", &embedding("new"))?;
+            store_embedding(conn, "memory", memory, "память старой модели", &embedding("old"))?;
+
+            let pending = pending_sources(conn, "new")?;
+            assert_eq!(pending, vec![
+                ("message", old, "старая модель".to_string()),
+                ("memory", memory, "память старой модели".to_string()),
+            ]);
+
+            // What `reindex` does with each of them
+            for (source_type, source_id, content) in &pending {
+                store_embedding(conn, source_type, *source_id, content, &embedding("new"))?;
+            }
+            assert!(pending_sources(conn, "new")?.is_empty());
+            let models: Vec<String> = conn.prepare("SELECT model FROM embeddings")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_>>()?;
+            assert_eq!(models, vec!["new"; 3]);
+            Ok::<_, rusqlite::Error>(())
+        }).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_state_is_stored_before_it_is_announced() {
+        let ready = EmbedderState::Ready { model: "test".to_string(), dim: 3, path: None };
+        let seen = Mutex::new(None);
+        set_state(ready.clone(), &|announced: &EmbedderState| {
+            assert_eq!(*announced, state());
+            *seen.lock().unwrap() = Some(announced.clone());
+        });
+        assert_eq!(seen.into_inner().unwrap(), Some(ready));
+        set_state(EmbedderState::Idle, &|_: &EmbedderState| {});
+    }
+
     #[test]
     fn test_md5_hash_consistency() {
         let hash1 = md5_hash("hello world");
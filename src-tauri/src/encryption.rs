@@ -0,0 +1,488 @@
+//! Optional encryption at rest.
+//!
+//! The database is encrypted with SQLCipher, voice recordings and export files with
+//! AES-256-GCM. Both keys are halves of one random 64-byte data key. The data key is
+//! stored in `wishmaster.keys` next to the database, wrapped (AES-256-GCM) under a key
+//! derived from the passphrase with PBKDF2-HMAC-SHA256, so changing the passphrase only
+//! re-wraps it and nothing else has to be re-encrypted.
+//!
+//! Without the `encryption` feature encryption is reported as unavailable and files
+//! are read and written as plain bytes.
+
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::database;
+
+const KEY_FILE: &str = "wishmaster.keys";
+const DB_FILE: &str = "wishmaster.db";
+const KEY_FILE_VERSION: u32 = 1;
+const KDF_NAME: &str = "pbkdf2-hmac-sha256";
+/// OWASP recommendation for PBKDF2-HMAC-SHA256
+const KDF_ITERATIONS: u32 = 600_000;
+const MIN_PASSPHRASE_LEN: usize = 8;
+const DATA_KEY_LEN: usize = 64;
+const NONCE_LEN: usize = 12;
+/// Prefix of every encrypted file, followed by the nonce and the ciphertext
+const FILE_MAGIC: &[u8] = b"WMENC\x01";
+const KEY_FILE_AAD: &[u8] = b"wishmaster-keys-v1";
+const FILE_AAD: &[u8] = b"wishmaster-file-v1";
+/// Directories whose files are encrypted when encryption is enabled
+const ENCRYPTED_DIRS: &[&str] = &["voice_recordings", "exports"];
+
+/// Set once the passphrase has been entered (or encryption was just enabled)
+static DATA_KEY: OnceCell<DataKey> = OnceCell::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptionStatus {
+    /// Built with SQLCipher support
+    pub available: bool,
+    /// A key file exists, the database needs a passphrase
+    pub enabled: bool,
+    pub unlocked: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptionReport {
+    pub voice_recordings: usize,
+    pub exports: usize,
+    /// Backup archives and pre-migration copies made before encryption was enabled.
+    /// They stay readable without the passphrase until deleted.
+    pub plaintext_copies: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyFile {
+    version: u32,
+    kdf: String,
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    wrapped_key: String,
+}
+
+struct DataKey([u8; DATA_KEY_LEN]);
+
+impl DataKey {
+    /// Raw key for `PRAGMA key` (SQLCipher skips its own KDF for `x'..'` keys)
+    fn sqlcipher_key(&self) -> String {
+        let hex: String = self.0[..32].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("x'{}'", hex)
+    }
+
+    fn file_key(&self) -> &[u8] {
+        &self.0[32..]
+    }
+}
+
+pub fn is_available() -> bool {
+    cfg!(feature = "encryption")
+}
+
+pub fn is_enabled(app_dir: &Path) -> bool {
+    app_dir.join(KEY_FILE).is_file()
+}
+
+pub fn is_unlocked() -> bool {
+    DATA_KEY.get().is_some()
+}
+
+pub fn status(app_dir: &Path) -> EncryptionStatus {
+    EncryptionStatus {
+        available: is_available(),
+        enabled: is_enabled(app_dir),
+        unlocked: is_unlocked(),
+    }
+}
+
+/// SQLCipher key of the unlocked database, None when encryption is off
+pub fn db_key() -> Option<String> {
+    DATA_KEY.get().map(DataKey::sqlcipher_key)
+}
+
+/// Open the encrypted database with the passphrase. Also finishes an `enable`
+/// that was interrupted (plaintext database or files left behind).
+pub fn unlock(app_dir: &Path, passphrase: &str) -> Result<(), String> {
+    if is_unlocked() {
+        return Ok(());
+    }
+    if !is_available() {
+        return Err("Приложение собрано без поддержки шифрования".to_string());
+    }
+
+    let key_file = read_key_file(app_dir)?;
+    let key = unwrap_data_key(&key_file, passphrase)?;
+
+    let db_path = app_dir.join(DB_FILE);
+    if database::is_plaintext_file(&db_path) {
+        database::encrypt_file(&db_path, &key.sqlcipher_key())
+            .map_err(|e| format!("Не удалось зашифровать базу данных: {}", e))?;
+    }
+
+    let _ = DATA_KEY.set(key);
+    database::init(&db_path).map_err(|e| format!("Не удалось открыть базу данных: {}", e))?;
+    encrypt_data_files(app_dir)?;
+    Ok(())
+}
+
+/// Turn encryption on for an existing plaintext install
+pub fn enable(app_dir: &Path, passphrase: &str) -> Result<EncryptionReport, String> {
+    if !is_available() {
+        return Err("Приложение собрано без поддержки шифрования".to_string());
+    }
+    if is_enabled(app_dir) {
+        return Err("Шифрование уже включено".to_string());
+    }
+    validate_passphrase(passphrase)?;
+
+    let key = DataKey(random_bytes()?);
+    // The key file goes first: if the conversion is interrupted, `unlock` resumes it
+    write_key_file(app_dir, &wrap_data_key(&key, passphrase, KDF_ITERATIONS)?)?;
+
+    if let Err(e) = database::encrypt_database(&key.sqlcipher_key()) {
+        let _ = std::fs::remove_file(app_dir.join(KEY_FILE));
+        return Err(format!("Не удалось зашифровать базу данных: {}", e));
+    }
+    let _ = DATA_KEY.set(key);
+
+    let (voice_recordings, exports) = encrypt_data_files(app_dir)?;
+    Ok(EncryptionReport {
+        voice_recordings,
+        exports,
+        plaintext_copies: count_plaintext_copies(app_dir),
+    })
+}
+
+/// Re-wrap the data key under a new passphrase
+pub fn change_passphrase(app_dir: &Path, current: &str, new: &str) -> Result<(), String> {
+    if !is_available() {
+        return Err("Приложение собрано без поддержки шифрования".to_string());
+    }
+    validate_passphrase(new)?;
+
+    let key_file = read_key_file(app_dir)?;
+    let key = unwrap_data_key(&key_file, current)?;
+    write_key_file(app_dir, &wrap_data_key(&key, new, KDF_ITERATIONS)?)
+}
+
+/// Key the connection if the database is encrypted; a no-op otherwise
+pub fn apply_key(conn: &Connection, key: Option<&str>) -> rusqlite::Result<()> {
+    match key {
+        Some(key) => conn.execute_batch(&format!("PRAGMA key = \"{}\";", key)),
+        None => Ok(()),
+    }
+}
+
+/// Write a data file, encrypted when encryption is on
+pub fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    let bytes = match DATA_KEY.get() {
+        Some(key) => seal_file(key.file_key(), data)?,
+        None => data.to_vec(),
+    };
+    std::fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+/// Read a data file, decrypting it if needed
+pub fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    if !bytes.starts_with(FILE_MAGIC) {
+        return Ok(bytes);
+    }
+    let key = DATA_KEY.get().ok_or_else(|| "База данных заблокирована".to_string())?;
+    open_file(key.file_key(), &bytes)
+}
+
+pub fn is_encrypted_file(path: &Path) -> bool {
+    use std::io::Read;
+    let mut magic = [0u8; FILE_MAGIC.len()];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| magic == FILE_MAGIC)
+}
+
+fn validate_passphrase(passphrase: &str) -> Result<(), String> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(format!("Парольная фраза должна быть не короче {} символов", MIN_PASSPHRASE_LEN));
+    }
+    Ok(())
+}
+
+fn read_key_file(app_dir: &Path) -> Result<KeyFile, String> {
+    let json = std::fs::read_to_string(app_dir.join(KEY_FILE))
+        .map_err(|_| "Шифрование не включено".to_string())?;
+    let key_file: KeyFile = serde_json::from_str(&json)
+        .map_err(|e| format!("Файл ключей повреждён: {}", e))?;
+    if key_file.version > KEY_FILE_VERSION || key_file.kdf != KDF_NAME {
+        return Err("Файл ключей создан более новой версией приложения".to_string());
+    }
+    Ok(key_file)
+}
+
+/// Written to a temporary file and renamed, so a crash never leaves a torn key file
+fn write_key_file(app_dir: &Path, key_file: &KeyFile) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(key_file).map_err(|e| e.to_string())?;
+    let tmp = app_dir.join(format!("{}.tmp", KEY_FILE));
+    std::fs::write(&tmp, json).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, app_dir.join(KEY_FILE)).map_err(|e| e.to_string())
+}
+
+fn wrap_data_key(key: &DataKey, passphrase: &str, iterations: u32) -> Result<KeyFile, String> {
+    let salt: [u8; 16] = random_bytes()?;
+    let nonce: [u8; NONCE_LEN] = random_bytes()?;
+    let kek = derive_kek(passphrase, &salt, iterations)?;
+    let wrapped = seal(&kek, &nonce, KEY_FILE_AAD, &key.0)?;
+
+    let b64 = base64::engine::general_purpose::STANDARD;
+    Ok(KeyFile {
+        version: KEY_FILE_VERSION,
+        kdf: KDF_NAME.to_string(),
+        iterations,
+        salt: b64.encode(salt),
+        nonce: b64.encode(nonce),
+        wrapped_key: b64.encode(wrapped),
+    })
+}
+
+fn unwrap_data_key(key_file: &KeyFile, passphrase: &str) -> Result<DataKey, String> {
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let damaged = |_| "Файл ключей повреждён".to_string();
+    let salt = b64.decode(&key_file.salt).map_err(damaged)?;
+    let nonce: [u8; NONCE_LEN] = b64.decode(&key_file.nonce).map_err(damaged)?
+        .try_into()
+        .map_err(|_| "Файл ключей повреждён".to_string())?;
+    let wrapped = b64.decode(&key_file.wrapped_key).map_err(damaged)?;
+
+    let kek = derive_kek(passphrase, &salt, key_file.iterations)?;
+    let key = open(&kek, &nonce, KEY_FILE_AAD, wrapped)
+        .map_err(|_| "Неверная парольная фраза".to_string())?;
+    let key: [u8; DATA_KEY_LEN] = key.try_into()
+        .map_err(|_| "Файл ключей повреждён".to_string())?;
+    Ok(DataKey(key))
+}
+
+fn seal_file(file_key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce: [u8; NONCE_LEN] = random_bytes()?;
+    let ciphertext = seal(file_key, &nonce, FILE_AAD, data)?;
+
+    let mut bytes = Vec::with_capacity(FILE_MAGIC.len() + NONCE_LEN + ciphertext.len());
+    bytes.extend_from_slice(FILE_MAGIC);
+    bytes.extend_from_slice(&nonce);
+    bytes.extend_from_slice(&ciphertext);
+    Ok(bytes)
+}
+
+fn open_file(file_key: &[u8], bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let body = &bytes[FILE_MAGIC.len()..];
+    if body.len() < NONCE_LEN {
+        return Err("Зашифрованный файл повреждён".to_string());
+    }
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| "Зашифрованный файл повреждён".to_string())?;
+    open(file_key, &nonce, FILE_AAD, ciphertext.to_vec())
+        .map_err(|_| "Зашифрованный файл повреждён или ключ не подходит".to_string())
+}
+
+/// Encrypt the plaintext files in the data directories in place.
+/// Returns how many recordings and exports were encrypted.
+fn encrypt_data_files(app_dir: &Path) -> Result<(usize, usize), String> {
+    let key = DATA_KEY.get().ok_or_else(|| "База данных заблокирована".to_string())?;
+    let mut counts = [0usize; 2];
+
+    for (count, dir) in counts.iter_mut().zip(ENCRYPTED_DIRS) {
+        let Ok(entries) = std::fs::read_dir(app_dir.join(dir)) else { continue };
+        for path in entries.flatten().map(|e| e.path()).filter(|p| p.is_file()) {
+            if is_encrypted_file(&path) {
+                continue;
+            }
+            let data = std::fs::read(&path).map_err(|e| e.to_string())?;
+            let tmp = path.with_extension("encrypting");
+            std::fs::write(&tmp, seal_file(key.file_key(), &data)?).map_err(|e| e.to_string())?;
+            std::fs::rename(&tmp, &path).map_err(|e| e.to_string())?;
+            *count += 1;
+        }
+    }
+
+    Ok((counts[0], counts[1]))
+}
+
+fn count_plaintext_copies(app_dir: &Path) -> usize {
+    let archives = crate::backup::list_backups(app_dir)
+        .map(|backups| backups.iter().filter(|b| !b.manifest.encrypted).count())
+        .unwrap_or(0);
+    let migration_copies = std::fs::read_dir(app_dir)
+        .map(|entries| {
+            entries.flatten()
+                .filter(|e| e.file_name().to_string_lossy().ends_with(".bak"))
+                .filter(|e| database::is_plaintext_file(&e.path()))
+                .count()
+        })
+        .unwrap_or(0);
+    archives + migration_copies
+}
+
+// ==================== Primitives (ring) ====================
+
+#[cfg(feature = "encryption")]
+fn random_bytes<const N: usize>() -> Result<[u8; N], String> {
+    use ring::rand::SecureRandom;
+    let mut bytes = [0u8; N];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Не удалось получить случайные данные".to_string())?;
+    Ok(bytes)
+}
+
+#[cfg(feature = "encryption")]
+fn derive_kek(passphrase: &str, salt: &[u8], iterations: u32) -> Result<[u8; 32], String> {
+    let iterations = std::num::NonZeroU32::new(iterations)
+        .ok_or_else(|| "Файл ключей повреждён".to_string())?;
+    let mut kek = [0u8; 32];
+    ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut kek);
+    Ok(kek)
+}
+
+#[cfg(feature = "encryption")]
+fn aead_key(key: &[u8]) -> Result<ring::aead::LessSafeKey, String> {
+    ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, key)
+        .map(ring::aead::LessSafeKey::new)
+        .map_err(|_| "Некорректный ключ шифрования".to_string())
+}
+
+#[cfg(feature = "encryption")]
+fn seal(key: &[u8], nonce: &[u8; NONCE_LEN], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    use ring::aead::{Aad, Nonce};
+    let mut in_out = data.to_vec();
+    aead_key(key)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(*nonce), Aad::from(aad), &mut in_out)
+        .map_err(|_| "Ошибка шифрования".to_string())?;
+    Ok(in_out)
+}
+
+#[cfg(feature = "encryption")]
+fn open(key: &[u8], nonce: &[u8; NONCE_LEN], aad: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+    use ring::aead::{Aad, Nonce};
+    let len = aead_key(key)?
+        .open_in_place(Nonce::assume_unique_for_key(*nonce), Aad::from(aad), &mut data)
+        .map_err(|_| "Ошибка расшифровки".to_string())?
+        .len();
+    data.truncate(len);
+    Ok(data)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn random_bytes<const N: usize>() -> Result<[u8; N], String> {
+    Err("Приложение собрано без поддержки шифрования".to_string())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn derive_kek(_passphrase: &str, _salt: &[u8], _iterations: u32) -> Result<[u8; 32], String> {
+    Err("Приложение собрано без поддержки шифрования".to_string())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn seal(_key: &[u8], _nonce: &[u8; NONCE_LEN], _aad: &[u8], _data: &[u8]) -> Result<Vec<u8>, String> {
+    Err("Приложение собрано без поддержки шифрования".to_string())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open(_key: &[u8], _nonce: &[u8; NONCE_LEN], _aad: &[u8], _data: Vec<u8>) -> Result<Vec<u8>, String> {
+    Err("Приложение собрано без поддержки шифрования".to_string())
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Keeps the tests fast; real key files use KDF_ITERATIONS
+    const TEST_ITERATIONS: u32 = 1_000;
+
+    fn test_key() -> DataKey {
+        DataKey(random_bytes().unwrap())
+    }
+
+    #[test]
+    fn test_data_key_roundtrip() {
+        let key = test_key();
+        let key_file = wrap_data_key(&key, "correct horse", TEST_ITERATIONS).unwrap();
+
+        let json = serde_json::to_string(&key_file).unwrap();
+        assert!(json.contains("\"wrappedKey\""));
+        assert!(json.contains(KDF_NAME));
+
+        let unwrapped = unwrap_data_key(&key_file, "correct horse").unwrap();
+        assert_eq!(unwrapped.0, key.0);
+    }
+
+    #[test]
+    fn test_wrong_passphrase_rejected() {
+        let key_file = wrap_data_key(&test_key(), "correct horse", TEST_ITERATIONS).unwrap();
+        let err = unwrap_data_key(&key_file, "wrong horse").err().unwrap();
+        assert_eq!(err, "Неверная парольная фраза");
+    }
+
+    #[test]
+    fn test_passphrase_change_keeps_data_key() {
+        let dir = TempDir::new().unwrap();
+        let key = test_key();
+        write_key_file(dir.path(), &wrap_data_key(&key, "first passphrase", TEST_ITERATIONS).unwrap()).unwrap();
+
+        let rewrapped = unwrap_data_key(&read_key_file(dir.path()).unwrap(), "first passphrase").unwrap();
+        write_key_file(dir.path(), &wrap_data_key(&rewrapped, "second passphrase", TEST_ITERATIONS).unwrap()).unwrap();
+
+        let key_file = read_key_file(dir.path()).unwrap();
+        assert!(unwrap_data_key(&key_file, "first passphrase").is_err());
+        assert_eq!(unwrap_data_key(&key_file, "second passphrase").unwrap().0, key.0);
+    }
+
+    #[test]
+    fn test_file_seal_and_tamper_detection() {
+        let key = test_key();
+        let sealed = seal_file(key.file_key(), b"voice bytes").unwrap();
+        assert!(sealed.starts_with(FILE_MAGIC));
+        assert_eq!(open_file(key.file_key(), &sealed).unwrap(), b"voice bytes");
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_file(key.file_key(), &tampered).is_err());
+        assert!(open_file(test_key().file_key(), &sealed).is_err());
+        assert!(open_file(key.file_key(), FILE_MAGIC).is_err());
+    }
+
+    #[test]
+    fn test_short_passphrase_rejected() {
+        assert!(validate_passphrase("short").is_err());
+        assert!(validate_passphrase("пароль12").is_ok());
+    }
+
+    #[test]
+    fn test_encrypt_database_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(DB_FILE);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch("CREATE TABLE notes (text TEXT); INSERT INTO notes VALUES ('secret'); PRAGMA user_version = 3;").unwrap();
+        }
+        assert!(database::is_plaintext_file(&path));
+
+        let key = test_key().sqlcipher_key();
+        database::encrypt_file(&path, &key).unwrap();
+        assert!(!database::is_plaintext_file(&path));
+
+        let conn = Connection::open(&path).unwrap();
+        apply_key(&conn, Some(&key)).unwrap();
+        let text: String = conn.query_row("SELECT text FROM notes", [], |row| row.get(0)).unwrap();
+        assert_eq!(text, "secret");
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, 3);
+
+        let locked = Connection::open(&path).unwrap();
+        assert!(locked.query_row("SELECT COUNT(*) FROM notes", [], |row| row.get::<_, i64>(0)).is_err());
+    }
+}
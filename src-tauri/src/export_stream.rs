@@ -0,0 +1,543 @@
+//! Streaming export for large databases.
+//!
+//! Rows are read page by page and written straight to the output, either as JSON Lines
+//! or as one JSON document built up incrementally, optionally through gzip or zstd.
+//! Only the current page is held in memory. Encrypted exports are the exception: a file
+//! is sealed as a whole, so the (compressed) output is collected in memory first.
+//!
+//! A full export in the JSON layout has the same shape as `ExportData`. In the JSONL
+//! layout every line is `{"type": ..., "data": ...}` after a `{"type": "header"}` line.
+//! `read_full_export` reads both, compressed or not.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::database;
+use crate::encryption;
+use crate::redaction::{RedactionConfig, RedactionReport, Redactor};
+
+/// Rows read per database round trip
+const PAGE_SIZE: i64 = 500;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportContent {
+    /// Everything `import_full_export` restores
+    #[default]
+    Full,
+    /// Visible branch of every session as ShareGPT conversations
+    ShareGpt,
+}
+
+impl ExportContent {
+    fn file_stem(&self) -> &'static str {
+        match self {
+            ExportContent::Full => "full_export",
+            ExportContent::ShareGpt => "sharegpt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportLayout {
+    /// One record per line
+    #[default]
+    Jsonl,
+    /// A single JSON document
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    /// Needs the `zstd` feature
+    Zstd,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StreamExportOptions {
+    pub content: ExportContent,
+    pub layout: ExportLayout,
+    pub compression: Compression,
+    /// Replace personal data by placeholders
+    pub redaction: Option<RedactionConfig>,
+}
+
+impl StreamExportOptions {
+    /// `<content>_<timestamp>.<json|jsonl>[.gz|.zst]`
+    pub fn file_name(&self, timestamp: u64) -> String {
+        let extension = match self.layout {
+            ExportLayout::Jsonl => "jsonl",
+            ExportLayout::Json => "json",
+        };
+        let compression = match self.compression {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        };
+        format!("{}_{}.{}{}", self.content.file_stem(), timestamp, extension, compression)
+    }
+}
+
+/// Sent after every page
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportProgress {
+    /// "sessions", "messages", "memory", "persona", "feedback" or "conversations"
+    pub stage: &'static str,
+    pub written: u64,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamExportResult {
+    pub path: String,
+    pub records: u64,
+    /// Size of the written file
+    pub bytes: u64,
+    pub redaction: Option<RedactionReport>,
+}
+
+// ==================== Compression ====================
+
+enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(out: W, compression: Compression) -> Result<Self, String> {
+        match compression {
+            Compression::None => Ok(Encoder::Plain(out)),
+            Compression::Gzip => Ok(Encoder::Gzip(flate2::write::GzEncoder::new(out, flate2::Compression::default()))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::Encoder::new(out, 0)
+                .map(Encoder::Zstd)
+                .map_err(|e| e.to_string()),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err("Сжатие zstd недоступно в этой сборке".to_string()),
+        }
+    }
+
+    /// Write the compression trailer and hand back the underlying writer
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            Encoder::Plain(out) => Ok(out),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Plain(out) => out.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Plain(out) => out.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Undo gzip / zstd compression, recognised by the magic bytes
+fn decompress(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    if bytes.starts_with(GZIP_MAGIC) {
+        let mut out = Vec::new();
+        flate2::read::MultiGzDecoder::new(bytes.as_slice())
+            .read_to_end(&mut out)
+            .map_err(|e| format!("Не удалось распаковать gzip: {}", e))?;
+        return Ok(out);
+    }
+    if bytes.starts_with(ZSTD_MAGIC) {
+        #[cfg(feature = "zstd")]
+        return zstd::stream::decode_all(bytes.as_slice())
+            .map_err(|e| format!("Не удалось распаковать zstd: {}", e));
+        #[cfg(not(feature = "zstd"))]
+        return Err("Сжатие zstd недоступно в этой сборке".to_string());
+    }
+    Ok(bytes)
+}
+
+// ==================== Writing ====================
+
+/// Writes records in the chosen layout, redacting them on the way
+struct RecordWriter<'a> {
+    out: &'a mut dyn Write,
+    layout: ExportLayout,
+    redactor: Option<&'a mut Redactor>,
+    /// Records in the JSON array being written
+    in_array: usize,
+    records: u64,
+}
+
+impl RecordWriter<'_> {
+    fn raw(&mut self, text: &str) -> Result<(), String> {
+        self.out.write_all(text.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn value(&mut self, record: &impl Serialize) -> Result<serde_json::Value, String> {
+        let mut value = serde_json::to_value(record).map_err(|e| e.to_string())?;
+        if let Some(redactor) = self.redactor.as_mut() {
+            redactor.redact_record(&mut value);
+        }
+        Ok(value)
+    }
+
+    /// JSON layout: open `"key": [` (or a bare `[` without a key)
+    fn begin_array(&mut self, key: Option<&str>) -> Result<(), String> {
+        self.in_array = 0;
+        match (self.layout, key) {
+            (ExportLayout::Json, Some(key)) => self.raw(&format!("\"{}\": [", key)),
+            (ExportLayout::Json, None) => self.raw("["),
+            (ExportLayout::Jsonl, _) => Ok(()),
+        }
+    }
+
+    fn end_array(&mut self) -> Result<(), String> {
+        match self.layout {
+            ExportLayout::Json if self.in_array > 0 => self.raw("\n]"),
+            ExportLayout::Json => self.raw("]"),
+            ExportLayout::Jsonl => Ok(()),
+        }
+    }
+
+    /// One array element; in JSONL a line tagged with `kind` (untagged without one)
+    fn record(&mut self, kind: Option<&str>, record: &impl Serialize) -> Result<(), String> {
+        let value = self.value(record)?;
+        let line = match (self.layout, kind) {
+            (ExportLayout::Jsonl, Some(kind)) => serde_json::json!({ "type": kind, "data": value }),
+            _ => value,
+        };
+        let text = serde_json::to_string(&line).map_err(|e| e.to_string())?;
+        match self.layout {
+            ExportLayout::Json => {
+                let separator = if self.in_array == 0 { "\n" } else { ",\n" };
+                self.raw(separator)?;
+                self.raw(&text)?;
+            }
+            ExportLayout::Jsonl => {
+                self.raw(&text)?;
+                self.raw("\n")?;
+            }
+        }
+        self.in_array += 1;
+        self.records += 1;
+        Ok(())
+    }
+}
+
+/// Read a table page by page (by id, starting after 0) until a short page
+fn for_each_page<T>(
+    fetch: impl Fn(i64, i64) -> rusqlite::Result<Vec<T>>,
+    id: impl Fn(&T) -> i64,
+    mut each: impl FnMut(Vec<T>) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut after = 0;
+    loop {
+        let page = fetch(after, PAGE_SIZE).map_err(|e| e.to_string())?;
+        let Some(last) = page.last().map(&id) else {
+            return Ok(());
+        };
+        let short = (page.len() as i64) < PAGE_SIZE;
+        each(page)?;
+        if short {
+            return Ok(());
+        }
+        after = last;
+    }
+}
+
+/// Stream an export into `out`; returns the number of records written.
+/// `cancel` is checked between pages.
+pub fn stream(
+    options: &StreamExportOptions,
+    out: &mut dyn Write,
+    redactor: Option<&mut Redactor>,
+    progress: &mut dyn FnMut(&ExportProgress),
+    cancel: &AtomicBool,
+) -> Result<u64, String> {
+    let counts = database::export_counts().map_err(|e| e.to_string())?;
+    let mut writer = RecordWriter {
+        out,
+        layout: options.layout,
+        redactor,
+        in_array: 0,
+        records: 0,
+    };
+    let check = |stage: &'static str, writer: &RecordWriter, total: u64, progress: &mut dyn FnMut(&ExportProgress)| {
+        if cancel.load(Ordering::SeqCst) {
+            return Err("Экспорт отменён".to_string());
+        }
+        progress(&ExportProgress { stage, written: writer.records, total });
+        Ok(())
+    };
+
+    match options.content {
+        ExportContent::ShareGpt => {
+            let total = counts.conversations;
+            writer.begin_array(None)?;
+            for_each_page(database::export_conversation_ids_page, |id| *id, |ids| {
+                for session_id in ids {
+                    let messages = database::get_messages(session_id).map_err(|e| e.to_string())?;
+                    writer.record(None, &database::sharegpt_conversation(session_id, &messages))?;
+                }
+                check("conversations", &writer, total, progress)
+            })?;
+            writer.end_array()?;
+            if options.layout == ExportLayout::Json {
+                writer.raw("\n")?;
+            }
+        }
+        ExportContent::Full => {
+            let persona = database::get_user_persona().map_err(|e| e.to_string())?;
+            let total = counts.sessions + counts.messages + counts.memory + counts.feedback
+                + persona.is_some() as u64;
+            let exported_at = chrono::Utc::now().timestamp_millis();
+
+            match options.layout {
+                ExportLayout::Json => writer.raw("{\n")?,
+                ExportLayout::Jsonl => writer.raw(&format!(
+                    "{}\n", serde_json::json!({ "type": "header", "exportedAt": exported_at })
+                ))?,
+            }
+            let json = options.layout == ExportLayout::Json;
+
+            writer.begin_array(Some("sessions"))?;
+            for_each_page(database::export_sessions_page, |s| s.id, |page| {
+                page.iter().try_for_each(|s| writer.record(Some("session"), s))?;
+                check("sessions", &writer, total, progress)
+            })?;
+            writer.end_array()?;
+
+            if json { writer.raw(",\n")?; }
+            writer.begin_array(Some("messages"))?;
+            for_each_page(database::export_messages_page, |m| m.id, |page| {
+                page.iter().try_for_each(|m| writer.record(Some("message"), m))?;
+                check("messages", &writer, total, progress)
+            })?;
+            writer.end_array()?;
+
+            if json { writer.raw(",\n")?; }
+            writer.begin_array(Some("memory"))?;
+            for_each_page(database::export_memory_page, |m| m.id, |page| {
+                page.iter().try_for_each(|m| writer.record(Some("memory"), m))?;
+                check("memory", &writer, total, progress)
+            })?;
+            writer.end_array()?;
+
+            match (&persona, json) {
+                (Some(persona), true) => {
+                    let value = writer.value(persona)?;
+                    writer.raw(&format!(",\n\"persona\": {}", value))?;
+                    writer.records += 1;
+                }
+                (None, true) => writer.raw(",\n\"persona\": null")?,
+                (Some(persona), false) => writer.record(Some("persona"), persona)?,
+                (None, false) => {}
+            }
+            check("persona", &writer, total, progress)?;
+
+            if json { writer.raw(",\n")?; }
+            writer.begin_array(Some("feedback"))?;
+            for_each_page(database::export_feedback_page, |f| f.message_id, |page| {
+                page.iter().try_for_each(|f| writer.record(Some("feedback"), f))?;
+                check("feedback", &writer, total, progress)
+            })?;
+            writer.end_array()?;
+
+            if json {
+                writer.raw(&format!(",\n\"exportedAt\": {}\n}}\n", exported_at))?;
+            }
+        }
+    }
+
+    Ok(writer.records)
+}
+
+/// Stream an export into `path`, compressed as asked. Written to `<path>.part` and
+/// renamed when complete, so a failed or cancelled export leaves no file behind.
+/// With encryption unlocked the file is encrypted (and assembled in memory).
+pub fn export_to_file(
+    options: &StreamExportOptions,
+    path: &Path,
+    progress: &mut dyn FnMut(&ExportProgress),
+    cancel: &AtomicBool,
+) -> Result<StreamExportResult, String> {
+    let mut redactor = options.redaction.clone().map(Redactor::new).transpose()?;
+
+    let records = if encryption::is_unlocked() {
+        let mut encoder = Encoder::new(Vec::new(), options.compression)?;
+        let records = stream(options, &mut encoder, redactor.as_mut(), progress, cancel)?;
+        let data = encoder.finish().map_err(|e| e.to_string())?;
+        encryption::write_file(path, &data)?;
+        records
+    } else {
+        let part = path.with_file_name(format!(
+            "{}.part",
+            path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+        ));
+        let written = write_part(options, &part, redactor.as_mut(), progress, cancel)
+            .and_then(|records| std::fs::rename(&part, path).map(|_| records).map_err(|e| e.to_string()));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&part);
+        }
+        written?
+    };
+
+    Ok(StreamExportResult {
+        path: path.to_string_lossy().to_string(),
+        records,
+        bytes: std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        redaction: redactor.map(|r| r.report()),
+    })
+}
+
+fn write_part(
+    options: &StreamExportOptions,
+    part: &Path,
+    redactor: Option<&mut Redactor>,
+    progress: &mut dyn FnMut(&ExportProgress),
+    cancel: &AtomicBool,
+) -> Result<u64, String> {
+    let file = std::fs::File::create(part).map_err(|e| e.to_string())?;
+    let mut encoder = Encoder::new(std::io::BufWriter::new(file), options.compression)?;
+    let records = stream(options, &mut encoder, redactor, progress, cancel)?;
+    encoder.finish()
+        .and_then(|mut out| out.flush())
+        .map_err(|e| e.to_string())?;
+    Ok(records)
+}
+
+// ==================== Reading ====================
+
+/// Parse a full export: JSON or JSONL, plain, gzip or zstd (already decrypted)
+pub fn read_full_export(bytes: Vec<u8>) -> Result<database::ExportData, String> {
+    let bytes = decompress(bytes)?;
+    let first_line = bytes.split(|b| *b == b'\n').next().unwrap_or_default();
+    let is_jsonl = serde_json::from_slice::<serde_json::Value>(first_line)
+        .is_ok_and(|v| v["type"] == "header");
+    if !is_jsonl {
+        return serde_json::from_slice(&bytes).map_err(|e| e.to_string());
+    }
+
+    let mut data = database::ExportData {
+        sessions: Vec::new(),
+        messages: Vec::new(),
+        memory: Vec::new(),
+        persona: None,
+        feedback: Vec::new(),
+        exported_at: 0,
+    };
+    for (number, line) in bytes.split(|b| *b == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let mut line: serde_json::Value = serde_json::from_slice(line)
+            .map_err(|e| format!("строка {}: {}", number + 1, e))?;
+        let record = line["data"].take();
+        let parsed = match line["type"].as_str().unwrap_or_default() {
+            "header" => {
+                data.exported_at = line["exportedAt"].as_i64().unwrap_or(0);
+                Ok(())
+            }
+            "session" => serde_json::from_value(record).map(|r| data.sessions.push(r)),
+            "message" => serde_json::from_value(record).map(|r| data.messages.push(r)),
+            "memory" => serde_json::from_value(record).map(|r| data.memory.push(r)),
+            "persona" => serde_json::from_value(record).map(|r| data.persona = Some(r)),
+            "feedback" => serde_json::from_value(record).map(|r| data.feedback.push(r)),
+            // Records of newer versions
+            _ => Ok(()),
+        };
+        parsed.map_err(|e| format!("строка {}: {}", number + 1, e))?;
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_records(layout: ExportLayout, redactor: Option<&mut Redactor>) -> String {
+        let mut out = Vec::new();
+        let mut writer = RecordWriter { out: &mut out, layout, redactor, in_array: 0, records: 0 };
+        writer.begin_array(Some("memory")).unwrap();
+        writer.record(Some("memory"), &serde_json::json!({ "content": "почта ivan@example.com" })).unwrap();
+        writer.record(Some("memory"), &serde_json::json!({ "content": "второй" })).unwrap();
+        writer.end_array().unwrap();
+        assert_eq!(writer.records, 2);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_layouts() {
+        let json = write_records(ExportLayout::Json, None);
+        assert_eq!(json, "\"memory\": [\n{\"content\":\"почта ivan@example.com\"},\n{\"content\":\"второй\"}\n]");
+
+        let mut redactor = Redactor::new(RedactionConfig::default()).unwrap();
+        let jsonl = write_records(ExportLayout::Jsonl, Some(&mut redactor));
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], r#"{"data":{"content":"почта [EMAIL_1]"},"type":"memory"}"#);
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let text = "{\"type\":\"header\",\"exportedAt\":5}\n".repeat(100);
+        let compressions: &[Compression] = if cfg!(feature = "zstd") {
+            &[Compression::None, Compression::Gzip, Compression::Zstd]
+        } else {
+            &[Compression::None, Compression::Gzip]
+        };
+        for &compression in compressions {
+            let mut encoder = Encoder::new(Vec::new(), compression).unwrap();
+            encoder.write_all(text.as_bytes()).unwrap();
+            let bytes = encoder.finish().unwrap();
+            assert_eq!(decompress(bytes).unwrap(), text.as_bytes(), "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn test_read_jsonl_full_export() {
+        let jsonl = [
+            r#"{"type":"header","exportedAt":42}"#,
+            r#"{"type":"session","data":{"id":1,"title":"Чат","createdAt":1,"messageCount":1}}"#,
+            r#"{"type":"message","data":{"id":2,"sessionId":1,"sessionTitle":"Чат","content":"привет","isUser":true,"timestamp":3}}"#,
+            r#"{"type":"future","data":{}}"#,
+            "",
+        ].join("\n");
+
+        let data = read_full_export(jsonl.into_bytes()).unwrap();
+        assert_eq!(data.exported_at, 42);
+        assert_eq!(data.sessions.len(), 1);
+        assert_eq!(data.messages[0].content, "привет");
+        assert!(data.messages[0].is_active);
+        assert!(data.memory.is_empty() && data.persona.is_none());
+
+        let options = StreamExportOptions { layout: ExportLayout::Json, compression: Compression::Gzip, ..Default::default() };
+        assert_eq!(options.file_name(7), "full_export_7.json.gz");
+    }
+}
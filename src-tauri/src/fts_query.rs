@@ -0,0 +1,214 @@
+//! Search syntax for the full-text indexes.
+//!
+//! User input never reaches `MATCH` as is: FTS5 treats quotes, hyphens, `*`, `:`,
+//! parentheses and bare `AND` / `OR` / `NOT` as its own syntax, so ordinary text can
+//! make a query fail. `parse` reads a small, forgiving syntax and emits an expression
+//! in which every word is a quoted string:
+//!
+//! - `rust borrow` — both words (implicit AND)
+//! - `"borrow checker"` — exact phrase; an unclosed quote runs to the end
+//! - `borrow*` — prefix
+//! - `-unsafe`, `NOT unsafe` — exclude
+//! - `tokio OR async-std` — either
+//!
+//! Anything else is taken literally. `any_word` builds a looser expression from free
+//! text, for looking up context for a chat prompt.
+
+/// One word or phrase of the query
+#[derive(Debug, PartialEq)]
+struct Term {
+    text: String,
+    prefix: bool,
+    negated: bool,
+}
+
+impl Term {
+    /// FTS5 string: quotes doubled, `*` after the closing quote for a prefix
+    fn to_fts(&self) -> String {
+        format!("\"{}\"{}", self.text.replace('"', "\"\""), if self.prefix { "*" } else { "" })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Term(Term),
+    Or,
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    let mut negated = false;
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        // `-word` / `-"phrase"`; a lone `-` is just punctuation
+        if c == '-' {
+            chars.next();
+            if chars.peek().is_some_and(|next| !next.is_whitespace()) {
+                negated = true;
+            }
+            continue;
+        }
+
+        let (text, prefix) = if c == '"' {
+            chars.next();
+            let text: String = chars.by_ref().take_while(|&c| c != '"').collect();
+            let prefix = chars.next_if_eq(&'*').is_some();
+            (text, prefix)
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != '"') {
+                word.push(c);
+            }
+            match word.as_str() {
+                "OR" => {
+                    tokens.push(Token::Or);
+                    negated = false;
+                    continue;
+                }
+                "AND" => continue,
+                "NOT" => {
+                    negated = true;
+                    continue;
+                }
+                _ => {}
+            }
+            let stem = word.trim_end_matches('*');
+            let prefix = stem.len() < word.len();
+            (stem.to_string(), prefix)
+        };
+
+        // Words made only of punctuation index to nothing
+        if text.chars().any(char::is_alphanumeric) {
+            tokens.push(Token::Term(Term { text, prefix, negated }));
+        }
+        negated = false;
+    }
+
+    tokens
+}
+
+/// FTS5 MATCH expression for a user query; None when there is nothing to look for
+/// (empty input, only punctuation or only exclusions)
+pub fn parse(input: &str) -> Option<String> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    let mut excluded: Vec<String> = Vec::new();
+    let mut or_pending = false;
+
+    for token in tokenize(input) {
+        match token {
+            // `OR` only joins two words to look for
+            Token::Or => or_pending = !groups.is_empty(),
+            Token::Term(term) if term.negated => {
+                excluded.push(term.to_fts());
+                or_pending = false;
+            }
+            Token::Term(term) => {
+                match groups.last_mut() {
+                    Some(group) if or_pending => group.push(term.to_fts()),
+                    _ => groups.push(vec![term.to_fts()]),
+                }
+                or_pending = false;
+            }
+        }
+    }
+
+    if groups.is_empty() {
+        return None;
+    }
+    let included = groups.iter()
+        .map(|group| if group.len() > 1 { format!("({})", group.join(" OR ")) } else { group[0].clone() })
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    if excluded.is_empty() {
+        return Some(included);
+    }
+    Some(format!("({}) NOT ({})", included, excluded.join(" OR ")))
+}
+
+/// Words shorter than this are left out of `any_word` (mostly particles and prepositions)
+const MIN_WORD_CHARS: usize = 3;
+/// Upper bound on the words of an `any_word` expression
+const MAX_WORDS: usize = 24;
+
+/// Expression matching any word of free text (a chat prompt rather than a search
+/// query); bm25 then prefers rows matching more and rarer words. No syntax is read.
+pub fn any_word(text: &str) -> Option<String> {
+    let mut words: Vec<String> = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        let word = word.to_lowercase();
+        if word.chars().count() >= MIN_WORD_CHARS && !words.contains(&word) {
+            words.push(word);
+        }
+    }
+    if words.is_empty() {
+        return None;
+    }
+    Some(words.iter()
+        .take(MAX_WORDS)
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<_>>()
+        .join(" OR "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_parse_syntax() {
+        assert_eq!(parse("rust borrow").unwrap(), r#""rust" AND "borrow""#);
+        assert_eq!(parse(r#""borrow checker" lifetime*"#).unwrap(), r#""borrow checker" AND "lifetime"*"#);
+        assert_eq!(parse("tokio OR async-std runtime").unwrap(), r#"("tokio" OR "async-std") AND "runtime""#);
+        assert_eq!(parse("rust -unsafe NOT \"raw pointer\"").unwrap(), r#"("rust") NOT ("unsafe" OR "raw pointer")"#);
+        assert_eq!(parse(r#"say "hi"#).unwrap(), r#""say" AND "hi""#);
+        assert_eq!(parse(r#"a"b"#).unwrap(), r#""a" AND "b""#);
+        assert_eq!(parse("not or and").unwrap(), r#""not" AND "or" AND "and""#);
+
+        assert_eq!(parse(""), None);
+        assert_eq!(parse(" - * \"\" OR "), None);
+        assert_eq!(parse("-only -exclusions"), None);
+    }
+
+    #[test]
+    fn test_any_word() {
+        assert_eq!(any_word("Как настроить NOT-tokio в Rust? Rust!").unwrap(), r#""как" OR "настроить" OR "not" OR "tokio" OR "rust""#);
+        assert_eq!(any_word("а и в ??"), None);
+    }
+
+    #[test]
+    fn test_parsed_queries_run() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(r#"
+            CREATE VIRTUAL TABLE docs USING fts5(content);
+            INSERT INTO docs(rowid, content) VALUES
+                (1, 'The borrow checker rejects this code'),
+                (2, 'Используй tokio для async-std кода'),
+                (3, 'unsafe raw pointer arithmetic in Rust'),
+                (4, 'Rust lifetimes: NOT what you think');
+        "#).unwrap();
+        let search = |input: &str| -> Vec<i64> {
+            let expression = parse(input).unwrap();
+            let mut stmt = conn.prepare("SELECT rowid FROM docs WHERE docs MATCH ?1 ORDER BY rowid").unwrap();
+            stmt.query_map([expression], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect()
+        };
+
+        assert_eq!(search(r#""borrow checker""#), vec![1]);
+        assert_eq!(search("rust -unsafe"), vec![4]);
+        assert_eq!(search("lifetime*"), vec![4]);
+        assert_eq!(search("async-std OR borrow"), vec![1, 2]);
+        assert_eq!(search("кода"), vec![2]);
+        // Input that is FTS5 syntax on its own
+        for input in ["NOT", "rust:", "(rust", "a AND OR b", "\"\"\"", "^rust", "rust NEAR(x)", "'; DROP TABLE docs; --"] {
+            if let Some(expression) = parse(input) {
+                conn.query_row("SELECT COUNT(*) FROM docs WHERE docs MATCH ?1", [expression], |row| row.get::<_, i64>(0))
+                    .unwrap_or_else(|e| panic!("{}: {}", input, e));
+            }
+        }
+    }
+}
@@ -0,0 +1,562 @@
+//! Parsers for chat histories exported by other tools.
+//!
+//! Supported: ChatGPT `conversations.json` (unzipped from the data export), Claude
+//! `conversations.json`, ShareGPT and Alpaca files (including the ones Wishmaster
+//! exports itself) and generic `{"messages": [{"role", "content"}]}` conversations
+//! such as OpenAI fine-tuning JSONL. Files may be a JSON array, a single object or JSONL.
+//!
+//! Everything is turned into [`ImportedConversation`]s; writing them to the database is
+//! done by `database::import_conversation`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Titles longer than this are cut (Alpaca records use the instruction as title)
+const MAX_TITLE_CHARS: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// Detected per conversation
+    Auto,
+    ChatGpt,
+    Claude,
+    ShareGpt,
+    Alpaca,
+    /// `{"title"?, "messages": [{"role", "content", "timestamp"?}]}`
+    Generic,
+}
+
+impl ImportFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportFormat::Auto => "auto",
+            ImportFormat::ChatGpt => "chatgpt",
+            ImportFormat::Claude => "claude",
+            ImportFormat::ShareGpt => "sharegpt",
+            ImportFormat::Alpaca => "alpaca",
+            ImportFormat::Generic => "generic",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedMessage {
+    pub content: String,
+    pub is_user: bool,
+    /// Unix milliseconds; None when the source has no timestamps
+    pub timestamp: Option<i64>,
+    /// Index of the parent in `ImportedConversation::messages` (None = first message)
+    pub parent: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedConversation {
+    pub format: ImportFormat,
+    /// Id in the source tool, used to skip conversations that were already imported
+    pub external_id: Option<String>,
+    pub title: String,
+    pub created_at: Option<i64>,
+    /// Parents always come before their children
+    pub messages: Vec<ImportedMessage>,
+    /// Last message of the branch that was visible in the source tool
+    pub active_leaf: Option<usize>,
+}
+
+impl ImportedConversation {
+    /// `external_id` prefixed with the format, as stored in `sessions.external_id`
+    pub fn source_key(&self) -> Option<String> {
+        self.external_id.as_ref().map(|id| format!("{}:{}", self.format.as_str(), id))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    pub sessions: usize,
+    pub messages: usize,
+    /// Conversations imported before (same source and id)
+    pub skipped: usize,
+    /// Records that could not be understood, with the reason
+    pub errors: Vec<String>,
+}
+
+/// Parse a whole export file. Records that are not conversations are reported in the
+/// second value instead of failing the whole file.
+pub fn parse(data: &str, format: ImportFormat) -> Result<(Vec<ImportedConversation>, Vec<String>), String> {
+    let records = split_records(data)?;
+    let mut conversations = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, record) in records.iter().enumerate() {
+        let record_format = match format {
+            ImportFormat::Auto => match detect_format(record) {
+                Some(detected) => detected,
+                None => {
+                    errors.push(format!("Запись {}: неизвестный формат", index + 1));
+                    continue;
+                }
+            },
+            explicit => explicit,
+        };
+
+        match parse_record(record, record_format) {
+            Ok(conversation) if conversation.messages.is_empty() => {
+                errors.push(format!("Запись {}: нет сообщений", index + 1));
+            }
+            Ok(conversation) => conversations.push(conversation),
+            Err(e) => errors.push(format!("Запись {}: {}", index + 1, e)),
+        }
+    }
+
+    Ok((conversations, errors))
+}
+
+/// JSON array, single object, an object wrapping an array, or JSONL
+fn split_records(data: &str) -> Result<Vec<Value>, String> {
+    let data = data.trim_start_matches('\u{feff}');
+    match serde_json::from_str::<Value>(data) {
+        Ok(Value::Array(items)) => Ok(items),
+        Ok(Value::Object(object)) => {
+            let value = Value::Object(object);
+            if detect_format(&value).is_some() {
+                return Ok(vec![value]);
+            }
+            ["conversations", "data", "items"].iter()
+                .find_map(|key| value.get(*key).and_then(Value::as_array).cloned())
+                .ok_or_else(|| "В файле не найдено ни одного диалога".to_string())
+        }
+        Ok(_) => Err("В файле не найдено ни одного диалога".to_string()),
+        Err(json_error) => data.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_str::<Value>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Файл не является JSON или JSONL: {}", json_error)),
+    }
+}
+
+pub fn detect_format(record: &Value) -> Option<ImportFormat> {
+    if record.get("mapping").is_some_and(Value::is_object) {
+        Some(ImportFormat::ChatGpt)
+    } else if record.get("chat_messages").is_some_and(Value::is_array) {
+        Some(ImportFormat::Claude)
+    } else if record.get("conversations").and_then(Value::as_array)
+        .is_some_and(|turns| turns.iter().any(|t| t.get("from").is_some()))
+    {
+        Some(ImportFormat::ShareGpt)
+    } else if record.get("instruction").is_some() && record.get("output").is_some() {
+        Some(ImportFormat::Alpaca)
+    } else if record.get("messages").is_some_and(Value::is_array) {
+        Some(ImportFormat::Generic)
+    } else {
+        None
+    }
+}
+
+fn parse_record(record: &Value, format: ImportFormat) -> Result<ImportedConversation, String> {
+    match format {
+        ImportFormat::ChatGpt => parse_chatgpt(record),
+        ImportFormat::Claude => parse_claude(record),
+        ImportFormat::ShareGpt => parse_sharegpt(record),
+        ImportFormat::Alpaca => parse_alpaca(record),
+        ImportFormat::Generic => parse_generic(record),
+        ImportFormat::Auto => detect_format(record)
+            .map(|format| parse_record(record, format))
+            .unwrap_or_else(|| Err("неизвестный формат".to_string())),
+    }
+}
+
+// ==================== ChatGPT ====================
+
+/// `mapping` is a tree of nodes {id, parent, children, message}. The whole tree is
+/// kept (edits and regenerations become branches); system, tool and hidden nodes are
+/// dropped and their children attached to the nearest kept ancestor.
+fn parse_chatgpt(record: &Value) -> Result<ImportedConversation, String> {
+    let mapping = record.get("mapping").and_then(Value::as_object)
+        .ok_or("нет поля mapping")?;
+
+    let roots: Vec<&String> = mapping.iter()
+        .filter(|(_, node)| node.get("parent").and_then(Value::as_str).is_none_or(|p| !mapping.contains_key(p)))
+        .map(|(id, _)| id)
+        .collect();
+
+    let mut messages = Vec::new();
+    let mut index_of: HashMap<&str, usize> = HashMap::new();
+    // Depth-first from the roots, so parents are always emitted first
+    let mut stack: Vec<(&String, Option<usize>)> = roots.into_iter().rev().map(|id| (id, None)).collect();
+    let mut visited: HashSet<&str> = HashSet::new();
+
+    while let Some((id, parent)) = stack.pop() {
+        if !visited.insert(id.as_str()) {
+            return Err(format!("цикл в mapping (узел {})", id));
+        }
+        let node = &mapping[id];
+        let mut kept_parent = parent;
+
+        if let Some(message) = node.get("message").filter(|m| !m.is_null()) {
+            if let Some(imported) = chatgpt_message(message, parent) {
+                index_of.insert(id.as_str(), messages.len());
+                kept_parent = Some(messages.len());
+                messages.push(imported);
+            }
+        }
+
+        let children = node.get("children").and_then(Value::as_array).cloned().unwrap_or_default();
+        for child in children.iter().rev().filter_map(Value::as_str) {
+            if let Some((child_id, _)) = mapping.get_key_value(child) {
+                stack.push((child_id, kept_parent));
+            }
+        }
+    }
+
+    // current_node may be a hidden node; walk up to the nearest kept message
+    let mut active_leaf = None;
+    let mut cursor = record.get("current_node").and_then(Value::as_str);
+    let mut walked: HashSet<&str> = HashSet::new();
+    while let Some(id) = cursor {
+        if !walked.insert(id) {
+            return Err(format!("цикл в mapping (узел {})", id));
+        }
+        if let Some(&index) = index_of.get(id) {
+            active_leaf = Some(index);
+            break;
+        }
+        cursor = mapping.get(id).and_then(|node| node.get("parent")).and_then(Value::as_str);
+    }
+
+    Ok(ImportedConversation {
+        format: ImportFormat::ChatGpt,
+        external_id: string_field(record, &["conversation_id", "id"]),
+        title: title_or_default(record.get("title").and_then(Value::as_str), &messages),
+        created_at: record.get("create_time").and_then(parse_timestamp),
+        messages,
+        active_leaf,
+    })
+}
+
+fn chatgpt_message(message: &Value, parent: Option<usize>) -> Option<ImportedMessage> {
+    let is_user = match message.pointer("/author/role").and_then(Value::as_str)? {
+        "user" => true,
+        "assistant" => false,
+        _ => return None,
+    };
+    let hidden = message.pointer("/metadata/is_visually_hidden_from_conversation")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if hidden {
+        return None;
+    }
+
+    let content = message.get("content")?;
+    let text = match content.get("parts").and_then(Value::as_array) {
+        // multimodal parts mix strings with image/audio objects; only text is kept
+        Some(parts) => parts.iter()
+            .filter_map(|part| part.as_str().or_else(|| part.get("text").and_then(Value::as_str)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => content.get("text").and_then(Value::as_str).unwrap_or_default().to_string(),
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+
+    Some(ImportedMessage {
+        content: text.to_string(),
+        is_user,
+        timestamp: message.get("create_time").and_then(parse_timestamp),
+        parent,
+    })
+}
+
+// ==================== Claude ====================
+
+fn parse_claude(record: &Value) -> Result<ImportedConversation, String> {
+    let turns = record.get("chat_messages").and_then(Value::as_array)
+        .ok_or("нет поля chat_messages")?;
+
+    let items = turns.iter().filter_map(|turn| {
+        let is_user = role_is_user(turn.get("sender").and_then(Value::as_str)?)?;
+        let text = match turn.get("text").and_then(Value::as_str).filter(|t| !t.trim().is_empty()) {
+            Some(text) => text.to_string(),
+            None => turn.get("content").and_then(Value::as_array)?
+                .iter()
+                .filter(|block| block.get("type").and_then(Value::as_str) == Some("text"))
+                .filter_map(|block| block.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        let timestamp = turn.get("created_at").and_then(parse_timestamp);
+        Some((text, is_user, timestamp))
+    });
+
+    Ok(linear_conversation(
+        ImportFormat::Claude,
+        string_field(record, &["uuid", "id"]),
+        record.get("name").and_then(Value::as_str),
+        record.get("created_at").and_then(parse_timestamp),
+        items,
+    ))
+}
+
+// ==================== ShareGPT / Alpaca / generic ====================
+
+fn parse_sharegpt(record: &Value) -> Result<ImportedConversation, String> {
+    let turns = record.get("conversations").and_then(Value::as_array)
+        .ok_or("нет поля conversations")?;
+
+    let items = turns.iter().filter_map(|turn| {
+        let is_user = role_is_user(turn.get("from").and_then(Value::as_str)?)?;
+        let text = turn.get("value").and_then(Value::as_str)?.to_string();
+        Some((text, is_user, None))
+    });
+
+    Ok(linear_conversation(
+        ImportFormat::ShareGpt,
+        string_field(record, &["id"]),
+        record.get("title").and_then(Value::as_str),
+        None,
+        items,
+    ))
+}
+
+/// One conversation per record: the instruction (plus input) and the answer
+fn parse_alpaca(record: &Value) -> Result<ImportedConversation, String> {
+    let instruction = record.get("instruction").and_then(Value::as_str).ok_or("нет поля instruction")?;
+    let output = record.get("output").and_then(Value::as_str).ok_or("нет поля output")?;
+    let prompt = match record.get("input").and_then(Value::as_str).map(str::trim) {
+        Some(input) if !input.is_empty() => format!("{}\n\n{}", instruction.trim(), input),
+        _ => instruction.to_string(),
+    };
+
+    let items = [(prompt, true, None), (output.to_string(), false, None)];
+    Ok(linear_conversation(ImportFormat::Alpaca, None, None, None, items.into_iter()))
+}
+
+fn parse_generic(record: &Value) -> Result<ImportedConversation, String> {
+    let turns = record.get("messages").and_then(Value::as_array).ok_or("нет поля messages")?;
+
+    let items = turns.iter().filter_map(|turn| {
+        let role = turn.get("role").or_else(|| turn.get("author")).or_else(|| turn.get("sender"))?;
+        let is_user = role_is_user(role.as_str()?)?;
+        let text = match turn.get("content")? {
+            Value::String(text) => text.clone(),
+            // OpenAI-style content parts
+            Value::Array(parts) => parts.iter()
+                .filter_map(|part| part.as_str().or_else(|| part.get("text").and_then(Value::as_str)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => return None,
+        };
+        let timestamp = ["timestamp", "created_at", "create_time", "time"].iter()
+            .find_map(|key| turn.get(*key).and_then(parse_timestamp));
+        Some((text, is_user, timestamp))
+    });
+
+    Ok(linear_conversation(
+        ImportFormat::Generic,
+        string_field(record, &["id", "uuid"]),
+        record.get("title").or_else(|| record.get("name")).and_then(Value::as_str),
+        ["created_at", "create_time", "timestamp"].iter().find_map(|key| record.get(*key).and_then(parse_timestamp)),
+        items,
+    ))
+}
+
+// ==================== Helpers ====================
+
+/// Chain (text, is_user, timestamp) turns into a single branch, dropping empty ones
+fn linear_conversation(
+    format: ImportFormat,
+    external_id: Option<String>,
+    title: Option<&str>,
+    created_at: Option<i64>,
+    items: impl Iterator<Item = (String, bool, Option<i64>)>,
+) -> ImportedConversation {
+    let mut messages: Vec<ImportedMessage> = Vec::new();
+    for (text, is_user, timestamp) in items {
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let parent = messages.len().checked_sub(1);
+        messages.push(ImportedMessage { content: text.to_string(), is_user, timestamp, parent });
+    }
+
+    ImportedConversation {
+        format,
+        external_id,
+        title: title_or_default(title, &messages),
+        created_at,
+        active_leaf: messages.len().checked_sub(1),
+        messages,
+    }
+}
+
+/// true = user, false = assistant, None = system/tool/unknown (not imported)
+fn role_is_user(role: &str) -> Option<bool> {
+    match role.to_lowercase().as_str() {
+        "user" | "human" | "prompter" => Some(true),
+        "assistant" | "gpt" | "model" | "bot" | "chatgpt" | "claude" | "ai" => Some(false),
+        _ => None,
+    }
+}
+
+fn string_field(record: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| match record.get(*key)? {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    })
+}
+
+/// The source title, or the start of the first user message
+fn title_or_default(title: Option<&str>, messages: &[ImportedMessage]) -> String {
+    let title = title.map(str::trim).filter(|t| !t.is_empty())
+        .or_else(|| messages.iter().find(|m| m.is_user).and_then(|m| m.content.lines().next()))
+        .unwrap_or("Импортированный чат");
+
+    if title.chars().count() > MAX_TITLE_CHARS {
+        format!("{}…", title.chars().take(MAX_TITLE_CHARS).collect::<String>().trim_end())
+    } else {
+        title.to_string()
+    }
+}
+
+/// Unix seconds or milliseconds (numbers, fractional allowed) or an RFC 3339 string, as milliseconds
+pub fn parse_timestamp(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => {
+            let n = n.as_f64()?;
+            // Anything below ~5138 AD in seconds is treated as seconds
+            Some(if n.abs() < 1e11 { (n * 1000.0) as i64 } else { n as i64 })
+        }
+        Value::String(s) => chrono::DateTime::parse_from_rfc3339(s.trim())
+            .map(|dt| dt.timestamp_millis())
+            .ok()
+            .or_else(|| s.trim().parse::<f64>().ok().and_then(|n| parse_timestamp(&serde_json::json!(n)))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHATGPT: &str = r#"[{
+        "title": "Черепахи",
+        "create_time": 1700000000.5,
+        "conversation_id": "abc",
+        "current_node": "a2",
+        "mapping": {
+            "root": {"id": "root", "message": null, "parent": null, "children": ["sys"]},
+            "sys": {"id": "sys", "parent": "root", "children": ["u1"],
+                    "message": {"author": {"role": "system"}, "content": {"content_type": "text", "parts": [""]}}},
+            "u1": {"id": "u1", "parent": "sys", "children": ["a1", "a2"],
+                   "message": {"author": {"role": "user"}, "create_time": 1700000001, "content": {"content_type": "text", "parts": ["Сколько живут черепахи?"]}}},
+            "a1": {"id": "a1", "parent": "u1", "children": [],
+                   "message": {"author": {"role": "assistant"}, "create_time": 1700000002, "content": {"content_type": "text", "parts": ["Долго."]}}},
+            "a2": {"id": "a2", "parent": "u1", "children": [],
+                   "message": {"author": {"role": "assistant"}, "create_time": 1700000003, "content": {"content_type": "multimodal_text", "parts": ["До 150 лет.", {"content_type": "image_asset_pointer"}]}}}
+        }
+    }]"#;
+
+    #[test]
+    fn test_chatgpt_tree_and_active_branch() {
+        let (conversations, errors) = parse(CHATGPT, ImportFormat::Auto).unwrap();
+        assert!(errors.is_empty());
+        let conv = &conversations[0];
+
+        assert_eq!(conv.format, ImportFormat::ChatGpt);
+        assert_eq!(conv.source_key().as_deref(), Some("chatgpt:abc"));
+        assert_eq!(conv.title, "Черепахи");
+        assert_eq!(conv.created_at, Some(1_700_000_000_500));
+        assert_eq!(conv.messages.len(), 3, "system message dropped");
+        assert_eq!(conv.messages[0].parent, None, "user message re-attached to the root");
+        assert!(conv.messages[1..].iter().all(|m| m.parent == Some(0)));
+
+        let leaf = &conv.messages[conv.active_leaf.unwrap()];
+        assert_eq!(leaf.content, "До 150 лет.");
+        assert_eq!(leaf.timestamp, Some(1_700_000_003_000));
+    }
+
+    #[test]
+    fn test_chatgpt_cyclic_mapping_is_an_error() {
+        let node = |id: &str, parent: &str, child: &str| serde_json::json!({
+            "id": id, "parent": parent, "children": [child],
+            "message": {"author": {"role": "user"}, "content": {"parts": [id]}}
+        });
+        // a <-> b with no root, reachable only through current_node
+        let detached = serde_json::json!({"current_node": "a", "mapping": {"a": node("a", "b", "b"), "b": node("b", "a", "a")}});
+        assert!(parse_chatgpt(&detached).unwrap_err().contains("цикл"));
+
+        // root -> a -> b -> a
+        let mut reachable = detached.clone();
+        reachable["mapping"]["root"] = serde_json::json!({"id": "root", "parent": null, "children": ["a"], "message": null});
+        reachable["mapping"]["a"]["parent"] = "root".into();
+        assert!(parse_chatgpt(&reachable).unwrap_err().contains("цикл"));
+    }
+
+    #[test]
+    fn test_claude_export() {
+        let data = r#"[{"uuid": "c-1", "name": "", "created_at": "2024-03-01T10:00:00.000000Z",
+            "chat_messages": [
+                {"sender": "human", "text": "Привет", "created_at": "2024-03-01T10:00:01Z"},
+                {"sender": "assistant", "text": "", "content": [{"type": "text", "text": "Здравствуйте!"}], "created_at": "2024-03-01T10:00:02Z"}
+            ]}]"#;
+        let (conversations, _) = parse(data, ImportFormat::Auto).unwrap();
+        let conv = &conversations[0];
+
+        assert_eq!(conv.format, ImportFormat::Claude);
+        assert_eq!(conv.title, "Привет", "empty name falls back to the first user message");
+        assert_eq!(conv.messages[1].content, "Здравствуйте!");
+        assert_eq!(conv.messages[1].parent, Some(0));
+        assert_eq!(conv.messages[0].timestamp, Some(1_709_287_201_000));
+    }
+
+    #[test]
+    fn test_own_sharegpt_and_alpaca_exports() {
+        let sharegpt = r#"[{"id": "session_7", "conversations": [
+            {"from": "system", "value": "ignored"},
+            {"from": "human", "value": "Вопрос"},
+            {"from": "gpt", "value": "Ответ"}]}]"#;
+        let (conversations, _) = parse(sharegpt, ImportFormat::Auto).unwrap();
+        assert_eq!(conversations[0].format, ImportFormat::ShareGpt);
+        assert_eq!(conversations[0].messages.len(), 2);
+        assert_eq!(conversations[0].active_leaf, Some(1));
+
+        let alpaca = "{\"instruction\": \"Переведи\", \"input\": \"cat\", \"output\": \"кот\"}\n\
+                      {\"instruction\": \"Сложи 2 и 2\", \"input\": \"\", \"output\": \"4\"}\n";
+        let (conversations, errors) = parse(alpaca, ImportFormat::Auto).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(conversations.len(), 2, "one session per record");
+        assert_eq!(conversations[0].messages[0].content, "Переведи\n\ncat");
+        assert_eq!(conversations[1].messages[1].content, "4");
+    }
+
+    #[test]
+    fn test_generic_messages_and_errors() {
+        let data = r#"{"conversations": [
+            {"title": "Fine-tune", "messages": [
+                {"role": "system", "content": "be nice"},
+                {"role": "user", "content": [{"type": "text", "text": "hi"}], "timestamp": 1700000000000},
+                {"role": "assistant", "content": "hello"}]},
+            {"something": "else"}
+        ]}"#;
+        let (conversations, errors) = parse(data, ImportFormat::Auto).unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].messages[0].timestamp, Some(1_700_000_000_000));
+        assert_eq!(errors.len(), 1);
+
+        assert!(parse("not json at all", ImportFormat::Auto).is_err());
+    }
+
+    #[test]
+    fn test_import_format_deserialization() {
+        let format: ImportFormat = serde_json::from_str("\"chatgpt\"").unwrap();
+        assert_eq!(format, ImportFormat::ChatGpt);
+        let report = ImportReport { sessions: 1, ..Default::default() };
+        assert!(serde_json::to_string(&report).unwrap().contains("\"skipped\":0"));
+    }
+}
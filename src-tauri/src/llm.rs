@@ -9,9 +9,11 @@ use llama_cpp_2::token::data_array::LlamaTokenDataArray;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::num::NonZeroU32;
-use std::sync::Mutex;
+use std::sync::{mpsc, Mutex};
 use std::sync::atomic::{AtomicU32, Ordering};
 
+use crate::sampling::SamplingParams;
+
 static BACKEND: OnceCell<LlamaBackend> = OnceCell::new();
 static MODEL: OnceCell<Mutex<Option<LlamaModel>>> = OnceCell::new();
 static MODEL_PATH: OnceCell<Mutex<Option<String>>> = OnceCell::new();
@@ -31,6 +33,27 @@ pub struct GpuInfo {
     pub vram_free_mb: u64,
 }
 
+/// Why a generation run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// EOS token or a stop sequence
+    Stop,
+    /// `max_tokens` reached — the answer is truncated and can be continued
+    Length,
+    /// Stopped by the user
+    Cancelled,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::Cancelled => "cancelled",
+        }
+    }
+}
+
 /// Stop sequences for ChatML format
 const STOP_SEQUENCES: &[&str] = &[
     "<|im_end|>",
@@ -39,6 +62,9 @@ const STOP_SEQUENCES: &[&str] = &[
     "<|endoftext|>",
 ];
 
+/// How many recent tokens the repeat penalty looks at
+const REPEAT_PENALTY_LAST_N: i32 = 64;
+
 /// Default CPU threads when detection fails
 const DEFAULT_CPU_THREADS: i32 = 4;
 
@@ -55,7 +81,7 @@ fn next_seed() -> u32 {
     SEED_COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
-/// Sample a token with temperature using the new Sampler API
+/// Build the sampler chain for one generation run
 /// 
 /// Temperature controls randomness:
 /// - temp = 0.0: greedy (always pick highest probability)
@@ -63,22 +89,34 @@ fn next_seed() -> u32 {
 /// - temp = 0.5-1.0: balanced creativity
 /// - temp > 1.0: more random, creative
 /// 
-/// Returns None if sampling fails (should be rare but handled gracefully)
-fn sample_with_temperature(candidates: &mut LlamaTokenDataArray, temperature: f32) -> Option<llama_cpp_2::token::LlamaToken> {
-    if temperature <= 0.0 {
+/// top_k / top_p / min_p / repeat_penalty are only added when they differ from "off".
+/// The chain keeps state (penalty history), so it lives for the whole run.
+fn build_sampler(params: &SamplingParams) -> LlamaSampler {
+    let mut chain = Vec::new();
+    
+    if params.repeat_penalty > 0.0 && params.repeat_penalty != 1.0 {
+        chain.push(LlamaSampler::penalties(REPEAT_PENALTY_LAST_N, params.repeat_penalty, 0.0, 0.0));
+    }
+    
+    if params.temperature <= 0.0 {
         // Greedy sampling - pick the most likely token
-        candidates.apply_sampler(&LlamaSampler::greedy());
-        candidates.selected_token()
-    } else {
-        // Create a sampler chain: temperature -> random distribution
-        let mut sampler = LlamaSampler::chain_simple([
-            LlamaSampler::temp(temperature),
-            LlamaSampler::dist(next_seed()),
-        ]);
-        
-        candidates.apply_sampler(&mut sampler);
-        candidates.selected_token()
+        chain.push(LlamaSampler::greedy());
+        return LlamaSampler::chain_simple(chain);
+    }
+    
+    if params.top_k > 0 {
+        chain.push(LlamaSampler::top_k(params.top_k));
     }
+    if params.top_p > 0.0 && params.top_p < 1.0 {
+        chain.push(LlamaSampler::top_p(params.top_p, 1));
+    }
+    if params.min_p > 0.0 {
+        chain.push(LlamaSampler::min_p(params.min_p, 1));
+    }
+    chain.push(LlamaSampler::temp(params.temperature));
+    chain.push(LlamaSampler::dist(next_seed()));
+    
+    LlamaSampler::chain_simple(chain)
 }
 
 pub fn init() {
@@ -272,7 +310,14 @@ pub fn is_loaded() -> bool {
         .unwrap_or(false)
 }
 
-pub fn generate<F>(prompt: &str, temperature: f32, max_tokens: usize, mut callback: F) -> Result<(), String>
+/// Path of the loaded model, if any
+pub fn current_model_path() -> Option<String> {
+    MODEL_PATH.get()
+        .and_then(|p| p.lock().ok())
+        .and_then(|guard| guard.clone())
+}
+
+pub fn generate<F>(prompt: &str, sampling: &SamplingParams, max_tokens: usize, mut callback: F) -> Result<FinishReason, String>
 where
     F: FnMut(String) -> bool,
 {
@@ -291,7 +336,7 @@ where
     
     let n_threads = cpu_thread_count();
     println!("Generating: {} chars, temp={}, max_tokens={}, ctx={}, threads={}",
-             prompt.len(), temperature, max_tokens, ctx_size, n_threads);
+             prompt.len(), sampling.temperature, max_tokens, ctx_size, n_threads);
     
     // Create context with multi-threaded CPU inference
     let ctx_params = LlamaContextParams::default()
@@ -331,24 +376,29 @@ where
     let mut n_cur = tokens.len();
     let mut accumulated = String::new();
     let mut decoder = encoding_rs::UTF_8.new_decoder();
+    let mut finish_reason = FinishReason::Length;
+    let mut sampler = build_sampler(sampling);
 
     for _ in 0..max_tokens {
         // Get logits for the last token
         let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
         let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
 
-        // Sample with temperature
-        let new_token = match sample_with_temperature(&mut candidates_p, temperature) {
+        candidates_p.apply_sampler(&sampler);
+        let new_token = match candidates_p.selected_token() {
             Some(token) => token,
             None => {
                 eprintln!("⚠️ Sampling failed, ending generation");
+                finish_reason = FinishReason::Stop;
                 break;
             }
         };
+        sampler.accept(new_token);
 
         // Check for EOS
         if model.is_eog_token(new_token) {
             println!("EOS token reached");
+            finish_reason = FinishReason::Stop;
             break;
         }
 
@@ -369,12 +419,14 @@ where
         if !clean_token.is_empty() {
             if !callback(clean_token) {
                 println!("Generation stopped by user");
+                finish_reason = FinishReason::Cancelled;
                 break;
             }
         }
         
         if should_stop {
             println!("Stop sequence detected");
+            finish_reason = FinishReason::Stop;
             break;
         }
         
@@ -388,8 +440,133 @@ where
             .map_err(|e| format!("Decode error: {:?}", e))?;
     }
     
-    println!("Generation complete. {} tokens generated", n_cur - tokens.len());
-    Ok(())
+    println!("Generation complete. {} tokens generated ({})", n_cur - tokens.len(), finish_reason.as_str());
+    Ok(finish_reason)
+}
+
+// ==================== EMBEDDINGS ====================
+
+/// Longest text an embedding model reads, in tokens; the rest is cut off
+const MAX_EMBEDDING_TOKENS: u32 = 2048;
+
+/// Texts to embed and where to send their vectors
+type EmbeddingRequest = (Vec<String>, mpsc::Sender<Result<Vec<Vec<f32>>, String>>);
+
+/// A GGUF embedding model (nomic-embed, bge, e5...) run in llama.cpp's embedding mode,
+/// separate from the chat model. The model and one context for it live on a thread of
+/// their own (a context borrows the model and can't be shared between threads); the
+/// context is created once and its KV cache cleared between texts. Dropping the
+/// embedder stops the thread and frees both.
+pub struct LlamaEmbedder {
+    requests: mpsc::Sender<EmbeddingRequest>,
+    dim: usize,
+}
+
+/// Load an embedding model; kept on the CPU, small models embed fast enough there
+pub fn load_embedding_model(path: &std::path::Path) -> Result<LlamaEmbedder, String> {
+    if !path.exists() {
+        return Err(format!("Model file not found: {}", path.display()));
+    }
+    let backend = BACKEND.get().ok_or("LLM backend not initialized")?;
+    let path = path.to_path_buf();
+    let (loaded_tx, loaded) = mpsc::channel();
+    let (requests, received) = mpsc::channel::<EmbeddingRequest>();
+
+    std::thread::Builder::new()
+        .name("embedding-model".to_string())
+        .spawn(move || {
+            let model = match LlamaModel::load_from_file(backend, &path, &LlamaModelParams::default()) {
+                Ok(model) => model,
+                Err(LlamaModelLoadError::NullResult) => {
+                    let _ = loaded_tx.send(Err(
+                        "Не удалось загрузить модель эмбеддингов. Убедитесь что файл GGUF корректен и не повреждён.".to_string()
+                    ));
+                    return;
+                }
+                Err(e) => {
+                    let _ = loaded_tx.send(Err(format!("Failed to load embedding model: {:?}", e)));
+                    return;
+                }
+            };
+            let n_ctx = model.n_ctx_train().clamp(1, MAX_EMBEDDING_TOKENS);
+            let n_threads = cpu_thread_count();
+            // A text goes through in one batch: encoder models can't split it
+            let ctx_params = LlamaContextParams::default()
+                .with_n_ctx(NonZeroU32::new(n_ctx))
+                .with_n_batch(n_ctx)
+                .with_n_ubatch(n_ctx)
+                .with_n_threads(n_threads)
+                .with_n_threads_batch(n_threads)
+                .with_embeddings(true);
+            let mut ctx = match model.new_context(backend, ctx_params) {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    let _ = loaded_tx.send(Err(format!("Failed to create context: {:?}", e)));
+                    return;
+                }
+            };
+            let mut batch = LlamaBatch::new(n_ctx as usize, 1);
+            let _ = loaded_tx.send(Ok(model.n_embd().max(0) as usize));
+
+            // Ends when the embedder is dropped
+            for (texts, reply) in received {
+                let vectors = texts.iter()
+                    .map(|text| {
+                        // An empty batch would only fail later with an opaque decode error
+                        if text.trim().is_empty() {
+                            return Err("Cannot embed empty text".to_string());
+                        }
+                        let mut tokens = model.str_to_token(text, llama_cpp_2::model::AddBos::Always)
+                            .map_err(|e| format!("Tokenization error: {:?}", e))?;
+                        if tokens.is_empty() {
+                            return Err("Empty text after tokenization".to_string());
+                        }
+                        tokens.truncate(n_ctx as usize);
+
+                        batch.clear();
+                        for (i, token) in tokens.iter().enumerate() {
+                            batch.add(*token, i as i32, &[0], true)
+                                .map_err(|e| format!("Batch add error: {:?}", e))?;
+                        }
+                        ctx.clear_kv_cache();
+                        ctx.decode(&mut batch)
+                            .map_err(|e| format!("Decode error: {:?}", e))?;
+
+                        let embedding = ctx.embeddings_seq_ith(0)
+                            .map_err(|e| format!("Embedding failed: {:?}", e))?;
+                        Ok(normalized(embedding))
+                    })
+                    .collect();
+                let _ = reply.send(vectors);
+            }
+        })
+        .map_err(|e| format!("Failed to start embedding model thread: {}", e))?;
+
+    let dim = loaded.recv().map_err(|_| "Embedding model thread stopped".to_string())??;
+    Ok(LlamaEmbedder { requests, dim })
+}
+
+impl LlamaEmbedder {
+    /// Vector dimension
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// One normalized vector per text, pooled as the model file says (mean, CLS...)
+    pub fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        let (reply, vectors) = mpsc::channel();
+        self.requests.send((texts.to_vec(), reply))
+            .map_err(|_| "Embedding model thread stopped".to_string())?;
+        vectors.recv().map_err(|_| "Embedding model thread stopped".to_string())?
+    }
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
 }
 
 // ==================== TESTS ====================
@@ -496,8 +673,17 @@ mod tests {
         assert_eq!(clean, "текст");
     }
 
+    // ==================== Finish Reason Tests ====================
+
+    #[test]
+    fn test_finish_reason_strings() {
+        assert_eq!(FinishReason::Stop.as_str(), "stop");
+        assert_eq!(FinishReason::Length.as_str(), "length");
+        assert_eq!(FinishReason::Cancelled.as_str(), "cancelled");
+    }
+
     // ==================== Temperature Behavior Tests ====================
-    // Note: Can't test sample_with_temperature directly without model,
+    // Note: Can't test build_sampler directly without model,
     // but we can test the logic boundaries
 
     #[test]
@@ -634,4 +820,12 @@ mod tests {
         assert_eq!(prompt.len(), 10000);
         // In real scenario, this would be truncated to fit context
     }
+
+    // ==================== Embedding Tests ====================
+
+    #[test]
+    fn test_embedding_normalized() {
+        assert_eq!(normalized(&[3.0, 4.0]), vec![0.6, 0.8]);
+        assert_eq!(normalized(&[0.0, 0.0]), vec![0.0, 0.0]);
+    }
 }
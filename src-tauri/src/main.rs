@@ -1,13 +1,28 @@
 // Prevents additional console window on Windows in release
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod backup;
 mod commands;
 mod database;
-#[cfg(feature = "embeddings")]
+mod dataset;
+mod embedding_models;
+#[cfg(feature = "semantic-search")]
 mod embeddings;
+mod encryption;
+mod export_stream;
+mod fts_query;
 mod hf_models;
+mod importers;
 #[cfg(feature = "native-llm")]
 mod llm;
+mod migrations;
+mod quantization;
+mod redaction;
+mod retrieval;
+mod sampling;
+mod transcript;
+#[cfg(feature = "semantic-search")]
+mod vector_index;
 mod voice;
 
 use tauri::Manager;
@@ -33,7 +48,10 @@ fn main() {
             }
             
             let db_path = app_dir.join("wishmaster.db");
-            if let Err(e) = database::init(&db_path) {
+            if encryption::is_enabled(&app_dir) {
+                // Opened by unlock_database once the user enters the passphrase
+                println!("🔒 Database is encrypted, waiting for passphrase");
+            } else if let Err(e) = database::init(&db_path) {
                 eprintln!("Failed to initialize database: {}", e);
                 return Err(Box::new(std::io::Error::new(
                     std::io::ErrorKind::Other,
@@ -41,6 +59,9 @@ fn main() {
                 )));
             }
             
+            // Scheduled snapshots (off unless enabled in settings)
+            backup::start_scheduler(app_dir.clone());
+            
             // Initialize native LLM engine (only when built with native-llm)
             #[cfg(feature = "native-llm")]
             llm::init();
@@ -49,21 +70,14 @@ fn main() {
             voice::init();
             
             // Initialize embedding model (async, non-blocking)
-            #[cfg(feature = "embeddings")]
-            std::thread::spawn(|| {
-                if let Err(e) = embeddings::init_embedder() {
-                    eprintln!("Warning: Failed to initialize embeddings: {}", e);
-                } else {
-                    println!("🔍 Semantic search ready");
-                }
-            });
+            commands::start_embedder(app.handle().clone(), app_dir.clone());
             
             println!("🧞 Wishmaster Desktop started!");
             println!("📚 Memory system active - all conversations will be remembered");
-            #[cfg(feature = "embeddings")]
+            #[cfg(feature = "semantic-search")]
             println!("🔍 RAG/Vector search enabled");
-            #[cfg(not(feature = "embeddings"))]
-            println!("⚠️ Semantic search disabled (build without embeddings feature)");
+            #[cfg(not(feature = "semantic-search"))]
+            println!("⚠️ Semantic search disabled (build without embeddings and native-llm features)");
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -82,14 +96,45 @@ fn main() {
             commands::get_sessions,
             commands::create_session,
             commands::delete_session,
+            commands::rename_session,
+            commands::set_session_pinned,
+            commands::set_session_archived,
+            commands::set_session_folder,
+            commands::set_session_tags,
+            commands::get_session_folders,
+            commands::get_session_tags,
+            commands::get_session_settings,
+            commands::save_session_settings,
+            commands::activate_session,
+            // Assistants
+            commands::get_assistants,
+            commands::create_assistant,
+            commands::update_assistant,
+            commands::delete_assistant,
             // Messages
             commands::get_messages,
             commands::save_message,
+            commands::update_message,
+            commands::delete_message,
+            commands::get_message_alternatives,
+            commands::select_alternative,
+            commands::edit_message,
+            commands::switch_branch,
+            commands::get_message_tree,
+            // Feedback
+            commands::set_message_feedback,
+            commands::get_message_feedback,
+            commands::get_feedback,
             // Generation (with memory)
             commands::generate,
+            commands::regenerate_response,
+            commands::continue_response,
+            commands::generate_candidates,
             commands::stop_generation,
             // MEMORY SYSTEM
             commands::search_all_messages,
+            commands::search_messages,
+            commands::search,
             commands::get_recent_global_messages,
             commands::add_memory,
             commands::get_all_memories,
@@ -103,12 +148,39 @@ fn main() {
             commands::export_all_data,
             commands::export_alpaca_format,
             commands::export_sharegpt_format,
+            commands::export_openai_format,
+            commands::export_chatml_format,
+            commands::export_preference_pairs,
             commands::get_data_stats,
             commands::export_to_file,
+            commands::export_stream,
+            commands::cancel_export,
+            commands::build_dataset,
+            commands::get_transcript,
+            commands::export_transcript,
+            commands::export_transcripts,
+            // IMPORT (other tools, full exports)
+            commands::import_conversations,
+            commands::import_full_export,
+            // Backup
+            commands::create_backup,
+            commands::list_backups,
+            commands::restore_backup,
+            commands::delete_backup,
+            // Encryption
+            commands::get_encryption_status,
+            commands::unlock_database,
+            commands::enable_encryption,
+            commands::change_passphrase,
+            commands::decrypt_file,
             // SEMANTIC SEARCH (RAG)
             commands::find_rag_context,
+            commands::retrieve_context,
             commands::index_all_messages,
             commands::get_embedding_stats,
+            commands::get_embedding_models,
+            commands::get_embedder_state,
+            commands::reindex_embeddings,
             // Voice
             commands::get_voice_profiles,
             commands::create_voice_profile,
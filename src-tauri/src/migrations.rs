@@ -0,0 +1,734 @@
+//! Versioned schema migrations for wishmaster.db.
+//!
+//! The schema version lives in `PRAGMA user_version`. Every schema change is a new
+//! entry at the end of `MIGRATIONS`; released entries are never edited. Databases
+//! created before versioning existed report version 0, and may already contain some
+//! of the later columns, so migrations tolerate objects that are already there.
+
+use rusqlite::{params, Connection, Result};
+use std::path::{Path, PathBuf};
+
+use crate::quantization;
+
+/// One schema step. `up` runs inside a transaction together with the version bump.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    up: fn(&Connection) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial schema", up: initial_schema },
+    Migration { version: 2, name: "conversation trees", up: conversation_trees },
+    Migration { version: 3, name: "session organisation", up: session_organisation },
+    Migration { version: 4, name: "session settings", up: session_settings },
+    Migration { version: 5, name: "assistants", up: assistants },
+    Migration { version: 6, name: "import sources", up: import_sources },
+    Migration { version: 7, name: "message feedback", up: message_feedback },
+    Migration { version: 8, name: "message models", up: message_models },
+    Migration { version: 9, name: "memory and title search", up: memory_and_title_search },
+    Migration { version: 10, name: "quantized embeddings", up: quantized_embeddings },
+    Migration { version: 11, name: "embedding models", up: embedding_models },
+    Migration { version: 12, name: "memory feedback shifts", up: memory_feedback_shifts },
+];
+
+/// Version the schema has after all migrations
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Bring the database up to the latest version. If anything has to be migrated and the
+/// database already holds data, a copy is written next to it first.
+/// Returns the path of that backup, if one was made.
+pub fn run(conn: &mut Connection, db_path: &Path) -> Result<Option<PathBuf>> {
+    run_migrations(conn, db_path, MIGRATIONS)
+}
+
+fn run_migrations(conn: &mut Connection, db_path: &Path, migrations: &[Migration]) -> Result<Option<PathBuf>> {
+    let current = schema_version(conn)?;
+    let latest = migrations.last().map(|m| m.version).unwrap_or(0);
+
+    if current > latest {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+            Some(format!(
+                "База данных создана более новой версией приложения (схема v{}, поддерживается v{})",
+                current, latest
+            )),
+        ));
+    }
+    if current == latest {
+        return Ok(None);
+    }
+
+    let backup = if has_tables(conn)? && db_path.is_file() {
+        Some(backup_before_migration(conn, db_path, current)?)
+    } else {
+        None
+    };
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        println!("Migrating database to v{} ({})", migration.version, migration.name);
+        let tx = conn.transaction()?;
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+
+    Ok(backup)
+}
+
+fn has_tables(conn: &Connection) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table'",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Consistent copy of the database (VACUUM INTO works on an open connection),
+/// e.g. `wishmaster.db.v2-1718000000000.bak`. With SQLCipher the copy is encrypted
+/// with the same key, as the target is attached without a key of its own.
+fn backup_before_migration(conn: &Connection, db_path: &Path, version: u32) -> Result<PathBuf> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let file_name = db_path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "wishmaster.db".to_string());
+    let backup_path = db_path.with_file_name(format!("{}.v{}-{}.bak", file_name, version, timestamp));
+
+    conn.execute("VACUUM INTO ?1", params![backup_path.to_string_lossy()])?;
+    println!("Database backup before migration: {}", backup_path.display());
+
+    Ok(backup_path)
+}
+
+/// Add a column to an existing table unless it is already there
+fn ensure_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition))?;
+    }
+
+    Ok(())
+}
+
+// ==================== v1: initial schema ====================
+
+/// The schema as it was first released
+fn initial_schema(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+        -- Core tables
+        CREATE TABLE IF NOT EXISTS sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL DEFAULT 'Новый чат',
+            created_at INTEGER NOT NULL,
+            message_count INTEGER NOT NULL DEFAULT 0
+        );
+
+        CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            session_id INTEGER NOT NULL,
+            content TEXT NOT NULL,
+            is_user INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS voice_profiles (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            audio_path TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS voice_recordings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+
+        -- MEMORY SYSTEM: Long-term memory across all sessions
+        CREATE TABLE IF NOT EXISTS memory (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            content TEXT NOT NULL,
+            category TEXT NOT NULL DEFAULT 'fact',
+            source_session_id INTEGER,
+            source_message_id INTEGER,
+            importance INTEGER NOT NULL DEFAULT 5,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (source_session_id) REFERENCES sessions(id) ON DELETE SET NULL
+        );
+
+        -- USER PERSONA: Digital twin data
+        CREATE TABLE IF NOT EXISTS user_persona (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            writing_style TEXT NOT NULL DEFAULT 'casual',
+            avg_message_length REAL NOT NULL DEFAULT 0,
+            common_phrases TEXT NOT NULL DEFAULT '[]',
+            topics_of_interest TEXT NOT NULL DEFAULT '[]',
+            language TEXT NOT NULL DEFAULT 'ru',
+            emoji_usage TEXT NOT NULL DEFAULT 'minimal',
+            tone TEXT NOT NULL DEFAULT 'friendly',
+            messages_analyzed INTEGER NOT NULL DEFAULT 0,
+            last_updated INTEGER NOT NULL
+        );
+
+        -- Indexes for fast search
+        CREATE INDEX IF NOT EXISTS idx_messages_session ON messages(session_id);
+        CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp DESC);
+        CREATE INDEX IF NOT EXISTS idx_memory_category ON memory(category);
+        CREATE INDEX IF NOT EXISTS idx_memory_importance ON memory(importance DESC);
+        CREATE INDEX IF NOT EXISTS idx_memory_session ON memory(source_session_id);
+        CREATE INDEX IF NOT EXISTS idx_memory_created ON memory(created_at DESC);
+
+        -- Full-text search virtual table
+        CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+            content,
+            content='messages',
+            content_rowid='id'
+        );
+
+        -- Triggers to keep FTS in sync
+        CREATE TRIGGER IF NOT EXISTS messages_ai AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS messages_ad AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES('delete', old.id, old.content);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS messages_au AFTER UPDATE ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES('delete', old.id, old.content);
+            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+        END;
+
+        -- Vector embeddings storage
+        CREATE TABLE IF NOT EXISTS embeddings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            source_type TEXT NOT NULL,
+            source_id INTEGER NOT NULL,
+            content_hash TEXT NOT NULL,
+            vector BLOB NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE(source_type, source_id)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_embeddings_source ON embeddings(source_type, source_id);
+        CREATE INDEX IF NOT EXISTS idx_embeddings_hash ON embeddings(content_hash);
+    "#)
+}
+
+// ==================== v2: conversation trees ====================
+
+/// Triggers to keep FTS in sync. Only messages on the active branch of a session
+/// are indexed, so abandoned branches never show up in search results.
+const MESSAGES_FTS_TRIGGERS: &str = r#"
+    CREATE TRIGGER IF NOT EXISTS messages_ai AFTER INSERT ON messages WHEN new.is_active = 1 BEGIN
+        INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS messages_ad AFTER DELETE ON messages WHEN old.is_active = 1 BEGIN
+        INSERT INTO messages_fts(messages_fts, rowid, content) VALUES('delete', old.id, old.content);
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS messages_au AFTER UPDATE OF content, is_active ON messages BEGIN
+        INSERT INTO messages_fts(messages_fts, rowid, content) SELECT 'delete', old.id, old.content WHERE old.is_active = 1;
+        INSERT INTO messages_fts(rowid, content) SELECT new.id, new.content WHERE new.is_active = 1;
+    END;
+"#;
+
+/// Messages form a tree (`parent_id`), sessions point at the end of the visible branch
+/// and replies remember why generation stopped
+fn conversation_trees(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "messages", "parent_id", "INTEGER")?;
+    ensure_column(conn, "messages", "is_active", "INTEGER NOT NULL DEFAULT 1")?;
+    ensure_column(conn, "messages", "finish_reason", "TEXT")?;
+    ensure_column(conn, "sessions", "active_leaf_id", "INTEGER")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_messages_parent ON messages(parent_id);")?;
+
+    install_fts_triggers(conn)?;
+    backfill_message_tree(conn)
+}
+
+/// Replace the triggers that index every message with the active-branch ones
+/// and rebuild the index from the active branches
+fn install_fts_triggers(conn: &Connection) -> Result<()> {
+    let current: Option<String> = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'trigger' AND name = 'messages_ai'",
+        [],
+        |row| row.get(0),
+    ).ok();
+
+    if current.is_some_and(|sql| sql.contains("is_active")) {
+        return Ok(());
+    }
+
+    conn.execute_batch(&format!(r#"
+        DROP TRIGGER IF EXISTS messages_ai;
+        DROP TRIGGER IF EXISTS messages_ad;
+        DROP TRIGGER IF EXISTS messages_au;
+        {}
+        INSERT INTO messages_fts(messages_fts) VALUES('delete-all');
+        INSERT INTO messages_fts(rowid, content) SELECT id, content FROM messages WHERE is_active = 1;
+    "#, MESSAGES_FTS_TRIGGERS))
+}
+
+/// Link messages saved before conversation trees existed into a single chain
+/// (each message answers the previous one) and point the session at its last message.
+/// Only touches sessions that have no active leaf yet.
+fn backfill_message_tree(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+        UPDATE messages SET parent_id = (
+            SELECT p.id FROM messages p
+            WHERE p.session_id = messages.session_id
+            AND p.is_active = 1
+            AND (p.timestamp < messages.timestamp OR (p.timestamp = messages.timestamp AND p.id < messages.id))
+            ORDER BY p.timestamp DESC, p.id DESC LIMIT 1
+        )
+        WHERE parent_id IS NULL
+        AND session_id IN (SELECT id FROM sessions WHERE active_leaf_id IS NULL);
+
+        UPDATE sessions SET active_leaf_id = (
+            SELECT id FROM messages
+            WHERE session_id = sessions.id AND is_active = 1
+            ORDER BY timestamp DESC, id DESC LIMIT 1
+        )
+        WHERE active_leaf_id IS NULL;
+    "#)
+}
+
+// ==================== v3: session organisation ====================
+
+/// Pinned / archived flags, folders and free-form tags
+fn session_organisation(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "sessions", "pinned", "INTEGER NOT NULL DEFAULT 0")?;
+    ensure_column(conn, "sessions", "archived", "INTEGER NOT NULL DEFAULT 0")?;
+    ensure_column(conn, "sessions", "folder", "TEXT")?;
+    conn.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS session_tags (
+            session_id INTEGER NOT NULL,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (session_id, tag),
+            FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_sessions_folder ON sessions(folder);
+        CREATE INDEX IF NOT EXISTS idx_session_tags_tag ON session_tags(tag);
+    "#)
+}
+
+// ==================== v4: session settings ====================
+
+fn session_settings(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+        -- Per-session overrides of the global settings (NULL = use global)
+        CREATE TABLE IF NOT EXISTS session_settings (
+            session_id INTEGER PRIMARY KEY,
+            system_prompt TEXT,
+            model_path TEXT,
+            temperature REAL,
+            top_p REAL,
+            top_k INTEGER,
+            min_p REAL,
+            repeat_penalty REAL,
+            max_tokens INTEGER,
+            use_memory INTEGER,
+            use_rag INTEGER,
+            FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+        );
+    "#)
+}
+
+// ==================== v5: assistants ====================
+
+/// Named assistant personas; sessions and memories are tagged with their assistant
+fn assistants(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS assistants (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            system_prompt TEXT NOT NULL DEFAULT '',
+            model_path TEXT,
+            sampling TEXT,
+            voice_profile_id INTEGER,
+            memory_mode TEXT NOT NULL DEFAULT 'shared',
+            created_at INTEGER NOT NULL
+        );
+    "#)?;
+    ensure_column(conn, "sessions", "assistant_id", "INTEGER")?;
+    ensure_column(conn, "memory", "assistant_id", "INTEGER")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_memory_assistant ON memory(assistant_id);")
+}
+
+// ==================== v6: import sources ====================
+
+/// Where an imported session came from (`chatgpt:<id>`), so re-imports skip it
+fn import_sources(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "sessions", "external_id", "TEXT")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_sessions_external ON sessions(external_id);")
+}
+
+// ==================== v7: message feedback ====================
+
+/// Thumbs up/down, 1-5 score and a free-text correction per answer
+fn message_feedback(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS message_feedback (
+            message_id INTEGER PRIMARY KEY,
+            thumbs_up INTEGER,
+            score INTEGER CHECK (score BETWEEN 1 AND 5),
+            correction TEXT,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+        );
+    "#)
+}
+
+// ==================== v8: message models ====================
+
+/// Name of the model that generated an answer (NULL for user messages and older answers)
+fn message_models(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "messages", "model", "TEXT")
+}
+
+// ==================== v9: memory and title search ====================
+
+/// Full-text indexes over memories and session titles, kept in sync like `messages_fts`
+fn memory_and_title_search(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS memory_fts USING fts5(
+            content,
+            category,
+            content='memory',
+            content_rowid='id'
+        );
+
+        CREATE TRIGGER IF NOT EXISTS memory_ai AFTER INSERT ON memory BEGIN
+            INSERT INTO memory_fts(rowid, content, category) VALUES (new.id, new.content, new.category);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS memory_ad AFTER DELETE ON memory BEGIN
+            INSERT INTO memory_fts(memory_fts, rowid, content, category) VALUES('delete', old.id, old.content, old.category);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS memory_au AFTER UPDATE OF content, category ON memory BEGIN
+            INSERT INTO memory_fts(memory_fts, rowid, content, category) VALUES('delete', old.id, old.content, old.category);
+            INSERT INTO memory_fts(rowid, content, category) VALUES (new.id, new.content, new.category);
+        END;
+
+        CREATE VIRTUAL TABLE IF NOT EXISTS sessions_fts USING fts5(
+            title,
+            content='sessions',
+            content_rowid='id'
+        );
+
+        CREATE TRIGGER IF NOT EXISTS sessions_ai AFTER INSERT ON sessions BEGIN
+            INSERT INTO sessions_fts(rowid, title) VALUES (new.id, new.title);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS sessions_ad AFTER DELETE ON sessions BEGIN
+            INSERT INTO sessions_fts(sessions_fts, rowid, title) VALUES('delete', old.id, old.title);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS sessions_au AFTER UPDATE OF title ON sessions BEGIN
+            INSERT INTO sessions_fts(sessions_fts, rowid, title) VALUES('delete', old.id, old.title);
+            INSERT INTO sessions_fts(rowid, title) VALUES (new.id, new.title);
+        END;
+
+        INSERT INTO memory_fts(memory_fts) VALUES('rebuild');
+        INSERT INTO sessions_fts(sessions_fts) VALUES('rebuild');
+    "#)
+}
+
+// ==================== v10: quantized embeddings ====================
+
+/// Vectors become int8 codes with a scale, sign bits and half floats (see
+/// `quantization`); existing f32 vectors are converted in place
+fn quantized_embeddings(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "embeddings", "scale", "REAL NOT NULL DEFAULT 1")?;
+    ensure_column(conn, "embeddings", "bits", "BLOB")?;
+    ensure_column(conn, "embeddings", "half", "BLOB")?;
+
+    let mut select = conn.prepare("SELECT id, vector FROM embeddings WHERE bits IS NULL")?;
+    let mut update = conn.prepare("UPDATE embeddings SET vector = ?1, scale = ?2, bits = ?3, half = ?4 WHERE id = ?5")?;
+    let rows = select.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?;
+    for row in rows {
+        let (id, bytes) = row?;
+        let quantized = quantization::quantize(&quantization::floats_from_le_bytes(&bytes));
+        update.execute(params![quantized.codes, quantized.scale, quantized.bits, quantized.half, id])?;
+    }
+
+    Ok(())
+}
+
+// ==================== v11: embedding models ====================
+
+/// The model and dimension of every vector; all earlier ones came from multilingual-e5-small
+fn embedding_models(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "embeddings", "model", "TEXT NOT NULL DEFAULT 'multilingual-e5-small'")?;
+    ensure_column(conn, "embeddings", "dim", "INTEGER NOT NULL DEFAULT 384")?;
+    conn.execute_batch(r#"
+        UPDATE embeddings SET dim = LENGTH(vector);
+        CREATE INDEX IF NOT EXISTS idx_embeddings_model ON embeddings(model);
+    "#)
+}
+
+// ==================== v12: memory feedback shifts ====================
+
+/// How far feedback has moved each memory's importance, so that a changed rating takes
+/// back exactly that. Shifts made so far are assumed to have been applied up to the 1..10 bounds.
+fn memory_feedback_shifts(conn: &Connection) -> Result<()> {
+    ensure_column(conn, "memory", "feedback_shift", "INTEGER NOT NULL DEFAULT 0")?;
+    conn.execute_batch(r#"
+        UPDATE memory SET feedback_shift = importance - MAX(1, MIN(10, importance - (
+            SELECT COALESCE(f.score, CASE f.thumbs_up WHEN 1 THEN 5 WHEN 0 THEN 1 ELSE 3 END) - 3
+            FROM message_feedback f WHERE f.message_id = memory.source_message_id
+        )))
+        WHERE source_message_id IN (SELECT message_id FROM message_feedback);
+    "#)
+}
+
+// ==================== TESTS ====================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A database file as the app left it at `version`.
+    /// Version 0 is a database created before versioning: the initial schema, user_version 0.
+    fn fixture(dir: &TempDir, version: u32) -> PathBuf {
+        let path = dir.path().join(format!("fixture-v{}.db", version));
+        let mut conn = Connection::open(&path).expect("open fixture");
+
+        // Data written by the first release, then upgraded step by step like a real install
+        let tx = conn.transaction().expect("transaction");
+        initial_schema(&tx).expect("initial schema");
+        seed(&tx);
+        for migration in MIGRATIONS.iter().filter(|m| m.version > 1 && m.version <= version) {
+            (migration.up)(&tx).expect("fixture migration");
+        }
+        tx.pragma_update(None, "user_version", version).expect("set version");
+        tx.commit().expect("commit");
+
+        path
+    }
+
+    /// Data that only uses the columns of the initial schema
+    fn seed(conn: &Connection) {
+        conn.execute_batch(r#"
+            INSERT INTO sessions (id, title, created_at, message_count) VALUES (1, 'Старый чат', 1000, 3);
+            INSERT INTO messages (id, session_id, content, is_user, timestamp) VALUES (1, 1, 'Привет, меня зовут Аня', 1, 1001);
+            INSERT INTO messages (id, session_id, content, is_user, timestamp) VALUES (2, 1, 'Приятно познакомиться', 0, 1002);
+            INSERT INTO messages (id, session_id, content, is_user, timestamp) VALUES (3, 1, 'Расскажи про черепах', 1, 1003);
+            INSERT INTO memory (content, category, source_session_id, source_message_id, importance, created_at)
+                VALUES ('Пользователя зовут Аня', 'name', 1, 1, 9, 1004);
+            INSERT INTO settings (key, value) VALUES ('temperature', '0.5');
+            INSERT INTO embeddings (source_type, source_id, content_hash, vector, created_at)
+                VALUES ('message', 1, 'h', X'0000803F000000BF0000803E00000000', 1005);
+        "#).expect("seed");
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?1)").unwrap();
+        let names = stmt.query_map(params![table], |row| row.get(0)).unwrap();
+        names.map(|n| n.unwrap()).collect()
+    }
+
+    fn assert_latest_schema(conn: &Connection) {
+        assert_eq!(schema_version(conn).unwrap(), latest_version());
+
+        let messages = columns(conn, "messages");
+        for column in ["parent_id", "is_active", "finish_reason", "model"] {
+            assert!(messages.contains(&column.to_string()), "messages.{} missing", column);
+        }
+        let sessions = columns(conn, "sessions");
+        for column in ["active_leaf_id", "pinned", "archived", "folder", "assistant_id", "external_id"] {
+            assert!(sessions.contains(&column.to_string()), "sessions.{} missing", column);
+        }
+        let memory = columns(conn, "memory");
+        for column in ["assistant_id", "feedback_shift"] {
+            assert!(memory.contains(&column.to_string()), "memory.{} missing", column);
+        }
+        let embeddings = columns(conn, "embeddings");
+        for column in ["scale", "bits", "model", "dim", "half"] {
+            assert!(embeddings.contains(&column.to_string()), "embeddings.{} missing", column);
+        }
+        for table in ["session_tags", "session_settings", "assistants", "embeddings", "message_feedback", "memory_fts", "sessions_fts"] {
+            assert!(!columns(conn, table).is_empty(), "table {} missing", table);
+        }
+    }
+
+    fn assert_seed_preserved(conn: &Connection) {
+        let title: String = conn.query_row("SELECT title FROM sessions WHERE id = 1", [], |r| r.get(0)).unwrap();
+        assert_eq!(title, "Старый чат");
+        let memory: i64 = conn.query_row("SELECT COUNT(*) FROM memory", [], |r| r.get(0)).unwrap();
+        assert_eq!(memory, 1);
+
+        // Old messages are chained into one branch ending at the last message
+        let parents: Vec<Option<i64>> = conn.prepare("SELECT parent_id FROM messages ORDER BY id").unwrap()
+            .query_map([], |r| r.get(0)).unwrap()
+            .map(|p| p.unwrap())
+            .collect();
+        assert_eq!(parents, vec![None, Some(1), Some(2)]);
+        let leaf: i64 = conn.query_row("SELECT active_leaf_id FROM sessions WHERE id = 1", [], |r| r.get(0)).unwrap();
+        assert_eq!(leaf, 3);
+
+        // FTS still finds old messages and follows new ones
+        let hits: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM messages_fts WHERE messages_fts MATCH 'черепах'", [], |r| r.get(0)
+        ).unwrap();
+        assert_eq!(hits, 1);
+        let hits: i64 = conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM memory_fts WHERE memory_fts MATCH 'Аня')
+                + (SELECT COUNT(*) FROM sessions_fts WHERE sessions_fts MATCH 'старый')", [], |r| r.get(0)
+        ).unwrap();
+        assert_eq!(hits, 2);
+
+        // The f32 vector [1, -0.5, 0.25, 0] as int8 codes, sign bits and half floats
+        let (vector, scale, bits, half): (Vec<u8>, f32, Vec<u8>, Vec<u8>) = conn.query_row(
+            "SELECT vector, scale, bits, half FROM embeddings", [], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        ).unwrap();
+        assert_eq!(vector, vec![127, (-64i8) as u8, 32, 0]);
+        assert!((scale - 1.0 / 127.0).abs() < 1e-7);
+        assert_eq!(bits, vec![0b0101]);
+        assert_eq!(quantization::from_half(&half), vec![1.0, -0.5, 0.25, 0.0]);
+        let (model, dim): (String, i64) = conn.query_row(
+            "SELECT model, dim FROM embeddings", [], |r| Ok((r.get(0)?, r.get(1)?))
+        ).unwrap();
+        assert_eq!((model.as_str(), dim), ("multilingual-e5-small", 4));
+    }
+
+    #[test]
+    fn test_migration_versions_are_ordered() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as u32 + 1, "migration '{}' out of order", migration.name);
+        }
+        assert_eq!(latest_version(), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn test_fresh_database_gets_latest_schema() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("fresh.db");
+        let mut conn = Connection::open(&path).unwrap();
+
+        let backup = run(&mut conn, &path).unwrap();
+
+        assert!(backup.is_none(), "an empty database needs no backup");
+        assert_latest_schema(&conn);
+    }
+
+    #[test]
+    fn test_migrates_every_historical_version() {
+        let dir = TempDir::new().unwrap();
+
+        for version in 0..latest_version() {
+            let path = fixture(&dir, version);
+            let mut conn = Connection::open(&path).unwrap();
+
+            let backup = run(&mut conn, &path).unwrap();
+
+            assert_latest_schema(&conn);
+            assert_seed_preserved(&conn);
+
+            // The backup is the untouched old database
+            let backup = backup.unwrap_or_else(|| panic!("no backup when migrating from v{}", version));
+            let old = Connection::open(&backup).unwrap();
+            assert_eq!(schema_version(&old).unwrap(), version);
+            let messages: i64 = old.query_row("SELECT COUNT(*) FROM messages", [], |r| r.get(0)).unwrap();
+            assert_eq!(messages, 3);
+        }
+    }
+
+    #[test]
+    fn test_feedback_shifts_are_recovered() {
+        let dir = TempDir::new().unwrap();
+        let path = fixture(&dir, 11);
+        let mut conn = Connection::open(&path).unwrap();
+        // A thumbs-up (+2) already lifted the memory of message 1 from 7 to 9
+        conn.execute_batch("INSERT INTO message_feedback (message_id, thumbs_up, updated_at) VALUES (1, 1, 2000);").unwrap();
+
+        run(&mut conn, &path).unwrap();
+
+        let shift: i32 = conn.query_row("SELECT feedback_shift FROM memory", [], |r| r.get(0)).unwrap();
+        assert_eq!(shift, 2);
+    }
+
+    #[test]
+    fn test_unversioned_database_with_newer_columns() {
+        // Builds that added columns on startup left the full schema at user_version 0
+        let dir = TempDir::new().unwrap();
+        let path = fixture(&dir, latest_version());
+        Connection::open(&path).unwrap().pragma_update(None, "user_version", 0).unwrap();
+        let mut conn = Connection::open(&path).unwrap();
+
+        run(&mut conn, &path).unwrap();
+
+        assert_latest_schema(&conn);
+        assert_seed_preserved(&conn);
+    }
+
+    #[test]
+    fn test_up_to_date_database_is_untouched() {
+        let dir = TempDir::new().unwrap();
+        let path = fixture(&dir, latest_version());
+        let mut conn = Connection::open(&path).unwrap();
+
+        assert!(run(&mut conn, &path).unwrap().is_none());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1, "no backup expected");
+    }
+
+    #[test]
+    fn test_newer_database_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = fixture(&dir, latest_version());
+        let mut conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+
+        let err = run(&mut conn, &path).unwrap_err();
+        assert!(err.to_string().contains("более новой версией"));
+    }
+
+    #[test]
+    fn test_failed_migration_rolls_back() {
+        fn create_table(conn: &Connection) -> Result<()> {
+            conn.execute_batch("CREATE TABLE step_one (id INTEGER);")
+        }
+        fn broken(conn: &Connection) -> Result<()> {
+            conn.execute_batch("CREATE TABLE step_two (id INTEGER); SELECT * FROM missing_table;")
+        }
+        let migrations = [
+            Migration { version: 1, name: "ok", up: create_table },
+            Migration { version: 2, name: "broken", up: broken },
+        ];
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("broken.db");
+        let mut conn = Connection::open(&path).unwrap();
+
+        assert!(run_migrations(&mut conn, &path, &migrations).is_err());
+
+        // v1 committed, v2 left no trace
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+        assert!(!columns(&conn, "step_one").is_empty());
+        assert!(columns(&conn, "step_two").is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+//! Compact encodings of embedding vectors.
+//!
+//! A vector is stored three times, each smaller than raw f32 (1536 bytes at 384 dims):
+//!
+//! - int8 codes with a per-vector scale (`x ≈ code * scale`), 384 bytes — close
+//!   enough to the original for building the nearest-neighbour graph;
+//! - one sign bit per dimension, 48 bytes — Hamming distance between sign bits
+//!   roughly follows the angle between vectors, so a scan over them cheaply picks
+//!   candidates;
+//! - IEEE half floats, 768 bytes — what the candidates are re-scored on with the
+//!   f32 query, so the final order is that of the original vectors.
+//!
+//! Before schema v10 vectors were stored as raw little-endian f32.
+
+/// int8 codes, their scale, the sign bits and the half floats of one vector
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantized {
+    /// `i8` codes stored as bytes
+    pub codes: Vec<u8>,
+    pub scale: f32,
+    pub bits: Vec<u8>,
+    /// Little-endian half floats
+    pub half: Vec<u8>,
+}
+
+/// Symmetric per-vector int8 quantization: the largest component maps to ±127
+pub fn quantize(vector: &[f32]) -> Quantized {
+    let max = vector.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+    let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+    let codes = vector.iter()
+        .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8 as u8)
+        .collect();
+    Quantized { codes, scale, bits: sign_bits(vector), half: to_half(vector) }
+}
+
+pub fn dequantize(codes: &[u8], scale: f32) -> Vec<f32> {
+    codes.iter().map(|&code| code as i8 as f32 * scale).collect()
+}
+
+/// Little-endian IEEE 754 half floats, rounded to nearest even
+pub fn to_half(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|&x| f32_to_f16(x).to_le_bytes()).collect()
+}
+
+pub fn from_half(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(2)
+        .map(|chunk| f16_to_f32(u16::from_le_bytes([chunk[0], chunk[1]])))
+        .collect()
+}
+
+fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity stays infinity, NaN stays NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    // Bits shifted out of the mantissa, rounded to nearest even
+    let round = |value: u32, shift: u32| {
+        let kept = value >> shift;
+        let rest = value & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        kept + (rest > halfway || (rest == halfway && kept & 1 == 1)) as u32
+    };
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // Subnormal; the implicit leading one becomes explicit
+        return sign | round(mantissa | 0x80_0000, (14 - exponent) as u32) as u16;
+    }
+    // A carry out of the mantissa correctly bumps the exponent (up to infinity)
+    sign | round(((exponent as u32) << 23) | mantissa, 13) as u16
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    match exponent {
+        0 => {
+            let value = mantissa as f32 / (1 << 24) as f32;
+            if sign != 0 { -value } else { value }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
+
+/// Bit `i` (least significant first) is set when component `i` is positive
+pub fn sign_bits(vector: &[f32]) -> Vec<u8> {
+    let mut bits = vec![0u8; vector.len().div_ceil(8)];
+    for (i, x) in vector.iter().enumerate() {
+        if *x > 0.0 {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+    bits
+}
+
+/// Differing bits; the shorter input is compared as if padded with zeros
+pub fn hamming(a: &[u8], b: &[u8]) -> u32 {
+    let common: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+    let rest = if a.len() > b.len() { &a[b.len()..] } else { &b[a.len()..] };
+    common + rest.iter().map(|x| x.count_ones()).sum::<u32>()
+}
+
+/// Raw little-endian f32, the encoding before v10
+pub fn floats_from_le_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cosine(a: &[f32], b: &[f32]) -> f32 {
+        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        dot / (norm(a) * norm(b))
+    }
+
+    #[test]
+    fn test_quantize_roundtrip() {
+        let vector: Vec<f32> = (0..384).map(|i| ((i * 37 % 101) as f32 - 50.0) / 400.0).collect();
+        let quantized = quantize(&vector);
+        assert_eq!(quantized.codes.len(), 384);
+        assert_eq!(quantized.bits.len(), 48);
+
+        let restored = dequantize(&quantized.codes, quantized.scale);
+        for (x, y) in vector.iter().zip(&restored) {
+            assert!((x - y).abs() <= quantized.scale / 2.0 + 1e-6);
+        }
+        assert!(cosine(&vector, &restored) > 0.999);
+
+        let zero = quantize(&[0.0; 4]);
+        assert_eq!(dequantize(&zero.codes, zero.scale), vec![0.0; 4]);
+    }
+
+    #[test]
+    fn test_half_roundtrip() {
+        let exact = [0.0f32, -0.0, 1.0, -2.5, 0.5, 65504.0, 6.103_515_6e-5, 5.960_464_5e-8];
+        assert_eq!(from_half(&to_half(&exact)), exact);
+        assert_eq!(from_half(&to_half(&[1e6, -1e6, 1e-9])), vec![f32::INFINITY, f32::NEG_INFINITY, 0.0]);
+        assert!(from_half(&to_half(&[f32::NAN]))[0].is_nan());
+        // 1 + 2^-11 lies halfway between 1 and the next half float: ties go to even
+        assert_eq!(from_half(&to_half(&[1.0 + 1.0 / 2048.0, 1.0 + 3.0 / 2048.0])), vec![1.0, 1.0 + 4.0 / 2048.0]);
+
+        let vector: Vec<f32> = (0..384).map(|i| ((i * 37 % 101) as f32 - 50.0) / 400.0).collect();
+        let quantized = quantize(&vector);
+        assert_eq!(quantized.half.len(), 768);
+        let restored = from_half(&quantized.half);
+        for (x, y) in vector.iter().zip(&restored) {
+            assert!((x - y).abs() <= x.abs() / 2048.0 + 1e-7);
+        }
+    }
+
+    #[test]
+    fn test_sign_bits_and_hamming() {
+        let bits = sign_bits(&[1.0, -0.5, 0.25, 0.0, 0.0, 0.0, 0.0, 0.0, 3.0]);
+        assert_eq!(bits, vec![0b0000_0101, 0b0000_0001]);
+        assert_eq!(hamming(&bits, &bits), 0);
+        assert_eq!(hamming(&bits, &sign_bits(&[-1.0, 0.5, 0.25])), 3);
+    }
+
+    #[test]
+    fn test_floats_from_le_bytes() {
+        let original = [1.0f32, 2.5, -3.25, 0.0];
+        let bytes: Vec<u8> = original.iter().flat_map(|f| f.to_le_bytes()).collect();
+        assert_eq!(floats_from_le_bytes(&bytes), original);
+    }
+}
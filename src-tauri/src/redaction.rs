@@ -0,0 +1,439 @@
+//! PII redaction for exports that leave the machine.
+//!
+//! Detectors find emails, phone numbers, URLs, IP addresses, credit card numbers,
+//! API keys / tokens, home-directory paths and user-defined terms (names, company
+//! names...). Every distinct value becomes a numbered placeholder such as `[EMAIL_2]`.
+//! One [`Redactor`] is used for a whole export, so the same value gets the same
+//! placeholder in every record. The [`RedactionReport`] counts what was replaced
+//! without repeating the values themselves.
+
+use once_cell::sync::Lazy;
+use regex::{Match, Regex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiKind {
+    Secret,
+    Url,
+    Email,
+    Ip,
+    CreditCard,
+    Phone,
+    Path,
+    Term,
+}
+
+impl PiiKind {
+    fn placeholder(&self) -> &'static str {
+        match self {
+            PiiKind::Secret => "SECRET",
+            PiiKind::Url => "URL",
+            PiiKind::Email => "EMAIL",
+            PiiKind::Ip => "IP",
+            PiiKind::CreditCard => "CARD",
+            PiiKind::Phone => "PHONE",
+            PiiKind::Path => "PATH",
+            PiiKind::Term => "TERM",
+        }
+    }
+}
+
+/// Which detectors run; all built-in ones are on by default
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RedactionConfig {
+    pub emails: bool,
+    pub phones: bool,
+    pub urls: bool,
+    pub ips: bool,
+    pub credit_cards: bool,
+    /// API keys, tokens, private keys and `password=...` style assignments
+    pub secrets: bool,
+    /// Paths inside home directories (they contain the user name)
+    pub paths: bool,
+    /// Words and phrases to hide, matched case-insensitively as whole words
+    pub terms: Vec<String>,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        Self {
+            emails: true,
+            phones: true,
+            urls: true,
+            ips: true,
+            credit_cards: true,
+            secrets: true,
+            paths: true,
+            terms: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RedactionCount {
+    pub kind: PiiKind,
+    /// Replaced occurrences
+    pub occurrences: usize,
+    /// Distinct values, i.e. placeholders handed out
+    pub distinct: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RedactionReport {
+    pub total: usize,
+    pub counts: Vec<RedactionCount>,
+    /// Records (lines / entries) that had anything replaced
+    pub records_changed: usize,
+    /// User-defined terms that were searched for (how many, not which)
+    pub terms: usize,
+}
+
+/// A pattern and the capture group that holds the sensitive part
+struct Detector {
+    kind: PiiKind,
+    regex: Regex,
+    group: usize,
+    /// Extra check on the match (with the whole text around it), for patterns that
+    /// catch too much on their own
+    accept: fn(&str, &Match) -> bool,
+}
+
+fn detector(kind: PiiKind, pattern: &str, group: usize, accept: fn(&str, &Match) -> bool) -> Detector {
+    Detector {
+        kind,
+        regex: Regex::new(pattern).expect("invalid PII pattern"),
+        group,
+        accept,
+    }
+}
+
+fn any(_: &str, _: &Match) -> bool {
+    true
+}
+
+/// Built-in detectors in the order they run: secrets and URLs first, so the
+/// emails, IPs and numbers inside them are not replaced piecemeal
+static DETECTORS: Lazy<Vec<Detector>> = Lazy::new(|| vec![
+    detector(PiiKind::Secret, r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----", 0, any),
+    detector(
+        PiiKind::Secret,
+        r"\b(?:sk-(?:proj-|ant-)?[A-Za-z0-9_-]{20,}|gh[pousr]_[A-Za-z0-9]{30,}|github_pat_[A-Za-z0-9_]{30,}|xox[abprs]-[A-Za-z0-9-]{10,}|AKIA[0-9A-Z]{16}|AIza[0-9A-Za-z_-]{35}|hf_[A-Za-z0-9]{30,}|glpat-[A-Za-z0-9_-]{20,})",
+        0,
+        any,
+    ),
+    detector(PiiKind::Secret, r"\beyJ[A-Za-z0-9_-]{8,}\.eyJ[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}", 0, any),
+    detector(PiiKind::Secret, r"(?i)\bBearer\s+([A-Za-z0-9._~+/-]{16,}=*)", 1, any),
+    detector(
+        PiiKind::Secret,
+        r#"(?i)\b(?:api[_-]?key|access[_-]?token|auth[_-]?token|token|secret|client[_-]?secret|password|passwd|pwd|пароль)\b["']?\s*[:=]\s*["']?([^\s"',;]{6,})"#,
+        1,
+        any,
+    ),
+    detector(PiiKind::Url, r#"(?i)\b(?:https?|ftp)://[^\s<>"'`]+[^\s<>"'`.,;:!?)\]]"#, 0, any),
+    detector(PiiKind::Url, r#"(?i)\bwww\.[^\s<>"'`]+[^\s<>"'`.,;:!?)\]]"#, 0, any),
+    detector(PiiKind::Email, r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b", 0, any),
+    detector(
+        PiiKind::Ip,
+        r"\b(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\b",
+        0,
+        any,
+    ),
+    detector(PiiKind::Ip, r"(?i)\b(?:[0-9a-f]{1,4}:){7}[0-9a-f]{1,4}\b", 0, any),
+    detector(PiiKind::CreditCard, r"\b\d(?:[ -]?\d){12,18}\b", 0, |_, found| is_card_number(found.as_str())),
+    detector(PiiKind::Phone, r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{1,5}\)[\s.-]?)?\d{2,4}(?:[\s.-]?\d{2,4}){1,4}", 0, is_phone_match),
+    detector(PiiKind::Path, r#"(?:/home|/Users)/[^\s/"'<>`,;]+(?:/(?:[^\s"'<>`]*[^\s"'<>`.,;:!?)\]])?)?"#, 0, any),
+    detector(PiiKind::Path, r#"(?i)\b[a-z]:\\(?:Users|Documents and Settings)\\[^\s"'<>`]*[^\s"'<>`.,;:!?)\]]"#, 0, any),
+]);
+
+/// 13-19 digits passing the Luhn check
+fn is_card_number(text: &str) -> bool {
+    let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits.iter().rev().enumerate()
+        .map(|(i, &d)| if i % 2 == 1 { let d = d * 2; if d > 9 { d - 9 } else { d } } else { d })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Dates inside a phone-like match: 2024-01-15, 15.01.2024, 15/01/2024
+static DATE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\d{4}-\d{2}-\d{2}|\d{2}[./]\d{2}[./]\d{4}").expect("invalid date pattern")
+});
+
+/// A phone number standing on its own: not glued to letters, digits or a `+` (parts of
+/// ids, versions and longer numbers), and not next to a time such as `12:30`
+fn is_phone_match(text: &str, found: &Match) -> bool {
+    let before = &text[..found.start()];
+    let after = &text[found.end()..];
+    let mut previous = before.chars().rev();
+    let mut next = after.chars();
+    let glued = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+
+    let previous_char = previous.next();
+    if glued(previous_char) || previous_char == Some('+') || glued(next.clone().next()) {
+        return false;
+    }
+    let time_before = previous_char == Some(':') && previous.next().is_some_and(|c| c.is_ascii_digit());
+    let time_after = next.next() == Some(':') && next.next().is_some_and(|c| c.is_ascii_digit());
+    if time_before || time_after {
+        return false;
+    }
+    is_phone_number(found.as_str())
+}
+
+/// 10-15 digits written like a phone number: with a country code, brackets or
+/// separators, or 11 digits starting with 7 / 8 (Russian numbers). Plain digit runs
+/// such as ids and timestamps don't count, nor do dates.
+fn is_phone_number(text: &str) -> bool {
+    let digits: String = text.chars().filter(char::is_ascii_digit).collect();
+    if !(10..=15).contains(&digits.len()) || DATE.is_match(text) {
+        return false;
+    }
+    let formatted = text.starts_with('+') || text.contains('(') || text.chars().any(|c| matches!(c, ' ' | '-' | '.'));
+    formatted || (digits.len() == 11 && (digits.starts_with('7') || digits.starts_with('8')))
+}
+
+/// Key a value is recognised by, so `A@B.com` and `a@b.com` share a placeholder
+fn normalize(kind: PiiKind, value: &str) -> String {
+    match kind {
+        PiiKind::Phone | PiiKind::CreditCard => value.chars().filter(char::is_ascii_digit).collect(),
+        PiiKind::Email | PiiKind::Term | PiiKind::Ip => value.to_lowercase(),
+        _ => value.to_string(),
+    }
+}
+
+pub struct Redactor {
+    config: RedactionConfig,
+    terms: Option<Regex>,
+    pseudonyms: HashMap<(PiiKind, String), String>,
+    /// Distinct values seen per kind, i.e. the number of the last placeholder
+    distinct: HashMap<PiiKind, usize>,
+    occurrences: HashMap<PiiKind, usize>,
+    records_changed: usize,
+}
+
+impl Redactor {
+    pub fn new(config: RedactionConfig) -> Result<Self, String> {
+        let terms: Vec<String> = config.terms.iter()
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .map(regex::escape)
+            .collect();
+        let terms = if terms.is_empty() {
+            None
+        } else {
+            let pattern = format!(r"(?i)\b(?:{})\b", terms.join("|"));
+            Some(Regex::new(&pattern).map_err(|e| format!("Неверный список терминов: {}", e))?)
+        };
+
+        Ok(Self {
+            config,
+            terms,
+            pseudonyms: HashMap::new(),
+            distinct: HashMap::new(),
+            occurrences: HashMap::new(),
+            records_changed: 0,
+        })
+    }
+
+    fn enabled(&self, kind: PiiKind) -> bool {
+        match kind {
+            PiiKind::Secret => self.config.secrets,
+            PiiKind::Url => self.config.urls,
+            PiiKind::Email => self.config.emails,
+            PiiKind::Ip => self.config.ips,
+            PiiKind::CreditCard => self.config.credit_cards,
+            PiiKind::Phone => self.config.phones,
+            PiiKind::Path => self.config.paths,
+            PiiKind::Term => self.terms.is_some(),
+        }
+    }
+
+    /// Placeholder of a value, handing out the next number for a new one
+    fn pseudonym(&mut self, kind: PiiKind, value: &str) -> String {
+        *self.occurrences.entry(kind).or_default() += 1;
+        let distinct = &mut self.distinct;
+        self.pseudonyms
+            .entry((kind, normalize(kind, value)))
+            .or_insert_with(|| {
+                let number = distinct.entry(kind).or_default();
+                *number += 1;
+                format!("[{}_{}]", kind.placeholder(), number)
+            })
+            .clone()
+    }
+
+    /// Replace every detected value in `text`
+    pub fn redact(&mut self, text: &str) -> String {
+        let mut text = text.to_string();
+
+        for detector in DETECTORS.iter() {
+            if self.enabled(detector.kind) {
+                text = self.replace(&text, &detector.regex, detector.kind, detector.group, detector.accept);
+            }
+        }
+        if let Some(terms) = self.terms.clone() {
+            text = self.replace(&text, &terms, PiiKind::Term, 0, any);
+        }
+
+        text
+    }
+
+    fn replace(&mut self, text: &str, regex: &Regex, kind: PiiKind, group: usize, accept: fn(&str, &Match) -> bool) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut last = 0;
+
+        for captures in regex.captures_iter(text) {
+            let Some(found) = captures.get(group) else { continue };
+            if !accept(text, &found) {
+                continue;
+            }
+            result.push_str(&text[last..found.start()]);
+            result.push_str(&self.pseudonym(kind, found.as_str()));
+            last = found.end();
+        }
+
+        result.push_str(&text[last..]);
+        result
+    }
+
+    /// Redact every string inside a JSON value (object keys are left alone)
+    fn redact_strings(&mut self, value: &mut serde_json::Value) -> bool {
+        match value {
+            serde_json::Value::String(text) => {
+                let redacted = self.redact(text);
+                let changed = redacted != *text;
+                *text = redacted;
+                changed
+            }
+            serde_json::Value::Array(items) => {
+                let mut changed = false;
+                for item in items {
+                    changed |= self.redact_strings(item);
+                }
+                changed
+            }
+            serde_json::Value::Object(fields) => {
+                let mut changed = false;
+                for field in fields.values_mut() {
+                    changed |= self.redact_strings(field);
+                }
+                changed
+            }
+            _ => false,
+        }
+    }
+
+    /// Redact one exported record
+    pub fn redact_record(&mut self, record: &mut serde_json::Value) {
+        if self.redact_strings(record) {
+            self.records_changed += 1;
+        }
+    }
+
+    pub fn report(&self) -> RedactionReport {
+        let mut counts: Vec<RedactionCount> = self.occurrences.iter()
+            .map(|(&kind, &occurrences)| RedactionCount {
+                kind,
+                occurrences,
+                distinct: self.distinct.get(&kind).copied().unwrap_or(0),
+            })
+            .collect();
+        counts.sort_by_key(|c| c.kind);
+
+        RedactionReport {
+            total: counts.iter().map(|c| c.occurrences).sum(),
+            counts,
+            records_changed: self.records_changed,
+            terms: self.config.terms.iter().filter(|t| !t.trim().is_empty()).count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(terms: &[&str]) -> Redactor {
+        Redactor::new(RedactionConfig {
+            terms: terms.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_detectors() {
+        let mut r = redactor(&[]);
+        let text = "Пиши на Anna.K@mail.ru или звони +7 (916) 123-45-67, сайт https://example.com/a?b=1. \
+                    Сервер 192.168.0.10, карта 4111 1111 1111 1111, ключ sk-proj-abcdefghijklmnopqrstuvwx, \
+                    файл /home/anna/notes.txt, password=hunter2secret";
+
+        let redacted = r.redact(text);
+
+        assert_eq!(
+            redacted,
+            "Пиши на [EMAIL_1] или звони [PHONE_1], сайт [URL_1]. \
+             Сервер [IP_1], карта [CARD_1], ключ [SECRET_1], \
+             файл [PATH_1], password=[SECRET_2]"
+        );
+        assert_eq!(r.report().total, 8);
+    }
+
+    #[test]
+    fn test_plain_numbers_are_kept() {
+        let mut r = redactor(&[]);
+        let text = "В 2024 году было 1234567890123 запросов, заказ 4111111111111112, время 12:30:45";
+        assert_eq!(r.redact(text), text);
+    }
+
+    #[test]
+    fn test_dates_versions_and_ids_are_not_phones() {
+        let mut r = redactor(&[]);
+        for text in [
+            "Встреча 2024-01-15 12:30 в офисе",
+            "Срок 15.01.2024 10:00, потом 16/01/2024 11:00",
+            "Лог 12:30 45 67 89 01 23",
+            "Версия v10.15.7-2024.1234 и build_2024-1234-5678",
+            "Заказ A1234-5678-9012 и номер 12345678901234567890123",
+            "Сумма +12 345 678 901 234 567",
+        ] {
+            assert_eq!(r.redact(text), text);
+        }
+        assert_eq!(r.redact("Звони +7 916 123-45-67 до 18:00"), "Звони [PHONE_1] до 18:00");
+    }
+
+    #[test]
+    fn test_pseudonyms_are_consistent_across_records() {
+        let mut r = redactor(&["Анна Иванова", "Acme"]);
+        let mut first = serde_json::json!({"instruction": "Это анна иванова из ACME", "output": "a@b.com"});
+        let mut second = serde_json::json!({"messages": [{"role": "user", "content": "Письмо для A@B.com от Анна Иванова"}]});
+        let mut third = serde_json::json!({"text": "ничего личного"});
+
+        r.redact_record(&mut first);
+        r.redact_record(&mut second);
+        r.redact_record(&mut third);
+
+        assert_eq!(first["instruction"], "Это [TERM_1] из [TERM_2]");
+        assert_eq!(second["messages"][0]["content"], "Письмо для [EMAIL_1] от [TERM_1]");
+        assert_eq!(second["messages"][0]["role"], "user");
+
+        let report = r.report();
+        assert_eq!(report.records_changed, 2);
+        assert_eq!(report.terms, 2);
+        let terms = report.counts.iter().find(|c| c.kind == PiiKind::Term).unwrap();
+        assert_eq!((terms.occurrences, terms.distinct), (3, 2));
+    }
+
+    #[test]
+    fn test_disabled_detectors_are_skipped() {
+        let mut r = Redactor::new(RedactionConfig { emails: false, ..Default::default() }).unwrap();
+        assert_eq!(r.redact("a@b.com"), "a@b.com");
+    }
+}
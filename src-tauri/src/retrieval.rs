@@ -0,0 +1,351 @@
+//! Hybrid retrieval of context for a chat prompt.
+//!
+//! Two retrievers run side by side: bm25 over the full-text indexes of messages and
+//! memories, and vector search over their embeddings (`embeddings` feature). Their
+//! rankings are fused with reciprocal rank fusion: an item scores `weight / (k + rank)`
+//! in each ranking it appears in, so items both retrievers agree on come first without
+//! comparing bm25 and cosine scores directly. Messages of the current session, and
+//! anything repeating its history, are already in the prompt and are dropped.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::database::{self, SearchSource};
+#[cfg(feature = "semantic-search")]
+use crate::embeddings;
+use crate::fts_query;
+
+/// Vector matches fetched at most while looking for ones outside the current chat
+#[cfg(feature = "semantic-search")]
+const MAX_VECTOR_CANDIDATES: usize = 1000;
+
+/// Tunable knobs of the retriever, kept in the settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetrievalConfig {
+    /// Weight of the bm25 ranking in the fused score
+    pub keyword_weight: f32,
+    /// Weight of the vector ranking in the fused score
+    pub vector_weight: f32,
+    /// RRF constant; larger values flatten the difference between ranks
+    pub rrf_k: f32,
+    /// Candidates taken from each retriever
+    pub candidates: usize,
+    /// Items returned
+    pub limit: usize,
+    /// Vector matches below this cosine similarity are ignored
+    pub min_similarity: f32,
+}
+
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        Self {
+            keyword_weight: 1.0,
+            vector_weight: 1.0,
+            rrf_k: 60.0,
+            candidates: 20,
+            limit: 3,
+            min_similarity: 0.5,
+        }
+    }
+}
+
+/// One retrieved item with the scores behind its place
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetrievedItem {
+    pub source: SearchSource,
+    pub source_id: i64,
+    pub content: String,
+    /// Session and author of a message
+    pub session_id: Option<i64>,
+    pub session_title: Option<String>,
+    pub is_user: Option<bool>,
+    /// Fused RRF score; higher is more relevant
+    pub score: f32,
+    /// 1-based rank and bm25 score in the keyword ranking
+    pub keyword_rank: Option<usize>,
+    pub bm25: Option<f64>,
+    /// 1-based rank and cosine similarity in the vector ranking
+    pub vector_rank: Option<usize>,
+    pub similarity: Option<f32>,
+}
+
+/// A candidate before its content is loaded
+#[derive(Debug, Clone, PartialEq)]
+struct Fused {
+    source: SearchSource,
+    id: i64,
+    score: f32,
+    keyword_rank: Option<usize>,
+    bm25: Option<f64>,
+    vector_rank: Option<usize>,
+    similarity: Option<f32>,
+}
+
+/// Merge both rankings (each best first) with weighted reciprocal rank fusion
+fn fuse(
+    keyword: &[(SearchSource, i64, f64)],
+    vector: &[(SearchSource, i64, f32)],
+    config: &RetrievalConfig,
+) -> Vec<Fused> {
+    let mut fused: Vec<Fused> = Vec::new();
+    let mut index: HashMap<(SearchSource, i64), usize> = HashMap::new();
+    let mut entry = |source: SearchSource, id: i64| -> usize {
+        *index.entry((source, id)).or_insert_with(|| {
+            fused.push(Fused { source, id, score: 0.0, keyword_rank: None, bm25: None, vector_rank: None, similarity: None });
+            fused.len() - 1
+        })
+    };
+
+    let mut keyword_hits = Vec::new();
+    for (rank, &(source, id, bm25)) in keyword.iter().enumerate() {
+        keyword_hits.push((entry(source, id), rank + 1, bm25));
+    }
+    let mut vector_hits = Vec::new();
+    for (rank, &(source, id, similarity)) in vector.iter().enumerate() {
+        vector_hits.push((entry(source, id), rank + 1, similarity));
+    }
+
+    for (i, rank, bm25) in keyword_hits {
+        let item = &mut fused[i];
+        // The same row can't rank twice in one list; keep the first (best) place
+        if item.keyword_rank.is_none() {
+            item.keyword_rank = Some(rank);
+            item.bm25 = Some(bm25);
+            item.score += config.keyword_weight / (config.rrf_k + rank as f32);
+        }
+    }
+    for (i, rank, similarity) in vector_hits {
+        let item = &mut fused[i];
+        if item.vector_rank.is_none() {
+            item.vector_rank = Some(rank);
+            item.similarity = Some(similarity);
+            item.score += config.vector_weight / (config.rrf_k + rank as f32);
+        }
+    }
+
+    fused.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    fused
+}
+
+/// For comparing content with the session history
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn keyword_candidates(query: &str, session_id: i64, assistant_id: Option<i64>, limit: usize) -> Result<Vec<(SearchSource, i64, f64)>, String> {
+    let Some(expression) = fts_query::any_word(query) else {
+        return Ok(Vec::new());
+    };
+    database::rank_matches(&expression, session_id, assistant_id, limit as i64).map_err(|e| e.to_string())
+}
+
+/// Vector matches the keyword side could return too; empty when the embedder isn't loaded
+#[cfg(feature = "semantic-search")]
+fn vector_candidates(query: &str, session_id: i64, assistant_id: Option<i64>, config: &RetrievalConfig) -> Result<Vec<(SearchSource, i64, f32)>, String> {
+    let Ok(query_embedding) = embeddings::embed_query(query) else {
+        return Ok(Vec::new());
+    };
+    let memories = database::get_visible_memory_ids(assistant_id).map_err(|e| e.to_string())?;
+
+    // The index knows nothing about chats or assistants, and in a long chat its best
+    // matches are mostly the chat itself: fetch more until enough survive the filters
+    let mut wanted = config.candidates;
+    loop {
+        let matches = database::with_connection(|conn| {
+            embeddings::semantic_search(conn, &query_embedding, None, wanted as i32, config.min_similarity)
+        }).and_then(|matches| matches).map_err(|e| e.to_string())?;
+        let exhausted = matches.len() < wanted || wanted >= MAX_VECTOR_CANDIDATES;
+
+        let message_ids: Vec<i64> = matches.iter().filter(|m| m.1 == "message").map(|m| m.2).collect();
+        let messages = database::get_context_messages(&message_ids, session_id, assistant_id).map_err(|e| e.to_string())?;
+        let mut candidates: Vec<(SearchSource, i64, f32)> = matches.into_iter()
+            .filter_map(|(_, source_type, source_id, similarity)| match source_type.as_str() {
+                "message" if messages.contains_key(&source_id) => Some((SearchSource::Message, source_id, similarity)),
+                "memory" if memories.contains(&source_id) => Some((SearchSource::Memory, source_id, similarity)),
+                _ => None,
+            })
+            .collect();
+
+        if candidates.len() >= config.candidates || exhausted {
+            candidates.truncate(config.candidates);
+            return Ok(candidates);
+        }
+        wanted *= 4;
+    }
+}
+
+#[cfg(not(feature = "semantic-search"))]
+fn vector_candidates(_query: &str, _session_id: i64, _assistant_id: Option<i64>, _config: &RetrievalConfig) -> Result<Vec<(SearchSource, i64, f32)>, String> {
+    Ok(Vec::new())
+}
+
+/// Context for answering `query` in `session_id`, best first. Memories and chats hidden
+/// from the session's assistant are left out; `history` is the conversation in the prompt.
+pub fn retrieve(
+    query: &str,
+    session_id: i64,
+    assistant_id: Option<i64>,
+    history: &[&str],
+    config: &RetrievalConfig,
+) -> Result<Vec<RetrievedItem>, String> {
+    // Embedding the query is the slow part; bm25 runs meanwhile
+    let (keyword, vector) = std::thread::scope(|scope| {
+        let vector = scope.spawn(|| vector_candidates(query, session_id, assistant_id, config));
+        let keyword = keyword_candidates(query, session_id, assistant_id, config.candidates);
+        let vector = vector.join().unwrap_or(Ok(Vec::new())).unwrap_or_else(|e| {
+            eprintln!("Vector search failed: {}", e);
+            Vec::new()
+        });
+        (keyword, vector)
+    });
+    let fused = fuse(&keyword?, &vector, config);
+
+    let ids = |source: SearchSource| -> Vec<i64> {
+        fused.iter().filter(|f| f.source == source).map(|f| f.id).collect()
+    };
+    let messages = database::get_context_messages(&ids(SearchSource::Message), session_id, assistant_id).map_err(|e| e.to_string())?;
+    let memories = database::get_memories_by_ids(&ids(SearchSource::Memory)).map_err(|e| e.to_string())?;
+    let visible_memories = database::get_visible_memory_ids(assistant_id).map_err(|e| e.to_string())?;
+
+    let mut seen: HashSet<String> = history.iter().map(|text| normalize(text)).collect();
+    let mut items = Vec::new();
+    for candidate in fused {
+        let item = match candidate.source {
+            SearchSource::Message => match messages.get(&candidate.id) {
+                Some(message) => RetrievedItem {
+                    source: candidate.source,
+                    source_id: candidate.id,
+                    content: message.content.clone(),
+                    session_id: Some(message.session_id),
+                    session_title: Some(message.session_title.clone()),
+                    is_user: Some(message.is_user),
+                    score: candidate.score,
+                    keyword_rank: candidate.keyword_rank,
+                    bm25: candidate.bm25,
+                    vector_rank: candidate.vector_rank,
+                    similarity: candidate.similarity,
+                },
+                _ => continue,
+            },
+            SearchSource::Memory => match memories.get(&candidate.id) {
+                Some(memory) if visible_memories.contains(&memory.id) => RetrievedItem {
+                    source: candidate.source,
+                    source_id: candidate.id,
+                    content: memory.content.clone(),
+                    session_id: None,
+                    session_title: None,
+                    is_user: None,
+                    score: candidate.score,
+                    keyword_rank: candidate.keyword_rank,
+                    bm25: candidate.bm25,
+                    vector_rank: candidate.vector_rank,
+                    similarity: candidate.similarity,
+                },
+                _ => continue,
+            },
+            SearchSource::Session => continue,
+        };
+        // Same text as the history or as a better item
+        if !seen.insert(normalize(&item.content)) {
+            continue;
+        }
+        items.push(item);
+        if items.len() >= config.limit {
+            break;
+        }
+    }
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse_prefers_agreement() {
+        let config = RetrievalConfig::default();
+        let keyword = [
+            (SearchSource::Message, 1, -3.0),
+            (SearchSource::Memory, 7, -2.0),
+            (SearchSource::Message, 2, -1.0),
+        ];
+        let vector = [
+            (SearchSource::Message, 2, 0.9),
+            (SearchSource::Message, 5, 0.8),
+            (SearchSource::Memory, 7, 0.7),
+        ];
+
+        let fused = fuse(&keyword, &vector, &config);
+        let order: Vec<(SearchSource, i64)> = fused.iter().map(|f| (f.source, f.id)).collect();
+        // Message 2 ranks 3rd and 1st, memory 7 2nd and 3rd: both beat single hits
+        assert_eq!(order[..2], [(SearchSource::Message, 2), (SearchSource::Memory, 7)]);
+        assert_eq!(order.len(), 4);
+
+        let memory = &fused[1];
+        assert_eq!((memory.keyword_rank, memory.vector_rank), (Some(2), Some(3)));
+        assert_eq!(memory.similarity, Some(0.7));
+        assert!((memory.score - (1.0 / 62.0 + 1.0 / 63.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_fuse_weights() {
+        let keyword = [(SearchSource::Message, 1, -3.0)];
+        let vector = [(SearchSource::Message, 2, 0.9)];
+
+        let vector_only = RetrievalConfig { keyword_weight: 0.0, ..RetrievalConfig::default() };
+        assert_eq!(fuse(&keyword, &vector, &vector_only)[0].id, 2);
+        let keyword_first = RetrievalConfig { keyword_weight: 2.0, ..RetrievalConfig::default() };
+        assert_eq!(fuse(&keyword, &vector, &keyword_first)[0].id, 1);
+    }
+
+    fn isolated_assistant() -> i64 {
+        let assistant: database::Assistant = serde_json::from_value(serde_json::json!({
+            "name": "Тайный", "memoryMode": "isolated"
+        })).unwrap();
+        database::create_assistant(&assistant).unwrap()
+    }
+
+    fn retrieved_sessions(session_id: i64, assistant_id: Option<i64>) -> Vec<i64> {
+        let config = RetrievalConfig { limit: 10, ..RetrievalConfig::default() };
+        retrieve("кофе", session_id, assistant_id, &[], &config).unwrap()
+            .iter()
+            .filter_map(|item| item.session_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_isolated_assistant_chats_stay_apart() {
+        let _db = database::test_database();
+        let assistant_id = isolated_assistant();
+        let shared = database::create_session("общий", None).unwrap();
+        let isolated = database::create_session("тайный", Some(assistant_id)).unwrap();
+        database::insert_message(shared, "люблю кофе по утрам", true, None, None).unwrap();
+        database::insert_message(isolated, "тайный рецепт кофе", true, None, None).unwrap();
+
+        let current = database::create_session("новый", None).unwrap();
+        assert_eq!(retrieved_sessions(current, None), vec![shared]);
+        let current = database::create_session("новый тайный", Some(assistant_id)).unwrap();
+        assert_eq!(retrieved_sessions(current, Some(assistant_id)), vec![isolated]);
+    }
+
+    #[test]
+    fn test_other_chats_are_found_when_the_current_one_matches_best() {
+        let _db = database::test_database();
+        let other = database::create_session("старый", None).unwrap();
+        database::insert_message(other, "кофе лучше без сахара", false, None, None).unwrap();
+        let current = database::create_session("длинный", None).unwrap();
+        for i in 0..RetrievalConfig::default().candidates * 2 {
+            database::insert_message(current, &format!("кофе кофе кофе {}", i), i % 2 == 0, None, None).unwrap();
+        }
+
+        assert_eq!(retrieved_sessions(current, None), vec![other]);
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  Меня  зовут\nАня "), normalize("меня зовут аня"));
+    }
+}
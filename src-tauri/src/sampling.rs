@@ -0,0 +1,30 @@
+//! Sampler settings shared by the generation backends and the stored assistant configs.
+
+use serde::{Deserialize, Serialize};
+
+/// Sampling parameters for one generation run. Neutral values turn a sampler off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SamplingParams {
+    pub temperature: f32,
+    /// 1.0 = off
+    pub top_p: f32,
+    /// 0 = off
+    pub top_k: i32,
+    /// 0.0 = off
+    pub min_p: f32,
+    /// 1.0 = off
+    pub repeat_penalty: f32,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0.7,
+            top_p: 1.0,
+            top_k: 0,
+            min_p: 0.0,
+            repeat_penalty: 1.0,
+        }
+    }
+}
@@ -0,0 +1,460 @@
+//! Human-readable transcripts of one session: Markdown, standalone HTML or plain text.
+//!
+//! A transcript shows the visible branch with speaker names, timestamps and the
+//! model behind every answer. Reasoning blocks (`<think>...</think>`, as emitted by
+//! reasoning models) are left out unless asked for. Collecting the data is up to the
+//! caller; this module only renders it.
+
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+
+use crate::commands::{Message, Session};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptFormat {
+    #[default]
+    Markdown,
+    /// Standalone page with inline styles
+    Html,
+    Text,
+}
+
+impl TranscriptFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TranscriptFormat::Markdown => "md",
+            TranscriptFormat::Html => "html",
+            TranscriptFormat::Text => "txt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TranscriptOptions {
+    pub format: TranscriptFormat,
+    pub timestamps: bool,
+    /// Include the model's reasoning blocks
+    pub reasoning: bool,
+}
+
+impl Default for TranscriptOptions {
+    fn default() -> Self {
+        Self {
+            format: TranscriptFormat::default(),
+            timestamps: true,
+            reasoning: false,
+        }
+    }
+}
+
+/// Everything a transcript shows
+pub struct Transcript<'a> {
+    pub session: &'a Session,
+    /// Visible branch, oldest first
+    pub messages: &'a [Message],
+    pub assistant_name: &'a str,
+    /// Model of the session, for answers that don't record their own
+    pub default_model: Option<&'a str>,
+}
+
+impl Transcript<'_> {
+    fn model_of<'m>(&'m self, message: &'m Message) -> Option<&'m str> {
+        message.model.as_deref().or(self.default_model)
+    }
+
+    /// Models that wrote the answers, in order of first use
+    fn models(&self) -> Vec<&str> {
+        let mut models: Vec<&str> = Vec::new();
+        for message in self.messages.iter().filter(|m| !m.is_user) {
+            if let Some(model) = self.model_of(message) {
+                if !models.contains(&model) {
+                    models.push(model);
+                }
+            }
+        }
+        models
+    }
+
+    fn speaker(&self, message: &Message) -> String {
+        if message.is_user {
+            return "User".to_string();
+        }
+        match self.model_of(message) {
+            Some(model) => format!("{} ({})", self.assistant_name, model),
+            None => self.assistant_name.to_string(),
+        }
+    }
+}
+
+/// One message split into reasoning and answer text
+struct Parts {
+    reasoning: Vec<String>,
+    text: String,
+}
+
+/// Take `<think>` / `<thinking>` blocks out of an answer. An unclosed block
+/// (the answer was cut off while reasoning) runs to the end.
+fn split_reasoning(content: &str) -> Parts {
+    let mut reasoning = Vec::new();
+    let mut text = String::new();
+    let mut rest = content;
+
+    while let Some((start, open, close)) = ["<think>", "<thinking>"].iter()
+        .filter_map(|open| rest.find(open).map(|i| (i, *open)))
+        .min_by_key(|(i, _)| *i)
+        .map(|(i, open)| (i, open, if open == "<think>" { "</think>" } else { "</thinking>" }))
+    {
+        text.push_str(&rest[..start]);
+        let inner = &rest[start + open.len()..];
+        let (block, after) = match inner.find(close) {
+            Some(end) => (&inner[..end], &inner[end + close.len()..]),
+            None => (inner, ""),
+        };
+        if !block.trim().is_empty() {
+            reasoning.push(block.trim().to_string());
+        }
+        rest = after;
+    }
+    text.push_str(rest);
+
+    Parts { reasoning, text: text.trim().to_string() }
+}
+
+/// Note for answers that didn't finish normally
+fn finish_note(message: &Message) -> Option<&'static str> {
+    match message.finish_reason.as_deref() {
+        Some("length") => Some("answer cut off by the token limit"),
+        Some("cancelled") => Some("stopped"),
+        _ => None,
+    }
+}
+
+fn format_time(millis: i64) -> String {
+    chrono::Local.timestamp_millis_opt(millis)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// File name for a session's transcript: `<id>-<title>.<ext>` with the title made safe
+pub fn file_name(session: &Session, format: TranscriptFormat) -> String {
+    let title: String = session.title.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-");
+    let title: String = title.chars().take(60).collect();
+    if title.is_empty() {
+        format!("{}.{}", session.id, format.extension())
+    } else {
+        format!("{}-{}.{}", session.id, title, format.extension())
+    }
+}
+
+pub fn render(transcript: &Transcript, options: &TranscriptOptions) -> String {
+    match options.format {
+        TranscriptFormat::Markdown => render_markdown(transcript, options),
+        TranscriptFormat::Html => render_html(transcript, options),
+        TranscriptFormat::Text => render_text(transcript, options),
+    }
+}
+
+// ==================== Markdown ====================
+
+fn render_markdown(transcript: &Transcript, options: &TranscriptOptions) -> String {
+    let mut out = format!("# {}\n\n", transcript.session.title);
+    out.push_str(&format!("- Started: {}\n", format_time(transcript.session.created_at)));
+    let models = transcript.models();
+    if !models.is_empty() {
+        out.push_str(&format!("- Model: {}\n", models.join(", ")));
+    }
+    out.push_str(&format!("- Messages: {}\n\n---\n", transcript.messages.len()));
+
+    for message in transcript.messages {
+        let parts = split_reasoning(&message.content);
+        out.push_str(&format!("\n**{}**", transcript.speaker(message)));
+        if options.timestamps {
+            out.push_str(&format!(" · {}", format_time(message.timestamp)));
+        }
+        out.push_str("\n\n");
+
+        if options.reasoning {
+            for block in &parts.reasoning {
+                out.push_str(&format!("<details>\n<summary>Reasoning</summary>\n\n{}\n\n</details>\n\n", block));
+            }
+        }
+        out.push_str(&parts.text);
+        out.push('\n');
+        if let Some(note) = finish_note(message) {
+            out.push_str(&format!("\n_({})_\n", note));
+        }
+    }
+
+    out
+}
+
+// ==================== HTML ====================
+
+const HTML_STYLE: &str = r#"
+body { font-family: -apple-system, "Segoe UI", Roboto, sans-serif; max-width: 860px; margin: 2rem auto; padding: 0 1rem; color: #1f2328; line-height: 1.55; }
+header { border-bottom: 1px solid #d0d7de; margin-bottom: 1.5rem; }
+header p { color: #59636e; margin: 0.2rem 0 1rem; }
+.message { border-radius: 10px; padding: 0.75rem 1rem; margin: 0.9rem 0; }
+.user { background: #eef4ff; }
+.assistant { background: #f6f8fa; }
+.meta { font-size: 0.85rem; color: #59636e; margin-bottom: 0.4rem; }
+.meta strong { color: #1f2328; }
+.note { font-size: 0.85rem; color: #9a6700; font-style: italic; }
+pre { background: #161b22; color: #e6edf3; padding: 0.8rem; border-radius: 8px; overflow-x: auto; }
+code { font-family: ui-monospace, "SFMono-Regular", Menlo, Consolas, monospace; font-size: 0.9em; }
+p code { background: #eaeef2; padding: 0.1em 0.3em; border-radius: 4px; }
+details { margin-bottom: 0.6rem; color: #59636e; }
+"#;
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Escaped text with `inline code` spans
+fn inline_html(text: &str) -> String {
+    text.split('`')
+        .enumerate()
+        .map(|(i, piece)| {
+            // Odd pieces sit between backticks; a trailing unmatched one stays literal
+            if i % 2 == 1 && text.matches('`').count() > i {
+                format!("<code>{}</code>", escape_html(piece))
+            } else if i % 2 == 1 {
+                format!("`{}", escape_html(piece))
+            } else {
+                escape_html(piece)
+            }
+        })
+        .collect()
+}
+
+/// Message text as HTML: fenced code blocks become `<pre><code>` with their language,
+/// the rest becomes paragraphs with line breaks kept
+fn content_html(text: &str) -> String {
+    let mut out = String::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut code: Option<(String, Vec<&str>)> = None;
+
+    let flush = |paragraph: &mut Vec<&str>, out: &mut String| {
+        if !paragraph.is_empty() {
+            let lines: Vec<String> = paragraph.iter().map(|l| inline_html(l)).collect();
+            out.push_str(&format!("<p>{}</p>\n", lines.join("<br>\n")));
+            paragraph.clear();
+        }
+    };
+
+    for line in text.lines() {
+        let fence = line.trim_start().strip_prefix("```");
+        match (&mut code, fence) {
+            (Some((language, lines)), Some(_)) => {
+                let class = if language.is_empty() { String::new() } else { format!(" class=\"language-{}\"", escape_html(language)) };
+                out.push_str(&format!("<pre><code{}>{}</code></pre>\n", class, escape_html(&lines.join("\n"))));
+                code = None;
+            }
+            (Some((_, lines)), None) => lines.push(line),
+            (None, Some(language)) => {
+                flush(&mut paragraph, &mut out);
+                code = Some((language.trim().to_string(), Vec::new()));
+            }
+            (None, None) if line.trim().is_empty() => flush(&mut paragraph, &mut out),
+            (None, None) => paragraph.push(line),
+        }
+    }
+    // An unclosed fence still shows as code
+    if let Some((_, lines)) = code {
+        out.push_str(&format!("<pre><code>{}</code></pre>\n", escape_html(&lines.join("\n"))));
+    }
+    flush(&mut paragraph, &mut out);
+
+    out
+}
+
+fn render_html(transcript: &Transcript, options: &TranscriptOptions) -> String {
+    let title = escape_html(&transcript.session.title);
+    let mut meta = vec![format!("Started {}", format_time(transcript.session.created_at))];
+    let models = transcript.models();
+    if !models.is_empty() {
+        meta.push(format!("Model: {}", escape_html(&models.join(", "))));
+    }
+    meta.push(format!("{} messages", transcript.messages.len()));
+
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<header>\n<h1>{}</h1>\n<p>{}</p>\n</header>\n",
+        title, HTML_STYLE, title, meta.join(" · ")
+    );
+
+    for message in transcript.messages {
+        let parts = split_reasoning(&message.content);
+        let class = if message.is_user { "user" } else { "assistant" };
+        out.push_str(&format!("<div class=\"message {}\">\n<div class=\"meta\"><strong>{}</strong>", class, escape_html(&transcript.speaker(message))));
+        if options.timestamps {
+            out.push_str(&format!(" · {}", format_time(message.timestamp)));
+        }
+        out.push_str("</div>\n");
+
+        if options.reasoning {
+            for block in &parts.reasoning {
+                out.push_str(&format!("<details>\n<summary>Reasoning</summary>\n{}</details>\n", content_html(block)));
+            }
+        }
+        out.push_str(&content_html(&parts.text));
+        if let Some(note) = finish_note(message) {
+            out.push_str(&format!("<div class=\"note\">{}</div>\n", note));
+        }
+        out.push_str("</div>\n");
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+// ==================== Plain text ====================
+
+fn render_text(transcript: &Transcript, options: &TranscriptOptions) -> String {
+    let title = &transcript.session.title;
+    let mut out = format!("{}\n{}\n", title, "=".repeat(title.chars().count().max(3)));
+    out.push_str(&format!("Started: {}\n", format_time(transcript.session.created_at)));
+    let models = transcript.models();
+    if !models.is_empty() {
+        out.push_str(&format!("Model: {}\n", models.join(", ")));
+    }
+
+    for message in transcript.messages {
+        let parts = split_reasoning(&message.content);
+        out.push('\n');
+        if options.timestamps {
+            out.push_str(&format!("[{}] ", format_time(message.timestamp)));
+        }
+        out.push_str(&format!("{}:\n", transcript.speaker(message)));
+
+        if options.reasoning {
+            for block in &parts.reasoning {
+                out.push_str(&format!("--- reasoning ---\n{}\n--- end of reasoning ---\n", block));
+            }
+        }
+        out.push_str(&parts.text);
+        out.push('\n');
+        if let Some(note) = finish_note(message) {
+            out.push_str(&format!("({})\n", note));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session {
+            id: 7,
+            title: "Rust: <lifetimes> & borrowing".to_string(),
+            created_at: 1_700_000_000_000,
+            message_count: 2,
+            pinned: false,
+            archived: false,
+            folder: None,
+            tags: Vec::new(),
+            assistant_id: None,
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        let message = |id, is_user, content: &str, model: Option<&str>, finish_reason: Option<&str>| Message {
+            id,
+            session_id: 7,
+            content: content.to_string(),
+            is_user,
+            timestamp: 1_700_000_000_000 + id * 60_000,
+            parent_id: (id > 1).then_some(id - 1),
+            finish_reason: finish_reason.map(str::to_string),
+            model: model.map(str::to_string),
+        };
+        vec![
+            message(1, true, "Покажи пример", None, None),
+            message(2, false, "<think>Нужен короткий пример</think>\nВот:\n\n```rust\nfn main() { println!(\"<hi>\"); }\n```", Some("qwen2.5-7b"), Some("stop")),
+            message(3, true, "Ещё", None, None),
+            message(4, false, "Второй `ответ`", None, Some("length")),
+        ]
+    }
+
+    fn render_with(format: TranscriptFormat, timestamps: bool, reasoning: bool) -> String {
+        let (session, messages) = (session(), messages());
+        let transcript = Transcript {
+            session: &session,
+            messages: &messages,
+            assistant_name: "Wishmaster",
+            default_model: Some("llama-3-8b"),
+        };
+        render(&transcript, &TranscriptOptions { format, timestamps, reasoning })
+    }
+
+    #[test]
+    fn test_split_reasoning() {
+        let parts = split_reasoning("<think>a</think>ответ<thinking>b</thinking> конец");
+        assert_eq!(parts.reasoning, vec!["a", "b"]);
+        assert_eq!(parts.text, "ответ конец");
+
+        let cut = split_reasoning("<think>думаю и обрыв");
+        assert_eq!(cut.reasoning, vec!["думаю и обрыв"]);
+        assert!(cut.text.is_empty());
+    }
+
+    #[test]
+    fn test_markdown() {
+        let md = render_with(TranscriptFormat::Markdown, false, false);
+
+        assert!(md.starts_with("# Rust: <lifetimes> & borrowing\n"));
+        assert!(md.contains("- Model: qwen2.5-7b, llama-3-8b\n"));
+        assert!(md.contains("\n**Wishmaster (qwen2.5-7b)**\n\nВот:\n\n```rust\nfn main() { println!(\"<hi>\"); }\n```\n"));
+        assert!(md.contains("_(answer cut off by the token limit)_"));
+        assert!(!md.contains("Нужен короткий пример"));
+        assert!(!md.contains(" · "));
+
+        let md = render_with(TranscriptFormat::Markdown, true, true);
+        assert!(md.contains("<summary>Reasoning</summary>\n\nНужен короткий пример"));
+        assert!(md.contains("**User** · "));
+    }
+
+    #[test]
+    fn test_html_escapes_and_keeps_code() {
+        let html = render_with(TranscriptFormat::Html, true, false);
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>Rust: &lt;lifetimes&gt; &amp; borrowing</title>"));
+        assert!(html.contains("<pre><code class=\"language-rust\">fn main() { println!(&quot;&lt;hi&gt;&quot;); }</code></pre>"));
+        assert!(html.contains("<p>Второй <code>ответ</code></p>"));
+        assert!(!html.contains("<think>"));
+    }
+
+    #[test]
+    fn test_text_and_file_name() {
+        let text = render_with(TranscriptFormat::Text, false, true);
+        assert!(text.contains("\nUser:\nПокажи пример\n"));
+        assert!(text.contains("--- reasoning ---\nНужен короткий пример\n--- end of reasoning ---\nВот:"));
+        assert!(text.contains("Wishmaster (llama-3-8b):\nВторой `ответ`\n(answer cut off by the token limit)\n"));
+
+        assert_eq!(file_name(&session(), TranscriptFormat::Html), "7-Rust-lifetimes-borrowing.html");
+        let untitled = Session { title: "???".to_string(), ..session() };
+        assert_eq!(file_name(&untitled, TranscriptFormat::Text), "7.txt");
+    }
+}
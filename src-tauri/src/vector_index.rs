@@ -0,0 +1,735 @@
+//! Approximate nearest-neighbour index over the `embeddings` table.
+//!
+//! An HNSW graph (hierarchical navigable small world) kept in memory: each vector is
+//! linked to its nearest neighbours on a few layers of decreasing density, and a
+//! search walks the graph greedily from the top layer down instead of scanning every
+//! row. Nodes are keyed by the `embeddings` row id; `store_embedding` and the deletes
+//! in `database` update the graph, and rows that vanish any other way are caught when
+//! a search re-reads its candidates from the table.
+//!
+//! The graph is saved next to the database with a checksum (encrypted like the other
+//! data files) and on startup only rows added or removed since the save are applied.
+//! Nodes remember the `created_at` of their row, so a row that took the id of another
+//! one (a restored backup) is noticed too. Restoring or replacing the database drops
+//! the graph altogether (`reset`) and it is built again from the table.
+//! Small stores skip the index: a scan over the sign bits of every row picks
+//! `RESCORE_FACTOR` candidates per result there (see `embeddings::semantic_search`).
+//! Either way the candidates are re-scored on their half float vectors.
+//!
+//! The graph holds the vectors of one embedding model, the loaded one; vectors of
+//! other models are neither added to it nor searched with it.
+
+use once_cell::sync::{Lazy, OnceCell};
+use rusqlite::{params, Connection};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::RwLock;
+
+use crate::database;
+use crate::encryption;
+use crate::quantization;
+
+/// Below this many vectors searches scan the table instead
+pub const BRUTE_FORCE_BELOW: usize = 5_000;
+
+/// Links per node on the upper layers; layer 0 gets twice as many
+const M: usize = 16;
+/// Candidate list size while inserting
+const EF_CONSTRUCTION: usize = 100;
+/// Candidate list size while searching (at least the number of results)
+const EF_SEARCH: usize = 64;
+/// Save after this many changes (the rest is caught up from the table on startup)
+const SAVE_EVERY: usize = 1_000;
+
+const FILE_NAME: &str = "embeddings.index";
+const MAGIC: &[u8; 8] = b"WMHNSW03";
+
+static INDEX: Lazy<RwLock<Option<Hnsw>>> = Lazy::new(|| RwLock::new(None));
+static INDEX_PATH: OnceCell<PathBuf> = OnceCell::new();
+static UNSAVED: AtomicUsize = AtomicUsize::new(0);
+
+// ==================== Graph ====================
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    /// `embeddings.id`
+    id: i64,
+    /// `embeddings.created_at`, to tell the row from a later one with the same id
+    created_at: i64,
+    source_type: String,
+    source_id: i64,
+    /// Normalized, so similarity is a dot product
+    vector: Vec<f32>,
+    deleted: bool,
+    /// Neighbours on layers `0..=level`
+    links: Vec<Vec<u32>>,
+}
+
+/// Distance of a candidate during a layer search; ordered by distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate(f32, u32);
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hnsw {
+    /// Embedding model of the vectors
+    model: String,
+    dim: usize,
+    nodes: Vec<Node>,
+    entry: Option<u32>,
+    by_id: HashMap<i64, u32>,
+    by_source: HashMap<(String, i64), u32>,
+    deleted: usize,
+    rng: u64,
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+impl Hnsw {
+    pub fn new(model: &str, dim: usize) -> Self {
+        Self {
+            model: model.to_string(),
+            dim,
+            nodes: Vec::new(),
+            entry: None,
+            by_id: HashMap::new(),
+            by_source: HashMap::new(),
+            deleted: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Vectors that searches can return
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.deleted
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        self.by_id.get(&id).is_some_and(|&n| !self.nodes[n as usize].deleted)
+    }
+
+    /// `created_at` of the live node of row `id`
+    fn created_at(&self, id: i64) -> Option<i64> {
+        let &node = self.by_id.get(&id)?;
+        let node = &self.nodes[node as usize];
+        (!node.deleted).then_some(node.created_at)
+    }
+
+    fn distance(&self, query: &[f32], node: u32) -> f32 {
+        1.0 - dot(query, &self.nodes[node as usize].vector)
+    }
+
+    /// Random level with P(level >= l) = M^-l (xorshift, so builds are reproducible)
+    fn random_level(&mut self) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let uniform = ((self.rng >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() / (M as f64).ln()) as usize
+    }
+
+    fn max_links(level: usize) -> usize {
+        if level == 0 { M * 2 } else { M }
+    }
+
+    /// The `ef` nodes closest to `query` on `level`, reachable from `entry`; closest first
+    fn search_layer(&self, query: &[f32], entry: &[u32], ef: usize, level: usize) -> Vec<Candidate> {
+        let mut visited: HashSet<u32> = entry.iter().copied().collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        let mut found: BinaryHeap<Candidate> = BinaryHeap::new();
+        for &node in entry {
+            let candidate = Candidate(self.distance(query, node), node);
+            candidates.push(Reverse(candidate));
+            found.push(candidate);
+        }
+
+        while let Some(Reverse(Candidate(distance, node))) = candidates.pop() {
+            if found.len() >= ef && found.peek().is_some_and(|worst| distance > worst.0) {
+                break;
+            }
+            for &next in &self.nodes[node as usize].links[level] {
+                if !visited.insert(next) {
+                    continue;
+                }
+                let candidate = Candidate(self.distance(query, next), next);
+                if found.len() < ef || found.peek().is_some_and(|worst| candidate < *worst) {
+                    candidates.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+
+        found.into_sorted_vec()
+    }
+
+    /// Walk down from the top layer to `level`, one closest node at a time
+    fn descend(&self, query: &[f32], level: usize) -> Option<u32> {
+        let mut current = self.entry?;
+        let top = self.nodes[current as usize].links.len() - 1;
+        for layer in (level + 1..=top).rev() {
+            current = self.search_layer(query, &[current], 1, layer)[0].1;
+        }
+        Some(current)
+    }
+
+    /// Add a vector; a node already stored for the same row id or source is replaced
+    pub fn insert(&mut self, id: i64, source_type: &str, source_id: i64, vector: &[f32]) {
+        self.insert_row(id, 0, source_type, source_id, vector);
+    }
+
+    /// `insert` for a row of the `embeddings` table created at `created_at`
+    fn insert_row(&mut self, id: i64, created_at: i64, source_type: &str, source_id: i64, vector: &[f32]) {
+        if vector.len() != self.dim {
+            return;
+        }
+        // INSERT OR REPLACE may give a re-embedded source its old row id back
+        self.remove(id);
+        self.remove_source(source_type, source_id);
+
+        let vector = normalized(vector);
+        let level = self.random_level();
+        let node = self.nodes.len() as u32;
+        self.nodes.push(Node {
+            id,
+            created_at,
+            source_type: source_type.to_string(),
+            source_id,
+            vector: vector.clone(),
+            deleted: false,
+            links: vec![Vec::new(); level + 1],
+        });
+        self.by_id.insert(id, node);
+        self.by_source.insert((source_type.to_string(), source_id), node);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return;
+        };
+        let top = self.nodes[entry as usize].links.len() - 1;
+        let mut nearest = vec![self.descend(&vector, level.min(top)).unwrap_or(entry)];
+
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&vector, &nearest, EF_CONSTRUCTION, layer);
+            let max_links = Self::max_links(layer);
+            let neighbours: Vec<u32> = found.iter().take(max_links).map(|c| c.1).collect();
+            for &neighbour in &neighbours {
+                self.link(neighbour, node, layer);
+            }
+            self.nodes[node as usize].links[layer] = neighbours;
+            nearest = found.into_iter().map(|c| c.1).collect();
+        }
+
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+
+    /// Link `from` to `to`, dropping `from`'s farthest link when it has too many
+    fn link(&mut self, from: u32, to: u32, layer: usize) {
+        let max_links = Self::max_links(layer);
+        let links = &self.nodes[from as usize].links[layer];
+        if links.len() < max_links {
+            self.nodes[from as usize].links[layer].push(to);
+            return;
+        }
+        let base = self.nodes[from as usize].vector.clone();
+        let mut scored: Vec<Candidate> = links.iter()
+            .chain(std::iter::once(&to))
+            .map(|&n| Candidate(self.distance(&base, n), n))
+            .collect();
+        scored.sort();
+        scored.truncate(max_links);
+        self.nodes[from as usize].links[layer] = scored.into_iter().map(|c| c.1).collect();
+    }
+
+    /// Hide a node from searches; it stays in the graph as a waypoint until a rebuild
+    pub fn remove(&mut self, id: i64) {
+        if let Some(&node) = self.by_id.get(&id) {
+            self.tombstone(node);
+        }
+    }
+
+    pub fn remove_source(&mut self, source_type: &str, source_id: i64) {
+        if let Some(node) = self.by_source.remove(&(source_type.to_string(), source_id)) {
+            self.tombstone(node);
+        }
+    }
+
+    fn tombstone(&mut self, node: u32) {
+        let entry = &mut self.nodes[node as usize];
+        if entry.deleted {
+            return;
+        }
+        entry.deleted = true;
+        self.deleted += 1;
+        let key = (entry.source_type.clone(), entry.source_id);
+        if self.by_source.get(&key) == Some(&node) {
+            self.by_source.remove(&key);
+        }
+        // Waypoints cost search time; rebuild once they are the majority
+        if self.deleted * 2 > self.nodes.len() {
+            self.compact();
+        }
+    }
+
+    /// Rebuild the graph from the live nodes
+    fn compact(&mut self) {
+        let nodes = std::mem::take(&mut self.nodes);
+        let mut rebuilt = Hnsw::new(&self.model, self.dim);
+        for node in nodes.into_iter().filter(|n| !n.deleted) {
+            rebuilt.insert_row(node.id, node.created_at, &node.source_type, node.source_id, &node.vector);
+        }
+        *self = rebuilt;
+    }
+
+    /// Up to `limit` nearest live nodes as `(id, similarity)`, most similar first
+    pub fn search(&self, query: &[f32], limit: usize, source_type: Option<&str>) -> Vec<(i64, f32)> {
+        if query.len() != self.dim {
+            return Vec::new();
+        }
+        let query = normalized(query);
+        let Some(entry) = self.descend(&query, 0) else {
+            return Vec::new();
+        };
+        // Deleted and filtered-out nodes take places in the candidate list
+        let ef = EF_SEARCH.max(limit) * if source_type.is_some() { 4 } else { 1 };
+
+        self.search_layer(&query, &[entry], ef, 0)
+            .into_iter()
+            .map(|c| &self.nodes[c.1 as usize])
+            .filter(|node| !node.deleted)
+            .filter(|node| source_type.is_none_or(|st| node.source_type == st))
+            .take(limit)
+            .map(|node| (node.id, dot(&query, &node.vector)))
+            .collect()
+    }
+
+    // ==================== Persistence ====================
+
+    /// Serialized graph followed by its FNV-1a checksum
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.nodes.len() * (self.dim * 4 + M * 12) + 64);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&(self.model.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.model.as_bytes());
+        out.extend_from_slice(&(self.dim as u32).to_le_bytes());
+        out.extend_from_slice(&(self.nodes.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.entry.unwrap_or(u32::MAX).to_le_bytes());
+        out.extend_from_slice(&self.rng.to_le_bytes());
+        for node in &self.nodes {
+            out.extend_from_slice(&node.id.to_le_bytes());
+            out.extend_from_slice(&node.created_at.to_le_bytes());
+            out.push(node.source_type.len() as u8);
+            out.extend_from_slice(node.source_type.as_bytes());
+            out.extend_from_slice(&node.source_id.to_le_bytes());
+            out.push(node.deleted as u8);
+            out.push(node.links.len() as u8);
+            for x in &node.vector {
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            for links in &node.links {
+                out.extend_from_slice(&(links.len() as u32).to_le_bytes());
+                for link in links {
+                    out.extend_from_slice(&link.to_le_bytes());
+                }
+            }
+        }
+        let checksum = checksum(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// None when the data is truncated, corrupted or from another format
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (body, tail) = bytes.split_at_checked(bytes.len().checked_sub(8)?)?;
+        if checksum(body) != u64::from_le_bytes(tail.try_into().ok()?) {
+            return None;
+        }
+        let mut reader = Reader(body);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let model_len = reader.u32()? as usize;
+        let model = String::from_utf8(reader.take(model_len)?.to_vec()).ok()?;
+        let dim = reader.u32()? as usize;
+        let count = reader.u32()?;
+        let entry = Some(reader.u32()?).filter(|&e| e != u32::MAX);
+        let mut index = Hnsw::new(&model, dim);
+        index.rng = reader.u64()?;
+        index.entry = entry;
+
+        for n in 0..count {
+            let id = reader.u64()? as i64;
+            let created_at = reader.u64()? as i64;
+            let type_len = reader.take(1)?[0] as usize;
+            let source_type = String::from_utf8(reader.take(type_len)?.to_vec()).ok()?;
+            let source_id = reader.u64()? as i64;
+            let deleted = reader.take(1)?[0] != 0;
+            let levels = reader.take(1)?[0] as usize;
+            let vector = (0..dim).map(|_| reader.u32().map(f32::from_bits)).collect::<Option<Vec<_>>>()?;
+            let mut links = Vec::with_capacity(levels);
+            for _ in 0..levels {
+                let len = reader.u32()? as usize;
+                let layer = (0..len).map(|_| reader.u32().filter(|&l| l < count)).collect::<Option<Vec<_>>>()?;
+                links.push(layer);
+            }
+            if levels == 0 {
+                return None;
+            }
+            index.by_id.insert(id, n);
+            if deleted {
+                index.deleted += 1;
+            } else {
+                index.by_source.insert((source_type.clone(), source_id), n);
+            }
+            index.nodes.push(Node { id, created_at, source_type, source_id, vector, deleted, links });
+        }
+
+        if !reader.0.is_empty() || index.entry.is_some_and(|e| e >= count) {
+            return None;
+        }
+        Some(index)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (head, rest) = self.0.split_at_checked(n)?;
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+/// FNV-1a, 64 bit
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// ==================== Shared index ====================
+
+/// Rows of the table the graph is missing, and graph nodes whose row is gone
+/// or was replaced by another with the same id
+struct Changes {
+    added: Vec<(i64, i64, String, i64, Vec<f32>)>,
+    removed: Vec<i64>,
+}
+
+fn changes_since(conn: &Connection, index: &Hnsw) -> rusqlite::Result<Changes> {
+    let mut stmt = conn.prepare("SELECT id, created_at FROM embeddings WHERE model = ?1")?;
+    let rows: HashMap<i64, i64> = stmt.query_map(params![index.model], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let removed = index.by_id.keys()
+        .filter(|id| index.created_at(**id).is_some_and(|created_at| rows.get(id) != Some(&created_at)))
+        .copied()
+        .collect();
+
+    let mut stmt = conn.prepare("SELECT source_type, source_id, vector, scale FROM embeddings WHERE id = ?1")?;
+    let mut added = Vec::new();
+    for (id, created_at) in rows.into_iter().filter(|(id, created_at)| index.created_at(*id) != Some(*created_at)) {
+        let (source_type, source_id, codes, scale) = stmt.query_row(params![id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, Vec<u8>>(2)?, row.get::<_, f32>(3)?))
+        })?;
+        added.push((id, created_at, source_type, source_id, quantization::dequantize(&codes, scale)));
+    }
+    // Row ids grow with time, so a source re-embedded since the save ends up with its latest vector
+    added.sort_by_key(|(id, ..)| *id);
+
+    Ok(Changes { added, removed })
+}
+
+fn apply(index: &mut Hnsw, changes: Changes) -> usize {
+    let count = changes.added.len() + changes.removed.len();
+    for id in changes.removed {
+        index.remove(id);
+    }
+    for (id, created_at, source_type, source_id, vector) in changes.added {
+        index.insert_row(id, created_at, &source_type, source_id, &vector);
+    }
+    count
+}
+
+/// Load the saved graph of `model` from `app_dir` and bring it up to date with the
+/// table, building it from scratch when the file is missing, fails its checksum or
+/// belongs to another model. Searches scan the table until this finishes.
+pub fn load(app_dir: &Path, model: &str, dim: usize) -> Result<usize, String> {
+    let path = INDEX_PATH.get_or_init(|| app_dir.join(FILE_NAME)).clone();
+
+    let saved = if encryption::is_unlocked() && path.exists() && !encryption::is_encrypted_file(&path) {
+        // Left from before encryption was turned on
+        let _ = std::fs::remove_file(&path);
+        None
+    } else {
+        encryption::read_file(&path).ok().and_then(|bytes| Hnsw::from_bytes(&bytes))
+    };
+    let mut index = match saved {
+        Some(index) if index.model == model && index.dim == dim => index,
+        Some(_) | None => {
+            if path.exists() {
+                println!("Vector index is outdated or damaged, rebuilding");
+            }
+            Hnsw::new(model, dim)
+        }
+    };
+
+    // Inserting is the slow part and runs without holding the database
+    let changes = database::with_connection(|conn| changes_since(conn, &index))
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    let mut changed = apply(&mut index, changes);
+
+    // Rows stored meanwhile; the database lock is taken before the index, as in `store_embedding`
+    database::with_connection(|conn| -> Result<(), String> {
+        let changes = changes_since(conn, &index).map_err(|e| e.to_string())?;
+        changed += apply(&mut index, changes);
+        *INDEX.write().map_err(|e| e.to_string())? = Some(index);
+        Ok(())
+    }).map_err(|e| e.to_string())??;
+
+    if changed > 0 {
+        save()?;
+    }
+    Ok(len())
+}
+
+/// Write the graph to disk (encrypted when encryption is on)
+pub fn save() -> Result<(), String> {
+    let Some(path) = INDEX_PATH.get() else {
+        return Ok(());
+    };
+    let bytes = match INDEX.read().map_err(|e| e.to_string())?.as_ref() {
+        Some(index) => index.to_bytes(),
+        None => return Ok(()),
+    };
+    UNSAVED.store(0, AtomicOrdering::Relaxed);
+    let tmp = path.with_extension("tmp");
+    encryption::write_file(&tmp, &bytes)?;
+    std::fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+/// Drop the graph and its file after the `embeddings` table was swapped out from
+/// under it (restored backup, replacing import). Searches scan the table until
+/// `load` builds it again.
+pub fn reset() -> Result<(), String> {
+    *INDEX.write().map_err(|e| e.to_string())? = None;
+    UNSAVED.store(0, AtomicOrdering::Relaxed);
+    match INDEX_PATH.get().map(std::fs::remove_file) {
+        Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Vectors in the loaded graph; 0 until it is loaded
+pub fn len() -> usize {
+    INDEX.read().ok().and_then(|index| index.as_ref().map(Hnsw::len)).unwrap_or(0)
+}
+
+fn changed() {
+    if UNSAVED.fetch_add(1, AtomicOrdering::Relaxed) + 1 >= SAVE_EVERY {
+        if let Err(e) = save() {
+            eprintln!("Failed to save vector index: {}", e);
+        }
+    }
+}
+
+/// Record a stored vector; vectors of another model than the graph's are ignored
+pub fn insert(id: i64, created_at: i64, source_type: &str, source_id: i64, model: &str, vector: &[f32]) {
+    if let Ok(mut index) = INDEX.write() {
+        let Some(index) = index.as_mut().filter(|index| index.model == model) else { return };
+        index.insert_row(id, created_at, source_type, source_id, vector);
+    }
+    changed();
+}
+
+/// Record a deleted vector
+pub fn remove_source(source_type: &str, source_id: i64) {
+    if let Ok(mut index) = INDEX.write() {
+        let Some(index) = index.as_mut() else { return };
+        index.remove_source(source_type, source_id);
+    }
+    changed();
+}
+
+/// Drop nodes whose rows turned out to be gone
+pub fn remove(ids: &[i64]) {
+    if let Ok(mut index) = INDEX.write() {
+        let Some(index) = index.as_mut() else { return };
+        for &id in ids {
+            index.remove(id);
+        }
+    }
+}
+
+/// Candidate row ids near `query`, a vector of `model`; None when the graph isn't
+/// loaded, holds another model or is small enough that a scan is better
+pub fn search(model: &str, query: &[f32], limit: usize, source_type: Option<&str>) -> Option<Vec<(i64, f32)>> {
+    let index = INDEX.read().ok()?;
+    let index = index.as_ref().filter(|index| index.model == model && index.len() >= BRUTE_FORCE_BELOW)?;
+    Some(index.search(query, limit, source_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_vectors(count: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        };
+        (0..count).map(|_| (0..dim).map(|_| next()).collect()).collect()
+    }
+
+    fn exact(vectors: &[Vec<f32>], query: &[f32], limit: usize) -> Vec<i64> {
+        let query = normalized(query);
+        let mut scored: Vec<(i64, f32)> = vectors.iter().enumerate()
+            .map(|(i, v)| (i as i64, dot(&query, &normalized(v))))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(limit).map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn test_recall_against_brute_force() {
+        let vectors = random_vectors(2_000, 32, 7);
+        let mut index = Hnsw::new("test", 32);
+        for (i, v) in vectors.iter().enumerate() {
+            index.insert(i as i64, "message", i as i64, v);
+        }
+
+        let mut hits = 0;
+        for query in random_vectors(50, 32, 99) {
+            let expected = exact(&vectors, &query, 10);
+            let found: Vec<i64> = index.search(&query, 10, None).into_iter().map(|(id, _)| id).collect();
+            hits += found.iter().filter(|id| expected.contains(id)).count();
+        }
+        assert!(hits >= 450, "recall@10 = {}/500", hits);
+    }
+
+    #[test]
+    fn test_remove_and_replace() {
+        let vectors = random_vectors(300, 8, 3);
+        let mut index = Hnsw::new("test", 8);
+        for (i, v) in vectors.iter().enumerate() {
+            let source_type = if i % 2 == 0 { "message" } else { "memory" };
+            index.insert(i as i64, source_type, i as i64, v);
+        }
+
+        let query = vectors[10].clone();
+        assert_eq!(index.search(&query, 1, None)[0].0, 10);
+        index.remove(10);
+        assert!(index.search(&query, 5, None).iter().all(|(id, _)| *id != 10));
+        assert!(index.search(&query, 5, Some("memory")).iter().all(|(id, _)| id % 2 == 1));
+
+        // Re-embedding a source replaces its node
+        index.insert(1_000, "message", 12, &vectors[20]);
+        assert!(!index.contains(12));
+        assert_eq!(index.len(), 299);
+
+        // ...also when the new row got the old row id
+        index.insert(1_000, "message", 12, &vectors[30]);
+        assert_eq!(index.len(), 299);
+        assert_eq!(index.search(&vectors[30], 2, None).iter().filter(|(id, _)| *id == 1_000).count(), 1);
+        assert!(index.search(&vectors[20], 1, None)[0].0 != 1_000);
+
+        // Deleting most nodes triggers a rebuild that keeps the rest searchable
+        for id in 0..250 {
+            index.remove(id);
+        }
+        assert_eq!(index.len(), 51);
+        assert!(index.nodes.len() < 301 && index.deleted * 2 <= index.nodes.len());
+        assert_eq!(index.search(&vectors[299], 1, None)[0].0, 299);
+    }
+
+    #[test]
+    fn test_changes_since_notices_reused_ids() {
+        let vectors = random_vectors(4, 8, 11);
+        let mut index = Hnsw::new("test", 8);
+        index.insert_row(1, 100, "message", 1, &vectors[0]);
+        index.insert_row(2, 200, "message", 2, &vectors[1]);
+
+        // Row 1 is unchanged, row 2 comes from another database, row 3 is new
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE embeddings (id INTEGER PRIMARY KEY, source_type TEXT, source_id INTEGER, \
+             vector BLOB, scale REAL, model TEXT, created_at INTEGER)",
+        ).unwrap();
+        for (id, created_at, vector) in [(1, 100, &vectors[0]), (2, 300, &vectors[2]), (3, 300, &vectors[3])] {
+            let quantized = quantization::quantize(vector);
+            conn.execute(
+                "INSERT INTO embeddings VALUES (?1, 'message', ?1, ?2, ?3, 'test', ?4)",
+                params![id, quantized.codes, quantized.scale, created_at],
+            ).unwrap();
+        }
+
+        let changes = changes_since(&conn, &index).unwrap();
+        assert_eq!(changes.removed, vec![2]);
+        assert_eq!(changes.added.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(apply(&mut index, changes), 3);
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.created_at(2), Some(300));
+        assert_eq!(index.search(&vectors[2], 1, None)[0].0, 2);
+        assert_eq!(changes_since(&conn, &index).unwrap().added.len(), 0);
+    }
+
+    #[test]
+    fn test_persistence_checksum() {
+        let mut index = Hnsw::new("test", 8);
+        for (i, v) in random_vectors(100, 8, 5).iter().enumerate() {
+            index.insert(i as i64, "message", i as i64, v);
+        }
+        index.remove(3);
+
+        let mut bytes = index.to_bytes();
+        assert_eq!(Hnsw::from_bytes(&bytes).as_ref(), Some(&index));
+
+        bytes[40] ^= 1;
+        assert_eq!(Hnsw::from_bytes(&bytes), None);
+        assert_eq!(Hnsw::from_bytes(&bytes[..20]), None);
+    }
+}
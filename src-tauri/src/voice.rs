@@ -4,6 +4,8 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use once_cell::sync::OnceCell;
 
+use crate::encryption;
+
 // State flags
 static IS_RECORDING: AtomicBool = AtomicBool::new(false);
 static IS_SPEAKING: AtomicBool = AtomicBool::new(false);
@@ -54,6 +56,9 @@ pub fn init() {
     let _ = CURRENT_STT.set(Mutex::new(SttEngine::None));
     let _ = LAST_AUDIO_PATH.set(Mutex::new(None));
     
+    // WAVs left behind by a crash in the middle of a transcription
+    let _ = std::fs::remove_dir_all(stt_temp_dir());
+    
     // Check which TTS is available
     let tts = detect_tts_engine();
     if let Some(engine) = CURRENT_TTS.get() {
@@ -169,9 +174,28 @@ fn find_whisper_model() -> Option<PathBuf> {
     None
 }
 
-/// Convert WebM/audio file to WAV 16kHz mono (required for whisper.cpp)
-fn convert_to_wav(input_path: &Path) -> Result<PathBuf, String> {
-    let output_path = input_path.with_extension("wav");
+/// Directory of the WAVs handed to whisper; they may hold decrypted audio
+fn stt_temp_dir() -> PathBuf {
+    std::env::temp_dir().join("wishmaster-stt")
+}
+
+/// Convert WebM/audio file to WAV 16kHz mono (required for whisper.cpp).
+/// The WAV is a private temp file, deleted when the returned path is dropped.
+fn convert_to_wav(input_path: &Path) -> Result<tempfile::TempPath, String> {
+    let dir = stt_temp_dir();
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create temp dir: {}", e))?;
+    let output_path = tempfile::Builder::new()
+        .suffix(".wav")
+        .tempfile_in(&dir)
+        .map_err(|e| format!("Failed to create temp file: {}", e))?
+        .into_temp_path();
+    
+    // Encrypted recordings are decrypted in memory and piped in; only the WAV
+    // for whisper reaches the disk
+    if encryption::is_encrypted_file(input_path) {
+        let audio = encryption::read_file(input_path)?;
+        return convert_bytes_to_wav(audio, output_path);
+    }
     
     // Use ffmpeg for conversion
     let result = Command::new("ffmpeg")
@@ -212,6 +236,39 @@ fn convert_to_wav(input_path: &Path) -> Result<PathBuf, String> {
     }
 }
 
+/// Same as `convert_to_wav`, reading the audio from ffmpeg's stdin
+fn convert_bytes_to_wav(audio: Vec<u8>, output_path: tempfile::TempPath) -> Result<tempfile::TempPath, String> {
+    use std::io::Write;
+    use std::process::Stdio;
+    
+    let mut child = Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-i", "pipe:0",
+            "-ar", "16000",
+            "-ac", "1",
+            "-c:a", "pcm_s16le",
+            output_path.to_str().unwrap_or("")
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Audio conversion failed. Install ffmpeg: sudo apt install ffmpeg\nError: {}", e))?;
+    
+    // Feed stdin from another thread so a full stderr pipe cannot deadlock us
+    let mut stdin = child.stdin.take().ok_or("ffmpeg stdin unavailable")?;
+    let writer = std::thread::spawn(move || stdin.write_all(&audio));
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let _ = writer.join();
+    
+    if output.status.success() {
+        Ok(output_path)
+    } else {
+        Err(format!("ffmpeg conversion failed: {}", String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
 /// Transcribe audio using whisper.cpp
 fn transcribe_whisper_cpp(wav_path: &Path, model_path: &Path) -> Result<String, String> {
     println!("Transcribing with whisper.cpp: {:?}", wav_path);
@@ -338,12 +395,6 @@ pub fn stop_recording() -> Result<String, String> {
     
     match stt_engine {
         SttEngine::WhisperCpp => {
-            // Convert to WAV for whisper.cpp
-            let wav_path = match convert_to_wav(audio_file) {
-                Ok(p) => p,
-                Err(e) => return Err(format!("Audio conversion failed: {}", e)),
-            };
-            
             // Find whisper model
             let model_path = match find_whisper_model() {
                 Some(p) => p,
@@ -353,27 +404,22 @@ pub fn stop_recording() -> Result<String, String> {
                 ),
             };
             
-            // Transcribe
-            let result = transcribe_whisper_cpp(&wav_path, &model_path);
-            
-            // Cleanup temp WAV
-            let _ = std::fs::remove_file(&wav_path);
+            // Convert to WAV for whisper.cpp (removed when it goes out of scope)
+            let wav_path = match convert_to_wav(audio_file) {
+                Ok(p) => p,
+                Err(e) => return Err(format!("Audio conversion failed: {}", e)),
+            };
             
-            result
+            transcribe_whisper_cpp(&wav_path, &model_path)
         }
         SttEngine::WhisperPython => {
-            // Convert to WAV for whisper
+            // Convert to WAV for whisper (removed when it goes out of scope)
             let wav_path = match convert_to_wav(audio_file) {
                 Ok(p) => p,
                 Err(e) => return Err(format!("Audio conversion failed: {}", e)),
             };
             
-            let result = transcribe_whisper_python(&wav_path);
-            
-            // Cleanup temp WAV
-            let _ = std::fs::remove_file(&wav_path);
-            
-            result
+            transcribe_whisper_python(&wav_path)
         }
         SttEngine::None => {
             // STT not available - frontend will use Web Speech API
@@ -396,19 +442,14 @@ pub fn transcribe_audio(audio_path: &str) -> Result<String, String> {
     
     match stt_engine {
         SttEngine::WhisperCpp => {
-            let wav_path = convert_to_wav(audio_file)?;
             let model_path = find_whisper_model()
                 .ok_or_else(|| "Whisper model not found".to_string())?;
-            
-            let result = transcribe_whisper_cpp(&wav_path, &model_path);
-            let _ = std::fs::remove_file(&wav_path);
-            result
+            let wav_path = convert_to_wav(audio_file)?;
+            transcribe_whisper_cpp(&wav_path, &model_path)
         }
         SttEngine::WhisperPython => {
             let wav_path = convert_to_wav(audio_file)?;
-            let result = transcribe_whisper_python(&wav_path);
-            let _ = std::fs::remove_file(&wav_path);
-            result
+            transcribe_whisper_python(&wav_path)
         }
         SttEngine::None => Err("No STT engine available".to_string())
     }